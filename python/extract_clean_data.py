@@ -16,6 +16,14 @@ for date in data:
             url = imgs[0]['url']
             img_name = f"{app_num}_{os.path.basename(url)}"
             index['imageName'] = img_name
+            # Prefer the labels recorded in the image's sidecar when the downloader wrote one
+            sidecar_path = f"dset/imgs/{img_name}.json"
+            if os.path.exists(sidecar_path):
+                with open(sidecar_path, 'r') as f:
+                    sidecar = json.load(f)
+                for k in ['descrOfDevice', 'chineseCharacter', 'wordsInMark']:
+                    if k in sidecar:
+                        index[k] = sidecar[k]
             entries.append(index)
         else:
             invalids.append((app_num, index, imgs))
@@ -1,6 +1,6 @@
 use anyhow::{Context, Result};
 use chrono::{Duration, NaiveDate};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
@@ -8,18 +8,25 @@ use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::BufWriter;
 use std::path::{Path, PathBuf};
+use tm_query::data::{self, DownloadTask};
+use tm_query::images::{self, ImagePhaseReport, ImageArgs, checksum, failures, manifest};
+use tm_query::images::shard::{self, Shard};
 use tokio::time::sleep;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Start date in YYYY-MM-DD format
-    #[arg(short, long)]
-    start_date: String,
+    #[arg(short, long, required = true)]
+    start_date: Option<String>,
 
     /// End date in YYYY-MM-DD format
-    #[arg(short, long)]
-    end_date: String,
+    #[arg(short, long, required = true)]
+    end_date: Option<String>,
 
     /// Output file path
     #[arg(short, long, default_value = "trademark_data.json")]
@@ -30,16 +37,70 @@ struct Args {
     chunk_size: u64,
 
     /// Maximum concurrent requests
-    #[arg(short = 'p', long, default_value_t = 30)]
+    #[arg(short = 'p', long, default_value_t = 30, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
     concurrency: usize,
 
     /// Download trademark images
     #[arg(short, long)]
     download_images: bool,
 
-    /// Directory to save images (defaults to ./images)
-    #[arg(long, default_value = "images")]
-    images_dir: PathBuf,
+    #[command(flatten)]
+    images: ImageArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download images for an existing data file without querying the API
+    Images {
+        /// Data file written by a previous fetch (.json, or .jsonl/.ndjson)
+        #[arg(required_unless_present = "retry_image_failures")]
+        input: Option<PathBuf>,
+
+        /// Retry exactly the documents in this failure report, rewriting it with those that still fail
+        #[arg(long, conflicts_with = "input")]
+        retry_image_failures: Option<PathBuf>,
+
+        /// Maximum concurrent downloads
+        #[arg(short = 'p', long, default_value_t = 30, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        concurrency: usize,
+
+        /// Regenerate the manifest from the files already on disk instead of downloading
+        #[arg(long)]
+        rebuild_manifest: bool,
+
+        #[command(flatten)]
+        images: Box<ImageArgs>,
+    },
+
+    /// Re-hash the images directory and compare it against SHA256SUMS
+    VerifyImages {
+        /// Directory containing the images
+        #[arg(long, default_value = "images")]
+        images_dir: PathBuf,
+
+        /// Checksum file (defaults to <images-dir>/SHA256SUMS)
+        #[arg(long)]
+        sums: Option<PathBuf>,
+
+        /// Number of files hashed in parallel (defaults to the number of CPUs)
+        #[arg(long)]
+        workers: Option<usize>,
+    },
+
+    /// Move existing images into another directory layout without re-downloading
+    Reshard {
+        /// Directory containing the images
+        #[arg(long, default_value = "images")]
+        images_dir: PathBuf,
+
+        /// Image manifest path (defaults to <images-dir>/images_manifest.json)
+        #[arg(long)]
+        manifest: Option<PathBuf>,
+
+        /// Layout to migrate to
+        #[arg(long, value_enum)]
+        shard: Shard,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -49,61 +110,155 @@ struct ApiResponse {
     items: Vec<Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Document {
-    #[serde(rename = "fileName")]
-    file_name: String,
+async fn run_images_only(input: &Path, concurrency: usize, rebuild_manifest: bool, args: &ImageArgs) -> Result<()> {
+    if let Some(csv_path) = &args.export_documents_csv {
+        return export_documents_csv(input, args, csv_path);
+    }
 
-    #[serde(rename = "lodgementDate")]
-    lodgement_date: String,
+    println!("Reading download tasks from {}", input.display());
 
-    #[serde(rename = "docType")]
-    doc_type: DocumentType,
+    let mut download_tasks = Vec::new();
+    data::for_each_record(input, |record| {
+        for item in &record.items {
+            download_tasks.extend(data::download_tasks(item));
+        }
+        Ok(())
+    })?;
 
-    #[serde(rename = "fileId")]
-    file_id: String,
+    if rebuild_manifest {
+        return images::rebuild_manifest(&download_tasks, args);
+    }
+    if let Some(export_path) = &args.export_urls {
+        images::export::export_urls(&download_tasks, args, export_path)?;
+        return Ok(());
+    }
+
+    // Documents of old data files may need their URLs refreshed
+    let mut args = args.clone();
+    args.data_file_age = fs::metadata(input)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok());
+
+    println!("Will download trademark images to {}", args.images_dir.display());
+    let client = Client::new();
+    let report = images::run_image_phase(&client, &download_tasks, &args, concurrency).await?;
+    exit_if_failed(&report);
 
-    url: String,
+    Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct DocumentType {
-    description: String,
-    code: String,
+/// Stream the data file into the documents CSV without collecting the tasks
+fn export_documents_csv(input: &Path, args: &ImageArgs, csv_path: &Path) -> Result<()> {
+    println!("Reading documents from {}", input.display());
+
+    let mut csv = images::export::DocumentsCsv::create(csv_path, args)?;
+    data::for_each_record(input, |record| {
+        for item in &record.items {
+            for task in data::download_tasks(item) {
+                csv.write(&task)?;
+            }
+        }
+        Ok(())
+    })?;
+    let rows = csv.finish()?;
+
+    println!("Exported {} documents to {}", rows, csv_path.display());
+    Ok(())
+}
+
+async fn run_retry_failures(report_path: &Path, concurrency: usize, args: &ImageArgs) -> Result<()> {
+    let download_tasks: Vec<DownloadTask> = failures::load(report_path)?
+        .into_iter()
+        .map(|failure| failure.task)
+        .collect();
+    println!("Retrying {} failed documents from {}", download_tasks.len(), report_path.display());
+
+    // The report is rewritten with whatever still fails
+    let mut args = args.clone();
+    args.failures = Some(report_path.to_path_buf());
+
+    let client = Client::new();
+    let report = images::run_image_phase(&client, &download_tasks, &args, concurrency).await?;
+    exit_if_failed(&report);
+
+    Ok(())
 }
 
-async fn download_image(
-    client: &Client,
-    url: &str,
-    app_num: &str,
-    file_name: &str,
-    dir: &Path
-) -> Result<PathBuf> {
-    // Path for the image file
-    let img_path = dir.join(format!("{}_{}", app_num, file_name));
-
-    // Check if file already exists
-    if img_path.exists() {
-        return Ok(img_path);
+/// Exit with status 1 when downloads failed or were left undone, after
+/// everything was saved
+fn exit_if_failed(report: &ImagePhaseReport) {
+    if report.failed > 0 || report.not_attempted > 0 {
+        std::process::exit(1);
     }
+}
+
+async fn run_verify_images(images_dir: &Path, sums: Option<&Path>, workers: Option<usize>) -> Result<()> {
+    let sums_path = sums.map(Path::to_path_buf)
+        .unwrap_or_else(|| images_dir.join(checksum::SUMS_FILE_NAME));
+    let workers = workers.unwrap_or_else(|| {
+        std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4)
+    });
 
-    // Download the image
-    let response = client.get(url).send().await.context("Failed to download image")?;
-    let bytes = response.bytes().await.context("Failed to read image bytes")?;
+    println!("Verifying {} against {} with {} workers", images_dir.display(), sums_path.display(), workers);
+    let report = checksum::verify(images_dir, &sums_path, workers).await?;
 
-    // Save the image to file
-    fs::write(&img_path, bytes).context("Failed to save image file")?;
+    for name in &report.missing {
+        println!("MISSING   {}", name);
+    }
+    for name in &report.extra {
+        println!("EXTRA     {}", name);
+    }
+    for name in &report.corrupted {
+        println!("CORRUPTED {}", name);
+    }
+    println!("Checked {} files: {} missing, {} extra, {} corrupted",
+        report.checked,
+        report.missing.len(),
+        report.extra.len(),
+        report.corrupted.len()
+    );
+
+    if !report.is_ok() {
+        std::process::exit(report.exit_code());
+    }
 
-    Ok(img_path)
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    let start_date = NaiveDate::parse_from_str(&args.start_date, "%Y-%m-%d")
+    match &args.command {
+        Some(Command::Images { input, concurrency, rebuild_manifest, retry_image_failures, images }) => {
+            if let Some(report_path) = retry_image_failures {
+                return run_retry_failures(report_path, *concurrency, images).await;
+            }
+            let input = input.as_deref().context("An input data file is required")?;
+            return run_images_only(input, *concurrency, *rebuild_manifest, images).await;
+        }
+        Some(Command::VerifyImages { images_dir, sums, workers }) => {
+            return run_verify_images(images_dir, sums.as_deref(), *workers).await;
+        }
+        Some(Command::Reshard { images_dir, manifest, shard }) => {
+            let manifest_path = manifest.clone()
+                .unwrap_or_else(|| images_dir.join(manifest::MANIFEST_FILE_NAME));
+            let resharded = shard::reshard(images_dir, &manifest_path, *shard)?;
+            println!("Moved {} images into the {:?} layout", resharded.moved, shard);
+            if !resharded.skipped.is_empty() {
+                println!("Left {} images in place, another file already at their new path: {}", resharded.skipped.len(),
+                    resharded.skipped.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "));
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+
+    // Both dates are required whenever no subcommand is given
+    let start_date = NaiveDate::parse_from_str(args.start_date.as_deref().unwrap_or_default(), "%Y-%m-%d")
         .context("Failed to parse start date")?;
-    let end_date = NaiveDate::parse_from_str(&args.end_date, "%Y-%m-%d")
+    let end_date = NaiveDate::parse_from_str(args.end_date.as_deref().unwrap_or_default(), "%Y-%m-%d")
         .context("Failed to parse end date")?;
 
     if start_date > end_date {
@@ -115,8 +270,8 @@ async fn main() -> Result<()> {
     println!("Maximum concurrent requests: {}", args.concurrency);
 
     if args.download_images {
-        println!("Will download trademark images to {}", args.images_dir.display());
-        fs::create_dir_all(&args.images_dir).context("Failed to create images directory")?;
+        println!("Will download trademark images to {}", args.images.images_dir.display());
+        fs::create_dir_all(&args.images.images_dir).context("Failed to create images directory")?;
     }
 
     let client = Client::new();
@@ -141,10 +296,7 @@ async fn main() -> Result<()> {
             let client = client.clone();
 
             tasks.push(tokio::spawn(async move {
-                let url = format!(
-                    "https://api.data.gov.sg/v1/technology/ipos/trademarks?lodgement_date={}",
-                    date_str
-                );
+                let url = format!("{}?lodgement_date={}", data::API_URL, date_str);
 
                 println!("Fetching data for date: {}", date_str);
 
@@ -183,21 +335,20 @@ async fn main() -> Result<()> {
 
         // Process results from this batch
         for task in tasks {
-            if let Ok(result) = task.await {
-                if let Ok((date, response)) = result {
-                    all_data.insert(date, response);
-                }
+            if let Ok(Ok((date, response))) = task.await {
+                all_data.insert(date, response);
             }
         }
 
+        let batches = total_dates.div_ceil(args.concurrency);
         println!("Completed batch {}/{} ({:.1}%)",
             i + 1,
-            (total_dates + args.concurrency - 1) / args.concurrency,
-            (i + 1) as f64 * 100.0 / ((total_dates + args.concurrency - 1) / args.concurrency) as f64
+            batches,
+            (i + 1) as f64 * 100.0 / batches as f64
         );
 
         // Add delay between batches to avoid rate limiting
-        sleep(tokio::time::Duration::from_millis(500)).await;
+        sleep(data::BATCH_DELAY).await;
     }
 
     // Save all data to output file
@@ -221,78 +372,31 @@ async fn main() -> Result<()> {
 
     println!("Successfully saved trademark data to {}", args.output.display());
 
-    // Download images if requested
-    if args.download_images && !all_data.is_empty() {
-        println!("Downloading trademark images...");
-        let mut download_tasks = Vec::new();
-        let mut total_tasks = 0;
-
-        // Collect all download tasks
-        for (_, api_response) in all_data.iter() {
-            for item in &api_response.items {
-                if let Some(documents) = item.get("documents").and_then(|d| d.as_array()) {
-                    if let Some(app_num) = item.get("applicationNum").and_then(|a| a.as_str()) {
-                        for doc in documents {
-                            if let (Some(url), Some(file_name)) = (doc.get("url").and_then(|u| u.as_str()),
-                                                                 doc.get("fileName").and_then(|f| f.as_str())) {
-                                // Store the task information
-                                download_tasks.push((url.to_string(), app_num.to_string(), file_name.to_string()));
-                                total_tasks += 1;
-                            }
-                        }
-                    }
-                }
-            }
-        }
-
-        println!("Found {} images to download", total_tasks);
-        let mut downloaded_count = 0;
-
-        // Process in batches to control concurrency
-        for (batch_idx, chunk) in download_tasks.chunks(args.concurrency).enumerate() {
-            let mut tasks: Vec<tokio::task::JoinHandle<bool>> = Vec::new();
-
-            for (url, app_num, file_name) in chunk {
-                let client = client.clone();
-                let url = url.clone();
-                let app_num = app_num.clone();
-                let file_name = file_name.clone();
-                let images_dir = args.images_dir.clone();
-
-                tasks.push(tokio::spawn(async move {
-                    let result = match download_image(&client, &url, &app_num, &file_name, &images_dir).await {
-                        Ok(_) => true,
-                        Err(e) => {
-                            eprintln!("Failed to download image {}: {}", url, e);
-                            false
-                        }
-                    };
-                    result
-                }));
-            }
-
-            // Process results from this batch
-            for task in tasks {
-                if let Ok(result) = task.await {
-                    if result {
-                        downloaded_count += 1;
-                    }
-                }
-            }
-
-            println!("Completed batch {}/{} - Downloaded {}/{} images ({:.1}%)",
-                batch_idx + 1,
-                (total_tasks + args.concurrency - 1) / args.concurrency,
-                downloaded_count,
-                total_tasks,
-                downloaded_count as f64 * 100.0 / total_tasks as f64
-            );
+    let download_tasks: Vec<DownloadTask> = all_data.values()
+        .flat_map(|api_response| api_response.items.iter())
+        .flat_map(data::download_tasks)
+        .collect();
 
-            // Add delay between batches to avoid rate limiting
-            sleep(tokio::time::Duration::from_millis(500)).await;
+    // Export the document inventory or URLs instead of downloading them if requested
+    if let Some(csv_path) = &args.images.export_documents_csv {
+        let mut csv = images::export::DocumentsCsv::create(csv_path, &args.images)?;
+        for task in &download_tasks {
+            csv.write(task)?;
         }
+        let rows = csv.finish()?;
+        println!("Exported {} documents to {}", rows, csv_path.display());
+        return Ok(());
+    }
+    if let Some(export_path) = &args.images.export_urls {
+        images::export::export_urls(&download_tasks, &args.images, export_path)?;
+        return Ok(());
+    }
 
-        println!("Downloaded {}/{} images", downloaded_count, total_tasks);
+    // Download images if requested
+    if args.download_images && !all_data.is_empty() {
+        println!("Downloading trademark images...");
+        let report = images::run_image_phase(&client, &download_tasks, &args.images, args.concurrency).await?;
+        exit_if_failed(&report);
     }
 
     Ok(())
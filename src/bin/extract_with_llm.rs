@@ -1,260 +1,3580 @@
 use anyhow::{anyhow, Context, Result};
+use base64::write::EncoderStringWriter;
 use base64::{engine::general_purpose, Engine as _};
 use chrono::Local;
-use futures::future::join_all;
-use log::{warn, error, debug};
-use opencc_rust::OpenCC;
-use regex::Regex;
+use clap::{Parser, Subcommand};
+use indicatif::MultiProgress;
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::fs::{self, File};
-use std::io::{Read, Write};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::{OnceCell, Semaphore};
 use tokio::task;
-use rand::{rngs::StdRng, SeedableRng};
-use rand::seq::SliceRandom;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tm_query::extract::autotune::ConcurrencyTuner;
+use tm_query::extract::baseline::{self, Baseline};
+use tm_query::extract::bootstrap::Bootstrap;
+use tm_query::extract::breakdown::BucketBounds;
+use tm_query::extract::budget::{self, PayloadBudget, PayloadPermit};
+use tm_query::extract::cache::ResponseCache;
+use tm_query::extract::compare::{self, Comparison, ModelRun, ModelSpec, PairedComparison};
+use tm_query::extract::config::{self, RunConfig};
+use tm_query::extract::correlation::{self, RequestRecord};
+use tm_query::extract::dataset::{self, Input, MISSING_EXAMPLES};
+use tm_query::extract::dedup;
+use tm_query::extract::detect;
+use tm_query::extract::device::{self, DescriptionScorer, TokenOverlap};
+use tm_query::extract::dispatch::{self, Stages};
+use tm_query::extract::drift;
+use tm_query::extract::earlystop::{EarlyStop, EarlyStopSummary};
+use tm_query::extract::endpoints::{self, Endpoints};
+use tm_query::extract::enrich;
+use tm_query::extract::failures::{self, FailureList};
+use tm_query::extract::fewshot::FewShot;
+use tm_query::extract::format::{self, ImageKind};
+use tm_query::extract::health;
+use tm_query::extract::interrupt::{self, Interrupt};
+use tm_query::extract::ipos::OutputSchema;
+use tm_query::extract::jobs::{self, Job, Labels, Sampling, Selected};
+use tm_query::extract::logging::{self, LogFormat};
+use tm_query::extract::results::{self, Checkpoint, CsvResultsWriter, ExtractionResult, Mismatch, ResultsWriter, Status};
+use tm_query::extract::metrics::{self, Outcome, Scoreboard};
+use tm_query::extract::normalize::{Normalize, Normalizer, TextRules, Whitespace};
+use tm_query::extract::outputs::{self, Writers};
+use tm_query::extract::parse::ParseMethod;
+use tm_query::extract::pipeline::PipelineStats;
+use tm_query::extract::progress::Progress;
+use tm_query::extract::preprocess::Preprocess;
+use tm_query::extract::prompt::Prompt;
+use tm_query::extract::protocol::{self, ApiResponse, Backend, Field, Generation, ImageEncoding, Protocol};
+use tm_query::extract::ratelimit::RequestLimiter;
+use tm_query::extract::raw_data;
+use tm_query::extract::remote::{self, DatasetSource};
+use tm_query::extract::resize::{self, Resize, SentImage};
+use tm_query::extract::responses::{ResponseLog, SavedResponse};
+use tm_query::extract::retry;
+use tm_query::extract::review::{self, LabelReview};
+use tm_query::extract::sample::{self, Stratify};
+use tm_query::extract::script::{self, Script};
+use tm_query::extract::skiplist::{self, SkipList};
+use tm_query::extract::snapshot::Snapshot;
+use tm_query::extract::source::ImageSource;
+use tm_query::extract::split::{self, Ratios, SplitCount, SplitManifest, StratumCount};
+use tm_query::extract::sweep::{Setting, Sweep};
+use tm_query::extract::terms;
+use tm_query::extract::usage::{Pricing, Usage};
+use tm_query::extract::validate;
+use tm_query::extract::vote::{self, Votes};
+use tm_query::extract::words;
+use tm_query::images::{self, throttle};
 
-// Structure for the dataset entries
-#[derive(Debug, Deserialize)]
-struct DatasetEntry {
-    #[serde(rename = "imageName")]
-    image_name: String,
-    #[serde(rename = "chineseCharacter")]
-    chinese_character: Option<String>,
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Dataset file: cleaned_data.json or an images_manifest.json from the
+    /// downloader, or an http(s):// or s3:// URL to download it from
+    #[arg(long, default_value = "python/dset/cleaned_data.json")]
+    dataset: PathBuf,
+
+    /// Expected SHA-256 of a --dataset URL; the download must match it, and
+    /// a matching earlier download is reused
+    #[arg(long)]
+    dataset_sha256: Option<String>,
+
+    /// Header sent when downloading a --dataset URL, as "Name: value";
+    /// may be given several times
+    #[arg(long, value_parser = remote::parse_header)]
+    dataset_header: Vec<(String, String)>,
+
+    /// The downloader's trademark_data.json, read with the images in
+    /// --images-dir instead of --dataset
+    #[arg(long, conflicts_with = "dataset")]
+    raw_data: Option<PathBuf>,
+
+    /// Directory holding the images named in the dataset
+    #[arg(long, default_value = "./python/dset/imgs")]
+    images_dir: PathBuf,
+
+    /// Read the images from this zip, tar, tar.gz or tar.zst archive instead
+    /// of --images-dir, each as needed
+    #[arg(long, conflicts_with = "raw_data")]
+    images_archive: Option<PathBuf>,
+
+    /// Base URL of the extraction API (defaults to http://localhost:1234,
+    /// https://api.anthropic.com for anthropic or http://localhost:11434 for ollama).
+    /// Give it several times for endpoints to fail over to, in order of preference
+    #[arg(long)]
+    base_url: Vec<String>,
+
+    /// Wire format of the extraction API
+    #[arg(long, value_enum, default_value_t = Protocol::Invoke)]
+    protocol: Protocol,
+
+    /// Model name passed to the API; give it once per --base-url for
+    /// endpoints that name the model differently
+    #[arg(long)]
+    model: Vec<String>,
+
+    /// Allow --base-url endpoints serving different models, whose results
+    /// then mix in one evaluation
+    #[arg(long)]
+    allow_mixed_models: bool,
+
+    /// Failed requests in a row after which an endpoint is skipped in favor
+    /// of the next --base-url
+    #[arg(long, default_value_t = endpoints::DEFAULT_FAILURE_THRESHOLD, value_parser = clap::value_parser!(u32).range(1..))]
+    failover_threshold: u32,
+
+    /// Seconds an endpoint that failed is skipped before it is tried again
+    #[arg(long, default_value_t = endpoints::DEFAULT_COOLDOWN_SECS)]
+    failover_cooldown_secs: u64,
+
+    /// Don't check that the endpoints answer and serve --model before the
+    /// run; --dry-run never checks them
+    #[arg(long)]
+    skip_health_check: bool,
+
+    /// Model to compare on the same sample, as name=protocol,url[,model][,rpm=N];
+    /// give it once per model. Each runs in turn with the other options,
+    /// its files in a directory named after it next to --output
+    #[arg(long, conflicts_with_all = ["base_url", "model", "baseline"])]
+    model_spec: Vec<ModelSpec>,
+
+    /// Prompt template sent as the system prompt, with {{fields}} and {{language}} placeholders
+    #[arg(long)]
+    prompt_file: Option<PathBuf>,
+
+    /// Manifest of example images with their correct JSON answers, shown to
+    /// the model as earlier turns before each image; the examples are left
+    /// out of the evaluation
+    #[arg(long)]
+    few_shot: Option<PathBuf>,
+
+    /// Most base64 bytes the --few-shot images may add to each request,
+    /// e.g. 2MB; larger examples are shrunk to fit
+    #[arg(long, value_parser = throttle::parse_size, default_value = "1MB", requires = "few_shot")]
+    few_shot_max_bytes: u64,
+
+    /// Fields to extract and score, comma separated; the others are left out
+    /// of the prompt and the metrics and recorded as null
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = Field::ALL)]
+    fields: Vec<Field>,
+
+    /// First ask the model whether the mark has any characters, and extract
+    /// only the images where it does
+    #[arg(long)]
+    two_stage: bool,
+
+    /// Also process images without ground truth characters, recording their
+    /// predictions as new extractions outside the accuracy metrics
+    #[arg(long, conflicts_with = "only_unlabeled")]
+    include_unlabeled: bool,
+
+    /// Process only the images without ground truth characters, to label new data
+    #[arg(long)]
+    only_unlabeled: bool,
+
+    /// Language of the characters to extract, substituted for {{language}}
+    #[arg(long, default_value = "Chinese")]
+    language: String,
+
+    /// Sampling temperature; 0 keeps the extraction deterministic
+    #[arg(long, default_value_t = 0.0, value_parser = parse_temperature, allow_negative_numbers = true)]
+    temperature: f64,
+
+    /// Temperatures to run the sample at one after the other, comma
+    /// separated, comparing their accuracy and CER at the end
+    #[arg(long, value_delimiter = ',', value_parser = parse_temperature, allow_negative_numbers = true,
+        conflicts_with_all = ["temperature", "votes", "model_spec"])]
+    sweep_temperature: Vec<f64>,
+
+    /// Preprocessing modes to run the sample with one after the other,
+    /// comma separated, comparing their accuracy and CER at the end
+    #[arg(long, value_enum, value_delimiter = ',',
+        conflicts_with_all = ["preprocess", "sweep_temperature", "model_spec"])]
+    sweep_preprocess: Vec<Preprocess>,
+
+    /// Samples asked for each image, the answer being elected by majority
+    /// vote per field; a temperature of 0 is raised so the samples can differ
+    #[arg(long, default_value_t = 1)]
+    votes: usize,
+
+    /// Nucleus sampling probability mass, between 0 and 1
+    #[arg(long, value_parser = parse_top_p)]
+    top_p: Option<f64>,
+
+    /// Longest reply the model may generate, in tokens
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_tokens: Option<u32>,
+
+    /// Sequence that ends the model's reply; may be repeated
+    #[arg(long)]
+    stop: Vec<String>,
+
+    /// Price of 1000 input tokens, for the estimated cost of the run
+    #[arg(long, value_parser = parse_price)]
+    price_per_1k_input: Option<f64>,
+
+    /// Price of 1000 output tokens, for the estimated cost of the run
+    #[arg(long, value_parser = parse_price)]
+    price_per_1k_output: Option<f64>,
+
+    /// API key sent with every request (or set EXTRACT_API_KEY)
+    #[arg(long)]
+    api_key: Option<String>,
+
+    /// Don't ask chat APIs for a JSON response format, for servers that reject it
+    #[arg(long)]
+    no_json_mode: bool,
+
+    /// How the image is put into the request (default: data-url for
+    /// openai, base64 for the others)
+    #[arg(long, value_enum)]
+    image_encoding: Option<ImageEncoding>,
+
+    /// Maximum number of images to process
+    #[arg(long, default_value_t = 10000)]
+    limit: usize,
+
+    /// Number of images to sample at random from the dataset (defaults to --limit)
+    #[arg(long)]
+    sample_size: Option<usize>,
+
+    /// Stop sending images once the 95% interval of the accuracy is within
+    /// --target-ci of the estimate
+    #[arg(long)]
+    early_stop: bool,
+
+    /// Half-width of the accuracy interval --early-stop stops at, e.g. 0.02
+    /// for two percentage points either way
+    #[arg(long, default_value_t = 0.02, requires = "early_stop")]
+    target_ci: f64,
+
+    /// Labeled images --early-stop scores before it may stop
+    #[arg(long, default_value_t = 100, requires = "early_stop")]
+    early_stop_min: usize,
+
+    /// Seed of the random sample
+    #[arg(long, default_value_t = 42)]
+    seed: u64,
+
+    /// Spread the sample over the labeled images' strata instead of drawing
+    /// it from the whole dataset
+    #[arg(long, value_enum, conflicts_with_all = ["reuse_sample", "mismatches_only"])]
+    stratify_by: Option<Stratify>,
+
+    /// Take the same number of images from each stratum rather than each
+    /// stratum's share of the dataset
+    #[arg(long, requires = "stratify_by")]
+    equal_strata: bool,
+
+    /// Where to record the sampled image names
+    #[arg(long, default_value = sample::SAMPLE_MANIFEST_FILE_NAME)]
+    sample_manifest: PathBuf,
+
+    /// Process exactly the images of an earlier sample manifest instead of sampling
+    #[arg(long, conflicts_with_all = ["sample_size", "seed"])]
+    reuse_sample: Option<PathBuf>,
+
+    /// Query again only the images of an earlier mismatches.jsonl, ignoring --dataset
+    #[arg(long, conflicts_with_all = ["reuse_sample", "sample_size", "seed", "raw_data"])]
+    mismatches_only: Option<PathBuf>,
+
+    /// Process again only the images of an earlier extract_failures.json,
+    /// merging their results into --output and rewriting the file with
+    /// those still failing
+    #[arg(long, conflicts_with_all = ["reuse_sample", "mismatches_only", "sample_size", "seed", "stratify_by", "resume"])]
+    retry_failed: Option<PathBuf>,
+
+    /// Maximum concurrent requests
+    #[arg(short = 'p', long, default_value_t = 10)]
+    concurrency: usize,
+
+    /// Pick the concurrency while the run goes: start low, raise it while
+    /// throughput grows without more failed requests, and lower it when
+    /// latency degrades
+    #[arg(long, conflicts_with = "concurrency")]
+    auto_tune: bool,
+
+    /// Highest concurrency --auto-tune tries
+    #[arg(long, default_value_t = 64, requires = "auto_tune")]
+    auto_tune_max: usize,
+
+    /// Seconds between the measurements --auto-tune adjusts on
+    #[arg(long, default_value_t = 30, requires = "auto_tune")]
+    auto_tune_interval: u64,
+
+    /// Images sent in one request, for protocols whose messages take
+    /// several; the model answers with one object per image
+    #[arg(long, default_value_t = 1)]
+    batch_size: usize,
+
+    /// Cap on the image payload held in memory by the requests in flight,
+    /// e.g. 512MB or 1GiB; requests wait for room rather than exceed it
+    #[arg(long, value_parser = throttle::parse_size)]
+    max_inflight_bytes: Option<u64>,
+
+    /// Workers reading, converting and encoding images ahead of the
+    /// requests [default: number of CPUs]
+    #[arg(long)]
+    preprocess_workers: Option<usize>,
+
+    /// Images, or batches of them, kept encoded and ready for the request
+    /// workers [default: --concurrency]
+    #[arg(long)]
+    prefetch: Option<usize>,
+
+    /// Times to retry a request after a timeout, connection error or 5xx response
+    #[arg(long, default_value_t = 3)]
+    max_retries: u32,
+
+    /// Maximum requests per minute across all workers
+    #[arg(long, conflicts_with = "rps")]
+    rpm: Option<f64>,
+
+    /// Maximum requests per second across all workers
+    #[arg(long)]
+    rps: Option<f64>,
+
+    /// Request timeout in seconds, covering the whole request and response
+    #[arg(long, default_value_t = 30)]
+    timeout_secs: u64,
+
+    /// Timeout for establishing the connection, in seconds
+    #[arg(long, default_value_t = 10)]
+    connect_timeout_secs: u64,
+
+    /// Convert predictions and ground truth to one script before comparing them
+    #[arg(long, value_enum, default_value_t = Normalize::T2s)]
+    normalize: Normalize,
+
+    /// Don't compose characters to Unicode NFC before comparing
+    #[arg(long)]
+    no_nfc: bool,
+
+    /// Fold full-width letters, digits and punctuation to half-width before comparing
+    #[arg(long)]
+    fold_width: bool,
+
+    /// Lower-case letters before comparing
+    #[arg(long)]
+    fold_case: bool,
+
+    /// Remove punctuation such as "·" and "。" before comparing
+    #[arg(long)]
+    strip_punctuation: bool,
+
+    /// What happens to whitespace before comparing
+    #[arg(long, value_enum, default_value_t = Whitespace::Strip)]
+    whitespace: Whitespace,
+
+    /// Count predictions that aren't in Chinese characters alone, such as
+    /// pinyin, kana or English, as wrong even when they equal the ground truth
+    #[arg(long)]
+    strict_script: bool,
+
+    /// After the run, look up the current label of each mismatched image at
+    /// IPOS and set apart the images whose label changed since the dataset
+    /// was built, writing the new labels to label_corrections.jsonl
+    #[arg(long)]
+    verify_labels: bool,
+
+    /// Days of trademark records --verify-labels fetches at a time, as the
+    /// downloader's --concurrency
+    #[arg(long, default_value_t = 30, requires = "verify_labels")]
+    verify_labels_concurrency: usize,
+
+    /// Ignore common words such as "a", "of" and "device" when comparing device descriptions
+    #[arg(long)]
+    device_stopwords: bool,
+
+    /// Device description similarity, from 0 to 1, from which a description counts as good
+    #[arg(long, default_value_t = device::Thresholds::default().good)]
+    device_good: f64,
+
+    /// Device description similarity from which a description counts as partial rather than poor
+    #[arg(long, default_value_t = device::Thresholds::default().partial)]
+    device_partial: f64,
+
+    /// Keep API responses in this directory and reuse them for the same
+    /// image and request instead of calling the API again
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Write every raw API response, with its request minus the image, to
+    /// this directory
+    #[arg(long)]
+    save_responses: Option<PathBuf>,
+
+    /// Most responses --save-responses writes in one run
+    #[arg(long, requires = "save_responses")]
+    save_responses_limit: Option<usize>,
+
+    /// Only save responses that were errors or didn't parse
+    #[arg(long, requires = "save_responses")]
+    save_failures_only: bool,
+
+    /// Don't send each request's id as an X-Request-Id header, for
+    /// endpoints that reject headers they don't know
+    #[arg(long)]
+    no_request_id_header: bool,
+
+    /// Don't read or write --cache-dir for this run
+    #[arg(long, conflicts_with = "refresh_cache")]
+    no_cache: bool,
+
+    /// Call the API for every image and replace the responses in --cache-dir
+    #[arg(long, requires = "cache_dir")]
+    refresh_cache: bool,
+
+    /// Send every image, even when another image has the same contents
+    #[arg(long)]
+    no_dedup: bool,
+
+    /// Shrink images whose longest side is over this many pixels before
+    /// sending them
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_dimension: Option<u32>,
+
+    /// Quality of the JPEG a shrunk image is re-encoded as, 1-100
+    #[arg(long, default_value_t = 85, value_parser = clap::value_parser!(u8).range(1..=100))]
+    jpeg_quality: u8,
+
+    /// Processing of each image after any shrinking, to try whether it
+    /// helps the model read low-contrast marks
+    #[arg(long, value_enum, default_value_t = Preprocess::None)]
+    preprocess: Preprocess,
+
+    /// Image formats the endpoint takes, comma separated; others are
+    /// converted to PNG (default: jpeg,png,webp,gif for openai and
+    /// anthropic, jpeg,png for ollama, anything for invoke)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    accept_formats: Option<Vec<ImageKind>>,
+
+    /// Stop before sending anything if more than this percentage of the
+    /// images are empty, unrecognized or don't decode
+    #[arg(long, value_parser = parse_percent)]
+    strict_images: Option<f64>,
+
+    /// Skip image files larger than this, e.g. 50MB, before reading them
+    #[arg(long, default_value = "50MB", value_parser = throttle::parse_size)]
+    max_input_bytes: u64,
+
+    /// Results file, one JSON line per processed image
+    #[arg(short, long, default_value = results::RESULTS_FILE_NAME)]
+    output: PathBuf,
+
+    /// Skip images already in the results file for the same endpoint and model, appending the rest
+    #[arg(long)]
+    resume: bool,
+
+    /// Also write the results as CSV to this file
+    #[arg(long)]
+    output_csv: Option<PathBuf>,
+
+    /// Start the CSV with a UTF-8 byte order mark so Excel reads Chinese text correctly
+    #[arg(long, requires = "output_csv")]
+    bom: bool,
+
+    /// Write the dataset entries, or the raw data's items, to this file with
+    /// each image's predictions added under "llm_extraction"
+    #[arg(long, conflicts_with = "mismatches_only")]
+    enriched_output: Option<PathBuf>,
+
+    /// Also write the results in this schema next to the results file:
+    /// `ipos` writes results_ipos.json, the downloader's day records with
+    /// the predictions in place of each mark's text
+    #[arg(long, value_enum)]
+    output_schema: Option<OutputSchema>,
+
+    /// Write a self-contained HTML report of the run to this file
+    #[arg(long)]
+    html_report: Option<PathBuf>,
+
+    /// File of known-bad images to leave out of the run and its metrics, one
+    /// image name per line; "#" starts a comment
+    #[arg(long)]
+    skip_list: Option<PathBuf>,
+
+    /// Write gallery.html next to the results file: a grid of thumbnails with
+    /// the ground truth and prediction of each image, to filter by verdict
+    #[arg(long)]
+    gallery: bool,
+
+    /// Most images in the gallery; mismatches and errors are shown first
+    #[arg(long, default_value_t = 500, requires = "gallery")]
+    gallery_max: usize,
+
+    /// metrics.json of an earlier run to compare this run with; the run fails
+    /// if accuracy dropped by more than --regression-threshold
+    #[arg(long)]
+    baseline: Option<PathBuf>,
+
+    /// Accuracy drop from --baseline, in percentage points, that fails the run
+    #[arg(long, default_value_t = baseline::DEFAULT_REGRESSION_THRESHOLD, requires = "baseline")]
+    regression_threshold: f64,
+
+    /// Write this run's metrics to the --baseline file, unless accuracy regressed
+    #[arg(long, requires = "baseline")]
+    save_baseline: bool,
+
+    /// Directory of the log files, named extraction_<timestamp>.log
+    #[arg(long, default_value = logging::DEFAULT_LOG_DIR)]
+    log_dir: PathBuf,
+
+    /// Log file, instead of a timestamped one in --log-dir
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Format of the console and log file lines
+    #[arg(long, value_enum, default_value_t = LogFormat::Human)]
+    log_format: LogFormat,
+
+    /// Print every image's result and the debug lines to the console, as
+    /// the log file has them
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Seconds between the snapshots of accuracy, errors, throughput and ETA
+    /// logged and appended to progress_metrics.jsonl, each also saving
+    /// metrics.json so far; 0 turns them off
+    #[arg(long, default_value_t = 60)]
+    report_interval: u64,
+
+    /// Check the dataset and settings without contacting the endpoints:
+    /// report what a run would process and the request it would send, then exit
+    #[arg(long)]
+    dry_run: bool,
+
+    #[command(flatten)]
+    bootstrap: Bootstrap,
+
+    #[command(flatten)]
+    buckets: BucketBounds,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Compare two results files of earlier runs image by image, without
+    /// sending anything
+    Compare {
+        /// Results file of the first run, A
+        a: PathBuf,
+
+        /// Results file of the second run, B
+        b: PathBuf,
+
+        /// Configuration id of A to compare (default: the latest in the file)
+        #[arg(long)]
+        config_a: Option<String>,
+
+        /// Configuration id of B to compare (default: the latest in the file)
+        #[arg(long)]
+        config_b: Option<String>,
+
+        /// Where to write the comparison as JSON
+        #[arg(short, long, default_value = compare::PAIRED_FILE_NAME)]
+        output: PathBuf,
+
+        /// Where to write the images only one run got right, one JSON line each
+        #[arg(long, default_value = compare::REVIEW_FILE_NAME)]
+        review: PathBuf,
+
+        #[command(flatten)]
+        bootstrap: Bootstrap,
+    },
+    /// Split the labeled dataset into train, validation and test files,
+    /// keeping identical images in the same split
+    Split {
+        /// Dataset file: cleaned_data.json or an --enriched-output of it
+        #[arg(long, default_value = "python/dset/cleaned_data.json")]
+        dataset: PathBuf,
+
+        /// Directory holding the images named in the dataset
+        #[arg(long, default_value = "./python/dset/imgs")]
+        images_dir: PathBuf,
+
+        /// Shares of train, validation and test, comma separated
+        #[arg(long, default_value_t = Ratios::default())]
+        ratios: Ratios,
+
+        /// Seed of the shuffle
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+
+        /// Give each split its share of every stratum
+        #[arg(long, value_enum)]
+        stratify_by: Option<Stratify>,
+
+        /// Directory of the split files and the split manifest
+        #[arg(short, long, default_value = split::SPLITS_DIR)]
+        output_dir: PathBuf,
+
+        #[command(flatten)]
+        buckets: BucketBounds,
+    },
+    /// Go through the mismatches of a run in the terminal and mark each as
+    /// the model's or the label's mistake
+    Review {
+        /// Mismatches file of the run
+        #[arg(default_value = results::MISMATCHES_FILE_NAME)]
+        mismatches: PathBuf,
+
+        /// Where to append the verdicts (default: review_verdicts.jsonl next
+        /// to the mismatches)
+        #[arg(long)]
+        verdicts: Option<PathBuf>,
+
+        /// Program the o key opens the image with
+        #[arg(long, default_value = review::default_viewer())]
+        viewer: String,
+
+        /// Start with a low-resolution preview of the image drawn in the terminal
+        #[arg(long)]
+        preview: bool,
+
+        /// Instead of reviewing, append the images marked label wrong or
+        /// ambiguous to this --skip-list file
+        #[arg(long)]
+        export_skip_list: Option<PathBuf>,
+    },
+}
+
+fn parse_temperature(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(t) if t.is_finite() && t >= 0.0 => Ok(t),
+        _ => Err(format!("'{}' is not a non-negative number", s)),
+    }
+}
+
+fn parse_top_p(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if p > 0.0 && p <= 1.0 => Ok(p),
+        _ => Err(format!("'{}' is not a number in (0, 1]", s)),
+    }
+}
+
+fn parse_price(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if p.is_finite() && p >= 0.0 => Ok(p),
+        _ => Err(format!("'{}' is not a non-negative price", s)),
+    }
+}
+
+fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+        _ => Err(format!("'{}' is not a percentage between 0 and 100", s)),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // Setup logging
+    let bars = MultiProgress::new();
+    let log_path = args.log_file.clone().unwrap_or_else(|| logging::log_path(&args.log_dir));
+    logging::init(&log_path, args.log_format, args.verbose, &bars)?;
+    info!("Logging to {}", log_path.display());
+
+    match &args.command {
+        Some(Command::Compare { a, b, config_a, config_b, output, review, bootstrap }) => {
+            let comparison = PairedComparison::new(a, b, config_a.as_deref(), config_b.as_deref(), bootstrap)?;
+            compare_files(&comparison, output, review)
+        }
+        Some(Command::Split { dataset, images_dir, ratios, seed, stratify_by, output_dir, buckets }) => {
+            split_dataset(dataset, images_dir, ratios, *seed, *stratify_by, output_dir, buckets).await
+        }
+        Some(Command::Review { mismatches, verdicts, viewer, preview, export_skip_list }) => {
+            review_mismatches(mismatches, verdicts.as_deref(), viewer, *preview, export_skip_list.as_deref())
+        }
+        None => run(args, bars).await,
+    }
+}
+
+/// Check the settings of each run `args` asks for, fetch a dataset URL once
+/// for all of them, and make the runs: a sweep, a comparison of models or
+/// a single extraction
+async fn run(mut args: Args, bars: MultiProgress) -> Result<()> {
+    let swept: Vec<Setting> = if !args.sweep_temperature.is_empty() {
+        args.sweep_temperature.iter().map(|temperature| Setting::Temperature(*temperature)).collect()
+    } else {
+        args.sweep_preprocess.iter().map(|preprocess| Setting::Preprocess(*preprocess)).collect()
+    };
+    if !swept.is_empty() {
+        for setting in &swept {
+            check_args(&sweep_args(&args, *setting, None))?;
+        }
+    } else if !args.model_spec.is_empty() {
+        for spec in &args.model_spec {
+            check_args(&spec_args(&args, spec))?;
+        }
+    } else {
+        check_args(&args)?;
+    }
+
+    // Every run reads the same copy, so models and settings are scored on
+    // the same data
+    let dataset_source = fetch_dataset(&mut args).await?;
+    if !swept.is_empty() {
+        sweep(args, swept, dataset_source, bars).await
+    } else if args.model_spec.is_empty() {
+        extract(args, None, dataset_source, bars).await.map(drop)
+    } else {
+        compare_models(args, dataset_source, bars).await
+    }
+}
+
+/// Download a `--dataset` given as a URL, pointing `args` at the local copy
+async fn fetch_dataset(args: &mut Args) -> Result<Option<DatasetSource>> {
+    if !remote::is_remote(&args.dataset) || args.mismatches_only.is_some() || args.raw_data.is_some() {
+        return Ok(None);
+    }
+    let url = args.dataset.to_string_lossy().into_owned();
+    let source = remote::fetch(&url, &args.dataset_header, args.dataset_sha256.as_deref()).await?;
+    args.dataset = source.path.clone();
+    Ok(Some(source))
+}
+
+/// Report the comparison of two results files of earlier runs, and save it
+/// to `output` with the images only one run got right to `review`
+fn compare_files(comparison: &PairedComparison, output: &Path, review: &Path) -> Result<()> {
+    for run in [&comparison.a, &comparison.b] {
+        if run.other_config > 0 {
+            info!("Left out {} results of other configurations in {}; --config-a and --config-b pick one",
+                run.other_config, run.path.display());
+        }
+        if run.malformed > 0 {
+            warn!("Ignoring {} unreadable lines in {}", run.malformed, run.path.display());
+        }
+    }
+    if comparison.only_in_a > 0 || comparison.only_in_b > 0 {
+        warn!("The runs cover different samples: {} images only in A, {} only in B; only the {} in both are compared",
+            comparison.only_in_a, comparison.only_in_b, comparison.shared);
+    }
+    info!("{}", comparison.report());
+
+    comparison.save(output)?;
+    comparison.save_review(review)?;
+    info!("Comparison saved to {}, the {} images only one run got right to {}",
+        output.display(), comparison.review.len(), review.display());
+    Ok(())
+}
+
+/// Review the mismatches of a run in the terminal, appending the verdicts
+/// to `verdicts` as they are given
+fn review_mismatches(mismatches_path: &Path, verdicts: Option<&Path>, viewer: &str, preview: bool, export_skip_list: Option<&Path>) -> Result<()> {
+    let verdicts_path = verdicts.map_or_else(|| review::verdicts_path(mismatches_path), Path::to_path_buf);
+    if let Some(skip_list) = export_skip_list {
+        let verdicts = review::load_verdicts(&verdicts_path)?;
+        let added = skiplist::export_verdicts(&verdicts, skip_list)?;
+        info!("Added {} images marked label wrong or ambiguous in {} to {}", added, verdicts_path.display(), skip_list.display());
+        return Ok(());
+    }
+    if !std::io::stdout().is_terminal() {
+        anyhow::bail!("The review needs a terminal");
+    }
+    let mismatches = results::load_mismatches(mismatches_path)?;
+    if mismatches.is_empty() {
+        info!("No mismatches to review in {}", mismatches_path.display());
+        return Ok(());
+    }
+    let summary = review::run(mismatches, &verdicts_path, viewer, preview)?;
+    info!("Marked {} mismatches; {} of {} are reviewed, verdicts in {}",
+        summary.marked, summary.reviewed, summary.mismatches, verdicts_path.display());
+    Ok(())
+}
+
+/// Write the labeled entries of `dataset` to a train, validation and test
+/// file in `output_dir` by `ratios`, identical images always in the same
+/// split, and record how in the split manifest
+async fn split_dataset(
+    dataset: &Path,
+    images_dir: &Path,
+    ratios: &Ratios,
+    seed: u64,
+    stratify_by: Option<Stratify>,
+    output_dir: &Path,
+    buckets: &BucketBounds,
+) -> Result<()> {
+    buckets.validate()?;
+    info!("Loading dataset from {}", dataset.display());
+    let text = fs::read_to_string(dataset)
+        .with_context(|| format!("Failed to read dataset file: {}", dataset.display()))?;
+    let entries: Vec<Value> = serde_json::from_str(&text)
+        .with_context(|| format!("Failed to parse dataset JSON: {}", dataset.display()))?;
+
+    // Only labeled entries with their image on disk can be trained on
+    let mut unlabeled = 0;
+    let mut missing = Vec::new();
+    let mut labeled = Vec::new();
+    for entry in &entries {
+        let truth = entry.get("chineseCharacter").and_then(Value::as_str)
+            .map(metrics::comparable)
+            .filter(|truth| !truth.is_empty());
+        let (Some(name), Some(truth)) = (enrich::entry_image_name(entry), truth) else {
+            unlabeled += 1;
+            continue;
+        };
+        let image_path = entry.get("localPath").and_then(Value::as_str)
+            .map_or_else(|| images_dir.join(&name), PathBuf::from);
+        if !image_path.is_file() {
+            missing.push(name);
+            continue;
+        }
+        labeled.push((entry, name, truth, image_path));
+    }
+    if !missing.is_empty() {
+        let examples: Vec<&str> = missing.iter().take(MISSING_EXAMPLES).map(String::as_str).collect();
+        warn!("{} labeled images are not on disk and were left out, e.g. {}", missing.len(), examples.join(", "));
+    }
+    if labeled.is_empty() {
+        anyhow::bail!("No labeled images of {} are in {}", dataset.display(), images_dir.display());
+    }
+
+    // Sets of identical images, an image listed twice among them, go to a
+    // split together
+    let workers = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let groups = dedup::group_duplicates(&ImageSource::Files, labeled.iter().map(|(_, _, _, path)| path.clone()).collect(), workers).await;
+    let duplicate_groups = groups.iter().filter(|group| group.len() > 1).count();
+    let bucket_names = buckets.length_names();
+    let stratum = |i: usize| match stratify_by {
+        Some(Stratify::CharCount) => buckets.length_bucket(labeled[i].2.chars().count()),
+        None => 0,
+    };
+    let assigned = split::assign(
+        &groups.iter().map(|group| (stratum(group[0]), group.len())).collect::<Vec<_>>(),
+        ratios,
+        seed,
+    );
+    let mut split_of = vec![0; labeled.len()];
+    for (group, &split) in groups.iter().zip(&assigned) {
+        for &i in group {
+            split_of[i] = split;
+        }
+    }
+
+    fs::create_dir_all(output_dir)
+        .with_context(|| format!("Failed to create output directory: {}", output_dir.display()))?;
+    let mut splits = Vec::new();
+    for (index, name) in split::SPLIT_NAMES.iter().enumerate() {
+        let members: Vec<usize> = (0..labeled.len()).filter(|&i| split_of[i] == index).collect();
+        let file = output_dir.join(format!("{}.json", name));
+        split::save_split(&file, &members.iter().map(|&i| labeled[i].0).collect::<Vec<_>>())?;
+        let mut strata = vec![0; bucket_names.len()];
+        if stratify_by.is_some() {
+            for &i in &members {
+                strata[stratum(i)] += 1;
+            }
+        }
+        splits.push(SplitCount {
+            name: name.to_string(),
+            file,
+            images: members.len(),
+            sample_id: sample::sample_id(members.iter().map(|&i| labeled[i].1.as_str())),
+            strata: bucket_names.iter().zip(strata)
+                .filter(|(_, images)| *images > 0)
+                .map(|(bucket, images)| StratumCount { bucket: bucket.clone(), images })
+                .collect(),
+        });
+    }
+
+    let manifest = SplitManifest {
+        dataset: dataset.to_path_buf(),
+        seed,
+        ratios: *ratios,
+        stratify_by,
+        created_at: Local::now().to_rfc3339(),
+        images: labeled.len(),
+        unlabeled,
+        missing: missing.len(),
+        duplicate_groups,
+        splits,
+    };
+    let manifest_path = output_dir.join(split::SPLIT_MANIFEST_FILE_NAME);
+    manifest.save(&manifest_path)?;
+
+    info!("Split {} labeled images with seed {}{}, {} unlabeled entries left out",
+        labeled.len(), seed, stratify_by.map_or(String::new(), |by| format!(", stratified by {}", by.name())), unlabeled);
+    if duplicate_groups > 0 {
+        info!("{} groups of identical images were kept in one split each", duplicate_groups);
+    }
+    for split in &manifest.splits {
+        info!("  {:<5} {:6} images in {}{}", split.name, split.images, split.file.display(),
+            if split.strata.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", split.strata.iter()
+                    .map(|stratum| format!("{} {}", stratum.bucket, stratum.images))
+                    .collect::<Vec<_>>().join(", "))
+            });
+    }
+    info!("Split manifest saved to {}", manifest_path.display());
+    Ok(())
+}
+
+/// `--api-key`, or the key set in the environment for the protocol
+fn api_key(args: &Args) -> Option<String> {
+    args.api_key.clone()
+        .or_else(|| std::env::var(protocol::API_KEY_VAR).ok())
+        .or_else(|| args.protocol.api_key_var().and_then(|var| std::env::var(var).ok()))
+        .filter(|key| !key.is_empty())
+}
+
+/// Refuse settings that contradict each other or can't work, before
+/// anything is read or sent
+fn check_args(args: &Args) -> Result<()> {
+    if args.concurrency == 0 {
+        anyhow::bail!("--concurrency must be at least 1");
+    }
+    if args.auto_tune && (args.auto_tune_max == 0 || args.auto_tune_interval == 0) {
+        anyhow::bail!("--auto-tune-max and --auto-tune-interval must be at least 1");
+    }
+    if args.batch_size == 0 {
+        anyhow::bail!("--batch-size must be at least 1");
+    }
+    if args.preprocess_workers == Some(0) || args.prefetch == Some(0) {
+        anyhow::bail!("--preprocess-workers and --prefetch must be at least 1");
+    }
+    if args.batch_size > 1 && !args.protocol.takes_batches() {
+        anyhow::bail!("--protocol {} takes one image per request, so --batch-size must be 1", args.protocol.name());
+    }
+    if args.early_stop && !(args.target_ci > 0.0 && args.target_ci < 0.5) {
+        anyhow::bail!("--target-ci must be between 0 and 0.5");
+    }
+    if args.early_stop && !args.fields.contains(&Field::Chinese) {
+        anyhow::bail!("--early-stop follows the accuracy of the characters, so --fields must include chinese");
+    }
+    if args.verify_labels && !args.fields.contains(&Field::Chinese) {
+        anyhow::bail!("--verify-labels checks the labels of the characters, so --fields must include chinese");
+    }
+    if args.votes == 0 {
+        anyhow::bail!("--votes must be at least 1");
+    }
+    if args.votes > 1 && !args.protocol.takes_prompt() {
+        anyhow::bail!("--votes samples the model's answers, which --protocol {} has no sampling settings for", args.protocol.name());
+    }
+    if args.votes > 1 && args.batch_size > 1 {
+        anyhow::bail!("--votes samples each image on its own, so --batch-size must be 1");
+    }
+    if args.few_shot.is_some() && !args.protocol.takes_examples() {
+        anyhow::bail!("--few-shot needs a protocol whose requests hold a conversation with images, not --protocol {}",
+            args.protocol.name());
+    }
+    if args.few_shot.is_some() && args.batch_size > 1 {
+        anyhow::bail!("--few-shot examples answer one image each, so --batch-size must be 1");
+    }
+    if args.batch_size > 1 && args.two_stage {
+        anyhow::bail!("--two-stage asks about each image on its own, so --batch-size must be 1");
+    }
+    if args.protocol.needs_model() && args.model.is_empty() {
+        anyhow::bail!("--protocol {} needs --model", args.protocol.name());
+    }
+    if args.model.len() > 1 && args.model.len() != args.base_url.len() {
+        anyhow::bail!("--model is given {} times for {} --base-url; give it once, or once per --base-url",
+            args.model.len(), args.base_url.len());
+    }
+    let mixed_models = args.model.iter().any(|model| *model != args.model[0]);
+    if mixed_models && !args.allow_mixed_models {
+        anyhow::bail!("The --base-url endpoints serve different models ({}), which mixes their results in one evaluation; \
+                       pass --allow-mixed-models to do so anyway", args.model.join(", "));
+    }
+    let image_encoding = args.image_encoding.unwrap_or(args.protocol.default_image_encoding());
+    if !args.protocol.image_encodings().contains(&image_encoding) {
+        anyhow::bail!("--protocol {} can't send images as {}; it takes {}", args.protocol.name(), image_encoding.name(),
+            args.protocol.image_encodings().iter().map(|e| e.name()).collect::<Vec<_>>().join(", "));
+    }
+    if args.protocol == Protocol::Anthropic && api_key(args).is_none() {
+        anyhow::bail!("--protocol anthropic needs an API key: pass --api-key or set ANTHROPIC_API_KEY");
+    }
+    if args.two_stage && !args.protocol.takes_prompt() {
+        anyhow::bail!("--two-stage needs a protocol that sends a prompt, not --protocol {}", args.protocol.name());
+    }
+    if args.two_stage && !args.fields.contains(&Field::Chinese) {
+        anyhow::bail!("--two-stage detects Chinese characters, so --fields must include chinese");
+    }
+    let rate = args.rps.or(args.rpm.map(|rpm| rpm / 60.0));
+    if rate.is_some_and(|rate| rate <= 0.0 || !rate.is_finite()) {
+        anyhow::bail!("--rpm and --rps must be greater than zero");
+    }
+    if !(0.0..=1.0).contains(&args.device_partial) || !(args.device_partial..=1.0).contains(&args.device_good) {
+        anyhow::bail!("--device-partial and --device-good must be between 0 and 1, with --device-partial no higher");
+    }
+    if args.regression_threshold < 0.0 || !args.regression_threshold.is_finite() {
+        anyhow::bail!("--regression-threshold must be a non-negative number of percentage points");
+    }
+    args.buckets.validate()
+}
+
+/// How a run of `extract` ended
+enum Run {
+    /// `--dry-run`, with the requests the run would make
+    DryRun { requests: usize },
+    /// Metrics of the configuration's results
+    Finished(Box<Scoreboard>),
+}
+
+/// Run the extraction `args` describe, tagging the results with the name of
+/// `model_spec` when it is one model of a comparison
+async fn extract(
+    mut args: Args,
+    model_spec: Option<String>,
+    dataset_source: Option<DatasetSource>,
+    bars: MultiProgress,
+) -> Result<Run> {
+    // Check the inputs before anything is sent
+    check_args(&args)?;
+    if args.votes > 1 && args.temperature == 0.0 {
+        info!("Sampling the {} votes at temperature {} rather than 0, which would give the same answer each time",
+            args.votes, vote::VOTE_TEMPERATURE);
+        args.temperature = vote::VOTE_TEMPERATURE;
+    }
+    let input = args.mismatches_only.as_ref().or(args.raw_data.as_ref()).unwrap_or(&args.dataset);
+    if !input.is_file() {
+        anyhow::bail!("Dataset file not found: {}", input.display());
+    }
+    let mixed_models = args.model.iter().any(|model| *model != args.model[0]);
+    let image_encoding = args.image_encoding.unwrap_or(args.protocol.default_image_encoding());
+    let api_key = api_key(&args);
+    let mut fields = Vec::new();
+    for field in &args.fields {
+        if !fields.contains(field) {
+            fields.push(*field);
+        }
+    }
+    let prompt = Prompt::load(args.prompt_file.as_deref(), &args.language, &fields)?;
+    let rate = args.rps.or(args.rpm.map(|rpm| rpm / 60.0));
+    // A missing baseline is only expected when this run is to create it
+    let baseline = match &args.baseline {
+        Some(path) if args.save_baseline && !path.exists() => None,
+        Some(path) => Some(Baseline::load(path)?),
+        None => None,
+    };
+
+    info!("Starting extraction process");
+
+    // Initialize HTTP client
+    let base_urls: Vec<&str> = if args.base_url.is_empty() {
+        vec![args.protocol.default_base_url()]
+    } else {
+        args.base_url.iter().map(|url| url.trim_end_matches('/')).collect()
+    };
+    let base_url = base_urls[0];
+    info!("Initializing API client with base URL: {}", base_url);
+
+    let client = Client::builder()
+        .timeout(Duration::from_secs(args.timeout_secs))
+        .connect_timeout(Duration::from_secs(args.connect_timeout_secs))
+        .build()
+        .context("Failed to create HTTP client")?;
+
+    let accepted_formats = args.accept_formats.clone().or_else(|| args.protocol.accepted_formats().map(<[_]>::to_vec));
+    let few_shot = match &args.few_shot {
+        Some(path) => {
+            let few_shot = FewShot::load(path, &fields, accepted_formats.as_deref(), args.jpeg_quality, args.preprocess, args.few_shot_max_bytes)?;
+            info!("Showing {} few-shot examples from {} before each image ({}, {} bytes{})",
+                few_shot.examples.len(), path.display(), few_shot.image_names().join(", "), few_shot.bytes(),
+                few_shot.max_dimension.map_or(String::new(), |max| format!(", shrunk to {} pixels", max)));
+            Some(Arc::new(few_shot))
+        }
+        None => None,
+    };
+
+    let backend = Backend {
+        protocol: args.protocol,
+        base_url: base_url.to_string(),
+        model: args.model.first().cloned(),
+        api_key,
+        prompt: prompt.text.clone(),
+        user_prompt: protocol::USER_PROMPT,
+        generation: Generation {
+            temperature: args.temperature,
+            top_p: args.top_p,
+            max_tokens: args.max_tokens.or(args.protocol.default_max_tokens()),
+            stop: args.stop.clone(),
+        },
+        json_mode: !args.no_json_mode,
+        fields: fields.clone(),
+        image_encoding,
+        few_shot: few_shot.clone(),
+    };
+    let model_name = args.model.first().map_or("local-api", String::as_str);
+
+    info!("Using API endpoint: {} ({} protocol, model {}, images as {})",
+        backend.url(), args.protocol.name(), model_name, backend.image_encoding.name());
+    let endpoints = Endpoints::new(
+        base_urls.iter()
+            .enumerate()
+            .map(|(index, url)| (url.to_string(), args.model.get(index).or(args.model.first()).cloned()))
+            .collect(),
+        args.failover_threshold,
+        Duration::from_secs(args.failover_cooldown_secs),
+    );
+    if endpoints.has_fallback() {
+        info!("Failing over to {} when an endpoint can't be connected to or fails {} requests in a row, skipping it for {}s",
+            base_urls[1..].join(", then "), args.failover_threshold, args.failover_cooldown_secs);
+    }
+    // The invoke protocol sends only the image
+    let generation = args.protocol.takes_prompt().then(|| backend.generation.clone());
+    if let Some(generation) = &generation {
+        info!("Generation parameters: {}", serde_json::to_string(generation)?);
+    }
+    let prompt_hash = if args.protocol.takes_prompt() {
+        info!("Using {} prompt {}",
+            args.prompt_file.as_ref().map_or("the default".to_string(), |p| p.display().to_string()), prompt.hash);
+        Some(prompt.hash.clone())
+    } else {
+        if args.prompt_file.is_some() {
+            warn!("The invoke protocol sends no prompt, ignoring --prompt-file");
+        }
+        None
+    };
+    let detection_prompt = args.two_stage.then(|| detect::prompt(&args.language));
+    if let Some(detection_prompt) = &detection_prompt {
+        info!("Two-stage extraction: asking first whether each mark has {} characters, with prompt {}",
+            args.language, detection_prompt.hash);
+    }
+    let detector = detection_prompt.as_ref().map(|prompt| detect::detector(&backend, prompt));
+    if let Some(key) = &backend.api_key {
+        info!("Sending the API key in the {} header", backend.auth_header(key).0);
+    }
+
+    // Find out now rather than from a run of failures that the server is
+    // down; a dry run plans a run offline
+    let health_checks = if args.skip_health_check || args.dry_run {
+        Vec::new()
+    } else {
+        health::check_all(&client, &endpoints.routes(&backend)).await?
+    };
+
+    // A retry processes the failed images of the dataset and writes back
+    // the ones still failing
+    let failures_path = args.retry_failed.clone()
+        .unwrap_or_else(|| args.output.with_file_name(failures::FAILURES_FILE_NAME));
+    let retry = args.retry_failed.as_ref().map(|path| FailureList::load(path)).transpose()?;
+    let retry_names: HashSet<String> = retry.iter()
+        .flat_map(|list| list.failures.iter().map(|failure| failure.image_name.clone()))
+        .collect();
+
+    // Load dataset, the downloader's data file, or the images of an earlier
+    // run's mismatches
+    let loaded = match (&args.mismatches_only, &args.raw_data) {
+        (Some(path), _) => Input::Mismatches(path),
+        (None, Some(path)) => Input::RawData { path, images_dir: &args.images_dir },
+        (None, None) => Input::Dataset(&args.dataset),
+    }
+    .load((!args.dry_run).then(|| args.output.with_file_name(raw_data::MISSING_IMAGES_FILE_NAME)).as_deref())?;
+    let (mut data, documents, mut records) = (loaded.entries, loaded.documents, loaded.records);
+
+    // The model has seen the answers of the examples
+    if let Some(few_shot) = &few_shot {
+        let names: HashSet<String> = few_shot.image_names().into_iter().collect();
+        let left_out = dataset::exclude(&mut data, |name| names.contains(name));
+        if left_out > 0 {
+            info!("Leaving the {} few-shot examples in the dataset out of the evaluation", left_out);
+        }
+    }
+
+    let skip_list = args.skip_list.as_deref().map(SkipList::load).transpose()?.unwrap_or_default();
+    let skip_listed = dataset::exclude(&mut data, |name| skip_list.contains(name));
+    if let Some(path) = &args.skip_list {
+        info!("Leaving out {} images of the dataset listed in {} ({} names)", skip_listed, path.display(), skip_list.len());
+    }
+
+    // Entries from cleaned_data.json are looked up in the images directory,
+    // or by name in --images-archive
+    let source = ImageSource::load(args.images_archive.as_deref()).await?;
+    if args.images_archive.is_none() && data.iter().any(|entry| entry.image_path.is_none()) && !args.images_dir.is_dir() {
+        anyhow::bail!("Images directory not found: {}", args.images_dir.display());
+    }
+
+    // Pick the images to process, either afresh or as recorded by an earlier run
+    let sampling = match &args.reuse_sample {
+        _ if args.mismatches_only.is_some() => Sampling::All,
+        _ if retry.is_some() => Sampling::Retry { names: &retry_names, from: &failures_path },
+        Some(path) => Sampling::Reuse(path),
+        None => Sampling::Fresh {
+            size: args.sample_size.unwrap_or(args.limit),
+            seed: args.seed,
+            stratify: args.stratify_by,
+            buckets: &args.buckets,
+            equal_strata: args.equal_strata,
+        },
+    };
+    let (data_to_process, sample_manifest) = jobs::pick(&data, sampling, input)?;
+    if let Some(manifest) = sample_manifest {
+        if args.dry_run {
+            info!("Sampled {} of {} images with seed {}", data_to_process.len(), data.len(), args.seed);
+        } else {
+            manifest.save(&args.sample_manifest)?;
+            info!("Sampled {} of {} images with seed {}, saved to {}",
+                data_to_process.len(), data.len(), args.seed, args.sample_manifest.display());
+        }
+    }
+
+    // Set processing parameters
+    let total = data_to_process.len();
+    let sample_id = sample::sample_id(data_to_process.iter().map(|entry| entry.image_name.as_str()));
+    info!("Processing {} images from dataset", total);
+
+
+    let generation_json = generation.as_ref().map(serde_json::to_string).transpose()?;
+    let field_names = fields.iter().map(|field| field.name()).collect::<Vec<_>>().join(",");
+    if args.votes > 1 {
+        info!("Sending each image {} times and electing the answer by majority vote; requests and cost are {} times those of one sample",
+            args.votes, args.votes);
+    }
+    if args.batch_size > 1 {
+        info!("Sending up to {} images per request", args.batch_size);
+    }
+    let resize = args.max_dimension.map(|max_dimension| Resize { max_dimension, jpeg_quality: args.jpeg_quality });
+    if let Some(resize) = &resize {
+        info!("Shrinking images larger than {} pixels, re-encoding JPEGs at quality {}",
+            resize.max_dimension, resize.jpeg_quality);
+    }
+    if args.preprocess != Preprocess::None {
+        info!("Preprocessing images: {}", args.preprocess.name());
+    }
+    let pricing = Pricing::new(args.price_per_1k_input, args.price_per_1k_output);
+    if let Some(pricing) = &pricing {
+        info!("Estimating cost at {} per 1000 input tokens and {} per 1000 output tokens",
+            pricing.input_per_1k, pricing.output_per_1k);
+    }
+    let image_settings = config::image_settings(resize.as_ref(), args.image_encoding, args.accept_formats.as_deref(), args.preprocess);
+    let config_id = RunConfig {
+        base_url,
+        model: model_name,
+        protocol: args.protocol,
+        mixed_models: mixed_models.then_some(args.model.as_slice()),
+        prompt_hash: prompt_hash.as_deref(),
+        generation: generation_json.as_deref(),
+        fields: &fields,
+        few_shot_hash: few_shot.as_ref().map(|few_shot| few_shot.hash.as_str()),
+        two_stage_hash: detection_prompt.as_ref().map(|prompt| prompt.hash.as_str()),
+        votes: args.votes,
+        batch_size: args.batch_size,
+        image_settings: &image_settings,
+    }
+    .id();
+    // The retried results must come from the configuration that failed, as
+    // the results they are merged with
+    if let Some(retry) = &retry
+        && retry.config_id != config_id
+    {
+        anyhow::bail!("{} lists failures of configuration {}, not {}; retry with the same endpoint, model, prompt and image settings",
+            failures_path.display(), retry.config_id, config_id);
+    }
+    let appending = args.resume || retry.is_some();
+    let checkpoint = if appending {
+        jobs::resume(&args.output, &config_id, &[&retry_names, skip_list.names()])?
+    } else {
+        Checkpoint::default()
+    };
+
+    let rules = TextRules {
+        nfc: !args.no_nfc,
+        fold_width: args.fold_width,
+        fold_case: args.fold_case,
+        strip_punctuation: args.strip_punctuation,
+        whitespace: args.whitespace,
+    };
+    let normalizer = Normalizer::new(args.normalize, rules);
+    if normalizer.mode() != args.normalize && !cfg!(feature = "opencc") {
+        info!("Built without the opencc feature, comparing characters as written; the raw and normalized match rates will be equal");
+    } else if normalizer.mode() != args.normalize {
+        info!("OpenCC data for --normalize {} is not installed, comparing characters as written", args.normalize.name());
+    } else {
+        info!("Normalizing characters before comparison: {}", normalizer.mode().name());
+    }
+    info!("Text rules before comparison: {}", rules.names().join(", "));
+
+    // Metrics cover every result of this configuration, including resumed ones
+    let metrics_path = args.output.with_file_name(metrics::METRICS_FILE_NAME);
+    let mut scoreboard = Scoreboard::new(&config_id, normalizer.mode().name(), &rules.names(), &sample_id);
+    let device_scorer = TokenOverlap { stopwords: args.device_stopwords };
+    let device_thresholds = device::Thresholds { good: args.device_good, partial: args.device_partial };
+    scoreboard.device_scorer = device_scorer.name().to_string();
+    scoreboard.device_thresholds = device_thresholds;
+    scoreboard.fields = fields.clone();
+    scoreboard.strict_script = args.strict_script;
+    scoreboard.preprocess = args.preprocess;
+    scoreboard.skip_listed = skip_listed;
+    scoreboard.health_checks = health_checks;
+    scoreboard.dataset_source = dataset_source;
+    scoreboard.few_shot = few_shot.as_ref().zip(args.few_shot.as_deref()).map(|(few_shot, path)| few_shot.summary(path));
+    if fields.len() < Field::ALL.len() {
+        info!("Extracting only {}", field_names);
+    }
+    for result in &checkpoint.results {
+        record(&mut scoreboard, result, result.alignment().as_deref());
+    }
+
+    let labels = Labels {
+        include_unlabeled: args.include_unlabeled,
+        only_unlabeled: args.only_unlabeled,
+        chinese: fields.contains(&Field::Chinese),
+    };
+    let (jobs, selected) = jobs::select(&data_to_process, &checkpoint, &source, &args.images_dir, labels);
+    let workers = std::thread::available_parallelism().map_or(4, |n| n.get());
+
+    if args.dry_run {
+        let summary = DryRun {
+            selected,
+            config_id: &config_id,
+            image_settings: &image_settings,
+            accepted_formats,
+            resize,
+            preprocess: args.preprocess,
+            source: &source,
+        };
+        let requests = dry_run(&args, &jobs, &backend, detector.as_ref(), summary, workers).await?;
+        return Ok(Run::DryRun { requests });
+    }
+
+    let writers = Writers::open(&args.output, appending, args.mismatches_only.as_deref(), args.report_interval,
+        args.output_csv.as_deref().map(|path| (path, args.bom)))?;
+    let (mismatches_path, progress_metrics) = (writers.mismatches_path.clone(), writers.progress_metrics.clone());
+
+    let cache = match &args.cache_dir {
+        Some(dir) if !args.no_cache => {
+            info!("{} API responses in {}",
+                if args.refresh_cache { "Refreshing cached" } else { "Caching" }, dir.display());
+            Some(ResponseCache::open(dir, args.refresh_cache)?)
+        }
+        _ => None,
+    };
+
+    let responses = match &args.save_responses {
+        Some(dir) => {
+            info!("Saving {} API responses to {}",
+                if args.save_failures_only { "failed" } else { "all" }, dir.display());
+            Some(ResponseLog::open(dir, args.save_responses_limit, args.save_failures_only)?)
+        }
+        None => None,
+    };
+
+    let limiter = rate.map(RequestLimiter::per_second);
+    if let Some(limiter) = &limiter {
+        info!("Pacing requests at one every {:.2}s ({:.1} per minute) with up to {} in flight",
+            limiter.interval().as_secs_f64(), 60.0 / limiter.interval().as_secs_f64(), args.concurrency);
+    }
+    let tuner = args.auto_tune.then(|| {
+        info!("Tuning the concurrency up to {}, measured every {}s", args.auto_tune_max, args.auto_tune_interval);
+        Arc::new(ConcurrencyTuner::new(args.auto_tune_max, Duration::from_secs(args.auto_tune_interval)))
+    });
+
+    let encodings = Encodings::of([Some(&backend), detector.as_ref()].into_iter().flatten());
+    let budget = PayloadBudget::new(args.max_inflight_bytes);
+    if let Some(limit) = budget.limit() {
+        info!("Holding at most {} of image payload in flight", images::format_bytes(limit));
+    }
+
+    // Create shared resources
+    let extractor = Arc::new(Extractor {
+        client,
+        backend,
+        endpoints,
+        detector,
+        cache,
+        responses,
+        resize,
+        preprocess: args.preprocess,
+        accepted_formats,
+        image_settings,
+        votes: args.votes,
+        source: source.clone(),
+        encodings,
+        budget,
+        pricing,
+        max_retries: args.max_retries,
+        limiter,
+        tuner,
+        throttled: AtomicUsize::new(0),
+        auth_failed: AtomicBool::new(false),
+        heuristic_parses: AtomicUsize::new(0),
+        failures: Mutex::new(BTreeMap::new()),
+        normalizer,
+        strict_script: args.strict_script,
+        device_scorer: Box::new(device_scorer),
+        device_thresholds,
+        scoreboard: Mutex::new(scoreboard),
+        started: Instant::now(),
+        metrics_path: metrics_path.clone(),
+        config_id,
+        model_spec,
+        buckets: args.buckets.clone(),
+        prompt_hash,
+        generation,
+        results: writers.results,
+        mismatches: writers.mismatches,
+        request_index: writers.request_index,
+        request_id_header: !args.no_request_id_header,
+        csv_results: writers.csv_results,
+        early_stop: args.early_stop.then(|| EarlyStop::new(jobs.iter().map(|job| job.index), args.target_ci, args.early_stop_min)),
+        progress: Progress::new(bars),
+    });
+    if args.early_stop {
+        info!("Stopping once the accuracy's 95% interval is within ±{} of the estimate, after at least {} labeled images",
+            args.target_ci, args.early_stop_min);
+    }
+    extractor.progress.start(jobs.len());
+
+    // Files over --max-input-bytes fail before anything reads them
+    let (jobs, too_large) = jobs::split_too_large(&source, jobs, args.max_input_bytes);
+    let too_large_names: Vec<String> = too_large.iter()
+        .map(|(job, e)| format!("{} ({})", job.image_name, images::format_bytes(e.bytes)))
+        .collect();
+    for (job, e) in too_large {
+        extractor.fail_unsent(&job, e.into(), total);
+    }
+
+    // Images that can't be read fail here rather than at the API
+    let (jobs, corrupt) = jobs::split_unreadable(&source, jobs, workers, args.strict_images).await?;
+    for (job, e) in corrupt {
+        extractor.fail_unsent(&job, e.into(), total);
+    }
+
+    // Send one image of each set with identical contents
+    let image_count = jobs.len();
+    let groups = jobs::group(&source, jobs, workers, !args.no_dedup).await;
+    let duplicate_groups = groups.iter().filter(|(_, duplicates)| !duplicates.is_empty()).count();
+    let saved_requests = image_count - groups.len();
+
+    // Each group, or `--batch-size` groups sent together, is read and
+    // encoded by one of the preprocessing workers and sent as soon as one
+    // of the `concurrency` slots frees up, until Ctrl+C stops the dispatch
+    let interrupt = Interrupt::listen();
+    let max_concurrency = if args.auto_tune { args.auto_tune_max } else { args.concurrency };
+    let stages = Stages {
+        batch_size: args.batch_size,
+        preprocess_workers: args.preprocess_workers.unwrap_or(workers),
+        prefetch: args.prefetch.unwrap_or(max_concurrency),
+        slots: match &extractor.tuner {
+            Some(tuner) => tuner.slots(),
+            None => Arc::new(Semaphore::new(args.concurrency)),
+        },
+    };
+    let tuning = extractor.tuner.as_ref().map(|tuner| task::spawn(Arc::clone(tuner).run()));
+    let snapshots = progress_metrics.as_ref().map(|writer| {
+        let (extractor, writer) = (Arc::clone(&extractor), Arc::clone(writer));
+        let interval = Duration::from_secs(args.report_interval);
+        task::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                extractor.report_snapshot(&writer);
+                extractor.save_metrics().await;
+            }
+        })
+    });
+    let stats = Arc::new(PipelineStats::default());
+
+    let prepare = {
+        let extractor = Arc::clone(&extractor);
+        move |batch: Vec<(Job, Vec<Job>)>| {
+            let extractor = Arc::clone(&extractor);
+            async move {
+                let prepared = extractor.prepare(&batch).await;
+                Ready { batch, prepared }
+            }
+        }
+    };
+    let send = {
+        let extractor = Arc::clone(&extractor);
+        move |Ready { mut batch, prepared }: Ready| {
+            let extractor = Arc::clone(&extractor);
+            async move {
+                let started = Instant::now();
+                if batch.len() > 1 {
+                    let span = info_span!("batch", image_name = %batch[0].0.image_name, images = batch.len());
+                    extractor.run_batch(batch, prepared, total).instrument(span).await;
+                } else if let Some((job, duplicates)) = batch.pop() {
+                    let span = info_span!("image", image_name = %job.image_name);
+                    extractor.run(job, duplicates, prepared.images.into_iter().next().flatten(), total).instrument(span).await;
+                }
+                if let Some(tuner) = &extractor.tuner {
+                    tuner.record_image(started.elapsed());
+                }
+            }
+        }
+    };
+    let stopped = || extractor.auth_failed.load(Ordering::Relaxed) || extractor.early_stop.as_ref().is_some_and(EarlyStop::stopped);
+    let dispatched = dispatch::run(groups, &stages, &interrupt, &stats, stopped, prepare, send).await?;
+    extractor.progress.finish();
+    if let Some(tuning) = tuning {
+        tuning.abort();
+    }
+    if let Some(snapshots) = snapshots {
+        snapshots.abort();
+    }
+    if let Some(writer) = &progress_metrics {
+        extractor.report_snapshot(writer);
+    }
+    let request_workers = extractor.tuner.as_ref().map_or(args.concurrency, |tuner| tuner.level());
+    if let Ok(mut scoreboard) = extractor.scoreboard.lock() {
+        scoreboard.pipeline = Some(stats.summary(dispatched.elapsed, stages.preprocess_workers, request_workers, stages.prefetch));
+        scoreboard.auto_tune = extractor.tuner.as_ref().and_then(|tuner| tuner.summary());
+        scoreboard.early_stop = extractor.early_stop.as_ref().and_then(EarlyStop::summary).map(|summary| EarlyStopSummary {
+            skipped: extractor.progress.remaining(),
+            ..summary
+        });
+    }
+
+    if interrupt.is_set() {
+        let unprocessed = extractor.save_partial(args.bootstrap)?;
+        warn!("Interrupted with {} images unprocessed; results are saved in {}, run again with --resume to finish",
+            unprocessed, args.output.display());
+        std::process::exit(interrupt::EXIT_INTERRUPTED);
+    }
+
+    if extractor.auth_failed.load(Ordering::Relaxed) {
+        extractor.save_metrics().await;
+        anyhow::bail!("The API rejected the credentials (HTTP 401/403), stopped before the rest of the dataset. \
+                       Check --api-key or {}", args.protocol.api_key_var().unwrap_or(protocol::API_KEY_VAR));
+    }
+
+    if let Some(limiter) = &extractor.limiter {
+        info!("{} requests waited for the rate limit", limiter.waits());
+    }
+    debug!(
+        event = "payload_peak",
+        peak_bytes = extractor.budget.peak(),
+        "Peak image payload in flight: {}{}",
+        images::format_bytes(extractor.budget.peak()),
+        extractor.budget.limit().map_or(String::new(), |limit| format!(" of {}", images::format_bytes(limit)))
+    );
+    // The retried images' new results replace their failures, and the
+    // images failing after this run are listed for the next retry
+    if retry.is_some() {
+        let replaced = results::drop_superseded(&args.output, &extractor.config_id, &retry_names)?;
+        info!("Merged the retried images into {}, replacing {} failed results", args.output.display(), replaced);
+    }
+    let (mut run_results, _) = results::load(&args.output)?;
+    run_results.retain(|result| !skip_list.contains(&result.image_name));
+    let failure_list = FailureList::from_results(&extractor.config_id, &run_results);
+    failure_list.save(&failures_path)?;
+    // All of the configuration's results, resumed ones included
+    let config_results = results::load_config(&args.output, &extractor.config_id, &skip_list)?;
+
+    // Intervals from the images' scores, with the change from a baseline
+    // that records its own
+    if let Ok(mut scoreboard) = extractor.scoreboard.lock() {
+        let scoreboard = &mut *scoreboard;
+        scoreboard.confidence = args.bootstrap.confidence(&scoreboard.image_scores);
+        if let (Some(confidence), Some(baseline)) = (&mut scoreboard.confidence, &baseline)
+            && !baseline.image_scores.is_empty()
+        {
+            confidence.baseline = Some(args.bootstrap.differences(&baseline.image_scores, &scoreboard.image_scores));
+        }
+    }
+    // Accuracy with the verdicts of a review of the mismatches, when there
+    // is one
+    let verdicts_path = review::verdicts_path(&mismatches_path);
+    if verdicts_path.exists() {
+        let verdicts = review::load_verdicts(&verdicts_path)?;
+        if let Ok(mut scoreboard) = extractor.scoreboard.lock() {
+            scoreboard.label_review = Some(LabelReview::new(&scoreboard, &verdicts));
+        }
+    }
+    // Labels of the mismatches as IPOS has them now
+    if args.verify_labels {
+        let mismatched = extractor.scoreboard.lock()
+            .map(|scoreboard| drift::mismatches(&scoreboard, &run_results))
+            .unwrap_or_default();
+        dataset::find_records(&mut records, mismatched.iter().map(|result| result.image_name.as_str()), &data, &source, &args.images_dir)?;
+        let corrections_path = args.output.with_file_name(drift::CORRECTIONS_FILE_NAME);
+        let mut label_drift = drift::verify(&extractor.client, &mismatched, &records, args.verify_labels_concurrency,
+            |text| extractor.normalizer.normalize(text), &corrections_path).await?;
+        if let Ok(mut scoreboard) = extractor.scoreboard.lock() {
+            label_drift.score(&scoreboard);
+            scoreboard.label_drift = Some(label_drift);
+        }
+        info!("Labels changed at IPOS saved to {}", corrections_path.display());
+    }
+    // Accuracy by bucket
+    if extractor.backend.fields.contains(&Field::Chinese)
+        && let Ok(mut scoreboard) = extractor.scoreboard.lock()
+    {
+        scoreboard.breakdown = Some(args.buckets.breakdown(&config_results));
+    }
+    if let Ok(scoreboard) = extractor.scoreboard.lock() {
+        outputs::save_metrics(&scoreboard, &args.output)?;
+    }
+
+    results::sort_mismatches(&mismatches_path)?;
+    info!("Mismatches saved to {}, largest edit distance first", mismatches_path.display());
+
+    // Compare with the baseline, and replace it if asked and nothing regressed
+    let mut regression = None;
+    if let Some(path) = &args.baseline
+        && let Ok(scoreboard) = extractor.scoreboard.lock()
+    {
+        regression = outputs::check_baseline(&scoreboard, baseline.as_ref(), path, args.regression_threshold, args.save_baseline)?;
+    }
+
+    if let Some(path) = &args.html_report
+        && let Ok(scoreboard) = extractor.scoreboard.lock()
+    {
+        let config = vec![
+            ("Generated", Local::now().format("%Y-%m-%d %H:%M:%S").to_string()),
+            ("Dataset", input.display().to_string()),
+            ("Endpoint", extractor.backend.url()),
+            ("Protocol", args.protocol.name().to_string()),
+            ("Model", model_name.to_string()),
+            ("Prompt hash", extractor.prompt_hash.clone().unwrap_or_else(|| "-".to_string())),
+            ("Generation", generation_json.clone().unwrap_or_else(|| "-".to_string())),
+            ("Few-shot examples", scoreboard.few_shot.as_ref()
+                .map_or("-".to_string(), |few_shot| format!("{} ({})", few_shot.image_names.join(", "), few_shot.hash))),
+            ("Normalization", scoreboard.normalize.clone()),
+            ("Text rules", scoreboard.text_rules.join(", ")),
+            ("Fields", field_names.clone()),
+            ("Configuration id", extractor.config_id.clone()),
+            ("Sample id", scoreboard.sample_id.clone()),
+            ("Results file", args.output.display().to_string()),
+        ];
+        outputs::save_html_report(path, config, &scoreboard, &config_results, &mismatches_path)?;
+    }
+
+    if let Some(path) = &args.enriched_output {
+        outputs::save_enriched(path, args.raw_data.as_deref(), &args.dataset, &config_results, &documents, model_name)?;
+    }
+
+    if args.gallery {
+        let title = format!("{} on {}", model_name, input.display());
+        outputs::save_gallery(&args.output, title, &config_results, &data, &source, &args.images_dir, args.gallery_max)?;
+    }
+
+    if args.output_schema == Some(OutputSchema::Ipos) {
+        outputs::save_ipos(&args.output, &config_results, &mut records, &data, &source, &args.images_dir, model_name)?;
+    }
+
+    extractor.log_counts();
+    if !failure_list.is_empty() {
+        info!("{} images have failed, listed in {}; run with --retry-failed {} to process them again",
+            failure_list.len(), failures_path.display(), failures_path.display());
+    }
+    if duplicate_groups > 0 {
+        info!("{} groups of identical images, {} requests saved by reusing their results",
+            duplicate_groups, saved_requests);
+    }
+    if !too_large_names.is_empty() {
+        warn!("Skipped {} images over --max-input-bytes of {}: {}",
+            too_large_names.len(), images::format_bytes(args.max_input_bytes), too_large_names.join(", "));
+    }
+
+    if args.resume {
+        info!("Skipped {} images already in {}", selected.resumed, args.output.display());
+    }
+
+    if args.two_stage
+        && let Ok(scoreboard) = extractor.scoreboard.lock()
+    {
+        info!("{} of {} images had no Chinese detected and skipped the extraction request",
+            scoreboard.detection.negatives(), scoreboard.detection.total());
+    }
+
+    if let Some(drop) = regression {
+        anyhow::bail!("Accuracy regressed by {:.1} percentage points against the baseline", drop);
+    }
+
+    let scoreboard = extractor.scoreboard.lock().map_err(|_| anyhow!("Scoreboard lock poisoned"))?;
+    Ok(Run::Finished(Box::new(scoreboard.clone())))
+}
+
+/// Run the sample through each `--model-spec` in turn, then compare the
+/// models on the images they answered. Each model's results, metrics,
+/// cache and saved responses go to a directory named after it.
+async fn compare_models(args: Args, dataset_source: Option<DatasetSource>, bars: MultiProgress) -> Result<()> {
+    let mut names = HashSet::new();
+    if let Some(spec) = args.model_spec.iter().find(|spec| !names.insert(spec.name.as_str())) {
+        anyhow::bail!("--model-spec {} is given more than once", spec.name);
+    }
+
+    // Each run checks its endpoint again, but a model that is down should
+    // stop the comparison before the others have run
+    if !args.skip_health_check && !args.dry_run {
+        let client = Client::builder()
+            .connect_timeout(Duration::from_secs(args.connect_timeout_secs))
+            .build()
+            .context("Failed to create HTTP client")?;
+        for spec in &args.model_spec {
+            let backend = Backend {
+                protocol: spec.protocol,
+                base_url: spec.base_url.clone(),
+                model: spec.model.clone(),
+                api_key: api_key(&spec_args(&args, spec)),
+                prompt: String::new(),
+                user_prompt: protocol::USER_PROMPT,
+                generation: Generation::default(),
+                json_mode: !args.no_json_mode,
+                fields: Field::ALL.to_vec(),
+                image_encoding: spec.protocol.default_image_encoding(),
+                few_shot: None,
+            };
+            let check = health::check(&client, &backend).await;
+            if let Some(error) = &check.error {
+                anyhow::bail!("Health check of model {} at {} failed: {}\n(--skip-health-check runs without it, for servers that don't answer it)",
+                    spec.name, check.url, error);
+            }
+        }
+        info!("The endpoints of all {} models answered their health check", args.model_spec.len());
+    }
+
+    let mut runs = Vec::new();
+    for spec in &args.model_spec {
+        let spec_args = spec_args(&args, spec);
+        info!("Model {}: {} protocol at {}, model {}{}", spec.name, spec.protocol.name(), spec.base_url,
+            spec.model.as_deref().unwrap_or("local-api"),
+            spec.rpm.map_or(String::new(), |rpm| format!(", {} requests per minute", rpm)));
+        if !args.dry_run
+            && let Some(dir) = spec_args.output.parent()
+        {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create output directory: {}", dir.display()))?;
+        }
+        let output = spec_args.output.clone();
+        let run = extract(spec_args, Some(spec.name.clone()), dataset_source.clone(), bars.clone())
+            .instrument(info_span!("model", name = %spec.name))
+            .await?;
+        runs.push((spec, output, run));
+    }
+
+    if args.dry_run {
+        let requests: Vec<String> = runs.iter()
+            .filter_map(|(spec, _, run)| match run {
+                Run::DryRun { requests } => Some(format!("{} {}", spec.name, requests)),
+                Run::Finished(_) => None,
+            })
+            .collect();
+        let total: usize = runs.iter().map(|(_, _, run)| match run {
+            Run::DryRun { requests } => *requests,
+            Run::Finished(_) => 0,
+        }).sum();
+        info!("Comparing {} models sends each image {} times, once to each: {} requests in all before retries ({})",
+            runs.len(), runs.len(), total, requests.join(", "));
+        return Ok(());
+    }
+
+    let mut finished = Vec::new();
+    for (spec, output, run) in runs {
+        let Run::Finished(scoreboard) = run else {
+            continue;
+        };
+        let (mut results, _) = results::load(&output)?;
+        results.retain(|result| result.config_id == scoreboard.config_id);
+        finished.push(ModelRun { spec: spec.clone(), scoreboard: *scoreboard, results });
+    }
+    let comparison = Comparison::new(&finished, &args.bootstrap);
+    let metrics_path = args.output.with_file_name(metrics::METRICS_FILE_NAME);
+    comparison.save(&metrics_path)?;
+    info!("{}", comparison.report(MISSING_EXAMPLES));
+    info!("Comparison saved to {}", metrics_path.display());
+    Ok(())
+}
+
+/// Run the sample once per setting of `--sweep-temperature` or
+/// `--sweep-preprocess`, each writing its files to a directory of its own,
+/// and compare the settings. The first run draws the sample and the others
+/// reuse its manifest, so they process the same images; the response cache
+/// is shared, its keys telling the settings apart.
+async fn sweep(args: Args, swept: Vec<Setting>, dataset_source: Option<DatasetSource>, bars: MultiProgress) -> Result<()> {
+    let mut settings: Vec<Setting> = Vec::new();
+    for setting in swept {
+        if !settings.contains(&setting) {
+            settings.push(setting);
+        }
+    }
+    let parameter = settings.first().map_or("temperature", |setting| setting.parameter());
+    if settings.len() < 2 {
+        anyhow::bail!("--sweep-{} needs at least two different settings", parameter);
+    }
+    if matches!(settings[0], Setting::Temperature(_)) && !args.protocol.takes_prompt() {
+        anyhow::bail!("--protocol {} has no sampling settings to sweep", args.protocol.name());
+    }
+
+    let mut runs = Vec::new();
+    for (index, setting) in settings.iter().enumerate() {
+        // A dry run saves no manifest, but the same seed draws the same sample
+        let reuse_sample = match &args.reuse_sample {
+            Some(path) => Some(path.clone()),
+            None if index > 0 && !args.dry_run && args.mismatches_only.is_none() && args.retry_failed.is_none() => {
+                Some(args.sample_manifest.clone())
+            }
+            None => None,
+        };
+        let sweep_args = sweep_args(&args, *setting, reuse_sample);
+        info!("Setting {} {} ({} of {})", parameter, setting, index + 1, settings.len());
+        if !args.dry_run
+            && let Some(dir) = sweep_args.output.parent()
+        {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create output directory: {}", dir.display()))?;
+        }
+        let run = extract(sweep_args, None, dataset_source.clone(), bars.clone())
+            .instrument(info_span!("sweep", setting = %setting))
+            .await?;
+        runs.push((*setting, run));
+    }
+
+    if args.dry_run {
+        let requests: Vec<String> = runs.iter()
+            .filter_map(|(setting, run)| match run {
+                Run::DryRun { requests } => Some(format!("{} {}", setting, requests)),
+                Run::Finished(_) => None,
+            })
+            .collect();
+        let total: usize = runs.iter().map(|(_, run)| match run {
+            Run::DryRun { requests } => *requests,
+            Run::Finished(_) => 0,
+        }).sum();
+        info!("Sweeping {} {} settings sends each image {} times, once with each: {} requests in all before retries ({})",
+            runs.len(), parameter, runs.len(), total, requests.join(", "));
+        return Ok(());
+    }
+
+    let finished: Vec<(Setting, Scoreboard)> = runs.into_iter()
+        .filter_map(|(setting, run)| match run {
+            Run::Finished(scoreboard) => Some((setting, *scoreboard)),
+            Run::DryRun { .. } => None,
+        })
+        .collect();
+    let sweep = Sweep::new(&finished)?;
+    let metrics_path = args.output.with_file_name(metrics::METRICS_FILE_NAME);
+    sweep.save(&metrics_path)?;
+    info!("{}", sweep.report());
+    info!("Sweep of {} saved to {}", parameter, metrics_path.display());
+    Ok(())
 }
 
-// Structure for the new API response
-#[derive(Debug, Deserialize)]
-struct ApiResponse {
-    #[serde(rename = "wordsInMark")]
-    words_in_mark: Option<String>,
-    #[serde(rename = "chineseCharacter")]
-    chinese_character: Option<String>,
-    #[serde(rename = "descrOfDevice")]
-    description_of_device: Option<String>,
+/// `args` for the run with `setting`, with the output files moved to a
+/// directory named after it next to `--output` and the saved responses in
+/// a subdirectory named after it
+fn sweep_args(args: &Args, setting: Setting, reuse_sample: Option<PathBuf>) -> Args {
+    let name = setting.dir_name();
+    let dir = args.output.with_file_name(&name);
+    let in_dir = |path: &PathBuf| dir.join(path.file_name().unwrap_or(path.as_os_str()));
+    let (temperature, preprocess) = match setting {
+        Setting::Temperature(temperature) => (temperature, args.preprocess),
+        Setting::Preprocess(preprocess) => (args.temperature, preprocess),
+    };
+    Args {
+        temperature,
+        preprocess,
+        output: in_dir(&args.output),
+        output_csv: args.output_csv.as_ref().map(in_dir),
+        enriched_output: args.enriched_output.as_ref().map(in_dir),
+        html_report: args.html_report.as_ref().map(in_dir),
+        save_responses: args.save_responses.as_ref().map(|save_responses| save_responses.join(&name)),
+        sample_size: if reuse_sample.is_some() { None } else { args.sample_size },
+        reuse_sample,
+        sweep_temperature: Vec::new(),
+        sweep_preprocess: Vec::new(),
+        ..args.clone()
+    }
 }
 
-// Setup logging
-fn setup_logging() -> (PathBuf, Arc<Mutex<File>>) {
-    // Create logs directory if it doesn't exist
-    let logs_dir = Path::new("logs");
-    if !logs_dir.exists() {
-        fs::create_dir_all(logs_dir).expect("Failed to create logs directory");
+/// `args` for the run of `spec`: its endpoint and rate, with the output
+/// files moved to a directory named after it next to `--output` and the
+/// cache and saved responses in a subdirectory named after it
+fn spec_args(args: &Args, spec: &ModelSpec) -> Args {
+    let dir = args.output.with_file_name(&spec.name);
+    let in_dir = |path: &PathBuf| dir.join(path.file_name().unwrap_or(path.as_os_str()));
+    let (rpm, rps) = match spec.rpm {
+        Some(rpm) => (Some(rpm), None),
+        None => (args.rpm, args.rps),
+    };
+    Args {
+        protocol: spec.protocol,
+        base_url: vec![spec.base_url.clone()],
+        model: spec.model.iter().cloned().collect(),
+        rpm,
+        rps,
+        output: in_dir(&args.output),
+        output_csv: args.output_csv.as_ref().map(in_dir),
+        enriched_output: args.enriched_output.as_ref().map(in_dir),
+        html_report: args.html_report.as_ref().map(in_dir),
+        cache_dir: args.cache_dir.as_ref().map(|cache_dir| cache_dir.join(&spec.name)),
+        save_responses: args.save_responses.as_ref().map(|save_responses| save_responses.join(&spec.name)),
+        model_spec: Vec::new(),
+        ..args.clone()
     }
+}
 
-    // Set up timestamp for log filename
-    let timestamp = Local::now().format("%Y%m%d_%H%M%S").to_string();
-    let log_filename = logs_dir.join(format!("extraction_{}.log", timestamp));
+/// Count a result on the scoreboard for the fields it scores
+fn record(scoreboard: &mut Scoreboard, result: &ExtractionResult, edits: Option<&[metrics::Edit]>) {
+    if scoreboard.scores(Field::Chinese) && result.ground_truth.is_none() {
+        let extracted = result.chinese_character.as_deref().is_some_and(|c| !metrics::comparable(c).is_empty());
+        scoreboard.record_unlabeled(extracted);
+    } else if scoreboard.scores(Field::Chinese) {
+        scoreboard.record(&result.image_name, result.outcome(), result.is_raw_match(), edits, result.term_score(), result.script);
+    } else {
+        scoreboard.record_request(result.status == Status::Error);
+    }
+    if let Some(script) = result.script {
+        scoreboard.record_script(script);
+    }
+    if let Some(words) = result.word_score() {
+        scoreboard.record_words(words);
+    }
+    if let Some(similarity) = result.device_similarity {
+        scoreboard.record_device(similarity);
+    }
+    scoreboard.record_latency(&result.image_name, result.request_ms, result.preprocess_ms);
+    if let Some(bytes) = result.sent_bytes {
+        scoreboard.record_usage(result.usage, bytes, result.estimated_cost);
+    }
+    // Only labeled images say whether the mark has characters
+    if let (Some(detected), Some(truth)) = (result.detected, &result.ground_truth) {
+        scoreboard.record_detection(detected, !metrics::comparable(truth).is_empty());
+    }
+}
 
-    // Create log file for print statements
-    let print_log_filename = logs_dir.join(format!("print_output_{}.txt", timestamp));
+/// What `--dry-run` reports besides the images to process
+struct DryRun<'a> {
+    selected: Selected,
+    config_id: &'a str,
+    image_settings: &'a str,
+    accepted_formats: Option<Vec<ImageKind>>,
+    resize: Option<Resize>,
+    preprocess: Preprocess,
+    source: &'a ImageSource,
+}
 
-    // Create the log file for stdout redirection
-    let file = File::create(&print_log_filename).expect("Failed to create print log file");
-    let file_mutex = Arc::new(Mutex::new(file));
+/// Report what a run would do with `jobs` without sending anything: the
+/// images that can't be read, duplicates, cached responses and the requests
+/// left to make, the configuration, and the request of the first image.
+/// Returns the requests to make.
+async fn dry_run(
+    args: &Args,
+    jobs: &[Job],
+    backend: &Backend,
+    detector: Option<&Backend>,
+    summary: DryRun<'_>,
+    workers: usize,
+) -> Result<usize> {
+    let source = summary.source;
+    let sized: Vec<&Job> = jobs.iter().filter(|job| format::check_size(source, &job.image_path, args.max_input_bytes).is_ok()).collect();
+    let checks = format::check_all(source, sized.iter().map(|job| job.image_path.clone()).collect(), workers).await;
+    let readable: Vec<&Job> = sized.iter().zip(&checks).filter(|(_, check)| check.is_ok()).map(|(job, _)| *job).collect();
+    let groups = if args.no_dedup {
+        (0..readable.len()).map(|i| vec![i]).collect()
+    } else {
+        dedup::group_duplicates(source, readable.iter().map(|job| job.image_path.clone()).collect(), workers).await
+    };
 
-    // Initialize the logger
-    env_logger::Builder::from_default_env()
-        .format_timestamp_secs()
-        .format_target(false)
-        .init();
+    // A cache directory that doesn't exist yet holds nothing, and isn't created
+    let cache = match &args.cache_dir {
+        Some(dir) if !args.no_cache && dir.is_dir() => Some(ResponseCache::open(dir, args.refresh_cache)?),
+        _ => None,
+    };
+    // Each vote is a request of its own
+    let stages = match detector {
+        Some(detector) => vec![("Detection request", detector, 1), ("Extraction request", backend, args.votes)],
+        None => vec![("Request", backend, args.votes)],
+    };
+    // A batch makes one request for its images that aren't cached, if any
+    let batched = args.batch_size > 1;
+    let cache_settings = if batched { format!("{} batch", summary.image_settings) } else { summary.image_settings.to_string() };
+    let mut cached = 0;
+    let mut requests = 0;
+    let mut batches_sent = HashSet::new();
+    for (index, group) in groups.iter().enumerate() {
+        let job = readable[group[0]];
+        let uncached = stages.iter()
+            .flat_map(|(_, stage, votes)| {
+                let cache_settings = &cache_settings;
+                (0..*votes).map(move |vote| (*stage, vote::cache_settings(cache_settings, *votes, vote)))
+            })
+            .filter(|(stage, settings)| !cache.as_ref().is_some_and(|cache| {
+                ResponseCache::key(source, &job.image_path, settings, stage)
+                    .is_ok_and(|key| cache.get(&key).is_some())
+            }))
+            .count();
+        cached += usize::from(uncached == 0);
+        if !batched {
+            requests += uncached;
+        } else if uncached > 0 && batches_sent.insert(index / args.batch_size) {
+            requests += 1;
+        }
+    }
 
-    println!("Logging configured. Log file: {:?}", log_filename);
-    println!("Print output will be saved to: {:?}", print_log_filename);
+    let selected = summary.selected.resumed + summary.selected.missing + summary.selected.left_out + jobs.len();
+    let rate = args.rps.or(args.rpm.map(|rpm| rpm / 60.0));
+    let mut lines = vec![
+        "Dry run, no image was sent:".to_string(),
+        format!("  Selected images:        {:6}", selected),
+        format!("  Already in the results: {:6}", summary.selected.resumed),
+        format!("  Not on disk:            {:6}", summary.selected.missing),
+        format!("  Unlabeled:              {:6}", summary.selected.unlabeled),
+        format!("  Left out by label:      {:6}", summary.selected.left_out),
+        format!("  Too large:              {:6}, over --max-input-bytes", jobs.len() - sized.len()),
+        format!("  Corrupt or unreadable:  {:6}", sized.len() - readable.len()),
+        format!("  To process:             {:6}, {} of them unlabeled",
+            readable.len(), readable.iter().filter(|job| job.ground_truth.is_none()).count()),
+        format!("  Duplicates:             {:6}, answered by the request of an identical image", readable.len() - groups.len()),
+        format!("  Answered by the cache:  {:6}", cached),
+        format!("  Requests to make:       {:6}, {}before retries{}",
+            requests, if detector.is_some() { "at most, " } else { "" },
+            if batched { format!(", up to {} images each", args.batch_size) } else { String::new() }),
+        format!("  Votes per image:        {:6}{}", args.votes,
+            if args.votes > 1 { format!(", every image costs {} requests", args.votes) } else { String::new() }),
+        format!("Configuration {}:", summary.config_id),
+        format!("  Endpoint: {} ({} protocol, model {}, images as {})", backend.url(), backend.protocol.name(),
+            backend.model.as_deref().unwrap_or("local-api"), backend.image_encoding.name()),
+        format!("  Fallback endpoints: {}", if args.base_url.len() > 1 { args.base_url[1..].join(", ") } else { "none".to_string() }),
+        format!("  Fields: {}", backend.fields.iter().map(|field| field.name()).collect::<Vec<_>>().join(", ")),
+    ];
+    if backend.protocol.takes_prompt() {
+        lines.push(format!("  Generation: {}", serde_json::to_string(&backend.generation)?));
+    }
+    lines.extend([
+        format!("  Images: {}", if summary.image_settings.is_empty() { "as on disk" } else { summary.image_settings }),
+        format!("  Concurrency {}, up to {} retries, {}s timeout{}", args.concurrency, args.max_retries, args.timeout_secs,
+            rate.map_or(String::new(), |rate| format!(", {:.2} requests per second", rate))),
+        format!("  Results: {}{}", args.output.display(), if args.resume || args.retry_failed.is_some() { ", appended to" } else { "" }),
+    ]);
+    info!("{}", lines.join("\n"));
 
-    // Also write to the log file
-    if let Ok(mut file) = file_mutex.lock() {
-        let _ = writeln!(file, "Logging configured. Log file: {:?}", log_filename);
-        let _ = writeln!(file, "Print output will be saved to: {:?}", print_log_filename);
+    // What the model would receive for the first image
+    let encodings = Encodings::of(stages.iter().map(|(_, stage, _)| *stage));
+    if let Some(job) = readable.first() {
+        if backend.protocol.takes_prompt() {
+            info!("Prompt:\n{}", backend.prompt);
+        }
+        if batched && readable.len() > 1 {
+            let jobs: Vec<&Job> = groups.iter().take(args.batch_size).map(|group| readable[group[0]]).collect();
+            let mut images = Vec::new();
+            for job in &jobs {
+                images.push(encode_image(source, &job.image_path, summary.accepted_formats.clone(), summary.resize, summary.preprocess, encodings).await?);
+            }
+            let names: Vec<&str> = jobs.iter().map(|job| job.image_name.as_str()).collect();
+            info!("Request for the first batch:\n{}",
+                serde_json::to_string_pretty(&batch_request_summary(backend, &names, &images.iter().collect::<Vec<_>>()))?);
+        } else {
+            let image = encode_image(source, &job.image_path, summary.accepted_formats, summary.resize, summary.preprocess, encodings).await?;
+            for (name, stage, _) in stages {
+                info!("{} for {}:\n{}", name, job.image_name, serde_json::to_string_pretty(&request_summary(stage, &image))?);
+            }
+        }
+    }
+    if args.votes > 1 {
+        warn!("--votes {} sends every image {} times: the requests above, the time and the cost are {} times those of one sample",
+            args.votes, args.votes, args.votes);
     }
+    Ok(requests)
+}
+
+/// Times one image may be requeued after 429 responses before giving up
+const MAX_THROTTLES: u32 = 10;
 
-    (log_filename, file_mutex)
+/// State shared by every extraction task
+struct Extractor {
+    client: Client,
+    backend: Backend,
+    /// `--base-url`s the requests of `backend` and `detector` go to
+    endpoints: Endpoints,
+    /// Backend of the detection stage with `--two-stage`
+    detector: Option<Backend>,
+    cache: Option<ResponseCache>,
+    /// Where `--save-responses` writes the raw responses
+    responses: Option<ResponseLog>,
+    /// Shrinking of large images before they are sent
+    resize: Option<Resize>,
+    /// `--preprocess` applied to each image after shrinking
+    preprocess: Preprocess,
+    /// Formats sent as they are, others being converted to PNG; `None` sends anything
+    accepted_formats: Option<Vec<ImageKind>>,
+    /// `--max-dimension`, `--accept-formats` and `--preprocess` settings,
+    /// part of the cache key
+    image_settings: String,
+    /// Samples of the extraction asked for each image, `--votes`
+    votes: usize,
+    /// Where the images are read from
+    source: ImageSource,
+    /// Forms of each image kept for the requests
+    encodings: Encodings,
+    /// Image payload in flight, capped with `--max-inflight-bytes`
+    budget: PayloadBudget,
+    /// Token prices the estimated costs are computed with
+    pricing: Option<Pricing>,
+    max_retries: u32,
+    limiter: Option<RequestLimiter>,
+    /// Request slots sized by `--auto-tune`
+    tuner: Option<Arc<ConcurrencyTuner>>,
+    /// 429 responses received
+    throttled: AtomicUsize,
+    /// Set once the server refuses the credentials, to stop dispatching images
+    auth_failed: AtomicBool,
+    /// Replies whose fields were picked out of free text
+    heuristic_parses: AtomicUsize,
+    /// Failed images by failure class
+    failures: Mutex<BTreeMap<&'static str, usize>>,
+    normalizer: Normalizer,
+    /// Count predictions not in Chinese characters alone as wrong
+    strict_script: bool,
+    device_scorer: Box<dyn DescriptionScorer>,
+    device_thresholds: device::Thresholds,
+    /// Outcomes so far, saved to `metrics_path` at each snapshot and at the
+    /// end of the run
+    scoreboard: Mutex<Scoreboard>,
+    /// When the first image was dispatched, for the throughput
+    started: Instant,
+    metrics_path: PathBuf,
+    config_id: String,
+    /// Name of the `--model-spec` the run is for
+    model_spec: Option<String>,
+    /// Bounds of the accuracy breakdown buckets each result is put in
+    buckets: BucketBounds,
+    prompt_hash: Option<String>,
+    generation: Option<Generation>,
+    results: ResultsWriter,
+    /// Answers that differ from the ground truth, for review
+    mismatches: ResultsWriter,
+    /// Image, attempt and outcome of every request, by request id
+    request_index: ResultsWriter,
+    /// Send each request's id in `correlation::REQUEST_ID_HEADER`
+    request_id_header: bool,
+    csv_results: Option<CsvResultsWriter>,
+    /// Stopping rule of `--early-stop`, fed every completed image
+    early_stop: Option<EarlyStop>,
+    /// Bar or status lines on stderr, updated as each image completes
+    progress: Progress,
 }
 
-// Helper function to log both to console and file
-fn log_to_both(log_file: &Arc<Mutex<File>>, message: &str) {
-    println!("{}", message);
-    if let Ok(mut file) = log_file.lock() {
-        let _ = writeln!(file, "{}", message);
+impl Extractor {
+    /// Log the state of the run and append it to the progress metrics. The
+    /// scoreboard is only locked to copy its running counts.
+    fn report_snapshot(&self, writer: &ResultsWriter) {
+        let Ok((scored, exact_match, errors, cers, cer_sum)) = self.scoreboard.lock()
+            .map(|scoreboard| (scoreboard.total, scoreboard.exact_match, scoreboard.errors, scoreboard.cers.len(), scoreboard.cer_sum))
+        else {
+            return;
+        };
+        let errors_by_class = self.failures.lock()
+            .map(|failures| failures.iter().map(|(class, count)| (class.to_string(), *count)).collect())
+            .unwrap_or_default();
+        let ratio = |count: usize, of: usize| (of > 0).then(|| count as f64 / of as f64);
+        let processed = self.progress.done();
+        let snapshot = Snapshot {
+            at: Local::now().to_rfc3339(),
+            elapsed_secs: self.started.elapsed().as_secs(),
+            processed,
+            total: processed + self.progress.remaining(),
+            scored,
+            accuracy: ratio(exact_match, scored),
+            mean_cer: (cers > 0).then(|| cer_sum / cers as f64),
+            errors,
+            error_rate: ratio(errors, scored),
+            errors_by_class,
+            images_per_minute: self.progress.images_per_minute(),
+            eta_secs: self.progress.eta().map(|eta| eta.as_secs()),
+        };
+        info!(event = "snapshot", processed, "{}", snapshot);
+        if let Err(e) = writer.write(&snapshot) {
+            warn!("{:#}", e);
+        }
     }
-}
 
-// Encode image to base64
-fn encode_image(image_path: &Path) -> Result<String> {
-    let mut file = File::open(image_path)
-        .with_context(|| format!("Failed to open image file: {:?}", image_path))?;
+    /// Save the metrics so far. The scoreboard is only locked to copy it,
+    /// and the file is written off the runtime's threads.
+    async fn save_metrics(&self) {
+        let Ok(scoreboard) = self.scoreboard.lock().map(|scoreboard| scoreboard.clone()) else {
+            return;
+        };
+        let path = self.metrics_path.clone();
+        match task::spawn_blocking(move || scoreboard.save(&path)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("{:#}", e),
+            Err(e) => error!("Metrics saving task panicked: {}", e),
+        }
+    }
 
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)
-        .with_context(|| format!("Failed to read image file: {:?}", image_path))?;
+    /// Flush the results written so far and save the metrics of the run as
+    /// partial, returning the number of images left unprocessed
+    fn save_partial(&self, bootstrap: Bootstrap) -> Result<usize> {
+        let unprocessed = self.progress.remaining();
+        self.results.sync()?;
+        self.mismatches.sync()?;
+        if let Some(csv_results) = &self.csv_results {
+            csv_results.sync()?;
+        }
+        if let Ok(mut scoreboard) = self.scoreboard.lock() {
+            scoreboard.partial = true;
+            scoreboard.unprocessed = unprocessed;
+            scoreboard.confidence = bootstrap.confidence(&scoreboard.image_scores);
+            scoreboard.save(&self.metrics_path)?;
+            info!("{}", scoreboard.report());
+            info!("Partial metrics saved to {}", self.metrics_path.display());
+        }
+        Ok(unprocessed)
+    }
 
-    Ok(general_purpose::STANDARD.encode(&buffer))
-}
+    /// Log how the run's requests went: failures by class, replies parsed
+    /// heuristically, the cache, the endpoints, saved responses and throttling
+    fn log_counts(&self) {
+        if let Ok(failures) = self.failures.lock() {
+            let total: usize = failures.values().sum();
+            if total == 0 {
+                info!("No failed images");
+            } else {
+                let breakdown: Vec<String> = failures.iter().map(|(class, count)| format!("{} {}", class, count)).collect();
+                info!("{} failed images: {}", total, breakdown.join(", "));
+            }
+        }
+        let heuristic_parses = self.heuristic_parses.load(Ordering::Relaxed);
+        if heuristic_parses > 0 {
+            info!("{} replies were not JSON and were parsed heuristically", heuristic_parses);
+        }
+        if let Some(cache) = &self.cache {
+            info!("Response cache: {}", cache.describe());
+        }
+        if self.endpoints.has_fallback() {
+            info!("{}", self.endpoints.report());
+        }
+        if let Some(responses) = &self.responses {
+            info!("{}", responses.describe());
+        }
+        let throttled = self.throttled.load(Ordering::Relaxed);
+        if throttled > 0 {
+            info!("Throttled {} times by the server (HTTP 429)", throttled);
+        }
+    }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+    /// Extract one image and record the result for it and for each of its
+    /// duplicates. `prepared` is the image as the preprocessing workers
+    /// encoded it, if they did.
+    async fn run(&self, job: Job, duplicates: Vec<Job>, prepared: Option<(Result<EncodedImage>, Duration)>, total: usize) {
+        let started = Instant::now();
+        let sending = Sending::default();
+        match prepared {
+            Some((Ok(image), preprocess)) => {
+                let _ = sending.image.set(image);
+                sending.add(|sent| sent.preprocess = Some(preprocess));
+            }
+            Some((Err(e), preprocess)) => {
+                let sent = Sent { preprocess: Some(preprocess), ..Sent::default() };
+                let extracted = Extracted { outcome: Err(e), attempts: 0, detected: None, cached: false, sent };
+                self.finish_group(&job, &duplicates, &extracted, preprocess.as_millis() as u64, total);
+                return;
+            }
+            None => {}
+        }
+        let mut extracted = self.extract(&job, &sending).await;
+        extracted.sent = sending.into_sent();
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        self.finish_group(&job, &duplicates, &extracted, elapsed_ms, total);
+    }
 
-    // Setup logging
-    let (_, log_file) = setup_logging();
+    /// Record the result of `job` and of each of its duplicates
+    fn finish_group(&self, job: &Job, duplicates: &[Job], extracted: &Extracted, elapsed_ms: u64, total: usize) {
+        self.finish(job, extracted, elapsed_ms, None, total);
+        for duplicate in duplicates {
+            let _span = info_span!("duplicate", image_name = %duplicate.image_name).entered();
+            self.finish(duplicate, extracted, elapsed_ms, Some(&job.image_name), total);
+        }
+    }
 
-    log_to_both(&log_file, "Starting extraction process");
+    /// Extract the images of `batch`, each with its duplicates, in one
+    /// request, with the images the preprocessing workers encoded. Images
+    /// with a cached answer are not sent. When the reply can't be split
+    /// into exactly one valid object per image, each image is sent again on
+    /// its own with its own retries.
+    async fn run_batch(&self, batch: Vec<(Job, Vec<Job>)>, prepared: Prepared, total: usize) {
+        let started = Instant::now();
+        // Held until every image of the batch has its result
+        let _payload = prepared.payload;
+        let mut pending = Vec::new();
+        for ((job, duplicates), image) in batch.into_iter().zip(prepared.images) {
+            match self.cached_batch_item(&job) {
+                Some(response) => {
+                    let extracted = Extracted {
+                        outcome: Ok(Extraction::Fields(response)),
+                        attempts: 0,
+                        detected: None,
+                        cached: true,
+                        sent: Sent::default(),
+                    };
+                    self.finish_group(&job, &duplicates, &extracted, 0, total);
+                }
+                None => pending.push((job, duplicates, image)),
+            }
+        }
 
-    // Initialize HTTP client
-    let api_key = ""; // No longer needed but keeping for compatibility
-    let base_url = "http://localhost:1234"; // Updated to the new API URL
-    log_to_both(&log_file, &format!("Initializing API client with base URL: {}", base_url));
+        let mut images = Vec::new();
+        for (job, duplicates, image) in pending {
+            // An image the workers left, its answer cached when they looked,
+            // is encoded here
+            let (image, preprocess) = match image {
+                Some(prepared) => prepared,
+                None => {
+                    let preparing = Instant::now();
+                    (self.encode(&job).await, preparing.elapsed())
+                }
+            };
+            match image {
+                Ok(image) => images.push((job, duplicates, image, preprocess)),
+                Err(e) => {
+                    let sent = Sent { preprocess: Some(preprocess), ..Sent::default() };
+                    let extracted = Extracted { outcome: Err(e), attempts: 0, detected: None, cached: false, sent };
+                    self.finish_group(&job, &duplicates, &extracted, preprocess.as_millis() as u64, total);
+                }
+            }
+        }
+        if images.len() < 2 {
+            for (job, duplicates, image, preprocess) in images {
+                self.run(job, duplicates, Some((Ok(image), preprocess)), total).await;
+            }
+            return;
+        }
 
-    let client = Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build()
-        .context("Failed to create HTTP client")?;
+        let names: Vec<&str> = images.iter().map(|(job, _, _, _)| job.image_name.as_str()).collect();
+        let label = names.join(", ");
+        let encoded: Vec<(&str, &str)> = images.iter().map(|(_, _, image, _)| (image.base64.as_str(), image.media_type)).collect();
+        let unencoded: Vec<&EncodedImage> = images.iter().map(|(_, _, image, _)| image).collect();
+        let attempt = AtomicU32::new(0);
+        let sending = Sending::default();
+        let (outcome, attempts) = self.request_with_retries(&label, || async {
+            let attempt = attempt.fetch_add(1, Ordering::Relaxed) + 1;
+            let request_id = correlation::current();
+            let endpoint = self.endpoints.pick();
+            let backend = &self.endpoints.route(&self.backend, endpoint);
+            let save = |http_status: Option<u16>, body: &str, error: Option<&anyhow::Error>| {
+                let responses = self.responses.as_ref()?;
+                let response = SavedResponse {
+                    request_id: &request_id,
+                    image_name: names[0],
+                    stage: "batch",
+                    attempt,
+                    http_status,
+                    error: error.map(|e| format!("{:#}", e)),
+                    saved_at: Local::now().to_rfc3339(),
+                    request: batch_request_summary(backend, &names, &unencoded),
+                    body,
+                };
+                match responses.save(&response) {
+                    Ok(Some(path)) => {
+                        sending.add(|sent| sent.response_file = Some(path.clone()));
+                        Some(path)
+                    }
+                    Ok(None) => None,
+                    Err(e) => {
+                        warn!("{:#}", e);
+                        None
+                    }
+                }
+            };
+            debug!("Processing a batch of {} images: {}", encoded.len(), label);
+            let requested = Instant::now();
+            let posted = post_batch(&self.client, backend, &encoded, self.request_id_header.then_some(request_id.as_str())).await;
+            let elapsed = requested.elapsed();
+            let failed = posted.as_ref().err().filter(|e| retry::is_retryable(e) && retry::rate_limited(e).is_none());
+            self.endpoints.record(endpoint, failed.is_some(), failed.is_some_and(|e| retry::classify(e) == "connect"));
+            let usage = posted.as_ref().ok().and_then(|body| backend.usage(body));
+            sending.add(|sent| {
+                sent.endpoint = Some(endpoint);
+                sent.request_id = Some(request_id.clone());
+                sent.request = Some(sent.request.unwrap_or_default() + elapsed);
+                if let Some(usage) = usage {
+                    sent.usage.get_or_insert_default().add(usage);
+                }
+            });
+            let body = match posted {
+                Ok(body) => body,
+                Err(e) => {
+                    let saved = e.downcast_ref::<retry::HttpStatusError>()
+                        .and_then(|status| save(Some(status.status), &status.body, Some(&e)));
+                    self.index_request(&names, "batch", attempt, endpoint, Some(&e), saved);
+                    return Err(e);
+                }
+            };
+            // A reply that doesn't split is sent again image by image, not
+            // retried as a batch
+            let parsed = parse_batch(backend, &body, images.len());
+            let saved = save(None, &body, parsed.as_ref().err());
+            self.index_request(&names, "batch", attempt, endpoint, parsed.as_ref().err(), saved);
+            Ok((parsed, endpoint))
+        }).await;
 
-    // We no longer need to get model information since we're using a simple REST API
-    let model_name = "local-api"; // Just a placeholder value
+        let sent = sending.into_sent();
+        let request = sent.request.unwrap_or_default();
+        let sent_bytes = images.iter().map(|(_, _, image, _)| image.len as u64).sum::<u64>() * u64::from(attempts);
+        if let Ok(mut scoreboard) = self.scoreboard.lock() {
+            scoreboard.record_batch(request.as_millis() as u64, images.len());
+            // The images sent again on their own count their own requests
+            if matches!(outcome, Ok((Err(_), _))) {
+                let cost = self.pricing.zip(sent.usage).map(|(pricing, usage)| pricing.cost(usage));
+                scoreboard.record_request_usage(sent.usage, sent_bytes, cost);
+            }
+        }
+        let responses: Vec<Result<ApiResponse>> = match outcome {
+            Ok((Ok(responses), endpoint)) => {
+                for ((job, _, _, _), response) in images.iter().zip(&responses) {
+                    self.cache_batch_item(job, response, endpoint);
+                }
+                responses.into_iter().map(Ok).collect()
+            }
+            Ok((Err(e), _)) => {
+                warn!("The reply for the batch of {} could not be split into one result per image ({:#}); sending each image on its own",
+                    label, e);
+                for (job, duplicates, image, preprocess) in images {
+                    self.run(job, duplicates, Some((Ok(image), preprocess)), total).await;
+                }
+                return;
+            }
+            Err(e) => images.iter().map(|_| Err(retry::SharedFailure::new(&e).into())).collect(),
+        };
+        // Images sent on their own after all count their misses when they
+        // look for their own answers
+        if let Some(cache) = &self.cache {
+            images.iter().for_each(|_| cache.record_miss());
+        }
 
-    log_to_both(&log_file, &format!("Using API endpoint: {}/invoke", base_url));
+        // Each image gets its share of the batch's request time and tokens
+        let share = images.len() as u32;
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        for ((job, duplicates, image, preprocess), outcome) in images.iter().zip(responses) {
+            let sent = Sent {
+                image: image.sent,
+                response_file: sent.response_file.clone(),
+                request_id: sent.request_id.clone(),
+                preprocess: Some(*preprocess),
+                request: Some(request / share),
+                usage: sent.usage.map(|usage| Usage {
+                    input_tokens: usage.input_tokens / u64::from(share),
+                    output_tokens: usage.output_tokens / u64::from(share),
+                }),
+                sent_bytes: Some(image.len as u64 * u64::from(attempts)),
+                endpoint: sent.endpoint,
+                batch: Some((images.len(), request)),
+                votes: None,
+            };
+            let extracted = Extracted {
+                outcome: outcome.map(Extraction::Fields),
+                attempts,
+                detected: None,
+                cached: false,
+                sent,
+            };
+            let _span = info_span!("image", image_name = %job.image_name).entered();
+            self.finish_group(job, duplicates, &extracted, elapsed_ms, total);
+        }
+    }
 
-    // Load dataset
-    log_to_both(&log_file, "Loading dataset from 'python/dset/cleaned_data.json'");
-    let data_file = fs::read_to_string("python/dset/cleaned_data.json")
-        .context("Failed to read dataset file")?;
+    /// Cache key of an image's object in a batch reply; the same whatever
+    /// images it was sent with
+    fn batch_cache_key(&self, job: &Job) -> Option<String> {
+        ResponseCache::key(&self.source, &job.image_path, &format!("{} batch", self.image_settings), &self.backend).ok()
+    }
 
-    let data: Vec<DatasetEntry> = serde_json::from_str(&data_file)
-        .context("Failed to parse dataset JSON")?;
+    /// The answer for `job` kept from an earlier batch, if it still reads.
+    /// A miss isn't counted here but once the image is sent, in a batch or
+    /// on its own, so it counts once.
+    fn cached_batch_item(&self, job: &Job) -> Option<ApiResponse> {
+        let cache = self.cache.as_ref()?;
+        let key = self.batch_cache_key(job)?;
+        match cache.get(&key).map(|text| parse_batch_item(&self.backend, &text)) {
+            Some(Ok(response)) => {
+                cache.record_hit();
+                Some(response)
+            }
+            Some(Err(_)) => {
+                cache.record_corrupt();
+                None
+            }
+            None => None,
+        }
+    }
 
-    // Uncomment these lines once the rand crate is built
-    let seed: u64 = 42; // You can change the seed value as needed
-    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
-    let mut shuffled_data: Vec<_> = data.iter().collect();
-    shuffled_data.shuffle(&mut rng);
+    /// Add the request being made for `image_names` to the request index,
+    /// with its outcome and the response saved of it
+    fn index_request(&self, image_names: &[&str], stage: &str, attempt: u32, endpoint: usize, error: Option<&anyhow::Error>, saved: Option<PathBuf>) {
+        let request_id = correlation::current();
+        let outcome = error.map_or("ok", |e| retry::failure_class(&retry::classify(e)));
+        let http_status = error.and_then(|e| e.downcast_ref::<retry::HttpStatusError>()).map(|status| status.status);
+        for image_name in image_names {
+            let record = RequestRecord {
+                request_id: &request_id,
+                image_name,
+                stage,
+                attempt,
+                outcome,
+                http_status,
+                endpoint: self.endpoints.base_url(endpoint),
+                response_file: saved.as_deref(),
+                completed_at: Local::now().to_rfc3339(),
+            };
+            if let Err(e) = self.request_index.write(&record) {
+                warn!("{:#}", e);
+            }
+        }
+    }
 
-    // Set processing parameters
-    let total = 10000.min(data.len());
-    log_to_both(&log_file, &format!("Processing {} images from dataset", total));
+    /// Keep the object of `job` in a batch reply for later runs
+    fn cache_batch_item(&self, job: &Job, response: &ApiResponse, endpoint: usize) {
+        // The key is that of the preferred endpoint's model
+        if let Some(cache) = &self.cache
+            && self.endpoints.serves(endpoint, &self.backend)
+            && let Some(key) = self.batch_cache_key(job)
+            && let Err(e) = cache.put(&key, &response.raw_output)
+        {
+            warn!("{:#}", e);
+        }
+    }
 
+    /// Score, write and record the result of `job` from an extraction, made
+    /// for the image itself or, with `duplicate_of`, for an identical one
+    /// Record the failure of an image that is not sent, such as one too
+    /// large or unreadable
+    fn fail_unsent(&self, job: &Job, error: anyhow::Error, total: usize) {
+        let extracted = Extracted {
+            outcome: Err(error),
+            attempts: 0,
+            detected: None,
+            cached: false,
+            sent: Sent::default(),
+        };
+        let _span = info_span!("image", image_name = %job.image_name).entered();
+        self.finish(job, &extracted, 0, None, total);
+    }
 
-    // Create shared resources
-    let client = Arc::new(client);
-    let log_file = Arc::clone(&log_file);
+    fn finish(&self, job: &Job, extracted: &Extracted, elapsed_ms: u64, duplicate_of: Option<&str>, total: usize) {
+        let mut raw_output = None;
+        let mut result = ExtractionResult {
+            image_name: job.image_name.clone(),
+            ground_truth: job.ground_truth.clone(),
+            chinese_character: None,
+            words_in_mark: None,
+            description_of_device: None,
+            normalized_ground_truth: None,
+            normalized_chinese_character: None,
+            status: Status::Ok,
+            error: None,
+            error_kind: None,
+            error_class: None,
+            edit_distance: None,
+            cer: None,
+            term_precision: None,
+            term_recall: None,
+            term_f1: None,
+            ground_truth_words_in_mark: job.ground_truth_words.clone(),
+            words_match: None,
+            wer: None,
+            ground_truth_description_of_device: job.ground_truth_device.clone(),
+            device_similarity: None,
+            device_grade: None,
+            elapsed_ms,
+            preprocess_ms: None,
+            request_ms: None,
+            wait_ms: None,
+            batch_size: None,
+            batch_ms: None,
+            usage: None,
+            sent_bytes: None,
+            estimated_cost: None,
+            attempts: if duplicate_of.is_some() { 0 } else { extracted.attempts },
+            config_id: self.config_id.clone(),
+            prompt_hash: self.prompt_hash.clone(),
+            generation: self.generation.clone(),
+            parse_method: None,
+            completed_at: None,
+            cached: extracted.cached,
+            sent_image: extracted.sent.image,
+            response_file: extracted.sent.response_file.clone(),
+            request_id: if duplicate_of.is_some() { None } else { extracted.sent.request_id.clone() },
+            endpoint: None,
+            model_spec: self.model_spec.clone(),
+            file_bytes: self.source.size(&job.image_path).ok(),
+            buckets: None,
+            detected: extracted.detected,
+            duplicate_of: duplicate_of.map(String::from),
+            votes: extracted.sent.votes.clone(),
+            script: None,
+            script_rejected: false,
+            preprocess: self.preprocess,
+            provenance: job.provenance.clone(),
+        };
+        // Copies made no request of their own
+        if duplicate_of.is_none() {
+            let sent = &extracted.sent;
+            result.preprocess_ms = sent.preprocess.map(|d| d.as_millis() as u64);
+            result.request_ms = sent.request.map(|d| d.as_millis() as u64);
+            result.wait_ms = sent.request.map(|request| {
+                let busy = request + sent.preprocess.unwrap_or_default();
+                Duration::from_millis(elapsed_ms).saturating_sub(busy).as_millis() as u64
+            });
+            result.batch_size = sent.batch.map(|(images, _)| images);
+            result.batch_ms = sent.batch.map(|(_, request)| request.as_millis() as u64);
+            result.usage = sent.usage;
+            result.sent_bytes = sent.sent_bytes;
+            result.estimated_cost = self.pricing.zip(sent.usage).map(|(pricing, usage)| pricing.cost(usage));
+            if self.endpoints.has_fallback() {
+                result.endpoint = sent.endpoint.map(|index| self.endpoints.base_url(index).to_string());
+            }
+        }
 
-    // Process images in chunks
-    let chunk_size = 10;
-    let data_to_process: Vec<_> = data.iter().take(total).collect();
+        let message = match &extracted.outcome {
+            Ok(Extraction::NotDetected(reply)) => {
+                result.status = Status::NoChineseDetected;
+                raw_output = Some(reply.clone());
+                format!("[{:6}/{:6}] No Chinese detected, Original: '{}', File: {}",
+                    job.index, total, job.ground_truth.as_deref().unwrap_or("None"), job.image_name)
+            }
+            Ok(Extraction::Fields(api_response)) => {
+                let message = format!(
+                    "[{:6}/{:6}] API Response - Chinese character: '{}', Words in mark: '{}', Device: '{}', Original: '{}', File: {}",
+                    job.index,
+                    total,
+                    api_response.chinese_character.as_deref().unwrap_or("None"),
+                    api_response.words_in_mark.as_deref().unwrap_or("None"),
+                    api_response.description_of_device.as_deref().unwrap_or("None"),
+                    job.ground_truth.as_deref().unwrap_or("None"),
+                    job.image_name
+                );
+                result.chinese_character = api_response.chinese_character.clone();
+                result.words_in_mark = api_response.words_in_mark.clone();
+                result.description_of_device = api_response.description_of_device.clone();
+                result.parse_method = Some(api_response.parse_method);
+                raw_output = Some(api_response.raw_output.clone());
+                if api_response.parse_method == ParseMethod::Heuristic && duplicate_of.is_none() {
+                    self.heuristic_parses.fetch_add(1, Ordering::Relaxed);
+                }
+                message
+            },
+            Err(e) => {
+                if retry::is_auth_error(e) {
+                    self.auth_failed.store(true, Ordering::Relaxed);
+                }
+                let kind = retry::classify(e);
+                let class = retry::failure_class(&kind);
+                if let Ok(mut failures) = self.failures.lock() {
+                    *failures.entry(class).or_default() += 1;
+                }
+                error!(
+                    event = "failure",
+                    image_name = %job.image_name,
+                    error_kind = %kind,
+                    error_class = class,
+                    attempts = extracted.attempts,
+                    error = %format!("{:#}", e),
+                    "Error processing {:?} ({}): {:#}", job.image_path, kind, e
+                );
+                let message = format!("[{:6}/{:6}] Failed ({}), Original: '{}', File: {}",
+                    job.index, total, class, job.ground_truth.as_deref().unwrap_or("None"), job.image_name);
+                result.status = Status::Error;
+                result.error = Some(format!("{:#}", e));
+                result.error_kind = Some(kind);
+                result.error_class = Some(class.to_string());
+                message
+            }
+        };
 
-    for (chunk_idx, chunk) in data_to_process.chunks(chunk_size).enumerate() {
-        let mut tasks = Vec::new();
+        // Images stopped by the detection stage are scored as predicting none,
+        // unlabeled ones aren't scored
+        let labeled = self.backend.fields.contains(&Field::Chinese) && job.ground_truth.is_some();
+        if result.status != Status::Error {
+            if self.backend.fields.contains(&Field::Chinese) {
+                result.normalized_chinese_character = result.chinese_character.as_deref().map(|c| self.normalizer.normalize(c));
+                result.script = result.chinese_character.as_deref().and_then(script::classify);
+                result.script_rejected = self.strict_script && result.script.is_some_and(|script| script != Script::Cjk);
+            }
+            if labeled {
+                result.normalized_ground_truth = job.ground_truth.as_deref().map(|t| self.normalizer.normalize(t));
+                let terms = terms::score(job.ground_truth.as_deref(), result.chinese_character.as_deref(), |t| self.normalizer.normalize(t));
+                result.term_precision = Some(terms.precision);
+                result.term_recall = Some(terms.recall);
+                result.term_f1 = Some(terms.f1);
+            }
+            if self.backend.fields.contains(&Field::Words)
+                && let Some(words) = job.ground_truth_words.as_deref().and_then(|w| words::score(w, result.words_in_mark.as_deref()))
+            {
+                result.words_match = Some(words.exact_match);
+                result.wer = Some(words.wer);
+            }
+            if self.backend.fields.contains(&Field::Device)
+                && let Some(truth) = job.ground_truth_device.as_deref().filter(|t| !t.trim().is_empty())
+            {
+                let similarity = self.device_scorer.score(truth, result.description_of_device.as_deref().unwrap_or_default());
+                result.device_similarity = Some(similarity);
+                result.device_grade = Some(self.device_thresholds.grade(similarity));
+            }
+        }
 
-        for (idx_in_chunk, entry) in chunk.iter().enumerate() {
-            let image_path = PathBuf::from("./python/dset/imgs").join(&entry.image_name);
+        let edits = result.alignment().filter(|_| labeled);
+        if let Some(edits) = &edits {
+            result.edit_distance = Some(metrics::distance(edits));
+            result.cer = Some(metrics::character_error_rate(edits));
+        }
 
-            // Skip if file doesn't exist
-            if !image_path.exists() {
-                warn!("Image not found: {:?}", image_path);
-                continue;
+        debug!(
+            event = "result",
+            image_name = %job.image_name,
+            status = result.status.name(),
+            duration_ms = result.elapsed_ms,
+            request_ms = result.request_ms,
+            attempts = result.attempts,
+            ground_truth = result.ground_truth.as_deref(),
+            chinese_character = result.chinese_character.as_deref(),
+            words_in_mark = result.words_in_mark.as_deref(),
+            description_of_device = result.description_of_device.as_deref(),
+            exact_match = (labeled && result.status != Status::Error).then(|| result.is_match()),
+            edit_distance = result.edit_distance,
+            error_class = result.error_class.as_deref(),
+            cached = result.cached,
+            duplicate_of = result.duplicate_of.as_deref(),
+            raw_output = raw_output.as_deref(),
+            "{}", message
+        );
+        result.buckets = Some(self.buckets.assign(&result));
+        result.completed_at = Some(Local::now().to_rfc3339());
+        if let Err(e) = self.results.write(&result) {
+            error!("{:#}", e);
+        }
+        if let (Some(raw_output), Some(edit_distance)) = (raw_output, result.edit_distance)
+            && result.outcome() != Outcome::ExactMatch
+        {
+            let mismatch = Mismatch {
+                image_name: job.image_name.clone(),
+                image_path: job.image_path.clone(),
+                ground_truth: result.ground_truth.clone(),
+                prediction: result.chinese_character.clone(),
+                edit_distance,
+                raw_output,
+                provenance: job.provenance.clone(),
+            };
+            if let Err(e) = self.mismatches.write(&mismatch) {
+                error!("{:#}", e);
             }
+        }
+        if let Ok(mut scoreboard) = self.scoreboard.lock() {
+            record(&mut scoreboard, &result, edits.as_deref());
+            scoreboard.record_completion(self.started.elapsed().as_millis() as u64);
+            self.progress.record(scoreboard.scores(Field::Chinese).then(|| scoreboard.accuracy()), scoreboard.errors);
+        }
+        if let Some(csv_results) = &self.csv_results
+            && let Err(e) = csv_results.write(&result)
+        {
+            error!("{:#}", e);
+        }
+        // Failures count as misses, as in the accuracy
+        if let Some(early_stop) = &self.early_stop
+            && let Some(summary) = early_stop.record(job.index, labeled.then(|| result.status != Status::Error && result.is_match()))
+        {
+            info!(event = "early_stop", images = summary.images, half_width = summary.half_width,
+                "Accuracy {:.1}% ±{:.1} points after {} images, within --target-ci; sending no more images",
+                summary.accuracy * 100.0, summary.half_width * 100.0, summary.images);
+        }
+    }
 
-            // Get Chinese character and skip if None
-            let chinese_chars = &entry.chinese_character;
-            if chinese_chars.is_none() {
-                continue;
+    /// Extract the fields of an image, after asking the detector whether it
+    /// has characters if there is one
+    async fn extract(&self, job: &Job, sending: &Sending) -> Extracted {
+        let Some(detector) = &self.detector else {
+            let (outcome, attempts) = self.extract_fields(job, sending).await;
+            let cached = outcome.as_ref().is_ok_and(|(_, cached)| *cached);
+            return Extracted { outcome: outcome.map(|(response, _)| Extraction::Fields(response)), attempts, detected: None, cached, sent: Sent::default() };
+        };
+
+        let (answer, detect_attempts) = self.stage(job, sending, "detect", detector, &self.image_settings, |body| parse_detection(detector, body)).await;
+        match answer {
+            Ok(((true, _), detect_cached)) => {
+                let (outcome, attempts) = self.extract_fields(job, sending).await;
+                let cached = detect_cached && outcome.as_ref().is_ok_and(|(_, cached)| *cached);
+                Extracted {
+                    outcome: outcome.map(|(response, _)| Extraction::Fields(response)),
+                    attempts: detect_attempts + attempts,
+                    detected: Some(true),
+                    cached,
+                    sent: Sent::default(),
+                }
             }
+            Ok(((false, reply), cached)) => Extracted {
+                outcome: Ok(Extraction::NotDetected(reply)),
+                attempts: detect_attempts,
+                detected: Some(false),
+                cached,
+                sent: Sent::default(),
+            },
+            Err(e) => Extracted { outcome: Err(e), attempts: detect_attempts, detected: None, cached: false, sent: Sent::default() },
+        }
+    }
 
-            // Create clones of Arc resources for the task
-            let client_clone = Arc::clone(&client);
-            let model_name_clone = model_name.clone();
-            let base_url_clone = base_url.to_string();
-            let api_key_clone = api_key.to_string();
-            let image_name_clone = entry.image_name.clone();
-            let chinese_chars_clone = chinese_chars.clone();
-            let image_path_clone = image_path.clone();
-            let log_file_clone = Arc::clone(&log_file);
-
-            // Calculate global index
-            let global_idx = chunk_idx * chunk_size + idx_in_chunk;
-
-            // Spawn a task for each image
-            let task = task::spawn(async move {
-                match process_image(
-                    &client_clone,
-                    &image_path_clone,
-                    &model_name_clone,
-                    &base_url_clone,
-                    &api_key_clone,
-                    global_idx,
-                    total,
-                    &image_name_clone,
-                ).await {
-                    Ok(api_response) => {
-                        let message = format!(
-                            "[{:6}/{:6}] API Response - Chinese character: '{}', Words in mark: '{}', Device: '{}', Original: '{}', File: {}",
-                            global_idx,
-                            total,
-                            api_response.chinese_character.unwrap_or_else(|| "None".to_string()),
-                            api_response.words_in_mark.unwrap_or_else(|| "None".to_string()),
-                            api_response.description_of_device.unwrap_or_else(|| "None".to_string()),
-                            chinese_chars_clone.unwrap_or_else(|| "None".to_string()),
-                            image_name_clone
-                        );
-                        log_to_both(&log_file_clone, &message);
-                    },
+    /// Ask for the fields of an image, `--votes` times when more than once,
+    /// electing the answer from the samples that were answered. The votes
+    /// are recorded in `sending`; the answer counts as cached when every
+    /// sample was.
+    async fn extract_fields(&self, job: &Job, sending: &Sending) -> (Result<(ApiResponse, bool)>, u32) {
+        let parse = |body: &str| parse_extraction(&self.backend, body);
+        if self.votes == 1 {
+            return self.stage(job, sending, "extract", &self.backend, &self.image_settings, parse).await;
+        }
+        let mut answers = Vec::new();
+        let mut attempts = 0;
+        let mut cached = true;
+        let mut last_error = None;
+        for vote in 0..self.votes {
+            let settings = vote::cache_settings(&self.image_settings, self.votes, vote);
+            let (outcome, made) = self.stage(job, sending, "extract", &self.backend, &settings, parse).await;
+            attempts += made;
+            match outcome {
+                Ok((response, from_cache)) => {
+                    cached &= from_cache;
+                    answers.push(response);
+                }
+                // Every other sample would be refused too
+                Err(e) if retry::is_auth_error(&e) => return (Err(e), attempts),
+                Err(e) => {
+                    cached = false;
+                    last_error = Some(e);
+                }
+            }
+        }
+        match vote::elect(answers, &self.backend.fields, self.votes) {
+            Some((response, votes)) => {
+                debug!(
+                    event = "votes",
+                    image_name = %job.image_name,
+                    answered = votes.answered,
+                    agreement = votes.agreement,
+                    "{} of {} samples answered for {}, {:.0}% agreeing with the elected answer",
+                    votes.answered, votes.samples, job.image_name, votes.agreement * 100.0
+                );
+                sending.add(|sent| sent.votes = Some(votes));
+                (Ok((response, cached)), attempts)
+            }
+            None => (Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No sample was answered"))), attempts),
+        }
+    }
+
+    /// Get the reply of `backend` for an image from the cache, or else from
+    /// the API with retries, and read it with `parse`. Replies that parse
+    /// are stored in the cache under `cache_settings`, and responses are
+    /// saved with `--save-responses`. Returns the parsed reply, whether it
+    /// came from the cache, and the number of requests made.
+    async fn stage<T>(
+        &self,
+        job: &Job,
+        sending: &Sending,
+        name: &str,
+        backend: &Backend,
+        cache_settings: &str,
+        parse: impl Fn(&str) -> Result<T>,
+    ) -> (Result<(T, bool)>, u32) {
+        let cache = self.cache.as_ref()
+            .and_then(|cache| ResponseCache::key(&self.source, &job.image_path, cache_settings, backend).ok().map(|key| (cache, key)));
+        if let Some((cache, key)) = &cache {
+            match cache.get(key).map(|body| parse(&body)) {
+                Some(Ok(value)) => {
+                    cache.record_hit();
+                    return (Ok((value, true)), 0);
+                }
+                Some(Err(_)) => cache.record_corrupt(),
+                None => {}
+            }
+            cache.record_miss();
+        }
+
+        let attempt = AtomicU32::new(0);
+        let (outcome, attempts) = self.request_with_retries(&job.image_name, || async {
+            // Set by the preprocessing workers, unless the answers looked
+            // cached to them
+            let image = sending.image.get_or_try_init(|| async {
+                let started = Instant::now();
+                let image = self.encode(job).await;
+                sending.add(|sent| sent.preprocess = Some(started.elapsed()));
+                image
+            }).await?;
+            let attempt = attempt.fetch_add(1, Ordering::Relaxed) + 1;
+            let request_id = correlation::current();
+            let endpoint = self.endpoints.pick();
+            let backend = &self.endpoints.route(backend, endpoint);
+            let save = |http_status: Option<u16>, body: &str, error: Option<&anyhow::Error>| {
+                let responses = self.responses.as_ref()?;
+                let response = SavedResponse {
+                    request_id: &request_id,
+                    image_name: &job.image_name,
+                    stage: name,
+                    attempt,
+                    http_status,
+                    error: error.map(|e| format!("{:#}", e)),
+                    saved_at: Local::now().to_rfc3339(),
+                    request: request_summary(backend, image),
+                    body,
+                };
+                match responses.save(&response) {
+                    Ok(Some(path)) => {
+                        sending.add(|sent| sent.response_file = Some(path.clone()));
+                        Some(path)
+                    }
+                    Ok(None) => None,
                     Err(e) => {
-                        error!("Error processing {:?}: {}", image_path_clone, e);
-                        if let Ok(mut file) = log_file_clone.lock() {
-                            let _ = writeln!(file, "Error processing {:?}: {}", image_path_clone, e);
-                        }
+                        warn!("{:#}", e);
+                        None
                     }
                 }
+            };
+            let started = Instant::now();
+            let posted = post_image(&self.client, backend, image, &job.image_name,
+                self.request_id_header.then_some(request_id.as_str())).await;
+            let elapsed = started.elapsed();
+            // Only failures of the server or the network count against it
+            let failed = posted.as_ref().err().filter(|e| retry::is_retryable(e) && retry::rate_limited(e).is_none());
+            self.endpoints.record(endpoint, failed.is_some(), failed.is_some_and(|e| retry::classify(e) == "connect"));
+            let usage = posted.as_ref().ok().and_then(|body| backend.usage(body));
+            sending.add(|sent| {
+                sent.endpoint = Some(endpoint);
+                sent.request_id = Some(request_id.clone());
+                sent.request = Some(sent.request.unwrap_or_default() + elapsed);
+                *sent.sent_bytes.get_or_insert(0) += image.len as u64;
+                if let Some(usage) = usage {
+                    sent.usage.get_or_insert_default().add(usage);
+                }
             });
+            let body = match posted {
+                Ok(body) => body,
+                Err(e) => {
+                    let saved = e.downcast_ref::<retry::HttpStatusError>()
+                        .and_then(|status| save(Some(status.status), &status.body, Some(&e)));
+                    self.index_request(&[&job.image_name], name, attempt, endpoint, Some(&e), saved);
+                    return Err(e);
+                }
+            };
+            let parsed = parse(&body);
+            let saved = save(None, &body, parsed.as_ref().err());
+            self.index_request(&[&job.image_name], name, attempt, endpoint, parsed.as_ref().err(), saved);
+            parsed.map(|value| (value, body, endpoint))
+        }).await;
+        let outcome = outcome.map(|(value, body, endpoint)| {
+            // The key is that of the preferred endpoint's model
+            if let Some((cache, key)) = &cache
+                && self.endpoints.serves(endpoint, backend)
+                && let Err(e) = cache.put(key, &body)
+            {
+                warn!("{:#}", e);
+            }
+            (value, false)
+        });
+        (outcome, attempts)
+    }
+
+    /// `encode_image` with this run's formats, `--max-dimension` and
+    /// `--preprocess`
+    async fn encode(&self, job: &Job) -> Result<EncodedImage> {
+        encode_image(&self.source, &job.image_path, self.accepted_formats.clone(), self.resize, self.preprocess, self.encodings).await
+    }
 
-            tasks.push(task);
+    /// Read and encode the images of a group or batch that will be sent,
+    /// once they fit in `--max-inflight-bytes`; images with every answer
+    /// cached are left out. A batch takes its whole share of the budget at
+    /// once, so batches waiting on each other can't each hold part of it.
+    async fn prepare(&self, batch: &[(Job, Vec<Job>)]) -> Prepared {
+        let sent: Vec<bool> = batch.iter().map(|(job, _)| !self.answered_by_cache(job, batch.len() > 1)).collect();
+        if !sent.contains(&true) {
+            return Prepared { images: batch.iter().map(|_| None).collect(), payload: None };
+        }
+        let bytes = batch.iter().zip(&sent).filter(|(_, sent)| **sent).map(|((job, _), _)| self.payload_bytes(job)).sum();
+        let payload = self.budget.acquire(bytes).await;
+        let mut images = Vec::new();
+        for ((job, _), sent) in batch.iter().zip(sent) {
+            if !sent {
+                images.push(None);
+                continue;
+            }
+            let started = Instant::now();
+            let image = self.encode(job).await;
+            images.push(Some((image, started.elapsed())));
         }
+        Prepared { images, payload: Some(payload) }
+    }
 
-        // Wait for all tasks in the current chunk to complete
-        join_all(tasks).await;
+    /// Whether every answer `job` needs is in the cache, so its image won't
+    /// be sent. `batched` looks for its object from a batch reply.
+    fn answered_by_cache(&self, job: &Job, batched: bool) -> bool {
+        let Some(cache) = &self.cache else {
+            return false;
+        };
+        if batched {
+            return self.batch_cache_key(job).is_some_and(|key| cache.get(&key).is_some());
+        }
+        let cached = |backend: &Backend, settings: &str| {
+            ResponseCache::key(&self.source, &job.image_path, settings, backend).is_ok_and(|key| cache.get(&key).is_some())
+        };
+        (0..self.votes).all(|vote| cached(&self.backend, &vote::cache_settings(&self.image_settings, self.votes, vote)))
+            && self.detector.as_ref().is_none_or(|detector| cached(detector, &self.image_settings))
     }
 
+    /// Estimated bytes of `job`'s image in flight, from its size on disk
+    fn payload_bytes(&self, job: &Job) -> u64 {
+        let file_bytes = self.source.size(&job.image_path).unwrap_or_default();
+        budget::payload_bytes(file_bytes, self.encodings.raw, self.encodings.base64)
+    }
 
+    /// Make a request, retrying transient failures with backoff. A 429
+    /// response puts the image back in line after the delay the server asked
+    /// for, without using up its retries. Returns the last outcome and the
+    /// number of requests made.
+    async fn request_with_retries<T, F, Fut>(&self, image_name: &str, request: F) -> (Result<T>, u32)
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempts = 0;
+        let mut retries = 0;
+        let mut throttles = 0;
+        loop {
+            if let Some(limiter) = &self.limiter {
+                limiter.acquire().await;
+            }
+            attempts += 1;
+            let request_id = correlation::new_id();
+            let span = info_span!("request", request_id = %request_id);
+            let outcome = correlation::scope(request_id.clone(), request()).instrument(span).await;
+            if let Some(tuner) = &self.tuner {
+                tuner.record_request(outcome.as_ref().err().is_some_and(|e| retry::is_retryable(e) || retry::rate_limited(e).is_some()));
+            }
+            let Err(e) = &outcome else {
+                return (outcome, attempts);
+            };
 
-    Ok(())
+            let delay = if let Some(throttle) = retry::rate_limited(e)
+                && throttles < MAX_THROTTLES
+            {
+                throttles += 1;
+                self.throttled.fetch_add(1, Ordering::Relaxed);
+                let delay = throttle.retry_after.unwrap_or_else(|| retry::backoff(throttles, Duration::from_secs(1)));
+                if let Some(limiter) = &self.limiter {
+                    limiter.pause(delay).await;
+                }
+                warn!(
+                    event = "throttle",
+                    image_name = %image_name,
+                    request_id = %request_id,
+                    delay_ms = delay.as_millis() as u64,
+                    "Throttled on {}, requeueing in {:.1}s", image_name, delay.as_secs_f64()
+                );
+                delay
+            } else if retries < self.max_retries && retry::is_retryable(e) {
+                retries += 1;
+                let delay = retry::backoff(retries, Duration::from_secs(1));
+                warn!(
+                    event = "retry",
+                    image_name = %image_name,
+                    request_id = %request_id,
+                    error_class = retry::failure_class(&retry::classify(e)),
+                    attempt = retries,
+                    delay_ms = delay.as_millis() as u64,
+                    error = %format!("{:#}", e),
+                    "Request for {} failed: {:#}, retrying in {:.1}s (attempt {}/{})",
+                    image_name, e, delay.as_secs_f64(), retries, self.max_retries
+                );
+                delay
+            } else {
+                return (outcome, attempts);
+            };
+            tokio::time::sleep(delay).await;
+        }
+    }
 }
 
-async fn process_image(
-    client: &Client,
-    image_path: &Path,
-    model_name: &str,
-    base_url: &str,
-    api_key: &str,
-    _idx: usize,
-    _total: usize,
-    image_name: &str,
-) -> Result<ApiResponse> {
-    // Encode image to base64
-    let base64_image = encode_image(image_path)?;
-    debug!("Processing image: {}", image_name);
+/// What the request stages made of an image, with the requests it took
+struct Extracted {
+    outcome: Result<Extraction>,
+    /// Requests made over both stages; none when served from the cache
+    attempts: u32,
+    /// Answer of the detection stage
+    detected: Option<bool>,
+    /// Every stage was served from the cache
+    cached: bool,
+    sent: Sent,
+}
 
-    // Prepare the new request for the RESTful API
-    let request_url = format!("{}/invoke", base_url);
+/// What was sent for an image, and where its time went
+#[derive(Debug, Default)]
+struct Sent {
+    /// The image as sent, when it was converted or went through `--max-dimension`
+    image: Option<SentImage>,
+    /// Last response written by `--save-responses`
+    response_file: Option<PathBuf>,
+    /// Id of the last request, see `correlation`
+    request_id: Option<String>,
+    /// Reading, converting and encoding the image
+    preprocess: Option<Duration>,
+    /// Requests and replies over every attempt of both stages
+    request: Option<Duration>,
+    /// Tokens reported by the responses of every request
+    usage: Option<Usage>,
+    /// Image bytes sent over every request
+    sent_bytes: Option<u64>,
+    /// Index in `Extractor::endpoints` of the endpoint of the last request
+    endpoint: Option<usize>,
+    /// How the samples of `--votes` voted
+    votes: Option<Votes>,
+    /// Images of the `--batch-size` request the image went in, and the time
+    /// of the whole request, of which `request` is the image's share
+    batch: Option<(usize, Duration)>,
+}
 
-    // Create the request body with just the base64 encoded image
-    let request_body = serde_json::json!({
-        "image": base64_image
-    });
+/// A group, or `--batch-size` groups, with the images the preprocessing
+/// workers read for it
+struct Ready {
+    batch: Vec<(Job, Vec<Job>)>,
+    prepared: Prepared,
+}
+
+/// Images encoded ahead of the requests, in the order of their batch, with
+/// the time each took; `None` for images whose answers were cached
+struct Prepared {
+    images: Vec<Option<(Result<EncodedImage>, Duration)>>,
+    /// Share of `--max-inflight-bytes` held by the images
+    payload: Option<PayloadPermit>,
+}
+
+/// What the requests for one image share
+#[derive(Default)]
+struct Sending {
+    /// The image, read and encoded for the first request
+    image: OnceCell<EncodedImage>,
+    sent: Mutex<Sent>,
+}
+
+impl Sending {
+    fn add(&self, update: impl FnOnce(&mut Sent)) {
+        if let Ok(mut sent) = self.sent.lock() {
+            update(&mut sent);
+        }
+    }
+
+    fn into_sent(self) -> Sent {
+        let image = self.image.get().and_then(|image| image.sent);
+        let mut sent = self.sent.into_inner().unwrap_or_default();
+        sent.image = image;
+        sent
+    }
+}
+
+/// Forms of an image the backends of a run send: raw bytes in a multipart
+/// form, base64 in a JSON body. Only those are kept for the requests.
+#[derive(Debug, Clone, Copy)]
+struct Encodings {
+    raw: bool,
+    base64: bool,
+}
+
+impl Encodings {
+    fn of<'a>(backends: impl IntoIterator<Item = &'a Backend>) -> Self {
+        let mut encodings = Encodings { raw: false, base64: false };
+        for backend in backends {
+            match backend.image_encoding {
+                ImageEncoding::Multipart => encodings.raw = true,
+                ImageEncoding::Base64 | ImageEncoding::DataUrl => encodings.base64 = true,
+            }
+        }
+        encodings
+    }
+}
+
+/// An image encoded for the request body
+struct EncodedImage {
+    /// Empty unless a backend sends the image as a multipart file
+    bytes: Vec<u8>,
+    /// Empty unless a backend sends the image in a JSON body
+    base64: String,
+    /// Size of the image as sent
+    len: usize,
+    media_type: &'static str,
+    /// Set when the image went through `--max-dimension`
+    sent: Option<SentImage>,
+}
+
+/// Read and base64-encode an image, converting it to a format the endpoint
+/// takes and shrinking it with `--max-dimension` on a blocking thread. An
+/// image sent as it is on disk and only needed as base64 is encoded
+/// straight from the file, without reading it into memory first.
+async fn encode_image(
+    source: &ImageSource,
+    path: &Path,
+    accepted: Option<Vec<ImageKind>>,
+    resize: Option<Resize>,
+    preprocess: Preprocess,
+    encodings: Encodings,
+) -> Result<EncodedImage> {
+    let (source, path) = (source.clone(), path.to_path_buf());
+    task::spawn_blocking(move || {
+        // An archive member is read whole either way
+        if !encodings.raw
+            && matches!(source, ImageSource::Files)
+            && let Some(passed) = resize::pass_through(&source, &path, accepted.as_deref(), resize, preprocess)?
+        {
+            let (base64, len) = encode_file(&path)?;
+            return Ok(EncodedImage { bytes: Vec::new(), base64, len, media_type: passed.media_type, sent: passed.sent });
+        }
+        let prepared = resize::prepare(&source, &path, accepted.as_deref(), resize, preprocess)?;
+        Ok(EncodedImage {
+            base64: if encodings.base64 { general_purpose::STANDARD.encode(&prepared.bytes) } else { String::new() },
+            len: prepared.bytes.len(),
+            bytes: if encodings.raw { prepared.bytes } else { Vec::new() },
+            media_type: prepared.media_type,
+            sent: prepared.sent,
+        })
+    })
+    .await
+    .context("Image preparation task panicked")?
+}
+
+/// Base64 of a file, written into a string sized for it up front as the
+/// file is read, with the file's length
+fn encode_file(path: &Path) -> Result<(String, usize)> {
+    let mut file = fs::File::open(path)
+        .with_context(|| format!("Failed to read image file: {:?}", path))?;
+    let len = file.metadata()?.len();
+    let mut encoder = EncoderStringWriter::from_consumer(
+        String::with_capacity(budget::base64_len(len) as usize),
+        &general_purpose::STANDARD,
+    );
+    let copied = std::io::copy(&mut file, &mut encoder)
+        .with_context(|| format!("Failed to read image file: {:?}", path))?;
+    Ok((encoder.into_inner(), copied as usize))
+}
+
+enum Extraction {
+    Fields(ApiResponse),
+    /// The detector found no characters; holds its reply
+    NotDetected(String),
+}
+
+/// Read and check the extracted fields of a response body
+fn parse_extraction(backend: &Backend, body: &str) -> Result<ApiResponse> {
+    let response = backend.parse(body).context("Failed to parse API response")?;
+    validate::validate(&response).context("Rejected API response")?;
 
-    // Make API call to the new endpoint
-    let response: ApiResponse = client
-        .post(&request_url)
-        .header("Content-Type", "application/json")
-        .json(&request_body)
+    Ok(response)
+}
+
+/// Read and check the fields of each image from the response body of a batch
+fn parse_batch(backend: &Backend, body: &str, images: usize) -> Result<Vec<ApiResponse>> {
+    let responses = backend.parse_batch(body, images).context("Failed to parse batch response")?;
+    for (index, response) in responses.iter().enumerate() {
+        validate::validate(response).with_context(|| format!("Rejected object {} of the batch response", index + 1))?;
+    }
+    Ok(responses)
+}
+
+/// Read and check the fields of an image kept from a batch reply
+fn parse_batch_item(backend: &Backend, text: &str) -> Result<ApiResponse> {
+    let response = backend.parse_batch_item(text)?;
+    validate::validate(&response).context("Rejected cached batch object")?;
+    Ok(response)
+}
+
+/// Read the detector's answer from a response body, with its reply
+fn parse_detection(detector: &Backend, body: &str) -> Result<(bool, String)> {
+    let reply = detector.reply_text(body).context("Failed to parse detection response")?;
+    let detected = detect::parse_answer(&reply).context("Failed to parse detection response")?;
+    Ok((detected, reply))
+}
+
+/// What `--save-responses` records of a request: everything but the image
+fn request_summary(backend: &Backend, image: &EncodedImage) -> Value {
+    let placeholder = format!("<{} bytes of image>", image.len);
+    json!({
+        "url": backend.url(),
+        "encoding": backend.image_encoding.name(),
+        "media_type": image.media_type,
+        "body": match backend.image_encoding {
+            ImageEncoding::Multipart => json!({ "image": placeholder }),
+            ImageEncoding::Base64 | ImageEncoding::DataUrl => backend.body(&placeholder, image.media_type),
+        },
+    })
+}
+
+/// What `--save-responses` records of a batch request: everything but the
+/// images, and the names of the images in the order they were sent
+fn batch_request_summary(backend: &Backend, names: &[&str], images: &[&EncodedImage]) -> Value {
+    let placeholders: Vec<String> = images.iter().map(|image| format!("<{} bytes of image>", image.len)).collect();
+    let encoded: Vec<(&str, &str)> = placeholders.iter().zip(images)
+        .map(|(placeholder, image)| (placeholder.as_str(), image.media_type))
+        .collect();
+    json!({
+        "url": backend.url(),
+        "encoding": backend.image_encoding.name(),
+        "images": names,
+        "body": backend.batch_body(&encoded),
+    })
+}
+
+/// Send several images to the backend in one request and return the
+/// response body
+async fn post_batch(client: &Client, backend: &Backend, images: &[(&str, &str)], request_id: Option<&str>) -> Result<String> {
+    let response = with_request_id(backend.batch_request(client, images)?, request_id)
+        .send()
+        .await
+        .map_err(|e| {
+            let hint = if e.is_connect() { backend.connect_hint() } else { None };
+            anyhow::Error::new(e).context(hint.unwrap_or_else(|| "Failed to send request to API".to_string()))
+        })?;
+    retry::check_status(response)
+        .await?
+        .text()
+        .await
+        .context("Failed to read API response")
+}
+
+/// `request` with the id of the attempt in `correlation::REQUEST_ID_HEADER`
+fn with_request_id(request: reqwest::RequestBuilder, request_id: Option<&str>) -> reqwest::RequestBuilder {
+    match request_id {
+        Some(id) => request.header(correlation::REQUEST_ID_HEADER, id),
+        None => request,
+    }
+}
+
+/// Send an image to the backend and return the response body
+async fn post_image(
+    client: &Client,
+    backend: &Backend,
+    image: &EncodedImage,
+    image_name: &str,
+    request_id: Option<&str>,
+) -> Result<String> {
+    debug!("Processing image: {} as {} ({})", image_name, backend.image_encoding.name(), image.media_type);
+
+    let response = with_request_id(backend.request(client, &image.bytes, &image.base64, image.media_type, image_name)?, request_id)
         .send()
         .await
-        .context("Failed to send request to API")?
-        .json()
+        .map_err(|e| {
+            let hint = if e.is_connect() { backend.connect_hint() } else { None };
+            anyhow::Error::new(e).context(hint.unwrap_or_else(|| "Failed to send request to API".to_string()))
+        })?;
+    retry::check_status(response)
+        .await?
+        .text()
         .await
-        .context("Failed to parse API response")?;
+        .context("Failed to read API response")
+}
 
-    // Return the full API response
-    Ok(response)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Requests the mock API is answering at a time, and the most it ever was
+    #[derive(Default)]
+    struct InFlight {
+        now: AtomicUsize,
+        peak: AtomicUsize,
+        requests: AtomicUsize,
+    }
+
+    /// Reply of the invoke protocol to every image
+    fn invoke_reply(_request: &str) -> String {
+        r#"{"chineseCharacter":"商标","wordsInMark":null,"descrOfDevice":null}"#.to_string()
+    }
+
+    /// Serve an extraction API, each POST answered with `reply` to its body
+    /// after `delay`
+    async fn mock_api(delay: Duration, reply: fn(&str) -> String) -> (String, Arc<InFlight>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let in_flight = Arc::new(InFlight::default());
+        let counters = Arc::clone(&in_flight);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(answer(stream, Arc::clone(&counters), delay, reply));
+            }
+        });
+        (url, in_flight)
+    }
+
+    async fn answer(mut stream: TcpStream, in_flight: Arc<InFlight>, delay: Duration, reply: fn(&str) -> String) {
+        let mut request = Vec::new();
+        let mut buf = [0; 8192];
+        let body_start = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                return;
+            }
+            request.extend_from_slice(&buf[..n]);
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+        let length: usize = head.lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .map_or(0, |value| value.trim().parse().unwrap());
+        while request.len() < body_start + length {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        let body = if head.starts_with("post") {
+            in_flight.requests.fetch_add(1, Ordering::SeqCst);
+            let now = in_flight.now.fetch_add(1, Ordering::SeqCst) + 1;
+            in_flight.peak.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(delay).await;
+            in_flight.now.fetch_sub(1, Ordering::SeqCst);
+            reply(&String::from_utf8_lossy(&request[body_start..]))
+        } else {
+            String::new()
+        };
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(), body);
+        let _ = stream.write_all(response.as_bytes()).await;
+    }
+
+    /// A dataset of `count` distinct small images labeled 商标, in a new
+    /// directory
+    fn dataset(count: usize) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tm-query-extract-{}", uuid::Uuid::new_v4()));
+        let images = dir.join("imgs");
+        fs::create_dir_all(&images).unwrap();
+        let mut entries = Vec::new();
+        for n in 0..count {
+            let name = format!("{}X_rep.png", 40201900000u64 + n as u64);
+            let shade = image::Rgb([n as u8, 255 - n as u8, 128]);
+            image::RgbImage::from_pixel(16, 16, shade).save(images.join(&name)).unwrap();
+            entries.push(json!({ "imageName": name, "chineseCharacter": "商标" }));
+        }
+        fs::write(dir.join("cleaned_data.json"), Value::Array(entries).to_string()).unwrap();
+        dir
+    }
+
+    fn args(dir: &Path, url: &str, extra: &[&str]) -> Args {
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let mut argv = vec![
+            "extract_with_llm".to_string(),
+            "--dataset".to_string(), path("cleaned_data.json"),
+            "--images-dir".to_string(), path("imgs"),
+            "--base-url".to_string(), url.to_string(),
+            "--output".to_string(), path("results.jsonl"),
+            "--sample-manifest".to_string(), path("sample_manifest.json"),
+            "--normalize".to_string(), "none".to_string(),
+        ];
+        argv.extend(extra.iter().map(|arg| arg.to_string()));
+        Args::try_parse_from(argv).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn no_more_than_concurrency_requests_are_in_flight() {
+        let dir = dataset(24);
+        let (url, in_flight) = mock_api(Duration::from_millis(50), invoke_reply).await;
+        let bars = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+
+        let run = extract(args(&dir, &url, &["--concurrency", "3"]), None, None, bars).await.unwrap();
+        let Run::Finished(scoreboard) = run else {
+            panic!("expected a finished run");
+        };
+        assert_eq!(scoreboard.total, 24);
+        assert_eq!(scoreboard.exact_match, 24);
+        assert_eq!(in_flight.requests.load(Ordering::SeqCst), 24);
+        assert_eq!(in_flight.peak.load(Ordering::SeqCst), 3);
+        let results = fs::read_to_string(dir.join("results.jsonl")).unwrap();
+        assert_eq!(results.lines().count(), 24);
+        let metrics: Value = serde_json::from_str(&fs::read_to_string(dir.join(metrics::METRICS_FILE_NAME)).unwrap()).unwrap();
+        assert_eq!(metrics["total"], 24);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Chat completion of an OpenAI-compatible server: a batch is answered
+    /// with one object whatever its size, so it never splits
+    fn unsplittable_batch_reply(request: &str) -> String {
+        let (content, input_tokens, output_tokens) = if request.contains("Extract the fields of each of these") {
+            (r#"{"images": [{"chineseCharacter": "商标"}]}"#, 1000, 50)
+        } else {
+            (r#"{"chineseCharacter": "商标"}"#, 100, 10)
+        };
+        json!({
+            "choices": [{ "message": { "role": "assistant", "content": content } }],
+            "usage": { "prompt_tokens": input_tokens, "completion_tokens": output_tokens },
+        })
+        .to_string()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn batch_that_does_not_split_still_counts_its_usage() {
+        let dir = dataset(6);
+        let (url, in_flight) = mock_api(Duration::ZERO, unsplittable_batch_reply).await;
+        let bars = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let cache_dir = dir.join("cache").to_string_lossy().into_owned();
+        let extra = [
+            "--protocol", "openai", "--batch-size", "3", "--concurrency", "1", "--cache-dir", &cache_dir,
+            "--price-per-1k-input", "1", "--price-per-1k-output", "1",
+        ];
+
+        let Run::Finished(scoreboard) = extract(args(&dir, &url, &extra), None, None, bars).await.unwrap() else {
+            panic!("expected a finished run");
+        };
+        assert_eq!(scoreboard.exact_match, 6);
+        // Two batches, then each image on its own
+        assert_eq!(in_flight.requests.load(Ordering::SeqCst), 8);
+        let usage = scoreboard.usage;
+        assert_eq!(usage.images, 6);
+        assert_eq!((usage.input_tokens, usage.output_tokens), (2 * 1000 + 6 * 100, 2 * 50 + 6 * 10));
+        assert!((usage.estimated_cost.unwrap() - 2.76).abs() < 1e-9);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn dry_run_does_not_contact_the_endpoint() {
+        let dir = dataset(3);
+        // Nothing listens on the port once the listener is dropped
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let bars = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+
+        let run = extract(args(&dir, &url, &["--dry-run"]), None, None, bars).await.unwrap();
+        assert!(matches!(run, Run::DryRun { requests: 3 }));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Serve `body` at every URL, counting the requests
+    async fn serve_file(body: String) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/cleaned_data.json", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, requests)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn a_dataset_url_is_fetched_once_for_all_runs() {
+        let dir = dataset(3);
+        let (dataset_url, downloads) = serve_file(fs::read_to_string(dir.join("cleaned_data.json")).unwrap()).await;
+        let bars = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+
+        let mut sweep = args(&dir, "http://127.0.0.1:9", &["--sweep-preprocess", "none,grayscale", "--dry-run"]);
+        sweep.dataset = PathBuf::from(&dataset_url);
+        run(sweep, bars.clone()).await.unwrap();
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+
+        // Flags are checked before anything is downloaded
+        let mut invalid = args(&dir, "http://127.0.0.1:9", &["--concurrency", "0"]);
+        invalid.dataset = PathBuf::from(&dataset_url);
+        assert!(run(invalid, bars).await.is_err());
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
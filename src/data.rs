@@ -0,0 +1,348 @@
+use anyhow::{Context, Result};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use serde::de::{self, DeserializeSeed, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Trademark endpoint of the data.gov.sg API, queried one lodgement date at a time
+pub const API_URL: &str = "https://api.data.gov.sg/v1/technology/ipos/trademarks";
+
+/// Pause between batches of day queries, to stay clear of the API's rate limit
+pub const BATCH_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// One day of trademarks as written by the downloader
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DayRecord {
+    pub date: String,
+    pub count: u32,
+    pub items: Vec<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Document {
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+
+    #[serde(rename = "lodgementDate")]
+    pub lodgement_date: String,
+
+    #[serde(rename = "docType")]
+    pub doc_type: DocumentType,
+
+    #[serde(rename = "fileId")]
+    pub file_id: String,
+
+    pub url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentType {
+    pub description: String,
+    pub code: String,
+}
+
+/// Text describing a mark, taken from the first `markIndex` entry of an item
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MarkText {
+    #[serde(rename = "wordsInMark")]
+    pub words_in_mark: Option<String>,
+
+    #[serde(rename = "chineseCharacter")]
+    pub chinese_character: Option<String>,
+
+    #[serde(rename = "descrOfDevice")]
+    pub descr_of_device: Option<String>,
+}
+
+impl MarkText {
+    pub fn from_item(item: &Value) -> Self {
+        item.get("markIndex")
+            .and_then(|m| m.as_array())
+            .and_then(|m| m.first())
+            .and_then(|m| serde_json::from_value(m.clone()).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// A single document to fetch during the image phase
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadTask {
+    pub url: String,
+    #[serde(rename = "applicationNum")]
+    pub app_num: String,
+    pub file_name: String,
+    pub file_id: Option<String>,
+    pub doc_type: Option<String>,
+    #[serde(default)]
+    pub doc_type_description: Option<String>,
+    pub lodgement_date: Option<String>,
+    #[serde(flatten)]
+    pub mark: MarkText,
+}
+
+/// Collect the download tasks for one trademark item.
+///
+/// Items without an application number, and documents without a url or
+/// file name, are skipped.
+pub fn download_tasks(item: &Value) -> Vec<DownloadTask> {
+    let mut tasks = Vec::new();
+
+    if let Some(documents) = item.get("documents").and_then(|d| d.as_array())
+        && let Some(app_num) = item.get("applicationNum").and_then(|a| a.as_str())
+    {
+        let mark = MarkText::from_item(item);
+
+        for doc in documents {
+            if let (Some(url), Some(file_name)) = (doc.get("url").and_then(|u| u.as_str()),
+                                                 doc.get("fileName").and_then(|f| f.as_str())) {
+                tasks.push(DownloadTask {
+                    url: url.to_string(),
+                    app_num: app_num.to_string(),
+                    file_name: file_name.to_string(),
+                    file_id: doc.get("fileId").and_then(|f| f.as_str()).map(String::from),
+                    doc_type: doc.pointer("/docType/code").and_then(|c| c.as_str()).map(String::from),
+                    doc_type_description: doc.pointer("/docType/description").and_then(|c| c.as_str()).map(String::from),
+                    lodgement_date: doc.get("lodgementDate").and_then(|d| d.as_str()).map(String::from),
+                    mark: mark.clone(),
+                });
+            }
+        }
+    }
+
+    tasks
+}
+
+/// Fetch the trademark items lodged on `date` (YYYY-MM-DD) from the API
+pub async fn fetch_items(client: &reqwest::Client, date: &str) -> Result<Vec<Value>> {
+    #[derive(Deserialize)]
+    struct Response {
+        items: Vec<Value>,
+    }
+
+    let response: Response = client.get(API_URL)
+        .query(&[("lodgement_date", date)])
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .with_context(|| format!("Failed to fetch trademarks for {}", date))?
+        .json()
+        .await
+        .with_context(|| format!("Failed to parse trademarks for {}", date))?;
+
+    Ok(response.items)
+}
+
+/// Stream the day records of a data file, calling `f` for each one.
+///
+/// Files ending in `.jsonl` or `.ndjson` are read as one record per line,
+/// `.parquet` files one row group at a time, anything else as the JSON array
+/// written by the downloader. Records are deserialized one at a time so
+/// multi-GB files never sit in memory whole.
+pub fn for_each_record<F>(path: &Path, mut f: F) -> Result<()>
+where
+    F: FnMut(DayRecord) -> Result<()>,
+{
+    let has_extension = |names: &[&str]| {
+        path.extension().is_some_and(|e| names.iter().any(|name| e.eq_ignore_ascii_case(name)))
+    };
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open data file: {}", path.display()))?;
+
+    if has_extension(&["parquet"]) {
+        return for_each_parquet_record(path, file, f);
+    }
+
+    let reader = BufReader::new(file);
+
+    if has_extension(&["jsonl", "ndjson"]) {
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line.context("Failed to read data file")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: DayRecord = serde_json::from_str(&line)
+                .with_context(|| format!("Failed to parse line {} of {}", line_no + 1, path.display()))?;
+            f(record)?;
+        }
+        return Ok(());
+    }
+
+    let mut error = None;
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let result = RecordSeq { f: &mut f, error: &mut error }.deserialize(&mut deserializer);
+
+    // An error raised by the callback takes precedence over the serde error
+    // we used to abort the visitor
+    if let Some(e) = error {
+        return Err(e);
+    }
+    result.with_context(|| format!("Failed to parse data file: {}", path.display()))?;
+    deserializer.end().context("Trailing data after JSON array")?;
+
+    Ok(())
+}
+
+/// A parquet data file has a `date`, a `count` and an `items` column per day.
+/// `items` is either a list of items or the items as a JSON string, as
+/// writers that flatten nested columns leave it.
+fn for_each_parquet_record<F>(path: &Path, file: File, mut f: F) -> Result<()>
+where
+    F: FnMut(DayRecord) -> Result<()>,
+{
+    let reader = SerializedFileReader::new(file)
+        .with_context(|| format!("Failed to read parquet data file: {}", path.display()))?;
+
+    for group in 0..reader.metadata().num_row_groups() {
+        let group_reader = reader.get_row_group(group)
+            .with_context(|| format!("Failed to read row group {} of {}", group, path.display()))?;
+        let rows = group_reader.get_row_iter(None)
+            .with_context(|| format!("Failed to read row group {} of {}", group, path.display()))?;
+        for (row_no, row) in rows.enumerate() {
+            let context = || format!("Failed to parse row {} of row group {} of {}", row_no + 1, group, path.display());
+            let mut value = row.with_context(context)?.to_json_value();
+            if let Some(items) = value.get_mut("items")
+                && let Some(json) = items.as_str()
+            {
+                *items = serde_json::from_str(json).with_context(context)?;
+            }
+            let record: DayRecord = serde_json::from_value(value).with_context(context)?;
+            f(record)?;
+        }
+    }
+
+    Ok(())
+}
+
+struct RecordSeq<'a, F> {
+    f: &'a mut F,
+    error: &'a mut Option<anyhow::Error>,
+}
+
+impl<'de, F> DeserializeSeed<'de> for RecordSeq<'_, F>
+where
+    F: FnMut(DayRecord) -> Result<()>,
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, F> Visitor<'de> for RecordSeq<'_, F>
+where
+    F: FnMut(DayRecord) -> Result<()>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of day records")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(record) = seq.next_element::<DayRecord>()? {
+            if let Err(e) = (self.f)(record) {
+                *self.error = Some(e);
+                return Err(de::Error::custom("aborted by callback"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::data_type::{ByteArray, ByteArrayType, Int32Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tm-query-data-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    fn collect(path: &Path) -> Vec<DayRecord> {
+        let mut records = Vec::new();
+        for_each_record(path, |record| {
+            records.push(record);
+            Ok(())
+        }).unwrap();
+        records
+    }
+
+    /// Write each slice of days as its own row group, with the items as JSON
+    fn write_parquet(path: &Path, groups: &[&[(&str, i32, Value)]]) {
+        let schema = parse_message_type(
+            "message day { REQUIRED BYTE_ARRAY date (UTF8); REQUIRED INT32 count; REQUIRED BYTE_ARRAY items (UTF8); }",
+        ).unwrap();
+        let file = File::create(path).unwrap();
+        let mut writer = SerializedFileWriter::new(file, Arc::new(schema), Arc::new(WriterProperties::builder().build())).unwrap();
+        for days in groups {
+            let mut group = writer.next_row_group().unwrap();
+            let dates: Vec<ByteArray> = days.iter().map(|(date, _, _)| ByteArray::from(*date)).collect();
+            let counts: Vec<i32> = days.iter().map(|(_, count, _)| *count).collect();
+            let items: Vec<ByteArray> = days.iter().map(|(_, _, items)| ByteArray::from(items.to_string().as_str())).collect();
+
+            let mut column = group.next_column().unwrap().unwrap();
+            column.typed::<ByteArrayType>().write_batch(&dates, None, None).unwrap();
+            column.close().unwrap();
+            let mut column = group.next_column().unwrap().unwrap();
+            column.typed::<Int32Type>().write_batch(&counts, None, None).unwrap();
+            column.close().unwrap();
+            let mut column = group.next_column().unwrap().unwrap();
+            column.typed::<ByteArrayType>().write_batch(&items, None, None).unwrap();
+            column.close().unwrap();
+            group.close().unwrap();
+        }
+        writer.close().unwrap();
+    }
+
+    #[test]
+    fn parquet_records_stream_across_row_groups() {
+        let path = temp_file("days.parquet");
+        let item = serde_json::json!({"applicationNum": "40201900001X"});
+        write_parquet(&path, &[
+            &[("2020-01-01", 1, serde_json::json!([item])), ("2020-01-02", 0, serde_json::json!([]))],
+            &[("2020-01-03", 2, serde_json::json!([item, item]))],
+        ]);
+
+        let records = collect(&path);
+        let days: Vec<_> = records.iter().map(|r| (r.date.as_str(), r.count, r.items.len())).collect();
+        assert_eq!(days, [("2020-01-01", 1, 1), ("2020-01-02", 0, 0), ("2020-01-03", 2, 2)]);
+        assert_eq!(records[0].items[0], item);
+    }
+
+    #[test]
+    fn callback_errors_stop_a_parquet_file() {
+        let path = temp_file("days.PARQUET");
+        write_parquet(&path, &[&[("2020-01-01", 0, serde_json::json!([])), ("2020-01-02", 0, serde_json::json!([]))]]);
+
+        let mut seen = 0;
+        let result = for_each_record(&path, |_| {
+            seen += 1;
+            anyhow::bail!("stop")
+        });
+        assert_eq!(result.unwrap_err().to_string(), "stop");
+        assert_eq!(seen, 1);
+    }
+
+    #[test]
+    fn ndjson_extensions_match_in_any_case() {
+        for name in ["DAYS.JSONL", "days.NdJson"] {
+            let path = temp_file(name);
+            std::fs::write(&path, "{\"date\":\"2020-01-01\",\"count\":0,\"items\":[]}\n\n{\"date\":\"2020-01-02\",\"count\":0,\"items\":[]}\n").unwrap();
+            let dates: Vec<_> = collect(&path).into_iter().map(|r| r.date).collect();
+            assert_eq!(dates, ["2020-01-01", "2020-01-02"]);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Building blocks of the LLM extraction tool.
+
+pub mod autotune;
+pub mod baseline;
+pub mod bootstrap;
+pub mod breakdown;
+pub mod budget;
+pub mod cache;
+pub mod compare;
+pub mod config;
+pub mod correlation;
+pub mod dataset;
+pub mod dedup;
+pub mod detect;
+pub mod device;
+pub mod dispatch;
+pub mod drift;
+pub mod earlystop;
+pub mod endpoints;
+pub mod enrich;
+pub mod failures;
+pub mod fewshot;
+pub mod format;
+pub mod gallery;
+pub mod health;
+pub mod interrupt;
+pub mod ipos;
+pub mod jobs;
+pub mod latency;
+pub mod logging;
+pub mod metrics;
+pub mod normalize;
+pub mod outputs;
+pub mod parse;
+pub mod pipeline;
+pub mod progress;
+pub mod preprocess;
+pub mod prompt;
+pub mod protocol;
+pub mod ratelimit;
+pub mod raw_data;
+pub mod remote;
+pub mod report;
+pub mod resize;
+pub mod responses;
+pub mod results;
+pub mod retry;
+pub mod review;
+pub mod sample;
+pub mod script;
+pub mod skiplist;
+pub mod snapshot;
+pub mod source;
+pub mod split;
+pub mod sweep;
+pub mod terms;
+pub mod usage;
+pub mod validate;
+pub mod vote;
+pub mod words;
@@ -0,0 +1,293 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tracing::info;
+
+/// Concurrency `--auto-tune` starts at
+const START: usize = 2;
+
+/// Least throughput gain, as a share, for a higher level to be kept
+const MIN_GAIN: f64 = 0.05;
+
+/// Most the share of failed requests may rise at a higher level
+const ERROR_TOLERANCE: f64 = 0.02;
+
+/// Mean latency over the settled level's, and throughput under it, at
+/// which the level is lowered
+const LATENCY_DEGRADED: f64 = 1.5;
+const THROUGHPUT_DEGRADED: f64 = 0.9;
+
+/// Windows spent at a settled level before probing a higher one again
+const PROBE_EVERY: u32 = 10;
+
+/// Images a window needs at a level to be measured, so a few slow images
+/// don't decide it
+const MIN_WINDOW_IMAGES: u64 = 3;
+
+/// Concurrency picked while the run goes, with `--auto-tune`: the level
+/// starts low and is raised as long as each step brings more images per
+/// second without more failed requests, then settles at the best level. A
+/// settled level is lowered when latency degrades and probed upwards again
+/// now and then. The request slots are a semaphore whose permits follow the
+/// level, so the rate limit still paces the requests that get a slot.
+#[derive(Debug)]
+pub struct ConcurrencyTuner {
+    slots: Arc<Semaphore>,
+    max: usize,
+    interval: Duration,
+    started: Instant,
+    window: Window,
+    state: Mutex<State>,
+}
+
+/// Counts since the last measurement, updated by the workers without
+/// waiting on the tuner
+#[derive(Debug, Default)]
+struct Window {
+    images: AtomicU64,
+    busy_us: AtomicU64,
+    requests: AtomicU64,
+    /// Requests that timed out, failed to connect, got a server error or
+    /// were throttled
+    failed: AtomicU64,
+}
+
+#[derive(Debug)]
+struct State {
+    level: usize,
+    window_started: Instant,
+    /// Best level measured while probing
+    best: Option<Measure>,
+    /// Measure of the settled level, once settled
+    settled: Option<Settled>,
+    changes: Vec<LevelChange>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Settled {
+    /// Measure of the first window at the level
+    baseline: Option<Measure>,
+    windows: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Measure {
+    level: usize,
+    images_per_second: f64,
+    error_rate: f64,
+    mean_latency_ms: f64,
+}
+
+/// A change of the concurrency, as logged and saved in the metrics file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LevelChange {
+    /// Seconds into the run
+    pub at_secs: u64,
+    pub from: usize,
+    pub to: usize,
+    pub reason: String,
+}
+
+/// How `--auto-tune` went, saved in the metrics file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TuningSummary {
+    /// Level at the end of the run, to pin with `--concurrency`
+    pub final_concurrency: usize,
+    /// Most images per minute measured in a window, and the level it was at
+    pub best_images_per_minute: f64,
+    pub best_concurrency: usize,
+    pub changes: Vec<LevelChange>,
+}
+
+impl ConcurrencyTuner {
+    /// A tuner between 1 and `max` requests, measuring every `interval`
+    pub fn new(max: usize, interval: Duration) -> Self {
+        let level = START.min(max);
+        ConcurrencyTuner {
+            slots: Arc::new(Semaphore::new(level)),
+            max,
+            interval,
+            started: Instant::now(),
+            window: Window::default(),
+            state: Mutex::new(State {
+                level,
+                window_started: Instant::now(),
+                best: None,
+                settled: None,
+                changes: Vec::new(),
+            }),
+        }
+    }
+
+    /// The request slots, one permit per request allowed in flight
+    pub fn slots(&self) -> Arc<Semaphore> {
+        Arc::clone(&self.slots)
+    }
+
+    pub fn level(&self) -> usize {
+        self.state.lock().map_or(START, |state| state.level)
+    }
+
+    /// Count an image done after holding its slot for `busy`
+    pub fn record_image(&self, busy: Duration) {
+        self.window.images.fetch_add(1, Ordering::Relaxed);
+        self.window.busy_us.fetch_add(busy.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Count a request; `strained` when the server or the network failed it
+    /// or the server throttled it
+    pub fn record_request(&self, strained: bool) {
+        self.window.requests.fetch_add(1, Ordering::Relaxed);
+        if strained {
+            self.window.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Measure and adjust the level every interval until the task is
+    /// dropped or aborted
+    pub async fn run(self: Arc<Self>) {
+        let mut ticks = tokio::time::interval(self.interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            self.adjust();
+        }
+    }
+
+    /// Move to the level the last window points at, if it holds enough
+    /// images to tell
+    fn adjust(&self) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let images = self.window.images.load(Ordering::Relaxed);
+        if images < MIN_WINDOW_IMAGES.max(state.level as u64) {
+            return;
+        }
+        let elapsed = state.window_started.elapsed().as_secs_f64();
+        let busy_us = self.window.busy_us.swap(0, Ordering::Relaxed);
+        let requests = self.window.requests.swap(0, Ordering::Relaxed);
+        let failed = self.window.failed.swap(0, Ordering::Relaxed);
+        self.window.images.fetch_sub(images, Ordering::Relaxed);
+        state.window_started = Instant::now();
+        let measure = Measure {
+            level: state.level,
+            images_per_second: images as f64 / elapsed.max(f64::EPSILON),
+            error_rate: if requests > 0 { failed as f64 / requests as f64 } else { 0.0 },
+            mean_latency_ms: busy_us as f64 / 1000.0 / images as f64,
+        };
+
+        let Some((level, reason)) = next_level(&mut state, measure, self.max) else {
+            return;
+        };
+        let from = state.level;
+        if level == from {
+            return;
+        }
+        info!(
+            event = "concurrency",
+            from,
+            to = level,
+            images_per_minute = measure.images_per_second * 60.0,
+            error_rate = measure.error_rate,
+            mean_latency_ms = measure.mean_latency_ms as u64,
+            "Concurrency {} -> {}: {} ({:.1} images/minute, {:.1}% failed requests, {:.0} ms per image at {})",
+            from, level, reason, measure.images_per_second * 60.0, measure.error_rate * 100.0, measure.mean_latency_ms, from
+        );
+        state.changes.push(LevelChange { at_secs: self.started.elapsed().as_secs(), from, to: level, reason });
+        state.level = level;
+        if level > from {
+            self.slots.add_permits(level - from);
+        } else {
+            // Taken back as the requests holding them finish
+            let slots = Arc::clone(&self.slots);
+            let surplus = (from - level) as u32;
+            tokio::spawn(async move {
+                if let Ok(permits) = slots.acquire_many_owned(surplus).await {
+                    permits.forget();
+                }
+            });
+        }
+    }
+
+    pub fn summary(&self) -> Option<TuningSummary> {
+        let state = self.state.lock().ok()?;
+        let best = state.best.or(state.settled.and_then(|settled| settled.baseline));
+        Some(TuningSummary {
+            final_concurrency: state.level,
+            best_images_per_minute: best.map_or(0.0, |best| best.images_per_second * 60.0),
+            best_concurrency: best.map_or(state.level, |best| best.level),
+            changes: state.changes.clone(),
+        })
+    }
+}
+
+/// The level to move to after `measure`, with why, or `None` to stay
+fn next_level(state: &mut State, measure: Measure, max: usize) -> Option<(usize, String)> {
+    let raise = |level: usize| (level + (level / 2).max(1)).min(max);
+
+    if let Some(settled) = &mut state.settled {
+        let Some(baseline) = settled.baseline else {
+            settled.baseline = Some(measure);
+            return None;
+        };
+        let slower = measure.mean_latency_ms > baseline.mean_latency_ms * LATENCY_DEGRADED
+            && measure.images_per_second < baseline.images_per_second * THROUGHPUT_DEGRADED;
+        let failing = measure.error_rate > baseline.error_rate + ERROR_TOLERANCE;
+        if (slower || failing) && state.level > 1 {
+            let level = state.level - (state.level / 4).max(1);
+            state.settled = Some(Settled { baseline: None, windows: 0 });
+            let reason = if failing { "failed requests rose" } else { "latency degraded" };
+            return Some((level, reason.to_string()));
+        }
+        settled.windows += 1;
+        if settled.windows >= PROBE_EVERY && state.level < max {
+            state.settled = None;
+            state.best = Some(measure);
+            return Some((raise(state.level), "probing a higher level".to_string()));
+        }
+        return None;
+    }
+
+    match state.best {
+        None => {
+            state.best = Some(measure);
+            (state.level < max).then(|| (raise(state.level), "probing a higher level".to_string()))
+        }
+        Some(best) if measure.images_per_second > best.images_per_second * (1.0 + MIN_GAIN)
+            && measure.error_rate <= best.error_rate + ERROR_TOLERANCE =>
+        {
+            state.best = Some(measure);
+            if state.level < max {
+                Some((raise(state.level), "probing a higher level".to_string()))
+            } else {
+                state.settled = Some(Settled { baseline: Some(measure), windows: 0 });
+                None
+            }
+        }
+        Some(best) => {
+            let reason = if measure.error_rate > best.error_rate + ERROR_TOLERANCE {
+                format!("failed requests rose at {}, settling at {}", measure.level, best.level)
+            } else {
+                format!("no gain at {}, settling at {}", measure.level, best.level)
+            };
+            state.settled = Some(Settled { baseline: None, windows: 0 });
+            Some((best.level, reason))
+        }
+    }
+}
+
+impl fmt::Display for TuningSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let levels: Vec<String> = self.changes.iter().map(|change| format!("{}s: {}", change.at_secs, change.to)).collect();
+        write!(f, "Auto-tuned concurrency: {} at the end (best {:.1} images/minute at {}); pin it with --concurrency {}",
+            self.final_concurrency, self.best_images_per_minute, self.best_concurrency, self.final_concurrency)?;
+        if !levels.is_empty() {
+            write!(f, "\n  Levels: {}", levels.join(", "))?;
+        }
+        Ok(())
+    }
+}
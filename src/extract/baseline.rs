@@ -0,0 +1,138 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use tracing::{info, warn};
+
+use super::metrics::{ImageScore, Scoreboard};
+
+/// Default accuracy drop, in percentage points, that fails a run compared
+/// with a baseline
+pub const DEFAULT_REGRESSION_THRESHOLD: f64 = 1.0;
+
+/// The figures of an earlier run's metrics.json that a run is compared on
+#[derive(Debug, Clone, Deserialize)]
+pub struct Baseline {
+    pub config_id: String,
+    /// Missing from metrics written before samples were identified
+    #[serde(default)]
+    pub sample_id: String,
+    pub total: usize,
+    pub accuracy: f64,
+    pub answered_accuracy: f64,
+    pub mean_cer: Option<f64>,
+    pub median_cer: Option<f64>,
+    /// Missing from metrics written before images were scored one by one
+    #[serde(default)]
+    pub image_scores: BTreeMap<String, ImageScore>,
+}
+
+impl Baseline {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open baseline: {}", path.display()))?;
+        serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse baseline: {}", path.display()))
+    }
+
+    /// Whether the baseline was scored on the same images as `scoreboard`;
+    /// `None` when the baseline doesn't say
+    pub fn same_sample(&self, scoreboard: &Scoreboard) -> Option<bool> {
+        (!self.sample_id.is_empty()).then(|| self.sample_id == scoreboard.sample_id)
+    }
+
+    /// Drop in accuracy from the baseline, in percentage points; negative
+    /// when the run improved
+    pub fn accuracy_drop(&self, scoreboard: &Scoreboard) -> f64 {
+        100.0 * (self.accuracy - scoreboard.accuracy())
+    }
+
+    /// Log how `scoreboard` compares with the baseline read from `path`,
+    /// warning when the two aren't measured on the same sample; the
+    /// accuracy drop in percentage points when it is more than `threshold`
+    pub fn compare(&self, scoreboard: &Scoreboard, path: &Path, threshold: f64) -> Option<f64> {
+        match self.same_sample(scoreboard) {
+            Some(true) => {}
+            Some(false) => warn!(
+                "{} was measured on a different sample ({} images) than this run ({} images); \
+                 the comparison below is not like for like. Use --reuse-sample with the baseline's sample manifest.",
+                path.display(), self.total, scoreboard.total),
+            None => warn!(
+                "{} doesn't record its sample, so it can't be checked to cover the same images as this run",
+                path.display()),
+        }
+        info!("Comparison with baseline {}:\n{}", path.display(), self.report(scoreboard));
+        let change = scoreboard.confidence.as_ref()
+            .and_then(|confidence| confidence.baseline)
+            .and_then(|differences| differences.describe("Current", "baseline"));
+        match change {
+            Some(change) => info!("{}", change),
+            None if self.image_scores.is_empty() => info!(
+                "{} doesn't record the score of each image, so the change has no confidence interval", path.display()),
+            None => {}
+        }
+
+        let drop = self.accuracy_drop(scoreboard);
+        (drop > threshold).then(|| {
+            warn!("REGRESSION: accuracy dropped by {:.1} percentage points, more than the {:.1} allowed", drop, threshold);
+            drop
+        })
+    }
+
+    /// Table of the baseline's figures, the run's and the change
+    pub fn report(&self, scoreboard: &Scoreboard) -> String {
+        let percent = |name: &str, before: f64, after: f64| {
+            format!("  {:<18} {:>9.1}% {:>9.1}% {:>+9.1} pp", name, 100.0 * before, 100.0 * after, 100.0 * (after - before))
+        };
+        let cer = |name: &str, before: Option<f64>, after: Option<f64>| {
+            let value = |c: Option<f64>| c.map_or("-".to_string(), |c| format!("{:.3}", c));
+            let delta = match (before, after) {
+                (Some(before), Some(after)) => format!("{:+.3}", after - before),
+                _ => "-".to_string(),
+            };
+            format!("  {:<18} {:>10} {:>10} {:>12}", name, value(before), value(after), delta)
+        };
+        [
+            format!("  {:<18} {:>10} {:>10} {:>12}", "", "Baseline", "Current", "Change"),
+            format!("  {:<18} {:>10} {:>10} {:>+12}", "Images", self.total, scoreboard.total,
+                scoreboard.total as i64 - self.total as i64),
+            percent("Accuracy", self.accuracy, scoreboard.accuracy()),
+            percent("Answered accuracy", self.answered_accuracy, scoreboard.answered_accuracy()),
+            cer("Mean CER", self.mean_cer, scoreboard.mean_cer()),
+            cer("Median CER", self.median_cer, scoreboard.median_cer()),
+        ].join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn baseline(accuracy: f64) -> Baseline {
+        Baseline {
+            config_id: "abc".to_string(),
+            sample_id: "def".to_string(),
+            total: 100,
+            accuracy,
+            answered_accuracy: accuracy,
+            mean_cer: None,
+            median_cer: None,
+            image_scores: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn only_a_drop_over_the_threshold_is_a_regression() {
+        let mut scoreboard = Scoreboard::new("abc", "none", &[], "def");
+        scoreboard.total = 100;
+        scoreboard.exact_match = 80;
+        let path = Path::new("baseline.json");
+
+        let drop = baseline(0.9).compare(&scoreboard, path, DEFAULT_REGRESSION_THRESHOLD).unwrap();
+        assert!((drop - 10.0).abs() < 1e-9);
+        assert_eq!(baseline(0.805).compare(&scoreboard, path, DEFAULT_REGRESSION_THRESHOLD), None);
+        assert_eq!(baseline(0.7).compare(&scoreboard, path, DEFAULT_REGRESSION_THRESHOLD), None);
+    }
+}
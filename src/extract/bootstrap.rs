@@ -0,0 +1,154 @@
+use clap::Args;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::metrics::ImageScore;
+
+/// Default number of resamples of a bootstrap
+pub const DEFAULT_RESAMPLES: usize = 1000;
+
+/// Default seed of the resampling
+pub const DEFAULT_SEED: u64 = 42;
+
+/// Confidence level of the intervals
+pub const LEVEL: f64 = 0.95;
+
+/// Settings of the bootstrap confidence intervals
+#[derive(Args, Debug, Clone, Copy)]
+pub struct Bootstrap {
+    /// Resamples of the bootstrap behind the 95% confidence intervals; 0
+    /// leaves the intervals out
+    #[arg(long, default_value_t = DEFAULT_RESAMPLES)]
+    pub bootstrap_resamples: usize,
+
+    /// Seed of the bootstrap resampling
+    #[arg(long, default_value_t = DEFAULT_SEED)]
+    pub bootstrap_seed: u64,
+}
+
+/// Range a statistic falls in with `LEVEL` confidence
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Interval {
+    pub low: f64,
+    pub high: f64,
+}
+
+/// Mean change of a figure from one run to another on the images both
+/// scored, later run minus earlier
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Difference {
+    pub images: usize,
+    pub mean: f64,
+    pub low: f64,
+    pub high: f64,
+    /// Whether the interval leaves out zero, so the change is unlikely to be
+    /// noise
+    pub excludes_zero: bool,
+}
+
+/// Changes of accuracy and CER between two runs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Differences {
+    pub accuracy: Option<Difference>,
+    pub mean_cer: Option<Difference>,
+}
+
+/// Intervals of a run's figures, recorded in its metrics
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Confidence {
+    pub level: f64,
+    pub resamples: usize,
+    pub seed: u64,
+    pub accuracy: Option<Interval>,
+    pub mean_cer: Option<Interval>,
+    /// Change from `--baseline`, when it records its images' scores
+    #[serde(default)]
+    pub baseline: Option<Differences>,
+}
+
+impl Bootstrap {
+    /// Intervals of the accuracy and mean CER of the images in `scores`;
+    /// `None` when the bootstrap is turned off
+    pub fn confidence(&self, scores: &BTreeMap<String, ImageScore>) -> Option<Confidence> {
+        if self.bootstrap_resamples == 0 {
+            return None;
+        }
+        let accuracy: Vec<f64> = scores.values().map(|score| f64::from(u8::from(score.exact_match))).collect();
+        let cers: Vec<f64> = scores.values().filter_map(|score| score.cer).collect();
+        Some(Confidence {
+            level: LEVEL,
+            resamples: self.bootstrap_resamples,
+            seed: self.bootstrap_seed,
+            accuracy: self.mean(&accuracy),
+            mean_cer: self.mean(&cers),
+            baseline: None,
+        })
+    }
+
+    /// Changes of accuracy and mean CER from `before` to `after`, paired on
+    /// the images both scored
+    pub fn differences(&self, before: &BTreeMap<String, ImageScore>, after: &BTreeMap<String, ImageScore>) -> Differences {
+        let pairs: Vec<(&ImageScore, &ImageScore)> = before.iter()
+            .filter_map(|(image_name, score)| after.get(image_name).map(|other| (score, other)))
+            .collect();
+        let accuracy: Vec<f64> = pairs.iter()
+            .map(|(before, after)| f64::from(u8::from(after.exact_match)) - f64::from(u8::from(before.exact_match)))
+            .collect();
+        let cers: Vec<f64> = pairs.iter()
+            .filter_map(|(before, after)| Some(after.cer? - before.cer?))
+            .collect();
+        Differences { accuracy: self.difference(&accuracy), mean_cer: self.difference(&cers) }
+    }
+
+    /// Mean of paired `changes`, with its interval
+    pub fn difference(&self, changes: &[f64]) -> Option<Difference> {
+        let interval = self.mean(changes)?;
+        Some(Difference {
+            images: changes.len(),
+            mean: changes.iter().sum::<f64>() / changes.len() as f64,
+            low: interval.low,
+            high: interval.high,
+            excludes_zero: interval.low > 0.0 || interval.high < 0.0,
+        })
+    }
+
+    /// Percentile interval of the mean of `values`, from the means of
+    /// resamples of them drawn with replacement
+    pub fn mean(&self, values: &[f64]) -> Option<Interval> {
+        if values.is_empty() || self.bootstrap_resamples == 0 {
+            return None;
+        }
+        let mut rng = StdRng::seed_from_u64(self.bootstrap_seed);
+        let n = values.len();
+        let mut means: Vec<f64> = (0..self.bootstrap_resamples)
+            .map(|_| (0..n).map(|_| values[rng.gen_range(0..n)]).sum::<f64>() / n as f64)
+            .collect();
+        means.sort_by(f64::total_cmp);
+        let tail = (1.0 - LEVEL) / 2.0;
+        let at = |q: f64| means[((means.len() - 1) as f64 * q).round() as usize];
+        Some(Interval { low: at(tail), high: at(1.0 - tail) })
+    }
+}
+
+impl Differences {
+    /// `B - A on 480 images: accuracy +1.5 pp (95% CI -0.3 to +3.2), ...`,
+    /// or `None` without any image both runs scored
+    pub fn describe(&self, after: &str, before: &str) -> Option<String> {
+        let accuracy = self.accuracy?;
+        Some(format!("{} - {} on {} images: accuracy {}, mean CER {}", after, before, accuracy.images,
+            accuracy.describe(true), self.mean_cer.map_or("-".to_string(), |cer| cer.describe(false))))
+    }
+}
+
+impl Difference {
+    /// `+1.5 pp (95% CI -0.3 to +3.2)`, in percentage points with `percent`
+    pub fn describe(&self, percent: bool) -> String {
+        let scale = if percent { 100.0 } else { 1.0 };
+        let (precision, unit) = if percent { (1, " pp") } else { (3, "") };
+        format!("{:+.*}{} (95% CI {:+.*} to {:+.*}){}", precision, scale * self.mean, unit,
+            precision, scale * self.low, precision, scale * self.high,
+            if self.excludes_zero { "" } else { ", could be noise" })
+    }
+}
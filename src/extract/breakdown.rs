@@ -0,0 +1,215 @@
+use anyhow::Result;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+
+use super::resize::SentImage;
+use super::results::ExtractionResult;
+
+/// Default first character counts of the length buckets: 1, 2, 3-4 and 5+
+pub const DEFAULT_LENGTH_BUCKETS: [u64; 4] = [1, 2, 3, 5];
+
+/// Default bounds of the file size buckets, in KB
+pub const DEFAULT_SIZE_BUCKETS_KB: [u64; 3] = [20, 100, 500];
+
+/// Default bounds of the buckets of the image's longest side, in pixels
+pub const DEFAULT_DIMENSION_BUCKETS: [u64; 3] = [256, 512, 1024];
+
+/// Default number of images under which a bucket is flagged as low confidence
+pub const DEFAULT_MIN_BUCKET_SIZE: usize = 30;
+
+/// Where the accuracy breakdown splits the images
+#[derive(Args, Debug, Clone)]
+pub struct BucketBounds {
+    /// Ground truth character counts each length bucket starts at, comma separated
+    #[arg(long, value_delimiter = ',', default_values_t = DEFAULT_LENGTH_BUCKETS)]
+    pub length_buckets: Vec<u64>,
+
+    /// File sizes in KB each size bucket starts at, comma separated
+    #[arg(long, value_delimiter = ',', default_values_t = DEFAULT_SIZE_BUCKETS_KB)]
+    pub size_buckets_kb: Vec<u64>,
+
+    /// Longest sides in pixels each dimension bucket starts at, comma separated
+    #[arg(long, value_delimiter = ',', default_values_t = DEFAULT_DIMENSION_BUCKETS)]
+    pub dimension_buckets: Vec<u64>,
+
+    /// Buckets with fewer images are flagged as low confidence
+    #[arg(long, default_value_t = DEFAULT_MIN_BUCKET_SIZE)]
+    pub min_bucket_size: usize,
+}
+
+/// Buckets an image falls in, recorded with its result; `null` where the
+/// image has no ground truth characters, or its size or dimensions aren't
+/// known
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImageBuckets {
+    pub length: Option<String>,
+    pub file_size: Option<String>,
+    pub dimensions: Option<String>,
+}
+
+/// Exact matches and CER of the labeled images in one bucket
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BucketScore {
+    pub bucket: String,
+    pub images: usize,
+    pub exact_match: usize,
+    pub accuracy: f64,
+    pub mean_cer: Option<f64>,
+    /// Fewer images than `--min-bucket-size`
+    pub low_confidence: bool,
+}
+
+/// Accuracy of the labeled images by ground truth length, file size and
+/// longest side, each in bucket order
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Breakdown {
+    pub length: Vec<BucketScore>,
+    pub file_size: Vec<BucketScore>,
+    pub dimensions: Vec<BucketScore>,
+    pub min_bucket_size: usize,
+}
+
+/// One way of splitting the images: the bucket of a result, the bounds the
+/// buckets start at, and whether a bucket of whole numbers is named by its
+/// last value (`3-4`) rather than the next bound (`100-500`)
+struct Split<'a> {
+    value: fn(&ExtractionResult) -> Option<u64>,
+    bounds: &'a [u64],
+    scale: u64,
+    whole: bool,
+    unit: &'static str,
+}
+
+impl BucketBounds {
+    pub fn validate(&self) -> Result<()> {
+        for (name, bounds) in [
+            ("--length-buckets", &self.length_buckets),
+            ("--size-buckets-kb", &self.size_buckets_kb),
+            ("--dimension-buckets", &self.dimension_buckets),
+        ] {
+            if bounds.is_empty() || bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+                anyhow::bail!("{} must be increasing numbers, got {:?}", name, bounds);
+            }
+        }
+        Ok(())
+    }
+
+    fn splits(&self) -> [Split<'_>; 3] {
+        [
+            Split {
+                value: |result| result.compared_ground_truth().map(|truth| truth.chars().count() as u64),
+                bounds: &self.length_buckets,
+                scale: 1,
+                whole: true,
+                unit: "",
+            },
+            Split { value: |result| result.file_bytes, bounds: &self.size_buckets_kb, scale: 1000, whole: false, unit: " KB" },
+            Split {
+                value: |result| result.sent_image.as_ref().and_then(SentImage::original_longest_side).map(u64::from),
+                bounds: &self.dimension_buckets,
+                scale: 1,
+                whole: false,
+                unit: " px",
+            },
+        ]
+    }
+
+    /// Names of the length buckets, in order
+    pub fn length_names(&self) -> Vec<String> {
+        let [length, _, _] = self.splits();
+        (0..=self.length_buckets.len()).map(|i| length.name(i)).collect()
+    }
+
+    /// Index in `length_names` of the bucket of a ground truth of
+    /// `characters`
+    pub fn length_bucket(&self, characters: usize) -> usize {
+        let [length, _, _] = self.splits();
+        length.index(characters as u64)
+    }
+
+    /// Buckets of `result`, by ground truth length, file size and the
+    /// longest side of the image
+    pub fn assign(&self, result: &ExtractionResult) -> ImageBuckets {
+        let [length, file_size, dimensions] = self.splits().map(|split| split.bucket(result).map(|i| split.name(i)));
+        ImageBuckets { length, file_size, dimensions }
+    }
+
+    /// Scores of the labeled `results` in each bucket with images
+    pub fn breakdown(&self, results: &[ExtractionResult]) -> Breakdown {
+        let labeled: Vec<&ExtractionResult> = results.iter().filter(|result| result.ground_truth.is_some()).collect();
+        let [length, file_size, dimensions] = self.splits().map(|split| {
+            let mut buckets = vec![(0, 0, Vec::new()); split.bounds.len() + 1];
+            for result in &labeled {
+                if let Some(i) = split.bucket(result) {
+                    buckets[i].0 += 1;
+                    buckets[i].1 += usize::from(result.is_match());
+                    buckets[i].2.extend(result.cer);
+                }
+            }
+            buckets.into_iter()
+                .enumerate()
+                .filter(|(_, (images, _, _))| *images > 0)
+                .map(|(i, (images, exact_match, cers))| BucketScore {
+                    bucket: split.name(i),
+                    images,
+                    exact_match,
+                    accuracy: exact_match as f64 / images as f64,
+                    mean_cer: (!cers.is_empty()).then(|| cers.iter().sum::<f64>() / cers.len() as f64),
+                    low_confidence: images < self.min_bucket_size,
+                })
+                .collect()
+        });
+        Breakdown { length, file_size, dimensions, min_bucket_size: self.min_bucket_size }
+    }
+}
+
+impl Split<'_> {
+    /// Index of the bucket of `result`: 0 below the first bound, `i + 1`
+    /// from bound `i`
+    fn bucket(&self, result: &ExtractionResult) -> Option<usize> {
+        Some(self.index((self.value)(result)?))
+    }
+
+    fn index(&self, value: u64) -> usize {
+        self.bounds.iter().take_while(|&&bound| value >= bound * self.scale).count()
+    }
+
+    fn name(&self, index: usize) -> String {
+        let unit = self.unit;
+        match (index.checked_sub(1).map(|i| self.bounds[i]), self.bounds.get(index)) {
+            (None, Some(next)) => format!("<{}{}", next, unit),
+            (Some(start), None) => format!("{}+{}", start, unit),
+            (Some(start), Some(&next)) if self.whole && next == start + 1 => format!("{}{}", start, unit),
+            (Some(start), Some(&next)) if self.whole => format!("{}-{}{}", start, next - 1, unit),
+            (Some(start), Some(next)) => format!("{}-{}{}", start, next, unit),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+impl Breakdown {
+    /// Compact table of each split, low confidence buckets marked
+    pub fn report(&self) -> String {
+        let mut lines = Vec::new();
+        for (title, buckets) in [
+            ("Accuracy by ground truth length", &self.length),
+            ("Accuracy by file size", &self.file_size),
+            ("Accuracy by longest side", &self.dimensions),
+        ] {
+            if buckets.is_empty() {
+                continue;
+            }
+            lines.push(format!("  {}:", title));
+            for bucket in buckets {
+                lines.push(format!("    {:<12} {:6} images {:6.1}%  CER {}{}", bucket.bucket, bucket.images, 100.0 * bucket.accuracy,
+                    bucket.mean_cer.map_or("-".to_string(), |cer| format!("{:.3}", cer)),
+                    if bucket.low_confidence { "  (few images)" } else { "" }));
+            }
+        }
+        if self.length.iter().chain(&self.file_size).chain(&self.dimensions).any(|bucket| bucket.low_confidence) {
+            lines.push(format!("  Buckets with fewer than {} images are marked (few images), their accuracy is not reliable",
+                self.min_bucket_size));
+        }
+        lines.join("\n")
+    }
+}
@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Granularity of the semaphore behind `--max-inflight-bytes`, whose
+/// permits are counted in `u32`
+const UNIT: u64 = 1024;
+
+/// Bytes of image payload held in memory by the requests in flight, capped
+/// with `--max-inflight-bytes`. Without a cap the bytes are only tracked,
+/// so the peak can be compared between runs.
+#[derive(Debug, Clone)]
+pub struct PayloadBudget {
+    /// Permits of `UNIT` bytes, with the total they started at
+    semaphore: Option<(Arc<Semaphore>, u32)>,
+    tracked: Arc<Tracked>,
+}
+
+#[derive(Debug, Default)]
+struct Tracked {
+    current: AtomicU64,
+    peak: AtomicU64,
+}
+
+/// A share of the budget, given back when dropped
+#[derive(Debug)]
+pub struct PayloadPermit {
+    bytes: u64,
+    tracked: Arc<Tracked>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl PayloadBudget {
+    pub fn new(max_bytes: Option<u64>) -> Self {
+        let semaphore = max_bytes.map(|bytes| {
+            let units = bytes.div_ceil(UNIT).clamp(1, u64::from(u32::MAX)) as u32;
+            (Arc::new(Semaphore::new(units as usize)), units)
+        });
+        PayloadBudget { semaphore, tracked: Arc::default() }
+    }
+
+    /// Wait until `bytes` fit in the budget and take them. A payload larger
+    /// than the whole budget waits for all of it, and so goes alone rather
+    /// than never.
+    pub async fn acquire(&self, bytes: u64) -> PayloadPermit {
+        let permit = match &self.semaphore {
+            Some((semaphore, total)) => {
+                let units = bytes.div_ceil(UNIT).clamp(1, u64::from(*total)) as u32;
+                // The semaphore is never closed
+                Arc::clone(semaphore).acquire_many_owned(units).await.ok()
+            }
+            None => None,
+        };
+        let current = self.tracked.current.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.tracked.peak.fetch_max(current, Ordering::Relaxed);
+        PayloadPermit { bytes, tracked: Arc::clone(&self.tracked), _permit: permit }
+    }
+
+    /// Most bytes held at once so far
+    pub fn peak(&self) -> u64 {
+        self.tracked.peak.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.semaphore.as_ref().map(|(_, total)| u64::from(*total) * UNIT)
+    }
+}
+
+impl Drop for PayloadPermit {
+    fn drop(&mut self) {
+        self.tracked.current.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// Length of the base64 encoding of `bytes` bytes, with padding
+pub fn base64_len(bytes: u64) -> u64 {
+    bytes.div_ceil(3) * 4
+}
+
+/// Bytes an image of `file_bytes` on disk holds while in flight: the raw
+/// bytes when they are sent as a multipart file and the base64 text when
+/// they are sent in JSON, each kept for retries, plus the request body
+/// built from them. Shrunk images hold less; images converted to PNG may
+/// hold more.
+pub fn payload_bytes(file_bytes: u64, raw: bool, base64: bool) -> u64 {
+    let raw = if raw { 2 * file_bytes } else { 0 };
+    let base64 = if base64 { 2 * base64_len(file_bytes) } else { 0 };
+    raw + base64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const MB: u64 = 1024 * 1024;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn budget_holds_under_load_from_large_files() {
+        // Sparse files the size of the IPOS TIFFs, between 5 and 20 MB
+        let dir = std::env::temp_dir().join(format!("tm-query-budget-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut payloads = Vec::new();
+        for n in 0..60u64 {
+            let path = dir.join(format!("{}.tif", n));
+            std::fs::File::create(&path).unwrap().set_len((5 + n % 16) * MB).unwrap();
+            let file_bytes = std::fs::metadata(&path).unwrap().len();
+            payloads.push(payload_bytes(file_bytes, false, true));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let limit = 200 * MB;
+        let budget = PayloadBudget::new(Some(limit));
+        let in_flight = Arc::new(AtomicU64::new(0));
+        let tasks: Vec<_> = payloads.iter().map(|&bytes| {
+            let (budget, in_flight) = (budget.clone(), Arc::clone(&in_flight));
+            tokio::spawn(async move {
+                let permit = budget.acquire(bytes).await;
+                let held = in_flight.fetch_add(bytes, Ordering::SeqCst) + bytes;
+                assert!(held <= limit, "{} bytes in flight over a budget of {}", held, limit);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                in_flight.fetch_sub(bytes, Ordering::SeqCst);
+                drop(permit);
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let largest = *payloads.iter().max().unwrap();
+        assert!(budget.peak() <= limit);
+        assert!(budget.peak() > largest, "the budget let only one payload through at a time");
+        assert_eq!(budget.limit(), Some(limit));
+    }
+
+    #[tokio::test]
+    async fn payload_over_the_budget_goes_alone() {
+        let budget = PayloadBudget::new(Some(10 * MB));
+        let small = budget.acquire(MB).await;
+        let large = {
+            let budget = budget.clone();
+            tokio::spawn(async move { budget.acquire(50 * MB).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!large.is_finished());
+        drop(small);
+        let large = large.await.unwrap();
+        assert_eq!(budget.peak(), 50 * MB);
+        drop(large);
+    }
+
+    #[tokio::test]
+    async fn without_a_limit_bytes_are_only_tracked() {
+        let budget = PayloadBudget::new(None);
+        let first = budget.acquire(300 * MB).await;
+        let second = budget.acquire(300 * MB).await;
+        assert_eq!(budget.peak(), 600 * MB);
+        assert_eq!(budget.limit(), None);
+        drop((first, second));
+    }
+
+    #[test]
+    fn payload_counts_each_copy_held() {
+        assert_eq!(base64_len(0), 0);
+        assert_eq!(base64_len(4), 8);
+        assert_eq!(payload_bytes(3 * MB, false, true), 8 * MB);
+        assert_eq!(payload_bytes(3 * MB, true, false), 6 * MB);
+        assert_eq!(payload_bytes(3 * MB, true, true), 14 * MB);
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::protocol::Backend;
+use super::source::ImageSource;
+
+/// One stored response
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Repeated inside the file so a misplaced or truncated file is caught
+    key: String,
+    body: String,
+}
+
+/// API response bodies stored on disk, keyed by the image contents and
+/// everything about the request that can change the reply
+#[derive(Debug)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    /// Ignore stored responses and replace them (`--refresh-cache`)
+    refresh: bool,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    /// Stored responses that couldn't be read or parsed, counted as misses too
+    corrupt: AtomicUsize,
+}
+
+impl ResponseCache {
+    pub fn open(dir: &Path, refresh: bool) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create cache directory: {}", dir.display()))?;
+        Ok(ResponseCache {
+            dir: dir.to_path_buf(),
+            refresh,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            corrupt: AtomicUsize::new(0),
+        })
+    }
+
+    /// Key of the request `backend` makes for an image: sha256 of the image
+    /// bytes, how it is converted and resized (`image_settings`), protocol,
+    /// model, prompt, few-shot examples and sampling settings
+    pub fn key(source: &ImageSource, image_path: &Path, image_settings: &str, backend: &Backend) -> Result<String> {
+        let image = source.read(image_path)
+            .with_context(|| format!("Failed to read image file: {:?}", image_path))?;
+        let generation = serde_json::to_string(&backend.generation)?;
+        let mut hasher = Sha256::new();
+        hasher.update(&image);
+        for part in [
+            backend.protocol.name(),
+            backend.model.as_deref().unwrap_or_default(),
+            &backend.prompt,
+            backend.user_prompt,
+            &generation,
+            if backend.json_mode { "json" } else { "text" },
+            image_settings,
+        ] {
+            hasher.update([0]);
+            hasher.update(part.as_bytes());
+        }
+        // Left out without examples so keys made before --few-shot still hold
+        if let Some(few_shot) = &backend.few_shot {
+            hasher.update([0]);
+            hasher.update(few_shot.hash.as_bytes());
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(format!("{}.json", key))
+    }
+
+    /// The stored response body for `key`; `None` when there is none, with
+    /// `--refresh-cache`, or when the entry is damaged
+    pub fn get(&self, key: &str) -> Option<String> {
+        if self.refresh {
+            return None;
+        }
+        let path = self.path(key);
+        let text = fs::read_to_string(&path).ok()?;
+        match serde_json::from_str::<Entry>(&text) {
+            Ok(entry) if entry.key == key => Some(entry.body),
+            _ => {
+                self.corrupt.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Store a response body, replacing any earlier one
+    pub fn put(&self, key: &str, body: &str) -> Result<()> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create cache directory: {}", parent.display()))?;
+        }
+        let tmp_path = path.with_extension("json.tmp");
+        let entry = Entry { key: key.to_string(), body: body.to_string() };
+        fs::write(&tmp_path, serde_json::to_string(&entry)?)
+            .with_context(|| format!("Failed to write cache entry: {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Failed to save cache entry: {}", path.display()))
+    }
+
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a stored response that no longer parses
+    pub fn record_corrupt(&self) {
+        self.corrupt.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn corrupt(&self) -> usize {
+        self.corrupt.load(Ordering::Relaxed)
+    }
+
+    /// `12 hits, 30 misses`, with the damaged entries there were
+    pub fn describe(&self) -> String {
+        let corrupt = self.corrupt();
+        format!("{} hits, {} misses{}", self.hits(), self.misses(),
+            if corrupt > 0 { format!(" ({} damaged entries called again)", corrupt) } else { String::new() })
+    }
+}
@@ -0,0 +1,563 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::bootstrap::{Bootstrap, Differences, Interval};
+use super::metrics::{ImageScore, Scoreboard};
+use super::protocol::Protocol;
+use super::results::{self, ExtractionResult, Status};
+
+/// One model of a comparison, given as
+/// `name=protocol,url[,model][,rpm=N]` with `--model-spec`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelSpec {
+    /// Names the model in the results and the comparison, and the directory
+    /// its files are written to
+    pub name: String,
+    pub protocol: Protocol,
+    pub base_url: String,
+    pub model: Option<String>,
+    /// Requests per minute sent to this model, instead of `--rpm`/`--rps`
+    pub rpm: Option<f64>,
+}
+
+impl FromStr for ModelSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = "expected name=protocol,url[,model][,rpm=N]";
+        let (name, rest) = s.split_once('=').ok_or_else(|| format!("{}, got {}", usage, s))?;
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) || name.starts_with('.') {
+            return Err(format!("model spec name {:?} must be letters, digits, '-', '_' or '.', as it names a directory", name));
+        }
+        let mut items = rest.split(',').map(str::trim);
+        let (Some(protocol), Some(base_url)) = (items.next(), items.next()) else {
+            return Err(format!("{}, got {}", usage, s));
+        };
+        let protocol = Protocol::from_str(protocol, true)
+            .map_err(|_| format!("unknown protocol {} in model spec {}", protocol, name))?;
+        if base_url.is_empty() {
+            return Err(format!("model spec {} has no url", name));
+        }
+        let mut spec = ModelSpec {
+            name: name.to_string(),
+            protocol,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model: None,
+            rpm: None,
+        };
+        for item in items {
+            match item.split_once('=') {
+                Some(("rpm", rpm)) => {
+                    let rpm: f64 = rpm.parse().map_err(|_| format!("rpm of model spec {} is not a number: {}", name, rpm))?;
+                    if rpm <= 0.0 || !rpm.is_finite() {
+                        return Err(format!("rpm of model spec {} must be greater than zero", name));
+                    }
+                    spec.rpm = Some(rpm);
+                }
+                Some((key, _)) => return Err(format!("unknown option {} in model spec {}; only rpm is taken", key, name)),
+                None if spec.model.is_none() && !item.is_empty() => spec.model = Some(item.to_string()),
+                None => return Err(format!("{}, got {}", usage, s)),
+            }
+        }
+        if protocol.needs_model() && spec.model.is_none() {
+            return Err(format!("model spec {} uses protocol {}, which needs a model", name, protocol.name()));
+        }
+        Ok(spec)
+    }
+}
+
+/// A finished run of one model, with its results of the run's configuration
+#[derive(Debug)]
+pub struct ModelRun {
+    pub spec: ModelSpec,
+    pub scoreboard: Scoreboard,
+    pub results: Vec<ExtractionResult>,
+}
+
+/// Figures of one model in a comparison
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelSummary {
+    pub name: String,
+    pub protocol: &'static str,
+    pub base_url: String,
+    pub model: Option<String>,
+    pub config_id: String,
+    pub total: usize,
+    pub exact_match: usize,
+    pub errors: usize,
+    pub accuracy: f64,
+    pub answered_accuracy: f64,
+    pub mean_cer: Option<f64>,
+    pub accuracy_interval: Option<Interval>,
+    pub mean_cer_interval: Option<Interval>,
+    pub estimated_cost: Option<f64>,
+}
+
+/// How often two models gave the same characters
+#[derive(Debug, Clone, Serialize)]
+pub struct Agreement {
+    pub a: String,
+    pub b: String,
+    /// Images both models answered
+    pub images: usize,
+    /// Of those, images where the predictions are equal after normalization
+    pub agreed: usize,
+    pub rate: f64,
+    /// B's accuracy and mean CER minus A's, on the labeled images both scored
+    pub differences: Differences,
+}
+
+/// An image the models answered differently
+#[derive(Debug, Clone, Serialize)]
+pub struct Disagreement {
+    pub image_name: String,
+    pub ground_truth: Option<String>,
+    /// Characters each model that answered the image extracted
+    pub predictions: BTreeMap<String, Option<String>>,
+    /// Models whose prediction matches the ground truth
+    pub correct: Vec<String>,
+}
+
+/// Models run on the same sample side by side, written under `comparison`
+/// in the metrics file next to `--output`
+#[derive(Debug, Clone, Serialize)]
+pub struct Comparison {
+    pub models: Vec<ModelSummary>,
+    /// Every pair of models, in the order they were given
+    pub agreement: Vec<Agreement>,
+    /// Images every model answered
+    pub answered_by_all: usize,
+    /// Images answered by at least two models whose predictions differ,
+    /// by image name
+    pub disagreements: Vec<Disagreement>,
+}
+
+impl Comparison {
+    pub fn new(runs: &[ModelRun], bootstrap: &Bootstrap) -> Self {
+        let models = runs.iter()
+            .map(|run| ModelSummary {
+                name: run.spec.name.clone(),
+                protocol: run.spec.protocol.name(),
+                base_url: run.spec.base_url.clone(),
+                model: run.spec.model.clone(),
+                config_id: run.scoreboard.config_id.clone(),
+                total: run.scoreboard.total,
+                exact_match: run.scoreboard.exact_match,
+                errors: run.scoreboard.errors,
+                accuracy: run.scoreboard.accuracy(),
+                answered_accuracy: run.scoreboard.answered_accuracy(),
+                mean_cer: run.scoreboard.mean_cer(),
+                accuracy_interval: run.scoreboard.confidence.as_ref().and_then(|confidence| confidence.accuracy),
+                mean_cer_interval: run.scoreboard.confidence.as_ref().and_then(|confidence| confidence.mean_cer),
+                estimated_cost: run.scoreboard.usage.estimated_cost,
+            })
+            .collect();
+
+        // Answered results of each model by image
+        let answered: Vec<HashMap<&str, &ExtractionResult>> = runs.iter()
+            .map(|run| run.results.iter()
+                .filter(|result| result.status != Status::Error)
+                .map(|result| (result.image_name.as_str(), result))
+                .collect())
+            .collect();
+
+        let mut agreement = Vec::new();
+        for (i, a) in answered.iter().enumerate() {
+            for (j, b) in answered.iter().enumerate().skip(i + 1) {
+                let mut images = 0;
+                let mut agreed = 0;
+                for (image_name, result) in a {
+                    if let Some(other) = b.get(image_name) {
+                        images += 1;
+                        agreed += usize::from(result.compared_prediction() == other.compared_prediction());
+                    }
+                }
+                agreement.push(Agreement {
+                    a: runs[i].spec.name.clone(),
+                    b: runs[j].spec.name.clone(),
+                    images,
+                    agreed,
+                    rate: if images == 0 { 0.0 } else { agreed as f64 / images as f64 },
+                    differences: bootstrap.differences(&runs[i].scoreboard.image_scores, &runs[j].scoreboard.image_scores),
+                });
+            }
+        }
+
+        let mut image_names: Vec<&str> = answered.iter().flat_map(|results| results.keys().copied()).collect();
+        image_names.sort_unstable();
+        image_names.dedup();
+        let mut answered_by_all = 0;
+        let mut disagreements = Vec::new();
+        for image_name in image_names {
+            let answers: Vec<(&str, &ExtractionResult)> = runs.iter().zip(&answered)
+                .filter_map(|(run, results)| results.get(image_name).map(|result| (run.spec.name.as_str(), *result)))
+                .collect();
+            answered_by_all += usize::from(answers.len() == runs.len());
+            let first = answers[0].1.compared_prediction();
+            if answers.len() < 2 || answers.iter().all(|(_, result)| result.compared_prediction() == first) {
+                continue;
+            }
+            disagreements.push(Disagreement {
+                image_name: image_name.to_string(),
+                ground_truth: answers[0].1.ground_truth.clone(),
+                predictions: answers.iter()
+                    .map(|(name, result)| (name.to_string(), result.chinese_character.clone()))
+                    .collect(),
+                correct: answers.iter()
+                    .filter(|(_, result)| result.ground_truth.is_some() && result.is_match())
+                    .map(|(name, _)| name.to_string())
+                    .collect(),
+            });
+        }
+
+        Comparison { models, agreement, answered_by_all, disagreements }
+    }
+
+    /// Table of the models, their agreement and some of the disagreements
+    pub fn report(&self, examples: usize) -> String {
+        let width = self.models.iter().map(|model| model.name.chars().count()).max().unwrap_or(0).max(5);
+        let mut lines = vec![
+            format!("Comparison of {} models, {} images answered by all of them:", self.models.len(), self.answered_by_all),
+            format!("  {:width$}  {:>8}  {:>13}  {:>8}  {:>8}  {:>6}  {:>10}",
+                "Model", "Accuracy", "95% CI", "Answered", "Mean CER", "Errors", "Cost"),
+        ];
+        for model in &self.models {
+            lines.push(format!("  {:width$}  {:>7.1}%  {:>13}  {:>7.1}%  {:>8}  {:>6}  {:>10}",
+                model.name, 100.0 * model.accuracy, percent_interval(model.accuracy_interval), 100.0 * model.answered_accuracy,
+                model.mean_cer.map_or("-".to_string(), |cer| format!("{:.3}", cer)),
+                model.errors,
+                model.estimated_cost.map_or("-".to_string(), |cost| format!("{:.4}", cost))));
+        }
+        if !self.agreement.is_empty() {
+            lines.push("Agreement:".to_string());
+            for pair in &self.agreement {
+                lines.push(format!("  {} and {}: {:.1}% of {} images", pair.a, pair.b, 100.0 * pair.rate, pair.images));
+                if let Some(line) = pair.differences.describe(&pair.b, &pair.a) {
+                    lines.push(format!("    {}", line));
+                }
+            }
+        }
+        lines.push(format!("{} images answered differently{}", self.disagreements.len(),
+            if self.disagreements.is_empty() { "" } else { ", all listed in the metrics file:" }));
+        for disagreement in self.disagreements.iter().take(examples) {
+            let predictions: Vec<String> = disagreement.predictions.iter()
+                .map(|(name, prediction)| format!("{} {}", name, prediction.as_deref().unwrap_or("-")))
+                .collect();
+            lines.push(format!("  {}: truth {}; {}", disagreement.image_name,
+                disagreement.ground_truth.as_deref().unwrap_or("-"), predictions.join(", ")));
+        }
+        lines.join("\n")
+    }
+
+    /// Write the comparison under `comparison` to `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        #[derive(Serialize)]
+        struct ComparisonFile<'a> {
+            comparison: &'a Comparison,
+        }
+
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create metrics file: {}", path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &ComparisonFile { comparison: self })
+            .context("Failed to write metrics file")
+    }
+}
+
+/// Default name of the JSON report of `compare`
+pub const PAIRED_FILE_NAME: &str = "comparison.json";
+
+/// Default name of the images only one of the compared runs got right
+pub const REVIEW_FILE_NAME: &str = "comparison_review.jsonl";
+
+/// P-value under which the difference of two runs is reported as unlikely
+/// to be chance
+const SIGNIFICANCE: f64 = 0.05;
+
+/// What a paired comparison keeps of a result
+#[derive(Debug)]
+struct Answer {
+    labeled: bool,
+    correct: bool,
+    error: bool,
+    cer: Option<f64>,
+    ground_truth: Option<String>,
+    prediction: Option<String>,
+}
+
+impl From<ExtractionResult> for Answer {
+    fn from(result: ExtractionResult) -> Self {
+        Answer {
+            labeled: result.ground_truth.is_some(),
+            correct: result.ground_truth.is_some() && result.is_match(),
+            error: result.status == Status::Error,
+            cer: result.cer,
+            ground_truth: result.ground_truth,
+            prediction: result.chinese_character,
+        }
+    }
+}
+
+/// Figures of one results file in a paired comparison
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub path: PathBuf,
+    /// Configuration compared, the latest in the file unless one was given
+    pub config_id: String,
+    /// Lines of other configurations, left out
+    pub other_config: usize,
+    /// Lines that couldn't be parsed
+    pub malformed: usize,
+    /// Images of the configuration, a rerun of an image replacing the earlier result
+    pub images: usize,
+    pub labeled: usize,
+    pub exact_match: usize,
+    pub errors: usize,
+    pub accuracy: f64,
+    pub mean_cer: Option<f64>,
+    pub accuracy_interval: Option<Interval>,
+    pub mean_cer_interval: Option<Interval>,
+}
+
+/// An image one run got right and the other didn't
+#[derive(Debug, Clone, Serialize)]
+pub struct ReviewRow {
+    pub image_name: String,
+    /// `a` or `b`, whichever got the image right
+    pub right: &'static str,
+    pub ground_truth: Option<String>,
+    pub prediction_a: Option<String>,
+    pub prediction_b: Option<String>,
+    pub cer_a: Option<f64>,
+    pub cer_b: Option<f64>,
+}
+
+/// Two results files compared image by image
+#[derive(Debug, Clone, Serialize)]
+pub struct PairedComparison {
+    pub a: RunSummary,
+    pub b: RunSummary,
+    /// Images in both files
+    pub shared: usize,
+    pub only_in_a: usize,
+    pub only_in_b: usize,
+    /// Shared images with ground truth in both, which the counts below cover
+    pub paired: usize,
+    pub both_right: usize,
+    pub both_wrong: usize,
+    pub only_a_right: usize,
+    pub only_b_right: usize,
+    pub accuracy_a: f64,
+    pub accuracy_b: f64,
+    /// Two-sided exact McNemar test of the images only one run got right;
+    /// `null` when there are none
+    pub mcnemar_p: Option<f64>,
+    /// B's accuracy and mean CER minus A's on the paired images
+    pub differences: Differences,
+    #[serde(skip)]
+    pub review: Vec<ReviewRow>,
+}
+
+impl PairedComparison {
+    /// Compare the results of `a` and `b` on the images both have, each for
+    /// the given configuration or the latest one in the file. Images in only
+    /// one file are counted and left out.
+    pub fn new(a: &Path, b: &Path, config_a: Option<&str>, config_b: Option<&str>, bootstrap: &Bootstrap) -> Result<Self> {
+        let (summary_a, answers_a) = read_run(a, config_a, bootstrap)?;
+        let (summary_b, answers_b) = read_run(b, config_b, bootstrap)?;
+
+        let mut names: Vec<&String> = answers_a.keys().filter(|name| answers_b.contains_key(*name)).collect();
+        names.sort_unstable();
+        let mut comparison = PairedComparison {
+            shared: names.len(),
+            only_in_a: answers_a.len() - names.len(),
+            only_in_b: answers_b.len() - names.len(),
+            a: summary_a,
+            b: summary_b,
+            paired: 0,
+            both_right: 0,
+            both_wrong: 0,
+            only_a_right: 0,
+            only_b_right: 0,
+            accuracy_a: 0.0,
+            accuracy_b: 0.0,
+            mcnemar_p: None,
+            differences: bootstrap.differences(&image_scores(&answers_a), &image_scores(&answers_b)),
+            review: Vec::new(),
+        };
+        for name in names {
+            let (a, b) = (&answers_a[name], &answers_b[name]);
+            if !a.labeled || !b.labeled {
+                continue;
+            }
+            comparison.paired += 1;
+            let right = match (a.correct, b.correct) {
+                (true, true) => {
+                    comparison.both_right += 1;
+                    continue;
+                }
+                (false, false) => {
+                    comparison.both_wrong += 1;
+                    continue;
+                }
+                (true, false) => {
+                    comparison.only_a_right += 1;
+                    "a"
+                }
+                (false, true) => {
+                    comparison.only_b_right += 1;
+                    "b"
+                }
+            };
+            comparison.review.push(ReviewRow {
+                image_name: name.clone(),
+                right,
+                ground_truth: a.ground_truth.clone(),
+                prediction_a: a.prediction.clone(),
+                prediction_b: b.prediction.clone(),
+                cer_a: a.cer,
+                cer_b: b.cer,
+            });
+        }
+        if comparison.paired > 0 {
+            let paired = comparison.paired as f64;
+            comparison.accuracy_a = (comparison.both_right + comparison.only_a_right) as f64 / paired;
+            comparison.accuracy_b = (comparison.both_right + comparison.only_b_right) as f64 / paired;
+        }
+        comparison.mcnemar_p = mcnemar_p(comparison.only_a_right, comparison.only_b_right);
+        Ok(comparison)
+    }
+
+    /// Table of both runs and the counts of the images they share
+    pub fn report(&self) -> String {
+        let cer = |cer: Option<f64>| cer.map_or("-".to_string(), |cer| format!("{:.3}", cer));
+        let mut lines = vec![
+            format!("Comparing A: {} (configuration {})", self.a.path.display(), self.a.config_id),
+            format!("     with B: {} (configuration {})", self.b.path.display(), self.b.config_id),
+            format!("  {:12}  {:>8}  {:>8}", "", "A", "B"),
+            format!("  {:12}  {:>8}  {:>8}", "Images", self.a.images, self.b.images),
+            format!("  {:12}  {:>8}  {:>8}", "Labeled", self.a.labeled, self.b.labeled),
+            format!("  {:12}  {:>7.1}%  {:>7.1}%", "Accuracy", 100.0 * self.a.accuracy, 100.0 * self.b.accuracy),
+            format!("  {:12}  {:>8}  {:>8}", "Mean CER", cer(self.a.mean_cer), cer(self.b.mean_cer)),
+            format!("  {:12}  {:>8}  {:>8}", "Errors", self.a.errors, self.b.errors),
+            format!("  Accuracy 95% CI: A {}, B {}", percent_interval(self.a.accuracy_interval), percent_interval(self.b.accuracy_interval)),
+            format!("On the {} labeled images in both: both right {}, both wrong {}, only A right {}, only B right {}",
+                self.paired, self.both_right, self.both_wrong, self.only_a_right, self.only_b_right),
+            format!("  Accuracy on them: A {:.1}%, B {:.1}%", 100.0 * self.accuracy_a, 100.0 * self.accuracy_b),
+        ];
+        lines.push(match self.mcnemar_p {
+            Some(p) if p < SIGNIFICANCE => format!("  McNemar exact test p = {:.4}: the difference is unlikely to be chance", p),
+            Some(p) => format!("  McNemar exact test p = {:.4}: the difference could be chance", p),
+            None => "  The runs got the same images right".to_string(),
+        });
+        lines.extend(self.differences.describe("B", "A").map(|line| format!("  {}", line)));
+        lines.join("\n")
+    }
+
+    /// Write the comparison as JSON to `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create comparison file: {}", path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .context("Failed to write comparison file")
+    }
+
+    /// Write the images only one run got right to `path`, one JSON line each
+    pub fn save_review(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create review file: {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        for row in &self.review {
+            serde_json::to_writer(&mut writer, row).context("Failed to write review file")?;
+            writeln!(writer).context("Failed to write review file")?;
+        }
+        writer.flush().context("Failed to write review file")
+    }
+}
+
+/// Summary and answers of the results of one configuration in `path`: the
+/// given one, or the latest. The file is read twice, line by line, once for
+/// its configurations and once for the answers of the one compared.
+fn read_run(path: &Path, config_id: Option<&str>, bootstrap: &Bootstrap) -> Result<(RunSummary, HashMap<String, Answer>)> {
+    let mut configs: HashMap<String, usize> = HashMap::new();
+    let mut latest = None;
+    results::scan(path, |result| {
+        *configs.entry(result.config_id.clone()).or_default() += 1;
+        latest = Some(result.config_id);
+    })?;
+    let config_id = match config_id {
+        Some(id) if configs.contains_key(id) => id.to_string(),
+        Some(id) => {
+            let mut found: Vec<&str> = configs.keys().map(String::as_str).collect();
+            found.sort_unstable();
+            anyhow::bail!("{} has no results of configuration {}; it has {}", path.display(), id,
+                if found.is_empty() { "none".to_string() } else { found.join(", ") });
+        }
+        None => latest.with_context(|| format!("{} has no results", path.display()))?,
+    };
+
+    let mut answers = HashMap::new();
+    let malformed = results::scan(path, |result| {
+        if result.config_id == config_id {
+            answers.insert(result.image_name.clone(), Answer::from(result));
+        }
+    })?;
+    let labeled = answers.values().filter(|answer| answer.labeled).count();
+    let exact_match = answers.values().filter(|answer| answer.correct).count();
+    let cers: Vec<f64> = answers.values().filter_map(|answer| answer.cer).collect();
+    let confidence = bootstrap.confidence(&image_scores(&answers));
+    let summary = RunSummary {
+        path: path.to_path_buf(),
+        other_config: configs.iter().filter(|(id, _)| **id != config_id).map(|(_, count)| count).sum(),
+        config_id,
+        malformed,
+        images: answers.len(),
+        labeled,
+        exact_match,
+        errors: answers.values().filter(|answer| answer.error).count(),
+        accuracy: if labeled == 0 { 0.0 } else { exact_match as f64 / labeled as f64 },
+        mean_cer: (!cers.is_empty()).then(|| cers.iter().sum::<f64>() / cers.len() as f64),
+        accuracy_interval: confidence.as_ref().and_then(|confidence| confidence.accuracy),
+        mean_cer_interval: confidence.as_ref().and_then(|confidence| confidence.mean_cer),
+    };
+    Ok((summary, answers))
+}
+
+/// Scores of the labeled images among `answers`
+fn image_scores(answers: &HashMap<String, Answer>) -> BTreeMap<String, ImageScore> {
+    answers.iter()
+        .filter(|(_, answer)| answer.labeled)
+        .map(|(image_name, answer)| (image_name.clone(), ImageScore { exact_match: answer.correct, cer: answer.cer }))
+        .collect()
+}
+
+/// `91.2-95.0%`, or `-` without an interval
+fn percent_interval(interval: Option<Interval>) -> String {
+    interval.map_or("-".to_string(), |interval| format!("{:.1}-{:.1}%", 100.0 * interval.low, 100.0 * interval.high))
+}
+
+/// Two-sided exact McNemar test: the chance, were both runs equally good, of
+/// the images only one got right splitting at least this unevenly
+fn mcnemar_p(only_a: usize, only_b: usize) -> Option<f64> {
+    let n = only_a + only_b;
+    if n == 0 {
+        return None;
+    }
+    // P(X <= k) for X ~ Binomial(n, 1/2), each term from its logarithm so
+    // large counts don't underflow
+    let ln_half_n = -(n as f64) * std::f64::consts::LN_2;
+    let mut ln_choose = 0.0;
+    let mut tail = 0.0;
+    for i in 0..=only_a.min(only_b) {
+        if i > 0 {
+            ln_choose += ((n - i + 1) as f64).ln() - (i as f64).ln();
+        }
+        tail += (ln_choose + ln_half_n).exp();
+    }
+    Some((2.0 * tail).min(1.0))
+}
@@ -0,0 +1,147 @@
+use super::format::ImageKind;
+use super::preprocess::Preprocess;
+use super::protocol::{Field, ImageEncoding, Protocol};
+use super::resize::Resize;
+use super::results;
+
+/// Settings that change what a run's results mean. Results of another
+/// endpoint, model, prompt or image handling must not count as done, so
+/// each of them is part of the configuration id `--resume` matches.
+#[derive(Debug, Clone, Copy)]
+pub struct RunConfig<'a> {
+    pub base_url: &'a str,
+    /// Model of the first endpoint, `local-api` without `--model`
+    pub model: &'a str,
+    pub protocol: Protocol,
+    /// Models of all the endpoints, when they serve different ones
+    pub mixed_models: Option<&'a [String]>,
+    pub prompt_hash: Option<&'a str>,
+    /// Generation parameters as JSON, for the protocols that send them
+    pub generation: Option<&'a str>,
+    pub fields: &'a [Field],
+    pub few_shot_hash: Option<&'a str>,
+    /// Hash of the detection prompt of a two-stage extraction
+    pub two_stage_hash: Option<&'a str>,
+    pub votes: usize,
+    pub batch_size: usize,
+    /// As [`image_settings`] describes them
+    pub image_settings: &'a str,
+}
+
+impl RunConfig<'_> {
+    /// The configuration id. A setting left at its default adds nothing, so
+    /// results from before the setting existed still match.
+    pub fn id(&self) -> String {
+        let mut config = vec![self.base_url.to_string(), self.model.to_string()];
+        if self.protocol != Protocol::Invoke {
+            config.push(self.protocol.name().to_string());
+        }
+        if let Some(models) = self.mixed_models {
+            config.push(format!("models {}", models.join(",")));
+        }
+        config.extend(self.prompt_hash.map(String::from));
+        config.extend(self.generation.map(String::from));
+        if self.fields.len() < Field::ALL.len() {
+            config.push(self.fields.iter().map(|field| field.name()).collect::<Vec<_>>().join(","));
+        }
+        config.extend(self.few_shot_hash.map(|hash| format!("few_shot {}", hash)));
+        config.extend(self.two_stage_hash.map(|hash| format!("two_stage {}", hash)));
+        // An elected answer isn't that of a single sample, and images
+        // answered together are asked differently
+        if self.votes > 1 {
+            config.push(format!("votes {}", self.votes));
+        }
+        if self.batch_size > 1 {
+            config.push(format!("batch {}", self.batch_size));
+        }
+        if !self.image_settings.is_empty() {
+            config.push(self.image_settings.to_string());
+        }
+        results::config_id(&config.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+}
+
+/// How the images are changed before they are sent, such as
+/// `max_dimension 1024 quality 85 preprocess grayscale`; empty when they go
+/// as they are on disk. Part of the configuration id and of the cache keys.
+pub fn image_settings(
+    resize: Option<&Resize>,
+    encoding: Option<ImageEncoding>,
+    accepted: Option<&[ImageKind]>,
+    preprocess: Preprocess,
+) -> String {
+    let mut settings = Vec::new();
+    if let Some(resize) = resize {
+        settings.push(format!("max_dimension {} quality {}", resize.max_dimension, resize.jpeg_quality));
+    }
+    if let Some(encoding) = encoding {
+        settings.push(format!("encoding {}", encoding.name()));
+    }
+    if let Some(formats) = accepted {
+        settings.push(format!("accept {}", formats.iter().map(|kind| kind.name()).collect::<Vec<_>>().join(",")));
+    }
+    if preprocess != Preprocess::None {
+        settings.push(format!("preprocess {}", preprocess.name()));
+    }
+    settings.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invoke() -> RunConfig<'static> {
+        RunConfig {
+            base_url: "http://localhost:8080",
+            model: "local-api",
+            protocol: Protocol::Invoke,
+            mixed_models: None,
+            prompt_hash: None,
+            generation: None,
+            fields: &Field::ALL,
+            few_shot_hash: None,
+            two_stage_hash: None,
+            votes: 1,
+            batch_size: 1,
+            image_settings: "",
+        }
+    }
+
+    #[test]
+    fn defaults_add_nothing_to_the_id() {
+        assert_eq!(invoke().id(), results::config_id(&["http://localhost:8080", "local-api"]));
+    }
+
+    #[test]
+    fn every_setting_is_part_of_the_id() {
+        let models = ["qwen".to_string(), "llava".to_string()];
+        let config = RunConfig {
+            protocol: Protocol::Openai,
+            mixed_models: Some(&models),
+            prompt_hash: Some("abc"),
+            generation: Some(r#"{"temperature":0.0}"#),
+            fields: &[Field::Chinese],
+            few_shot_hash: Some("def"),
+            two_stage_hash: Some("123"),
+            votes: 3,
+            batch_size: 4,
+            image_settings: "preprocess grayscale",
+            ..invoke()
+        };
+        let parts = [
+            "http://localhost:8080", "local-api", "openai", "models qwen,llava", "abc", r#"{"temperature":0.0}"#, "chinese",
+            "few_shot def", "two_stage 123", "votes 3", "batch 4", "preprocess grayscale",
+        ];
+        assert_eq!(config.id(), results::config_id(&parts));
+    }
+
+    #[test]
+    fn image_settings_name_each_change() {
+        assert_eq!(image_settings(None, None, None, Preprocess::None), "");
+        let resize = Resize { max_dimension: 1024, jpeg_quality: 85 };
+        assert_eq!(
+            image_settings(Some(&resize), Some(ImageEncoding::Base64), Some(&[ImageKind::Png, ImageKind::Jpeg]), Preprocess::Binarize),
+            "max_dimension 1024 quality 85 encoding base64 accept png,jpeg preprocess binarize"
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use serde::Serialize;
+use std::future::Future;
+use uuid::Uuid;
+
+/// Header carrying the id of a request, unless `--no-request-id-header`
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Index of every request of a run, written next to the results file
+pub const REQUEST_INDEX_FILE_NAME: &str = "request_index.jsonl";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// A fresh id for one request attempt
+pub fn new_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Run `request` as the attempt with id `request_id`, which it reads back
+/// with `current`
+pub async fn scope<F: Future>(request_id: String, request: F) -> F::Output {
+    REQUEST_ID.scope(request_id, request).await
+}
+
+/// Id of the attempt being made, outside of any attempt an empty string
+pub fn current() -> String {
+    REQUEST_ID.try_with(String::clone).unwrap_or_default()
+}
+
+/// One line of the request index: a request for one image, so a log line,
+/// result or saved response can be traced to the others by `request_id`.
+/// An image sent in a batch gets a line of its own with the batch's id.
+#[derive(Debug, Serialize)]
+pub struct RequestRecord<'a> {
+    pub request_id: &'a str,
+    pub image_name: &'a str,
+    /// `extract`, `detect` or `batch`
+    pub stage: &'a str,
+    /// Request number for this image and stage, from 1
+    pub attempt: u32,
+    /// `ok`, or the failure class of the request, see `retry::failure_class`
+    pub outcome: &'a str,
+    /// Status of an error response; `null` for a 2xx response or no response
+    pub http_status: Option<u16>,
+    pub endpoint: &'a str,
+    /// Response written by `--save-responses`
+    pub response_file: Option<&'a std::path::Path>,
+    pub completed_at: String,
+}
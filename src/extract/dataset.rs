@@ -0,0 +1,304 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::images::manifest::{self, Manifest, ManifestEntry};
+use crate::images::sidecar::Sidecar;
+
+use super::drift::MarkRecord;
+use super::raw_data::{RawDataset, RawImage};
+use super::results::{self, Mismatch, Provenance};
+use super::source::ImageSource;
+
+/// Image names listed in the log when some are missing or unmatched
+pub const MISSING_EXAMPLES: usize = 5;
+
+/// One image of the dataset, with the mark's text as ground truth
+#[derive(Debug, Clone, Deserialize)]
+pub struct DatasetEntry {
+    #[serde(rename = "imageName")]
+    pub image_name: String,
+    #[serde(rename = "chineseCharacter")]
+    pub chinese_character: Option<String>,
+    #[serde(rename = "wordsInMark", default)]
+    pub words_in_mark: Option<String>,
+    #[serde(rename = "descrOfDevice", default)]
+    pub descr_of_device: Option<String>,
+    #[serde(rename = "applicationNum", default)]
+    pub application_num: Option<String>,
+    #[serde(rename = "lodgementDate", default)]
+    pub lodgement_date: Option<String>,
+    #[serde(rename = "fileId", default)]
+    pub file_id: Option<String>,
+    /// Full image path, set when loading from an image manifest
+    #[serde(skip)]
+    pub image_path: Option<PathBuf>,
+}
+
+impl From<Mismatch> for DatasetEntry {
+    fn from(mismatch: Mismatch) -> Self {
+        DatasetEntry {
+            image_name: mismatch.image_name,
+            chinese_character: mismatch.ground_truth,
+            words_in_mark: None,
+            descr_of_device: None,
+            // A derived number is derived again from the name
+            application_num: mismatch.provenance.application_num.filter(|_| !mismatch.provenance.application_num_derived),
+            lodgement_date: mismatch.provenance.lodgement_date,
+            file_id: mismatch.provenance.file_id,
+            image_path: Some(mismatch.image_path),
+        }
+    }
+}
+
+impl From<ManifestEntry> for DatasetEntry {
+    fn from(entry: ManifestEntry) -> Self {
+        DatasetEntry {
+            image_name: entry.local_path.file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            image_path: Some(entry.image_path().to_path_buf()),
+            chinese_character: entry.chinese_character,
+            words_in_mark: None,
+            descr_of_device: None,
+            application_num: Some(entry.application_num),
+            lodgement_date: None,
+            file_id: entry.file_id,
+        }
+    }
+}
+
+impl From<RawImage> for DatasetEntry {
+    fn from(image: RawImage) -> Self {
+        DatasetEntry {
+            image_name: image.image_name,
+            chinese_character: image.mark.chinese_character,
+            words_in_mark: image.mark.words_in_mark,
+            descr_of_device: image.mark.descr_of_device,
+            application_num: Some(image.application_num),
+            lodgement_date: Some(image.lodgement_date),
+            file_id: image.file_id,
+            image_path: Some(image.image_path),
+        }
+    }
+}
+
+impl DatasetEntry {
+    /// Where the entry's document comes from, what the dataset lacks taken
+    /// from the image's sidecar and the application number, failing both,
+    /// from the image name
+    pub fn provenance(&self, sidecar: Option<&Sidecar>) -> Provenance {
+        Provenance {
+            application_num: self.application_num.clone().or_else(|| sidecar.map(|s| s.application_num.clone())),
+            application_num_derived: false,
+            lodgement_date: self.lodgement_date.clone().or_else(|| sidecar.and_then(|s| s.lodgement_date.clone())),
+            file_id: self.file_id.clone().or_else(|| sidecar.and_then(|s| s.file_id.clone())),
+        }
+        .or_derived(&self.image_name)
+    }
+}
+
+/// Load the dataset, accepting either cleaned_data.json or an images_manifest.json
+/// written by the downloader
+pub fn load(path: &Path) -> Result<Vec<DatasetEntry>> {
+    let data_file = fs::read_to_string(path)
+        .context("Failed to read dataset file")?;
+
+    match serde_json::from_str::<Vec<DatasetEntry>>(&data_file) {
+        Ok(data) => Ok(data),
+        Err(e) => match serde_json::from_str::<Vec<ManifestEntry>>(&data_file) {
+            Ok(manifest) => Ok(manifest.into_iter()
+                .filter(|entry| entry.skipped.is_none())
+                .map(DatasetEntry::from)
+                .collect()),
+            Err(_) => Err(e).context("Failed to parse dataset JSON"),
+        },
+    }
+}
+
+/// What a run reads its images from
+#[derive(Debug, Clone, Copy)]
+pub enum Input<'a> {
+    /// The mismatches file of an earlier run, `--mismatches-only`
+    Mismatches(&'a Path),
+    /// The downloader's data file, with the images in `images_dir`
+    RawData { path: &'a Path, images_dir: &'a Path },
+    /// cleaned_data.json or an image manifest
+    Dataset(&'a Path),
+}
+
+/// The entries of an input. Raw data images also remember their document
+/// for `--enriched-output` and their trademark record.
+#[derive(Debug, Default)]
+pub struct Loaded {
+    pub entries: Vec<DatasetEntry>,
+    /// Application number and document id, by image name
+    pub documents: HashMap<String, (String, String)>,
+    pub records: HashMap<String, MarkRecord>,
+}
+
+impl Input<'_> {
+    /// Load the entries of the input. The raw data images that aren't on
+    /// disk are left out, and listed in `missing_path` when there is one.
+    pub fn load(self, missing_path: Option<&Path>) -> Result<Loaded> {
+        match self {
+            Input::Mismatches(path) => {
+                info!("Loading mismatches from {}", path.display());
+                let entries = results::load_mismatches(path)?.into_iter().map(DatasetEntry::from).collect();
+                Ok(Loaded { entries, ..Default::default() })
+            }
+            Input::RawData { path, images_dir } => {
+                if !images_dir.is_dir() {
+                    anyhow::bail!("Images directory not found: {}", images_dir.display());
+                }
+                info!("Loading raw data from {} with the images in {}", path.display(), images_dir.display());
+                let manifest = Manifest::load(&images_dir.join(manifest::MANIFEST_FILE_NAME))?;
+                let raw = RawDataset::load(path, images_dir, &manifest)?;
+                info!("Found the images of {} of {} documents", raw.images.len(), raw.images.len() + raw.missing.len());
+                match missing_path {
+                    _ if raw.missing.is_empty() => {}
+                    Some(missing_path) => {
+                        raw.save_missing(missing_path)?;
+                        let examples: Vec<String> = raw.missing.iter().take(MISSING_EXAMPLES).map(|p| p.display().to_string()).collect();
+                        info!("{} images are not on disk and were left out, e.g. {}; all listed in {}",
+                            raw.missing.len(), examples.join(", "), missing_path.display());
+                    }
+                    None => info!("{} images are not on disk and would be left out", raw.missing.len()),
+                }
+                let documents = raw.images.iter()
+                    .map(|image| (image.image_name.clone(), (image.application_num.clone(), image.document_id.clone())))
+                    .collect();
+                let records = raw.images.iter()
+                    .map(|image| (image.image_name.clone(), MarkRecord {
+                        application_num: image.application_num.clone(),
+                        lodgement_date: Some(image.lodgement_date.clone()),
+                    }))
+                    .collect();
+                let entries = raw.images.into_iter().map(DatasetEntry::from).collect();
+                Ok(Loaded { entries, documents, records })
+            }
+            Input::Dataset(path) => {
+                info!("Loading dataset from {}", path.display());
+                Ok(Loaded { entries: load(path)?, ..Default::default() })
+            }
+        }
+    }
+}
+
+/// Leave out the entries `excluded` names, returning how many there were
+pub fn exclude(entries: &mut Vec<DatasetEntry>, excluded: impl Fn(&str) -> bool) -> usize {
+    let before = entries.len();
+    entries.retain(|entry| !excluded(&entry.image_name));
+    before - entries.len()
+}
+
+/// Add to `records` the trademark record of each image of `names` it
+/// lacks: from the image's sidecar, else from the image manifest or the
+/// downloader's naming of the image, which give no lodgement date
+pub fn find_records<'a>(
+    records: &mut HashMap<String, MarkRecord>,
+    names: impl IntoIterator<Item = &'a str>,
+    data: &[DatasetEntry],
+    source: &ImageSource,
+    images_dir: &Path,
+) -> Result<()> {
+    let names: Vec<&str> = names.into_iter().filter(|name| !records.contains_key(*name)).collect();
+    if names.is_empty() {
+        return Ok(());
+    }
+    let paths = image_paths(data, source, images_dir);
+    let manifest = Manifest::load(&images_dir.join(manifest::MANIFEST_FILE_NAME))?;
+    let applications: HashMap<String, &str> = manifest.entries()
+        .filter_map(|entry| Some((entry.local_path.file_name()?.to_string_lossy().into_owned(), entry.application_num.as_str())))
+        .collect();
+    for name in names {
+        let record = paths.get(name).and_then(|path| Sidecar::load(path)).map(|sidecar| MarkRecord::from_sidecar(&sidecar))
+            .or_else(|| applications.get(name).map(|application_num| MarkRecord {
+                application_num: application_num.to_string(),
+                lodgement_date: None,
+            }))
+            .or_else(|| MarkRecord::from_image_name(name));
+        if let Some(record) = record {
+            records.insert(name.to_string(), record);
+        }
+    }
+    Ok(())
+}
+
+/// Where each image of `data` is read from, by image name
+pub fn image_paths(data: &[DatasetEntry], source: &ImageSource, images_dir: &Path) -> HashMap<String, PathBuf> {
+    data.iter()
+        .map(|entry| (entry.image_name.clone(), entry.image_path.clone()
+            .unwrap_or_else(|| source.image_path(images_dir, &entry.image_name))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tm-query-dataset-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_dataset_is_cleaned_data_or_an_image_manifest() {
+        let dir = temp_dir();
+        let cleaned = dir.join("cleaned_data.json");
+        fs::write(&cleaned, r#"[{"imageName": "a.jpg", "chineseCharacter": "天"}, {"imageName": "b.jpg", "chineseCharacter": null}]"#).unwrap();
+        let entries = load(&cleaned).unwrap();
+        assert_eq!(entries.iter().map(|e| e.image_name.as_str()).collect::<Vec<_>>(), ["a.jpg", "b.jpg"]);
+        assert_eq!(entries[0].chinese_character.as_deref(), Some("天"));
+        assert!(entries[0].image_path.is_none());
+
+        let entry = |name: &str, skipped: Option<&str>| serde_json::json!({
+            "applicationNum": "40201900001X", "fileId": null, "docType": null, "fileName": name,
+            "localPath": dir.join(format!("40201900001X_{}", name)), "size": 1, "sha256": "", "downloadedAt": "",
+            "chineseCharacter": "福", "skipped": skipped,
+        });
+        let manifest = dir.join("images_manifest.json");
+        fs::write(&manifest, serde_json::to_string(&[entry("c.jpg", None), entry("d.jpg", Some("too large"))]).unwrap()).unwrap();
+        let entries = load(&manifest).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].image_name, "40201900001X_c.jpg");
+        assert_eq!(entries[0].image_path.as_deref(), Some(dir.join("40201900001X_c.jpg").as_path()));
+        assert_eq!(entries[0].application_num.as_deref(), Some("40201900001X"));
+
+        fs::write(&cleaned, "{}").unwrap();
+        assert!(load(&cleaned).is_err());
+    }
+
+    #[test]
+    fn raw_data_needs_its_images_directory() {
+        let dir = temp_dir();
+        let input = Input::RawData { path: &dir.join("trademark_data.json"), images_dir: &dir.join("images") };
+        let error = input.load(None).unwrap_err().to_string();
+        assert!(error.starts_with("Images directory not found"), "{}", error);
+    }
+
+    #[test]
+    fn excluded_entries_are_counted() {
+        let mut entries: Vec<DatasetEntry> = serde_json::from_str(
+            r#"[{"imageName": "a.jpg", "chineseCharacter": null}, {"imageName": "b.jpg", "chineseCharacter": null}, {"imageName": "c.jpg", "chineseCharacter": null}]"#,
+        ).unwrap();
+        assert_eq!(exclude(&mut entries, |name| name != "b.jpg"), 2);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(exclude(&mut entries, |_| false), 0);
+    }
+
+    #[test]
+    fn images_are_found_by_path_or_in_the_images_directory() {
+        let mut entries: Vec<DatasetEntry> = serde_json::from_str(
+            r#"[{"imageName": "a.jpg", "chineseCharacter": null}, {"imageName": "b.jpg", "chineseCharacter": null}]"#,
+        ).unwrap();
+        entries[1].image_path = Some(PathBuf::from("/elsewhere/b.jpg"));
+        let paths = image_paths(&entries, &ImageSource::Files, Path::new("images"));
+        assert_eq!(paths["a.jpg"], Path::new("images/a.jpg"));
+        assert_eq!(paths["b.jpg"], Path::new("/elsewhere/b.jpg"));
+    }
+}
@@ -0,0 +1,44 @@
+use anyhow::Context;
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use super::source::ImageSource;
+
+/// Group images with byte-identical contents. Each group lists indices into
+/// `paths` in order, the first being the representative sent to the API;
+/// groups are ordered by their representative. Files are hashed on blocking
+/// threads, `workers` at a time, and a file that can't be read is a group of
+/// its own.
+pub async fn group_duplicates(source: &ImageSource, paths: Vec<PathBuf>, workers: usize) -> Vec<Vec<usize>> {
+    let mut hashes: Vec<(usize, Option<String>)> = stream::iter(paths.into_iter().enumerate())
+        .map(|(index, path)| async move {
+            let source = source.clone();
+            let hash = tokio::task::spawn_blocking(move || source.hash(&path))
+                .await
+                .context("Hashing task panicked")
+                .and_then(|r| r)
+                .ok();
+            (index, hash)
+        })
+        .buffer_unordered(workers.max(1))
+        .collect()
+        .await;
+    hashes.sort_by_key(|(index, _)| *index);
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut by_hash: HashMap<String, usize> = HashMap::new();
+    for (index, hash) in hashes {
+        match hash {
+            Some(hash) => match by_hash.get(&hash) {
+                Some(&group) => groups[group].push(index),
+                None => {
+                    by_hash.insert(hash, groups.len());
+                    groups.push(vec![index]);
+                }
+            },
+            None => groups.push(vec![index]),
+        }
+    }
+    groups
+}
@@ -0,0 +1,109 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::prompt::Prompt;
+use super::protocol::Backend;
+
+/// Key of the JSON answer to the detection question
+const ANSWER_KEY: &str = "containsCharacters";
+
+/// Text sent with the image in the detection request
+const DETECTION_USER_PROMPT: &str = "Check this trademark.";
+
+/// Longest detection reply; the answer is a few tokens of JSON
+const DETECTION_MAX_TOKENS: u32 = 16;
+
+/// System prompt of the first stage of `--two-stage`, asking whether the
+/// mark has any characters of `language`
+pub fn prompt(language: &str) -> Prompt {
+    Prompt::new(format!(
+        "You check trademark images for {language} characters. Reply with a single JSON object, \
+         {{\"{ANSWER_KEY}\": true}} if the mark contains any {language} characters and \
+         {{\"{ANSWER_KEY}\": false}} if it doesn't."
+    ))
+}
+
+/// Backend for the detection stage: `extraction`'s endpoint and settings
+/// with the detection prompt, no few-shot examples and a short reply
+pub fn detector(extraction: &Backend, prompt: &Prompt) -> Backend {
+    let mut backend = extraction.clone();
+    backend.prompt = prompt.text.clone();
+    backend.user_prompt = DETECTION_USER_PROMPT;
+    // The examples answer the extraction question, not this one
+    backend.few_shot = None;
+    backend.generation.max_tokens = Some(backend.generation.max_tokens.map_or(DETECTION_MAX_TOKENS, |t| t.min(DETECTION_MAX_TOKENS)));
+    backend
+}
+
+/// Read the yes/no answer from a detection reply: the JSON object asked
+/// for, a bare JSON boolean, or a reply starting with yes, no, true or false
+pub fn parse_answer(text: &str) -> Result<bool> {
+    let answer = match serde_json::from_str::<Value>(text.trim()) {
+        Ok(Value::Object(object)) => object.get(ANSWER_KEY).and_then(word_answer),
+        Ok(value) => word_answer(&value),
+        Err(_) => None,
+    };
+    answer.or_else(|| starts_with_answer(text))
+        .with_context(|| format!("Detection reply is not a yes or no: {:?}", text.chars().take(50).collect::<String>()))
+}
+
+fn word_answer(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(answer) => Some(*answer),
+        Value::String(text) => starts_with_answer(text),
+        _ => None,
+    }
+}
+
+fn starts_with_answer(text: &str) -> Option<bool> {
+    let text = text.trim_start().to_lowercase();
+    if text.starts_with("yes") || text.starts_with("true") {
+        Some(true)
+    } else if text.starts_with("no") || text.starts_with("false") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Detection answers against whether the ground truth has characters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DetectionCounts {
+    pub true_positive: usize,
+    pub false_positive: usize,
+    pub false_negative: usize,
+    pub true_negative: usize,
+}
+
+impl DetectionCounts {
+    pub fn record(&mut self, detected: bool, present: bool) {
+        match (detected, present) {
+            (true, true) => self.true_positive += 1,
+            (true, false) => self.false_positive += 1,
+            (false, true) => self.false_negative += 1,
+            (false, false) => self.true_negative += 1,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.true_positive + self.false_positive + self.false_negative + self.true_negative
+    }
+
+    /// Images that stopped after detection
+    pub fn negatives(&self) -> usize {
+        self.false_negative + self.true_negative
+    }
+
+    /// Share of the detected images whose ground truth has characters
+    pub fn precision(&self) -> Option<f64> {
+        let detected = self.true_positive + self.false_positive;
+        (detected > 0).then(|| self.true_positive as f64 / detected as f64)
+    }
+
+    /// Share of the images with characters in the ground truth that were detected
+    pub fn recall(&self) -> Option<f64> {
+        let present = self.true_positive + self.false_negative;
+        (present > 0).then(|| self.true_positive as f64 / present as f64)
+    }
+}
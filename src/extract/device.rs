@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Words that carry little meaning in a device description, ignored with
+/// `--device-stopwords`
+const STOPWORDS: &[&str] = &[
+    "a", "an", "and", "as", "at", "by", "device", "for", "from", "in", "is", "of", "on", "or", "the", "to", "with",
+];
+
+/// Scores how close a predicted device description is to the ground truth,
+/// from 0 (nothing in common) to 1 (the same)
+pub trait DescriptionScorer: Send + Sync {
+    /// Name recorded in the metrics file
+    fn name(&self) -> &'static str;
+
+    fn score(&self, truth: &str, predicted: &str) -> f64;
+}
+
+/// F1 of the words the two descriptions share, counting repeated words as
+/// often as they appear in both. Case and punctuation are ignored.
+pub struct TokenOverlap {
+    /// Leave out `STOPWORDS`, so "device of a lion" and "lion" agree
+    pub stopwords: bool,
+}
+
+impl TokenOverlap {
+    fn tokens(&self, text: &str) -> Vec<String> {
+        text.to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty() && (!self.stopwords || !STOPWORDS.contains(word)))
+            .map(String::from)
+            .collect()
+    }
+}
+
+impl DescriptionScorer for TokenOverlap {
+    fn name(&self) -> &'static str {
+        if self.stopwords { "token_f1_without_stopwords" } else { "token_f1" }
+    }
+
+    fn score(&self, truth: &str, predicted: &str) -> f64 {
+        let truth = self.tokens(truth);
+        let predicted = self.tokens(predicted);
+        if truth.is_empty() || predicted.is_empty() {
+            return if truth.is_empty() && predicted.is_empty() { 1.0 } else { 0.0 };
+        }
+
+        let mut remaining: HashMap<&str, usize> = HashMap::new();
+        for word in &truth {
+            *remaining.entry(word).or_default() += 1;
+        }
+        let mut shared = 0;
+        for word in &predicted {
+            if let Some(count) = remaining.get_mut(word.as_str())
+                && *count > 0
+            {
+                *count -= 1;
+                shared += 1;
+            }
+        }
+        2.0 * shared as f64 / (truth.len() + predicted.len()) as f64
+    }
+}
+
+/// How well a predicted description agrees with the ground truth
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Grade {
+    Good,
+    Partial,
+    Poor,
+}
+
+/// Similarities at which a description counts as good or partial
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Thresholds {
+    pub good: f64,
+    pub partial: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds { good: 0.7, partial: 0.3 }
+    }
+}
+
+impl Thresholds {
+    pub fn grade(&self, similarity: f64) -> Grade {
+        if similarity >= self.good {
+            Grade::Good
+        } else if similarity >= self.partial {
+            Grade::Partial
+        } else {
+            Grade::Poor
+        }
+    }
+}
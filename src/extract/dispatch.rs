@@ -0,0 +1,235 @@
+use anyhow::{Context, Result};
+use futures::future::join_all;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task;
+
+use super::interrupt::{self, Interrupt};
+use super::pipeline::PipelineStats;
+
+/// Sizes of the two stages of a run
+#[derive(Debug, Clone)]
+pub struct Stages {
+    /// Groups prepared and sent together, `--batch-size`
+    pub batch_size: usize,
+    /// Groups or batches read and encoded at once, `--preprocess-workers`
+    pub preprocess_workers: usize,
+    /// Prepared groups or batches waiting for a request slot, `--prefetch`
+    pub prefetch: usize,
+    /// Request slots, `--concurrency` of them or as `--auto-tune` sizes them
+    pub slots: Arc<Semaphore>,
+}
+
+/// How the dispatch of a run ended
+#[derive(Debug, Clone, Copy)]
+pub struct Dispatched {
+    /// From the start of the dispatch until the last item in flight
+    /// finished or was abandoned
+    pub elapsed: Duration,
+    /// Every item sent finished, rather than being abandoned at the end of
+    /// the grace period after Ctrl+C
+    pub completed: bool,
+}
+
+/// Run `groups` through the two stages of a run. Each group, or
+/// `batch_size` groups together, is prepared by one of the preprocessing
+/// workers into a queue of `prefetch`, and sent as soon as one of the slots
+/// frees up, until everything is sent, `stopped` says so or Ctrl+C stops
+/// the dispatch. The queue bound holds the preprocessing back when the
+/// requests fall behind.
+///
+/// Each item holds its slot until `send` is done with it. Once
+/// interrupted, the items in flight get `interrupt::GRACE_PERIOD` to
+/// finish and are abandoned after it.
+pub async fn run<G, T, P, PFut, S, SFut>(
+    groups: Vec<G>,
+    stages: &Stages,
+    interrupt: &Interrupt,
+    stats: &Arc<PipelineStats>,
+    stopped: impl Fn() -> bool,
+    prepare: P,
+    send: S,
+) -> Result<Dispatched>
+where
+    G: Send + 'static,
+    T: Send + 'static,
+    P: Fn(Vec<G>) -> PFut + Send + Sync + 'static,
+    PFut: Future<Output = T> + Send,
+    S: Fn(T) -> SFut + Send + Sync + 'static,
+    SFut: Future<Output = ()> + Send,
+{
+    let (prepare, send) = (Arc::new(prepare), Arc::new(send));
+    let preprocess_slots = Arc::new(Semaphore::new(stages.preprocess_workers));
+    let (ready_tx, mut ready_rx) = mpsc::channel::<T>(stages.prefetch);
+    let mut groups = groups.into_iter().peekable();
+
+    let preprocessing = async {
+        while groups.peek().is_some() {
+            let batch: Vec<G> = groups.by_ref().take(stages.batch_size).collect();
+            let permit = tokio::select! {
+                permit = Arc::clone(&preprocess_slots).acquire_owned() => permit.context("Preprocessing slots closed")?,
+                _ = interrupt.wait() => break,
+            };
+            if stopped() || interrupt.is_set() || ready_tx.is_closed() {
+                break;
+            }
+            let (prepare, stats, ready_tx) = (Arc::clone(&prepare), Arc::clone(stats), ready_tx.clone());
+            // The worker keeps its slot until the queue takes its item, so
+            // no more are prepared than the queue and the workers hold
+            task::spawn(async move {
+                let started = Instant::now();
+                let ready = prepare(batch).await;
+                let busy = started.elapsed();
+                let queued = Instant::now();
+                // Fails only once the dispatch has stopped
+                let _ = ready_tx.send(ready).await;
+                stats.record_prepared(busy, queued.elapsed());
+                drop(permit);
+            });
+        }
+        drop(ready_tx);
+        anyhow::Ok(())
+    };
+
+    let mut tasks = Vec::new();
+    let dispatch = async {
+        loop {
+            let permit = tokio::select! {
+                permit = Arc::clone(&stages.slots).acquire_owned() => permit.context("Extraction slots closed")?,
+                _ = interrupt.wait() => break,
+            };
+            let waiting = Instant::now();
+            let ready = tokio::select! {
+                ready = ready_rx.recv() => ready,
+                _ = interrupt.wait() => break,
+            };
+            let Some(ready) = ready else {
+                break;
+            };
+            stats.record_received(waiting.elapsed(), ready_rx.len());
+            if stopped() || interrupt.is_set() {
+                break;
+            }
+            let (send, stats) = (Arc::clone(&send), Arc::clone(stats));
+            tasks.push(task::spawn(async move {
+                let started = Instant::now();
+                send(ready).await;
+                stats.record_request(started.elapsed());
+                drop(permit);
+            }));
+        }
+        // Items still queued or being prepared are dropped with the queue
+        ready_rx.close();
+        anyhow::Ok(())
+    };
+    let started = Instant::now();
+    let (preprocessed, dispatched) = tokio::join!(preprocessing, dispatch);
+    preprocessed?;
+    dispatched?;
+
+    // A result is written whole or not at all, so abandoning an item
+    // leaves the results file ready for --resume
+    let aborts: Vec<_> = tasks.iter().map(|task| task.abort_handle()).collect();
+    let mut in_flight = join_all(tasks);
+    let completed = tokio::select! {
+        _ = &mut in_flight => true,
+        _ = interrupt.wait() => tokio::time::timeout(interrupt::GRACE_PERIOD, &mut in_flight).await.is_ok(),
+    };
+    if !completed {
+        aborts.iter().for_each(|abort| abort.abort());
+    }
+    Ok(Dispatched { elapsed: started.elapsed(), completed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    fn stages(batch_size: usize, slots: usize) -> Stages {
+        Stages { batch_size, preprocess_workers: 2, prefetch: 2, slots: Arc::new(Semaphore::new(slots)) }
+    }
+
+    #[tokio::test]
+    async fn every_group_is_sent_once_in_batches_within_the_slots() {
+        let (interrupt, _trigger) = Interrupt::manual();
+        let stats = Arc::new(PipelineStats::default());
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let (in_flight, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+
+        let send = {
+            let (sent, in_flight, most) = (Arc::clone(&sent), Arc::clone(&in_flight), Arc::clone(&most));
+            move |batch: Vec<usize>| {
+                let (sent, in_flight, most) = (Arc::clone(&sent), Arc::clone(&in_flight), Arc::clone(&most));
+                async move {
+                    most.fetch_max(in_flight.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    sent.lock().unwrap().push(batch);
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        };
+        let dispatched = run((0..10).collect(), &stages(3, 2), &interrupt, &stats,
+            || false, |batch| async move { batch }, send).await.unwrap();
+
+        assert!(dispatched.completed);
+        let mut batches = sent.lock().unwrap().clone();
+        batches.sort();
+        assert_eq!(batches, [vec![0, 1, 2], vec![3, 4, 5], vec![6, 7, 8], vec![9]]);
+        assert!(most.load(Ordering::SeqCst) <= 2);
+        let summary = stats.summary(dispatched.elapsed, 2, 2, 2);
+        assert_eq!(summary.prepared, 4);
+        assert!(summary.max_queued <= 2);
+    }
+
+    #[tokio::test]
+    async fn nothing_more_is_sent_once_stopped() {
+        let (interrupt, _trigger) = Interrupt::manual();
+        let stats = Arc::new(PipelineStats::default());
+        let sent = Arc::new(AtomicUsize::new(0));
+        let send = {
+            let sent = Arc::clone(&sent);
+            move |_: Vec<usize>| {
+                sent.fetch_add(1, Ordering::SeqCst);
+                async {}
+            }
+        };
+        let stopped = || sent.load(Ordering::SeqCst) >= 3;
+        run((0..100).collect(), &stages(1, 1), &interrupt, &stats,
+            stopped, |batch| async move { batch }, send).await.unwrap();
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn items_in_flight_are_abandoned_after_the_grace_period() {
+        let (interrupt, trigger) = Interrupt::manual();
+        let stats = Arc::new(PipelineStats::default());
+        let started = Arc::new(AtomicUsize::new(0));
+        let send = {
+            let started = Arc::clone(&started);
+            move |_: Vec<usize>| {
+                started.fetch_add(1, Ordering::SeqCst);
+                std::future::pending::<()>()
+            }
+        };
+        let stop = async {
+            while started.load(Ordering::SeqCst) < 2 {
+                tokio::task::yield_now().await;
+            }
+            trigger.send(true).unwrap();
+        };
+        let (stages, started_at) = (stages(1, 2), tokio::time::Instant::now());
+        let (dispatched, _) = tokio::join!(
+            run((0..10).collect(), &stages, &interrupt, &stats,
+                || false, |batch| async move { batch }, send),
+            stop,
+        );
+        let dispatched = dispatched.unwrap();
+        assert!(!dispatched.completed);
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+        assert!(started_at.elapsed() >= interrupt::GRACE_PERIOD);
+    }
+}
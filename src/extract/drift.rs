@@ -0,0 +1,199 @@
+use anyhow::Result;
+use chrono::Local;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::data::{self, MarkText};
+use crate::images::{self, sidecar::Sidecar};
+use super::metrics::Scoreboard;
+use super::results::{ExtractionResult, ResultsWriter};
+
+/// Labels `--verify-labels` found changed at IPOS, written next to the
+/// results file
+pub const CORRECTIONS_FILE_NAME: &str = "label_corrections.jsonl";
+
+/// The trademark record an image's document was downloaded with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarkRecord {
+    pub application_num: String,
+    /// Lodgement date, YYYY-MM-DD, the day the API is queried for; not
+    /// known for every image
+    pub lodgement_date: Option<String>,
+}
+
+impl MarkRecord {
+    /// The record of an image's sidecar
+    pub fn from_sidecar(sidecar: &Sidecar) -> Self {
+        MarkRecord {
+            application_num: sidecar.application_num.clone(),
+            // Lodgement dates may carry a time component
+            lodgement_date: sidecar.lodgement_date.as_deref().and_then(|date| date.get(..10)).map(String::from),
+        }
+    }
+
+    /// The record named by the downloader's `{applicationNum}_{fileName}`
+    /// image names, without a lodgement date
+    pub fn from_image_name(image_name: &str) -> Option<Self> {
+        images::application_num_of(image_name)
+            .map(|application_num| MarkRecord { application_num: application_num.to_string(), lodgement_date: None })
+    }
+}
+
+/// One line of the corrections file, keyed like the dataset so it can be
+/// folded back into it: the label as IPOS has it now, and as it was
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Correction {
+    pub image_name: String,
+    pub application_num: String,
+    pub lodgement_date: String,
+    pub chinese_character: Option<String>,
+    pub previous_chinese_character: Option<String>,
+    /// The run's prediction, and whether it equals the current label
+    pub prediction: Option<String>,
+    pub prediction_matches: bool,
+    pub checked_at: String,
+}
+
+/// What `--verify-labels` found for the mismatches of a run, saved in the
+/// metrics file. Images whose label changed are a bucket of their own and
+/// left out of `accuracy`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabelDrift {
+    /// Answered mismatches of the run, and those whose record was looked up
+    pub mismatches: usize,
+    pub checked: usize,
+    /// Label changed since the dataset was built, and among them the ones
+    /// the prediction now matches
+    pub drifted: usize,
+    pub drifted_matches: usize,
+    /// Records the API no longer lists for their lodgement date
+    pub not_found: usize,
+    /// Mismatches without a known record and lodgement date, or whose day
+    /// couldn't be fetched
+    pub unchecked: usize,
+    /// Exact matches over the images without a drifted label
+    pub accuracy: f64,
+    pub corrections: PathBuf,
+}
+
+/// The answered mismatches of the run scored on `scoreboard`, the last
+/// result of each image in `results`
+pub fn mismatches<'a>(scoreboard: &Scoreboard, results: &'a [ExtractionResult]) -> Vec<&'a ExtractionResult> {
+    let mut seen = HashSet::new();
+    let mut mismatches: Vec<&ExtractionResult> = results.iter().rev()
+        .filter(|result| result.config_id == scoreboard.config_id && seen.insert(result.image_name.as_str()))
+        .filter(|result| scoreboard.image_scores.get(&result.image_name).is_some_and(|score| !score.exact_match && score.cer.is_some()))
+        .collect();
+    mismatches.reverse();
+    mismatches
+}
+
+/// Re-query the API for the records of `mismatches`, found by image name in
+/// `records` and only those with a lodgement date, and write each label that changed to `corrections_path`.
+/// Labels are compared after `normalize`, as the predictions are. Days are
+/// fetched once each, `concurrency` at a time with the downloader's pause
+/// between batches. The accuracy is left for `LabelDrift::score`.
+pub async fn verify(
+    client: &reqwest::Client,
+    mismatches: &[&ExtractionResult],
+    records: &HashMap<String, MarkRecord>,
+    concurrency: usize,
+    normalize: impl Fn(&str) -> String,
+    corrections_path: &Path,
+) -> Result<LabelDrift> {
+    let dates: BTreeSet<&str> = mismatches.iter()
+        .filter_map(|result| records.get(&result.image_name)?.lodgement_date.as_deref())
+        .collect();
+    info!("Re-fetching the trademark records of {} days to verify the labels of {} mismatches", dates.len(), mismatches.len());
+
+    let mut days: HashMap<&str, Vec<Value>> = HashMap::new();
+    let dates: Vec<&str> = dates.into_iter().collect();
+    for (i, chunk) in dates.chunks(concurrency.max(1)).enumerate() {
+        if i > 0 {
+            tokio::time::sleep(data::BATCH_DELAY).await;
+        }
+        let fetched = join_all(chunk.iter().map(|date| data::fetch_items(client, date))).await;
+        for (date, items) in chunk.iter().zip(fetched) {
+            match items {
+                Ok(items) => {
+                    days.insert(date, items);
+                }
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+    }
+
+    let writer = ResultsWriter::open(corrections_path, false)?;
+    let mut drift = LabelDrift {
+        mismatches: mismatches.len(),
+        checked: 0,
+        drifted: 0,
+        drifted_matches: 0,
+        not_found: 0,
+        unchecked: 0,
+        accuracy: 0.0,
+        corrections: corrections_path.to_path_buf(),
+    };
+    let checked_at = Local::now().to_rfc3339();
+    for result in mismatches {
+        let Some((record, date, items)) = records.get(&result.image_name)
+            .and_then(|record| {
+                let date = record.lodgement_date.as_deref()?;
+                Some((record, date, days.get(date)?))
+            })
+        else {
+            drift.unchecked += 1;
+            continue;
+        };
+        drift.checked += 1;
+        let Some(item) = items.iter().find(|item| item.get("applicationNum").and_then(Value::as_str) == Some(record.application_num.as_str())) else {
+            drift.not_found += 1;
+            continue;
+        };
+        let current = MarkText::from_item(item).chinese_character;
+        let comparable = |text: Option<&str>| normalize(text.unwrap_or_default());
+        if comparable(current.as_deref()) == comparable(result.ground_truth.as_deref()) {
+            continue;
+        }
+        let prediction_matches = comparable(current.as_deref()) == comparable(result.chinese_character.as_deref());
+        drift.drifted += 1;
+        drift.drifted_matches += usize::from(prediction_matches);
+        writer.write(&Correction {
+            image_name: result.image_name.clone(),
+            application_num: record.application_num.clone(),
+            lodgement_date: date.to_string(),
+            chinese_character: current,
+            previous_chinese_character: result.ground_truth.clone(),
+            prediction: result.chinese_character.clone(),
+            prediction_matches,
+            checked_at: checked_at.clone(),
+        })?;
+    }
+    writer.sync()?;
+    Ok(drift)
+}
+
+impl LabelDrift {
+    /// Set the accuracy of `scoreboard` without the drifted images
+    pub fn score(&mut self, scoreboard: &Scoreboard) {
+        let total = scoreboard.total.saturating_sub(self.drifted);
+        self.accuracy = if total > 0 { scoreboard.exact_match as f64 / total as f64 } else { 0.0 };
+    }
+}
+
+impl fmt::Display for LabelDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Label drift: {} of {} mismatches checked at IPOS, {} labels changed ({} now matching the prediction), {} records gone; accuracy without them {:.2}%",
+            self.checked, self.mismatches, self.drifted, self.drifted_matches, self.not_found, self.accuracy * 100.0)?;
+        if self.unchecked > 0 {
+            write!(f, "; {} mismatches not checked", self.unchecked)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// z of a two-sided 95% interval
+const Z: f64 = 1.96;
+
+/// `--early-stop`: a running estimate of the accuracy, stopping the run once
+/// its 95% interval is narrow enough. Only the longest run of completed
+/// images from the start of the sample counts, in the order the images
+/// were drawn, so images still in flight or finishing out of order never
+/// decide it and the same seed stops at the same image whatever the
+/// concurrency.
+#[derive(Debug)]
+pub struct EarlyStop {
+    /// Widest half-width of the interval at which the run stops
+    target: f64,
+    /// Scored images needed before the interval is trusted
+    min_images: usize,
+    stopped: AtomicBool,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Indices of the run's images in sample order
+    order: Vec<usize>,
+    /// Images of `order` counted so far
+    counted: usize,
+    /// Completed images past the counted ones: whether each matched, `None`
+    /// for images that aren't scored
+    pending: HashMap<usize, Option<bool>>,
+    scored: usize,
+    matched: usize,
+    stop: Option<EarlyStopSummary>,
+}
+
+/// Where `--early-stop` stopped, saved in the metrics file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EarlyStopSummary {
+    pub target_half_width: f64,
+    pub min_images: usize,
+    /// Images from the start of the sample the estimate covers, and the
+    /// labeled ones among them
+    pub images: usize,
+    pub scored: usize,
+    pub accuracy: f64,
+    /// Wilson 95% interval of the accuracy
+    pub low: f64,
+    pub high: f64,
+    pub half_width: f64,
+    /// Images of the sample never sent; set at the end of the run
+    #[serde(default)]
+    pub skipped: usize,
+}
+
+impl EarlyStop {
+    /// A stopping rule over the images with job `indices`, taken in
+    /// ascending order, the order of the sample
+    pub fn new(indices: impl IntoIterator<Item = usize>, target: f64, min_images: usize) -> Self {
+        let mut order: Vec<usize> = indices.into_iter().collect();
+        order.sort_unstable();
+        EarlyStop {
+            target,
+            min_images,
+            stopped: AtomicBool::new(false),
+            state: Mutex::new(State { order, counted: 0, pending: HashMap::new(), scored: 0, matched: 0, stop: None }),
+        }
+    }
+
+    /// Whether the rule has fired and no more images should be sent
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Count the completed image `index`, `matched` telling whether its
+    /// prediction matched for a scored image. Returns where the run stops
+    /// when this completion makes the rule fire.
+    pub fn record(&self, index: usize, matched: Option<bool>) -> Option<EarlyStopSummary> {
+        let mut state = self.state.lock().ok()?;
+        if state.stop.is_some() {
+            return None;
+        }
+        state.pending.insert(index, matched);
+        while let Some(next) = state.order.get(state.counted).copied() {
+            let Some(matched) = state.pending.remove(&next) else {
+                break;
+            };
+            state.counted += 1;
+            if let Some(matched) = matched {
+                state.scored += 1;
+                state.matched += usize::from(matched);
+            }
+            if state.scored < self.min_images.max(1) {
+                continue;
+            }
+            let (low, high) = wilson(state.matched, state.scored);
+            let half_width = (high - low) / 2.0;
+            if half_width <= self.target {
+                let summary = EarlyStopSummary {
+                    target_half_width: self.target,
+                    min_images: self.min_images,
+                    images: state.counted,
+                    scored: state.scored,
+                    accuracy: state.matched as f64 / state.scored as f64,
+                    low,
+                    high,
+                    half_width,
+                    skipped: 0,
+                };
+                state.stop = Some(summary.clone());
+                self.stopped.store(true, Ordering::Relaxed);
+                return Some(summary);
+            }
+        }
+        None
+    }
+
+    /// Where the run stopped, if it did
+    pub fn summary(&self) -> Option<EarlyStopSummary> {
+        self.state.lock().ok()?.stop.clone()
+    }
+}
+
+/// Wilson score interval of `matched` successes in `n`, at 95%
+fn wilson(matched: usize, n: usize) -> (f64, f64) {
+    let n = n as f64;
+    let p = matched as f64 / n;
+    let z2 = Z * Z;
+    let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let half = Z * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt() / (1.0 + z2 / n);
+    ((center - half).max(0.0), (center + half).min(1.0))
+}
+
+impl fmt::Display for EarlyStopSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Stopped early after {} images ({} scored): accuracy {:.1}% (95% CI {:.1}-{:.1}%, ±{:.1} points, target ±{:.1}); {} images not sent",
+            self.images, self.scored, self.accuracy * 100.0, self.low * 100.0, self.high * 100.0,
+            self.half_width * 100.0, self.target_half_width * 100.0, self.skipped)
+    }
+}
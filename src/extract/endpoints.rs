@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use super::protocol::Backend;
+
+/// Failed requests in a row after which an endpoint is skipped
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+
+/// Seconds an unhealthy endpoint is skipped for
+pub const DEFAULT_COOLDOWN_SECS: u64 = 60;
+
+/// One `--base-url`, with the model it is asked for
+#[derive(Debug)]
+struct Endpoint {
+    base_url: String,
+    model: Option<String>,
+    requests: AtomicUsize,
+    failures: AtomicUsize,
+    health: Mutex<Health>,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    /// Set while the endpoint is skipped
+    unhealthy_until: Option<Instant>,
+}
+
+/// Requests an endpoint was sent and how many it failed, for the summary
+#[derive(Debug, Clone)]
+pub struct EndpointStats {
+    pub base_url: String,
+    pub model: Option<String>,
+    pub requests: usize,
+    pub failures: usize,
+}
+
+impl EndpointStats {
+    pub fn failure_rate(&self) -> f64 {
+        if self.requests == 0 { 0.0 } else { self.failures as f64 / self.requests as f64 }
+    }
+}
+
+/// The endpoints of a run, in order of preference. Each request goes to the
+/// first healthy one. An endpoint that can't be connected to, or fails
+/// `threshold` requests in a row, is unhealthy for `cooldown` and its
+/// requests go to the next one; once the cool-down is over it is tried
+/// again.
+#[derive(Debug)]
+pub struct Endpoints {
+    endpoints: Vec<Endpoint>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl Endpoints {
+    /// `endpoints` as base URL and model, the preferred one first
+    pub fn new(endpoints: Vec<(String, Option<String>)>, threshold: u32, cooldown: Duration) -> Self {
+        Endpoints {
+            endpoints: endpoints.into_iter()
+                .map(|(base_url, model)| Endpoint {
+                    base_url,
+                    model,
+                    requests: AtomicUsize::new(0),
+                    failures: AtomicUsize::new(0),
+                    health: Mutex::new(Health::default()),
+                })
+                .collect(),
+            threshold,
+            cooldown,
+        }
+    }
+
+    /// Whether there is an endpoint to fail over to
+    pub fn has_fallback(&self) -> bool {
+        self.endpoints.len() > 1
+    }
+
+    /// Index of the endpoint the next request goes to: the first healthy
+    /// one, or the one back the soonest when none is
+    pub fn pick(&self) -> usize {
+        let now = Instant::now();
+        let mut soonest: Option<(usize, Instant)> = None;
+        for (index, endpoint) in self.endpoints.iter().enumerate() {
+            let until = endpoint.health.lock().ok().and_then(|health| health.unhealthy_until);
+            match until {
+                Some(until) if until > now => {
+                    if soonest.is_none_or(|(_, soonest)| until < soonest) {
+                        soonest = Some((index, until));
+                    }
+                }
+                _ => return index,
+            }
+        }
+        soonest.map_or(0, |(index, _)| index)
+    }
+
+    /// `backend` sending to the endpoint at `index`
+    pub fn route(&self, backend: &Backend, index: usize) -> Backend {
+        let endpoint = &self.endpoints[index];
+        Backend { base_url: endpoint.base_url.clone(), model: endpoint.model.clone(), ..backend.clone() }
+    }
+
+    /// `backend` sending to each endpoint, in order of preference
+    pub fn routes(&self, backend: &Backend) -> Vec<Backend> {
+        (0..self.endpoints.len()).map(|index| self.route(backend, index)).collect()
+    }
+
+    pub fn base_url(&self, index: usize) -> &str {
+        &self.endpoints[index].base_url
+    }
+
+    /// Whether the endpoint at `index` is asked for the model of `backend`
+    pub fn serves(&self, index: usize, backend: &Backend) -> bool {
+        self.endpoints[index].model == backend.model
+    }
+
+    /// Count a request to the endpoint at `index`. `failed` is set when the
+    /// endpoint is to blame for the failure, `unreachable` when it couldn't
+    /// be connected to at all.
+    pub fn record(&self, index: usize, failed: bool, unreachable: bool) {
+        let endpoint = &self.endpoints[index];
+        endpoint.requests.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            endpoint.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        let Ok(mut health) = endpoint.health.lock() else {
+            return;
+        };
+        if !failed {
+            *health = Health::default();
+            return;
+        }
+        health.consecutive_failures += 1;
+        let now = Instant::now();
+        let healthy = health.unhealthy_until.is_none_or(|until| until <= now);
+        if self.has_fallback() && healthy && (unreachable || health.consecutive_failures >= self.threshold) {
+            health.unhealthy_until = Some(now + self.cooldown);
+            let reason = if unreachable {
+                "can't be connected to".to_string()
+            } else {
+                format!("failed {} requests in a row", health.consecutive_failures)
+            };
+            warn!("Endpoint {} {}; skipping it for {}s", endpoint.base_url, reason, self.cooldown.as_secs());
+        }
+    }
+
+    pub fn stats(&self) -> Vec<EndpointStats> {
+        self.endpoints.iter()
+            .map(|endpoint| EndpointStats {
+                base_url: endpoint.base_url.clone(),
+                model: endpoint.model.clone(),
+                requests: endpoint.requests.load(Ordering::Relaxed),
+                failures: endpoint.failures.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Requests and failures of each endpoint, a line each
+    pub fn report(&self) -> String {
+        let lines: Vec<String> = self.stats().iter()
+            .map(|stats| format!("  {} ({}): {} requests, {} failed ({:.1}%)",
+                stats.base_url, stats.model.as_deref().unwrap_or("local-api"),
+                stats.requests, stats.failures, 100.0 * stats.failure_rate()))
+            .collect();
+        format!("Requests by endpoint:\n{}", lines.join("\n"))
+    }
+}
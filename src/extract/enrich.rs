@@ -0,0 +1,193 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::parse::ParseMethod;
+use super::results::{ExtractionResult, Provenance, Status};
+use crate::data;
+
+/// Key of the predictions added to each dataset entry or document
+pub const ENRICHED_KEY: &str = "llm_extraction";
+
+/// What the extractor made of an image, as added to its dataset entry
+#[derive(Debug, Serialize)]
+pub struct LlmExtraction<'a> {
+    pub chinese_character: Option<&'a str>,
+    pub words_in_mark: Option<&'a str>,
+    pub description_of_device: Option<&'a str>,
+    pub status: Status,
+    pub parse_method: Option<ParseMethod>,
+    /// Whether the prediction equals the ground truth after normalization;
+    /// `null` on error or without ground truth
+    #[serde(rename = "match")]
+    pub is_match: Option<bool>,
+    pub edit_distance: Option<usize>,
+    pub model: &'a str,
+    pub config_id: &'a str,
+    /// When the result was recorded
+    pub extracted_at: Option<&'a str>,
+    #[serde(flatten)]
+    pub provenance: &'a Provenance,
+}
+
+impl<'a> LlmExtraction<'a> {
+    pub fn new(result: &'a ExtractionResult, model: &'a str) -> Self {
+        let scored = result.status != Status::Error && result.ground_truth.is_some();
+        LlmExtraction {
+            chinese_character: result.chinese_character.as_deref(),
+            words_in_mark: result.words_in_mark.as_deref(),
+            description_of_device: result.description_of_device.as_deref(),
+            status: result.status,
+            parse_method: result.parse_method,
+            is_match: scored.then(|| result.is_match()),
+            edit_distance: result.edit_distance,
+            model,
+            config_id: &result.config_id,
+            extracted_at: result.completed_at.as_deref(),
+            provenance: &result.provenance,
+        }
+    }
+}
+
+/// Outcome of writing an enriched file
+#[derive(Debug, Default)]
+pub struct Enrichment {
+    /// Entries or documents given an extraction
+    pub enriched: usize,
+    /// Entries or documents without a result
+    pub without_result: usize,
+    /// Image names of the results that matched nothing in the input
+    pub unmatched: Vec<String>,
+}
+
+/// Copy a `cleaned_data.json` or `images_manifest.json` dataset to `output`
+/// with the result of each entry under `ENRICHED_KEY`, joined on the image
+/// name. Entries keep every other key, so the file can be read as a dataset
+/// again.
+pub fn enrich_dataset(dataset: &Path, results: &[ExtractionResult], model: &str, output: &Path) -> Result<Enrichment> {
+    let text = fs::read_to_string(dataset)
+        .with_context(|| format!("Failed to read dataset file: {}", dataset.display()))?;
+    let mut entries: Vec<Value> = serde_json::from_str(&text)
+        .with_context(|| format!("Failed to parse dataset JSON: {}", dataset.display()))?;
+
+    let by_name: HashMap<&str, &ExtractionResult> = results.iter().map(|r| (r.image_name.as_str(), r)).collect();
+    let mut enrichment = Enrichment::default();
+    let mut used = HashSet::new();
+    for entry in &mut entries {
+        let result = entry_image_name(entry).and_then(|name| by_name.get(name.as_str()).copied());
+        let Some(object) = entry.as_object_mut() else {
+            continue;
+        };
+        match result {
+            Some(result) => {
+                used.insert(result.image_name.as_str());
+                object.insert(ENRICHED_KEY.to_string(), serde_json::to_value(LlmExtraction::new(result, model))?);
+                enrichment.enriched += 1;
+            }
+            None => enrichment.without_result += 1,
+        }
+    }
+    enrichment.unmatched = unmatched(results, &used);
+
+    write_atomically(output, |writer| serde_json::to_writer_pretty(writer, &entries).map_err(Into::into))?;
+    Ok(enrichment)
+}
+
+/// Copy the downloader's data file to `output` as a JSON array of day
+/// records, adding to each document the result of its image under
+/// `ENRICHED_KEY`. Documents are joined on application number and file id,
+/// or file name when they have no id; `documents` maps each result's image
+/// name to that key.
+pub fn enrich_raw(
+    data_path: &Path,
+    results: &[ExtractionResult],
+    documents: &HashMap<String, (String, String)>,
+    model: &str,
+    output: &Path,
+) -> Result<Enrichment> {
+    let by_document: HashMap<(&str, &str), &ExtractionResult> = results.iter()
+        .filter_map(|r| documents.get(&r.image_name).map(|(app, id)| ((app.as_str(), id.as_str()), r)))
+        .collect();
+
+    let mut enrichment = Enrichment::default();
+    let mut used = HashSet::new();
+    write_atomically(output, |writer| {
+        writer.write_all(b"[")?;
+        let mut first = true;
+        data::for_each_record(data_path, |mut record| {
+            for item in &mut record.items {
+                let Some(app_num) = item.get("applicationNum").and_then(Value::as_str).map(String::from) else {
+                    continue;
+                };
+                let Some(docs) = item.get_mut("documents").and_then(Value::as_array_mut) else {
+                    continue;
+                };
+                for doc in docs {
+                    let Some(id) = document_id(doc) else {
+                        continue;
+                    };
+                    match by_document.get(&(app_num.as_str(), id.as_str())) {
+                        Some(result) => {
+                            used.insert(result.image_name.as_str());
+                            doc[ENRICHED_KEY] = serde_json::to_value(LlmExtraction::new(result, model))?;
+                            enrichment.enriched += 1;
+                        }
+                        None => enrichment.without_result += 1,
+                    }
+                }
+            }
+            if !first {
+                writer.write_all(b",")?;
+            }
+            first = false;
+            serde_json::to_writer(&mut *writer, &record)?;
+            Ok(())
+        })?;
+        writer.write_all(b"]\n")?;
+        Ok(())
+    })?;
+    enrichment.unmatched = unmatched(results, &used);
+    Ok(enrichment)
+}
+
+/// File id of a document, or its file name when it has none
+pub fn document_id(doc: &Value) -> Option<String> {
+    doc.get("fileId").and_then(Value::as_str)
+        .or_else(|| doc.get("fileName").and_then(Value::as_str))
+        .map(String::from)
+}
+
+/// Image name of a dataset entry: `imageName` in `cleaned_data.json`, the
+/// file name of `localPath` in an image manifest
+pub fn entry_image_name(entry: &Value) -> Option<String> {
+    if let Some(name) = entry.get("imageName").and_then(Value::as_str) {
+        return Some(name.to_string());
+    }
+    entry.get("localPath").and_then(Value::as_str)
+        .and_then(|path| Path::new(path).file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+fn unmatched(results: &[ExtractionResult], used: &HashSet<&str>) -> Vec<String> {
+    results.iter()
+        .filter(|r| !used.contains(r.image_name.as_str()))
+        .map(|r| r.image_name.clone())
+        .collect()
+}
+
+/// Write `output` through a temporary file so a failed write never leaves
+/// a truncated file behind
+fn write_atomically(output: &Path, write: impl FnOnce(&mut BufWriter<File>) -> Result<()>) -> Result<()> {
+    let tmp_path = output.with_extension("json.tmp");
+    let file = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create enriched file: {}", tmp_path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write(&mut writer).context("Failed to write enriched file")?;
+    writer.flush().context("Failed to write enriched file")?;
+    fs::rename(&tmp_path, output)
+        .with_context(|| format!("Failed to save enriched file: {}", output.display()))
+}
@@ -0,0 +1,82 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+
+use super::results::{ExtractionResult, Status};
+
+/// Images that failed for good, written next to the results file
+pub const FAILURES_FILE_NAME: &str = "extract_failures.json";
+
+/// The images of a configuration whose latest result is an error, after
+/// the retries of the run, so `--retry-failed` can process them again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FailureList {
+    /// Configuration of the failed results; a retry must run the same one
+    pub config_id: String,
+    pub created_at: String,
+    pub failures: Vec<Failure>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Failure {
+    pub image_name: String,
+    /// Coarse failure class, see `retry::failure_class`
+    pub error_class: Option<String>,
+    /// Requests made for the image in its last run
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+impl FailureList {
+    /// The failures among `results`, taking the last result of each image
+    /// of `config_id` as its outcome, in image name order
+    pub fn from_results(config_id: &str, results: &[ExtractionResult]) -> Self {
+        let mut latest: HashMap<&str, &ExtractionResult> = HashMap::new();
+        for result in results.iter().filter(|result| result.config_id == config_id) {
+            latest.insert(&result.image_name, result);
+        }
+        let mut failures: Vec<Failure> = latest.into_values()
+            .filter(|result| result.status == Status::Error)
+            .map(|result| Failure {
+                image_name: result.image_name.clone(),
+                error_class: result.error_class.clone(),
+                attempts: result.attempts,
+                last_error: result.error.clone(),
+            })
+            .collect();
+        failures.sort_by(|a, b| a.image_name.cmp(&b.image_name));
+        FailureList { config_id: config_id.to_string(), created_at: Local::now().to_rfc3339(), failures }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open failures file: {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse failures file: {}", path.display()))
+    }
+
+    /// Write the list atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("json.tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create failures file: {}", tmp_path.display()))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)
+            .context("Failed to write failures file")?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to save failures file: {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.failures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::{Context, Result};
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use super::format::ImageKind;
+use super::protocol::Field;
+use super::preprocess::Preprocess;
+use super::resize::{self, Resize};
+use super::source::ImageSource;
+
+/// Longest sides tried, largest first, when the examples as they are on
+/// disk are over the `--few-shot-max-bytes` budget
+const DOWNSCALE_STEPS: [u32; 7] = [1024, 768, 512, 384, 256, 192, 128];
+
+/// The `--few-shot` manifest: example images with the answer the model
+/// should give for each, paths relative to the manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FewShotManifest {
+    pub examples: Vec<ExampleEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExampleEntry {
+    pub image: PathBuf,
+    /// Name of the image in the dataset, kept out of the evaluation;
+    /// defaults to the file name of `image`
+    #[serde(default)]
+    pub image_name: Option<String>,
+    /// The correct JSON answer, with the keys of the extracted fields
+    pub answer: Value,
+}
+
+/// One worked example, sent as a user turn with the image and an assistant
+/// turn with the answer
+#[derive(Debug, Clone)]
+pub struct Example {
+    pub image_name: String,
+    pub base64: String,
+    pub media_type: &'static str,
+    /// The answer as the assistant's reply, only the extracted fields kept
+    pub answer: String,
+}
+
+/// The examples shown before every image, loaded once and shared by every
+/// request
+#[derive(Debug, Clone)]
+pub struct FewShot {
+    pub examples: Vec<Example>,
+    /// Short hash of the example images and answers, part of the
+    /// configuration and cache keys
+    pub hash: String,
+    /// Longest side the examples were shrunk to, to fit the budget
+    pub max_dimension: Option<u32>,
+}
+
+/// The examples of a run, recorded in the metrics file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FewShotSummary {
+    pub manifest: PathBuf,
+    pub hash: String,
+    pub image_names: Vec<String>,
+    /// Base64 bytes of the example images sent with every request
+    pub bytes: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_dimension: Option<u32>,
+}
+
+impl FewShot {
+    /// Load the examples listed at `path`, converting images outside
+    /// `accepted` and preprocessing them as the run does. When their base64 is over `max_bytes`
+    /// they are shrunk, step by step, until it fits; examples that can't be
+    /// made to fit are an error.
+    pub fn load(
+        path: &Path,
+        fields: &[Field],
+        accepted: Option<&[ImageKind]>,
+        jpeg_quality: u8,
+        preprocess: Preprocess,
+        max_bytes: u64,
+    ) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open few-shot manifest: {}", path.display()))?;
+        let manifest: FewShotManifest = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse few-shot manifest: {}", path.display()))?;
+        anyhow::ensure!(!manifest.examples.is_empty(), "Few-shot manifest {} lists no examples", path.display());
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        let mut hasher = Sha256::new();
+        let mut answers = Vec::new();
+        for entry in &manifest.examples {
+            let answer = answer_text(&entry.answer, fields)
+                .with_context(|| format!("Invalid answer for few-shot example {}", entry.image.display()))?;
+            hasher.update(ImageSource::Files.read(&dir.join(&entry.image))
+                .with_context(|| format!("Failed to read few-shot example: {}", entry.image.display()))?);
+            hasher.update(answer.as_bytes());
+            answers.push(answer);
+        }
+        let hash = format!("{:x}", hasher.finalize())[..12].to_string();
+
+        for max_dimension in std::iter::once(None).chain(DOWNSCALE_STEPS.map(Some)) {
+            let resize = max_dimension.map(|max_dimension| Resize { max_dimension, jpeg_quality });
+            let mut examples = Vec::new();
+            for (entry, answer) in manifest.examples.iter().zip(&answers) {
+                let image_path = dir.join(&entry.image);
+                let prepared = resize::prepare(&ImageSource::Files, &image_path, accepted, resize, preprocess)
+                    .with_context(|| format!("Failed to prepare few-shot example: {}", image_path.display()))?;
+                let image_name = match &entry.image_name {
+                    Some(name) => name.clone(),
+                    None => entry.image.file_name()
+                        .with_context(|| format!("Few-shot example has no file name: {}", entry.image.display()))?
+                        .to_string_lossy()
+                        .into_owned(),
+                };
+                examples.push(Example {
+                    image_name,
+                    base64: general_purpose::STANDARD.encode(&prepared.bytes),
+                    media_type: prepared.media_type,
+                    answer: answer.clone(),
+                });
+            }
+            let few_shot = FewShot { examples, hash: hash.clone(), max_dimension };
+            if few_shot.bytes() as u64 <= max_bytes {
+                return Ok(few_shot);
+            }
+        }
+        anyhow::bail!("The few-shot examples of {} don't fit in {} bytes even at {} pixels; raise --few-shot-max-bytes or list fewer examples",
+            path.display(), max_bytes, DOWNSCALE_STEPS[DOWNSCALE_STEPS.len() - 1])
+    }
+
+    /// Base64 bytes of the example images
+    pub fn bytes(&self) -> usize {
+        self.examples.iter().map(|example| example.base64.len()).sum()
+    }
+
+    pub fn image_names(&self) -> Vec<String> {
+        self.examples.iter().map(|example| example.image_name.clone()).collect()
+    }
+
+    pub fn summary(&self, manifest: &Path) -> FewShotSummary {
+        FewShotSummary {
+            manifest: manifest.to_path_buf(),
+            hash: self.hash.clone(),
+            image_names: self.image_names(),
+            bytes: self.bytes(),
+            max_dimension: self.max_dimension,
+        }
+    }
+}
+
+/// The answer as compact JSON holding only the keys of `fields`, the ones
+/// the model is asked for; a key the example leaves out is null
+fn answer_text(answer: &Value, fields: &[Field]) -> Result<String> {
+    let object = answer.as_object().context("The answer must be a JSON object")?;
+    let kept: Map<String, Value> = fields.iter()
+        .map(|field| (field.key().to_string(), object.get(field.key()).cloned().unwrap_or(Value::Null)))
+        .collect();
+    Ok(serde_json::to_string(&kept)?)
+}
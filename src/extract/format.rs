@@ -0,0 +1,299 @@
+use clap::ValueEnum;
+use futures::stream::{self, StreamExt};
+use image::{ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+
+use super::source::ImageSource;
+use crate::images;
+
+/// Bytes read to recognize a format
+const MAGIC_BYTES: usize = 16;
+
+/// Image file formats, told apart by their magic bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageKind {
+    Jpeg,
+    Png,
+    Webp,
+    Gif,
+    Tiff,
+    Bmp,
+}
+
+impl ImageKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageKind::Jpeg => "jpeg",
+            ImageKind::Png => "png",
+            ImageKind::Webp => "webp",
+            ImageKind::Gif => "gif",
+            ImageKind::Tiff => "tiff",
+            ImageKind::Bmp => "bmp",
+        }
+    }
+
+    /// Format of an image from its first bytes, whatever its extension says
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0xFF, 0xD8, 0xFF, ..] => Some(ImageKind::Jpeg),
+            [0x89, b'P', b'N', b'G', ..] => Some(ImageKind::Png),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(ImageKind::Webp),
+            [b'G', b'I', b'F', b'8', ..] => Some(ImageKind::Gif),
+            [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => Some(ImageKind::Tiff),
+            [b'B', b'M', ..] => Some(ImageKind::Bmp),
+            _ => None,
+        }
+    }
+
+    fn image_format(self) -> ImageFormat {
+        match self {
+            ImageKind::Jpeg => ImageFormat::Jpeg,
+            ImageKind::Png => ImageFormat::Png,
+            ImageKind::Webp => ImageFormat::WebP,
+            ImageKind::Gif => ImageFormat::Gif,
+            ImageKind::Tiff => ImageFormat::Tiff,
+            ImageKind::Bmp => ImageFormat::Bmp,
+        }
+    }
+
+    pub fn media_type(self) -> &'static str {
+        match self {
+            ImageKind::Jpeg => "image/jpeg",
+            ImageKind::Png => "image/png",
+            ImageKind::Webp => "image/webp",
+            ImageKind::Gif => "image/gif",
+            ImageKind::Tiff => "image/tiff",
+            ImageKind::Bmp => "image/bmp",
+        }
+    }
+}
+
+impl fmt::Display for ImageKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// An image in a format the endpoint doesn't accept that couldn't be
+/// converted to PNG; the image fails rather than being sent as it is
+#[derive(Debug)]
+pub struct ConversionError {
+    /// Detected format, `None` when the magic bytes were not recognized
+    pub from: Option<ImageKind>,
+    pub reason: String,
+}
+
+impl ConversionError {
+    /// Failure class recorded in the results file
+    pub fn class(&self) -> &'static str {
+        "conversion_error"
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.from {
+            Some(kind) => write!(f, "Failed to convert {} image to PNG: {}", kind, self.reason),
+            None => write!(f, "Failed to convert image of unknown format to PNG: {}", self.reason),
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// A file that isn't a readable image, found before anything is sent
+#[derive(Debug)]
+pub struct CorruptImage {
+    pub reason: String,
+}
+
+impl CorruptImage {
+    /// Failure class recorded in the results file
+    pub fn class(&self) -> &'static str {
+        "corrupt_image"
+    }
+}
+
+impl fmt::Display for CorruptImage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Corrupt image: {}", self.reason)
+    }
+}
+
+impl std::error::Error for CorruptImage {}
+
+/// A file over `--max-input-bytes`, left out before anything reads it
+#[derive(Debug)]
+pub struct TooLarge {
+    pub bytes: u64,
+    pub limit: u64,
+}
+
+impl TooLarge {
+    /// Failure class recorded in the results file
+    pub fn class(&self) -> &'static str {
+        "too_large"
+    }
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "File too large: {}, over --max-input-bytes of {}",
+            images::format_bytes(self.bytes), images::format_bytes(self.limit))
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// Check the size of a file against `limit` from its metadata alone. A file
+/// whose size can't be read passes, for `check` to report.
+pub fn check_size(source: &ImageSource, path: &Path, limit: u64) -> Result<(), TooLarge> {
+    match source.size(path) {
+        Ok(bytes) if bytes > limit => Err(TooLarge { bytes, limit }),
+        _ => Ok(()),
+    }
+}
+
+/// Check that a file looks like an image: not empty, starting with the
+/// magic bytes of a known format, and with a header that decodes when the
+/// image crate reads that format
+pub fn check(source: &ImageSource, path: &Path) -> Result<ImageKind, CorruptImage> {
+    let corrupt = |reason: String| CorruptImage { reason };
+    let mut file = source.open(path).map_err(|e| corrupt(format!("can't open file: {}", e)))?;
+    let mut magic = Vec::with_capacity(MAGIC_BYTES);
+    file.by_ref().take(MAGIC_BYTES as u64).read_to_end(&mut magic)
+        .map_err(|e| corrupt(format!("can't read file: {}", e)))?;
+    if magic.is_empty() {
+        return Err(corrupt("empty file".to_string()));
+    }
+    let kind = ImageKind::detect(&magic).ok_or_else(|| corrupt("not a known image format".to_string()))?;
+    if kind.image_format().reading_enabled() {
+        file.rewind().map_err(|e| corrupt(format!("can't read file: {}", e)))?;
+        ImageReader::new(std::io::BufReader::new(file))
+            .with_guessed_format()
+            .map_err(|e| corrupt(format!("can't read file: {}", e)))?
+            .into_dimensions()
+            .map_err(|e| corrupt(format!("{} header doesn't decode: {}", kind, e)))?;
+    }
+    Ok(kind)
+}
+
+/// `check` every file on blocking threads, `workers` at a time, in the
+/// order of `paths`
+pub async fn check_all(source: &ImageSource, paths: Vec<PathBuf>, workers: usize) -> Vec<Result<ImageKind, CorruptImage>> {
+    stream::iter(paths)
+        .map(|path| async move {
+            let source = source.clone();
+            tokio::task::spawn_blocking(move || check(&source, &path))
+                .await
+                .unwrap_or_else(|e| Err(CorruptImage { reason: format!("check panicked: {}", e) }))
+        })
+        .buffered(workers.max(1))
+        .collect()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// 1x1 GIF89a
+    const GIF: &[u8] = &[
+        0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0xFF, 0xFF, 0xFF,
+        0x00, 0x00, 0x00, 0x21, 0xF9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2C, 0x00, 0x00, 0x00, 0x00,
+        0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3B,
+    ];
+
+    /// 1x1 lossless WebP
+    const WEBP: &[u8] = &[
+        b'R', b'I', b'F', b'F', 0x1A, 0x00, 0x00, 0x00, b'W', b'E', b'B', b'P', b'V', b'P', b'8', b'L',
+        0x0D, 0x00, 0x00, 0x00, 0x2F, 0x00, 0x00, 0x00, 0x10, 0x07, 0x10, 0x11, 0x11, 0x88, 0x88, 0xFE,
+        0x07, 0x00,
+    ];
+
+    /// 1x1 white 24-bit BMP
+    const BMP: &[u8] = &[
+        b'B', b'M', 0x3A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x36, 0x00, 0x00, 0x00, 0x28, 0x00,
+        0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x18, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x13, 0x0B, 0x00, 0x00, 0x13, 0x0B, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0x00,
+    ];
+
+    /// A small gray image encoded as `format`
+    fn encoded(format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 3, image::Rgb([128, 128, 128])))
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    fn fixtures() -> Vec<(ImageKind, Vec<u8>)> {
+        vec![
+            (ImageKind::Jpeg, encoded(ImageFormat::Jpeg)),
+            (ImageKind::Png, encoded(ImageFormat::Png)),
+            (ImageKind::Tiff, encoded(ImageFormat::Tiff)),
+            (ImageKind::Gif, GIF.to_vec()),
+            (ImageKind::Webp, WEBP.to_vec()),
+            (ImageKind::Bmp, BMP.to_vec()),
+        ]
+    }
+
+    /// `bytes` written to a file in a new directory
+    fn file(name: &str, bytes: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tm-query-format-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn check_bytes(name: &str, bytes: &[u8]) -> Result<ImageKind, CorruptImage> {
+        let path = file(name, bytes);
+        let checked = check(&ImageSource::Files, &path);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        checked
+    }
+
+    #[test]
+    fn each_format_is_detected_from_its_magic_bytes() {
+        for (kind, bytes) in fixtures() {
+            assert_eq!(ImageKind::detect(&bytes), Some(kind));
+        }
+        assert_eq!(ImageKind::detect(b"%PDF-1.4"), None);
+        assert_eq!(ImageKind::detect(&[]), None);
+    }
+
+    #[test]
+    fn each_format_passes_the_check_whatever_its_extension() {
+        for (kind, bytes) in fixtures() {
+            assert_eq!(check_bytes("40201900001X_rep.jpg", &bytes).unwrap(), kind);
+        }
+    }
+
+    #[test]
+    fn unreadable_files_are_corrupt() {
+        assert_eq!(check_bytes("empty.png", &[]).unwrap_err().reason, "empty file");
+        assert_eq!(check_bytes("page.png", b"<html>not found</html>").unwrap_err().reason, "not a known image format");
+        let png = encoded(ImageFormat::Png);
+        let truncated = check_bytes("truncated.png", &png[..12]).unwrap_err();
+        assert!(truncated.reason.starts_with("png header doesn't decode"), "{}", truncated.reason);
+        assert_eq!(truncated.class(), "corrupt_image");
+    }
+
+    #[test]
+    fn files_over_the_limit_are_too_large() {
+        let path = file("large.png", &encoded(ImageFormat::Png));
+        let bytes = std::fs::metadata(&path).unwrap().len();
+        assert!(check_size(&ImageSource::Files, &path, bytes).is_ok());
+        let error = check_size(&ImageSource::Files, &path, bytes - 1).unwrap_err();
+        assert_eq!((error.bytes, error.limit), (bytes, bytes - 1));
+        assert!(check_size(&ImageSource::Files, &path.with_extension("missing"), 0).is_ok());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
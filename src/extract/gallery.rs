@@ -0,0 +1,131 @@
+use anyhow::{Context, Result};
+use base64::{Engine as _, engine::general_purpose};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::report::escape;
+use super::results::{ExtractionResult, Status};
+use crate::images::thumbnail;
+
+/// The gallery of `--gallery`, written next to the results file
+pub const GALLERY_FILE_NAME: &str = "gallery.html";
+
+/// Longest side of a thumbnail made for the gallery, in pixels
+const THUMBNAIL_SIZE: u32 = 200;
+
+/// Largest pre-generated thumbnail embedded as it is; bigger ones are
+/// shrunk like the images without one
+const MAX_THUMBNAIL_BYTES: u64 = 64 * 1024;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}\
+nav{margin-bottom:1em}nav button{margin-right:4px}\
+.grid{display:flex;flex-wrap:wrap;gap:8px}\
+figure{margin:0;width:212px;border:3px solid #ccc;padding:4px;font-size:smaller}\
+figure img{display:block;max-width:200px;max-height:200px;margin:0 auto}\
+figure .noimg{height:60px;line-height:60px;text-align:center;color:#888;background:#f3f3f3}\
+figcaption{word-break:break-all}\
+.match{border-color:#4a4}.mismatch{border-color:#d44}.error{border-color:#999;background:#eee}.unlabeled{border-color:#48c}";
+
+const SCRIPT: &str = "function show(verdict){\
+document.querySelectorAll('figure').forEach(function(cell){\
+cell.style.display=(verdict==='all'||cell.dataset.verdict===verdict)?'':'none';});}";
+
+/// Verdicts a cell can be filtered by, with their button labels
+const VERDICTS: [(&str, &str); 4] = [("match", "Match"), ("mismatch", "Mismatch"), ("error", "Error"), ("unlabeled", "Unlabeled")];
+
+/// How a result is shown in the gallery
+fn verdict(result: &ExtractionResult) -> &'static str {
+    if result.status == Status::Error {
+        "error"
+    } else if result.ground_truth.is_none() {
+        "unlabeled"
+    } else if result.is_match() {
+        "match"
+    } else {
+        "mismatch"
+    }
+}
+
+/// A grid of the images of `results` with their ground truth and
+/// prediction, for looking over what the model read
+pub struct Gallery<'a> {
+    pub title: String,
+    pub results: &'a [ExtractionResult],
+    /// Image of each result, by image name
+    pub images: &'a HashMap<String, PathBuf>,
+    /// Thumbnails already on disk, such as the downloader's `--thumbnails`,
+    /// by image name
+    pub thumbnails: &'a HashMap<String, PathBuf>,
+    /// Most images shown; mismatches and errors come first
+    pub limit: usize,
+}
+
+impl Gallery<'_> {
+    /// Write the gallery as a single HTML file with the thumbnails inlined.
+    /// Returns the number of images shown.
+    pub fn save(&self, path: &Path) -> Result<usize> {
+        let (html, shown) = self.render();
+        fs::write(path, html)
+            .with_context(|| format!("Failed to write gallery: {}", path.display()))?;
+        Ok(shown)
+    }
+
+    pub fn render(&self) -> (String, usize) {
+        // Stable, so each verdict keeps the order of the results
+        let mut shown: Vec<&ExtractionResult> = self.results.iter().collect();
+        shown.sort_by_key(|result| matches!(verdict(result), "match" | "unlabeled"));
+        shown.truncate(self.limit);
+
+        let mut html = String::new();
+        let _ = write!(html, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title>\
+            <style>{}</style><script>{}</script></head><body>\n<h1>{}</h1>\n",
+            escape(&self.title), STYLE, SCRIPT, escape(&self.title));
+        if shown.len() < self.results.len() {
+            let _ = writeln!(html, "<p>{} of {} images, mismatches and errors first.</p>", shown.len(), self.results.len());
+        }
+        html.push_str("<nav><button onclick=\"show('all')\">All</button>");
+        for (verdict, label) in VERDICTS {
+            let count = shown.iter().filter(|result| self::verdict(result) == verdict).count();
+            let _ = write!(html, "<button onclick=\"show('{}')\">{} ({})</button>", verdict, label, count);
+        }
+        html.push_str("</nav>\n<div class=\"grid\">\n");
+        for result in &shown {
+            let verdict = verdict(result);
+            let image = self.thumbnail(&result.image_name)
+                .map_or("<div class=\"noimg\">image unavailable</div>".to_string(), |(media_type, bytes)| {
+                    format!("<img src=\"data:{};base64,{}\" alt=\"\" loading=\"lazy\">", media_type, general_purpose::STANDARD.encode(bytes))
+                });
+            let prediction = match result.status {
+                Status::Error => result.error_class.as_deref().unwrap_or("error"),
+                _ => result.chinese_character.as_deref().unwrap_or(""),
+            };
+            let _ = writeln!(html, "<figure class=\"{}\" data-verdict=\"{}\">{}<figcaption><b>{}</b><br>Truth: {}<br>Predicted: {}</figcaption></figure>",
+                verdict, verdict, image,
+                escape(&result.image_name),
+                escape(result.ground_truth.as_deref().unwrap_or("")),
+                escape(prediction));
+        }
+        html.push_str("</div>\n</body></html>\n");
+        (html, shown.len())
+    }
+
+    /// The image's thumbnail and its media type: the one on disk when it is
+    /// small enough, otherwise one made from it or from the image. `None`
+    /// when neither can be read.
+    fn thumbnail(&self, image_name: &str) -> Option<(&'static str, Vec<u8>)> {
+        if let Some(path) = self.thumbnails.get(image_name) {
+            let small = path.metadata().is_ok_and(|metadata| metadata.len() <= MAX_THUMBNAIL_BYTES);
+            let is_png = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+            if small && let Ok(bytes) = fs::read(path) {
+                return Some((if is_png { "image/png" } else { "image/jpeg" }, bytes));
+            }
+            if let Ok(bytes) = thumbnail::thumbnail_jpeg(path, THUMBNAIL_SIZE) {
+                return Some(("image/jpeg", bytes));
+            }
+        }
+        let path = self.images.get(image_name)?;
+        thumbnail::thumbnail_jpeg(path, THUMBNAIL_SIZE).ok().map(|bytes| ("image/jpeg", bytes))
+    }
+}
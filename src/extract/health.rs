@@ -0,0 +1,203 @@
+use anyhow::Result;
+use chrono::Local;
+use reqwest::header::SERVER;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use super::protocol::{Backend, Protocol};
+
+/// Time the check of an endpoint may take
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Models named when `--model` isn't among them
+const LISTED_EXAMPLES: usize = 10;
+
+/// Outcome of checking an endpoint before a run, recorded in the metrics file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    pub base_url: String,
+    /// URL that was checked
+    pub url: String,
+    /// HTTP status of the reply; `null` when the server couldn't be reached
+    pub status: Option<u16>,
+    /// `Server` header of the reply, or the version Ollama reports
+    pub server: Option<String>,
+    /// Models the server lists; `null` when it lists none
+    pub models: Option<usize>,
+    /// Whether `--model` is among them; `null` when it can't be told
+    pub model_found: Option<bool>,
+    pub elapsed_ms: u64,
+    /// When the check was made, RFC 3339
+    pub checked_at: String,
+    /// Why the endpoint can't be used, with what to do about it
+    pub error: Option<String>,
+}
+
+impl HealthCheck {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Check that `backend` answers, and serves its model where the protocol
+/// lists models. Any reply counts as up, except an error that says the
+/// requests would fail as well: a refused key, a server error other than
+/// 501 for a method the server doesn't take, or for the invoke protocol
+/// nothing at the URL.
+pub async fn check(client: &reqwest::Client, backend: &Backend) -> HealthCheck {
+    let started = Instant::now();
+    let mut check = HealthCheck {
+        base_url: backend.base_url.clone(),
+        url: backend.probe_url(),
+        status: None,
+        server: None,
+        models: None,
+        model_found: None,
+        elapsed_ms: 0,
+        checked_at: Local::now().to_rfc3339(),
+        error: None,
+    };
+
+    match backend.probe(client).timeout(TIMEOUT).send().await {
+        Err(e) => {
+            check.error = Some(if e.is_timeout() {
+                format!("no reply within {}s: is the server at {} up and reachable?", TIMEOUT.as_secs(), backend.base_url)
+            } else if e.is_connect() {
+                format!("connection refused: is the server running at {}?", backend.base_url)
+            } else {
+                format!("request failed: {}", e)
+            });
+        }
+        Ok(response) => {
+            let status = response.status();
+            check.status = Some(status.as_u16());
+            check.server = response.headers().get(SERVER).and_then(|v| v.to_str().ok()).map(String::from);
+            let body = response.text().await.unwrap_or_default();
+            check.error = if status.as_u16() == 401 || status.as_u16() == 403 {
+                Some(format!("the server refused the API key (HTTP {}): check --api-key", status.as_u16()))
+            } else if status.is_server_error() && status.as_u16() != 501 {
+                Some(format!("server error (HTTP {}): {}", status.as_u16(), body.trim()))
+            } else if backend.protocol == Protocol::Invoke && status.as_u16() == 404 {
+                Some(format!("nothing at {} (HTTP 404): is --base-url right?", check.url))
+            } else {
+                None
+            };
+            if status.is_success()
+                && let Some(listed) = backend.listed_models(&body)
+            {
+                check.models = Some(listed.len());
+                if let Some(model) = &backend.model {
+                    let found = backend.lists_model(&listed, model);
+                    check.model_found = Some(found);
+                    if !found {
+                        check.error = Some(format!("model {} is not served at {}; it has {}{}", model, backend.base_url,
+                            listed.iter().take(LISTED_EXAMPLES).cloned().collect::<Vec<_>>().join(", "),
+                            if listed.len() > LISTED_EXAMPLES { ", ..." } else { "" }));
+                    }
+                }
+            }
+        }
+    }
+
+    if backend.protocol == Protocol::Ollama && check.server.is_none() && check.status.is_some() {
+        check.server = ollama_version(client, backend).await;
+    }
+    check.elapsed_ms = started.elapsed().as_millis() as u64;
+    check
+}
+
+/// Check each of the endpoints a run may send to. A fallback that is down
+/// only matters once every endpoint is, but a missing model is a mistake
+/// in the command, so either fails the run.
+pub async fn check_all(client: &reqwest::Client, routes: &[Backend]) -> Result<Vec<HealthCheck>> {
+    let mut checks = Vec::new();
+    for route in routes {
+        let check = check(client, route).await;
+        match &check.error {
+            None => info!("Health check of {}: HTTP {} in {} ms{}{}",
+                check.url, check.status.unwrap_or_default(), check.elapsed_ms,
+                check.server.as_ref().map_or(String::new(), |server| format!(", server {}", server)),
+                check.models.map_or(String::new(), |models| format!(", {} models listed", models))),
+            Some(error) => warn!("Health check of {} failed: {}", check.url, error),
+        }
+        checks.push(check);
+    }
+    let fatal = checks.iter().find(|check| check.model_found == Some(false))
+        .or_else(|| checks.iter().all(|check| !check.passed()).then(|| checks.first()).flatten());
+    if let Some(check) = fatal {
+        anyhow::bail!("Health check of {} failed: {}\n(--skip-health-check runs without it, for servers that don't answer it)",
+            check.url, check.error.as_deref().unwrap_or_default());
+    }
+    Ok(checks)
+}
+
+/// `ollama <version>` as reported by `/api/version`
+async fn ollama_version(client: &reqwest::Client, backend: &Backend) -> Option<String> {
+    let response = client.get(format!("{}/api/version", backend.base_url)).timeout(TIMEOUT).send().await.ok()?;
+    let value: serde_json::Value = response.json().await.ok()?;
+    Some(format!("ollama {}", value.get("version")?.as_str()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::protocol::{Field, Generation, USER_PROMPT};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn backend(base_url: String) -> Backend {
+        Backend {
+            protocol: Protocol::Invoke,
+            base_url,
+            model: None,
+            api_key: None,
+            prompt: String::new(),
+            user_prompt: USER_PROMPT,
+            generation: Generation::default(),
+            json_mode: true,
+            fields: Field::ALL.to_vec(),
+            image_encoding: Protocol::Invoke.default_image_encoding(),
+            few_shot: None,
+        }
+    }
+
+    /// Base URL of a server answering every request with 200
+    async fn up() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0u8; 4096];
+                let _ = stream.read(&mut request).await;
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await;
+            }
+        });
+        url
+    }
+
+    /// Base URL nothing listens at
+    async fn down() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    #[tokio::test]
+    async fn a_run_goes_ahead_while_one_endpoint_is_up() {
+        let client = reqwest::Client::new();
+        let routes = [backend(down().await), backend(up().await)];
+
+        let checks = check_all(&client, &routes).await.unwrap();
+        assert_eq!(checks.iter().map(HealthCheck::passed).collect::<Vec<_>>(), [false, true]);
+        assert_eq!(checks[1].status, Some(200));
+    }
+
+    #[tokio::test]
+    async fn a_run_stops_when_every_endpoint_is_down() {
+        let client = reqwest::Client::new();
+        let routes = [backend(down().await), backend(down().await)];
+
+        let error = check_all(&client, &routes).await.unwrap_err().to_string();
+        assert!(error.contains("connection refused"), "{}", error);
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+use tokio::signal;
+use tokio::sync::watch;
+use tokio::task::AbortHandle;
+use tracing::{error, warn};
+
+/// Time the images in flight get to finish after Ctrl+C
+pub const GRACE_PERIOD: Duration = Duration::from_secs(15);
+
+/// Exit code of a run stopped by Ctrl+C, as a shell reports SIGINT
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Ctrl+C during a run: the first stops sending images, the second quits
+/// at once. Dropping it stops listening.
+#[derive(Debug)]
+pub struct Interrupt {
+    received: watch::Receiver<bool>,
+    listener: AbortHandle,
+}
+
+impl Interrupt {
+    /// Listen for Ctrl+C in the background
+    pub fn listen() -> Self {
+        let (sender, received) = watch::channel(false);
+        let listener = tokio::spawn(async move {
+            if signal::ctrl_c().await.is_err() {
+                return;
+            }
+            warn!("Interrupted: sending no more images and waiting up to {}s for the ones in flight; press Ctrl+C again to quit now",
+                GRACE_PERIOD.as_secs());
+            let _ = sender.send(true);
+            if signal::ctrl_c().await.is_ok() {
+                error!("Interrupted again, quitting without waiting for the images in flight");
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+        });
+        Interrupt { received, listener: listener.abort_handle() }
+    }
+
+    /// An interrupt set by sending `true` rather than by Ctrl+C
+    #[cfg(test)]
+    pub(crate) fn manual() -> (Self, watch::Sender<bool>) {
+        let (sender, received) = watch::channel(false);
+        let listener = tokio::spawn(std::future::pending::<()>());
+        (Interrupt { received, listener: listener.abort_handle() }, sender)
+    }
+
+    pub fn is_set(&self) -> bool {
+        *self.received.borrow()
+    }
+
+    /// Wait for the first Ctrl+C; never returns if the handler couldn't be
+    /// installed
+    pub async fn wait(&self) {
+        let mut received = self.received.clone();
+        if received.wait_for(|&set| set).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl Drop for Interrupt {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::drift::MarkRecord;
+use super::parse::ParseMethod;
+use super::protocol::Generation;
+use super::results::{ExtractionResult, Status};
+use crate::data::{DayRecord, MarkText};
+
+/// The results in the shape of `--output-schema`, written next to the
+/// results file
+pub const IPOS_RESULTS_FILE_NAME: &str = "results_ipos.json";
+
+/// `extractionSource` of every item, telling its fields from the official ones
+pub const EXTRACTION_SOURCE: &str = "llm";
+
+/// Other shapes the results can be written in besides the results file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputSchema {
+    /// Items of the IPOS trademark API in day records, as the downloader
+    /// writes them
+    Ipos,
+}
+
+/// One result as an item of the API, its predictions in place of the
+/// mark's official text
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Item<'a> {
+    application_num: &'a str,
+    mark_index: [MarkText; 1],
+    extraction_source: &'static str,
+    extraction: Extraction<'a>,
+}
+
+/// What produced the predictions of an item
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Extraction<'a> {
+    image_name: &'a str,
+    model: &'a str,
+    config_id: &'a str,
+    prompt_hash: Option<&'a str>,
+    generation: Option<&'a Generation>,
+    parse_method: Option<ParseMethod>,
+    extracted_at: Option<&'a str>,
+}
+
+/// Outcome of writing the results in the IPOS schema
+#[derive(Debug, Default)]
+pub struct IposOutput {
+    pub items: usize,
+    /// Failed results, which have no predictions and are left out
+    pub failed: usize,
+    /// Images whose application number couldn't be derived, left out
+    pub unidentified: Vec<String>,
+}
+
+/// Write `results` to `output` as a JSON array of day records like the
+/// downloader's data file, one item per answered image with its
+/// application number from `records`. Records are grouped by lodgement
+/// date, images without one going into a record with an empty date.
+pub fn write(results: &[ExtractionResult], records: &HashMap<String, MarkRecord>, model: &str, output: &Path) -> Result<IposOutput> {
+    let mut written = IposOutput::default();
+    let mut days: BTreeMap<&str, Vec<serde_json::Value>> = BTreeMap::new();
+    for result in results {
+        if result.status == Status::Error {
+            written.failed += 1;
+            continue;
+        }
+        let Some(record) = records.get(&result.image_name) else {
+            written.unidentified.push(result.image_name.clone());
+            continue;
+        };
+        let item = Item {
+            application_num: &record.application_num,
+            mark_index: [MarkText {
+                words_in_mark: result.words_in_mark.clone(),
+                chinese_character: result.chinese_character.clone(),
+                descr_of_device: result.description_of_device.clone(),
+            }],
+            extraction_source: EXTRACTION_SOURCE,
+            extraction: Extraction {
+                image_name: &result.image_name,
+                model,
+                config_id: &result.config_id,
+                prompt_hash: result.prompt_hash.as_deref(),
+                generation: result.generation.as_ref(),
+                parse_method: result.parse_method,
+                extracted_at: result.completed_at.as_deref(),
+            },
+        };
+        days.entry(record.lodgement_date.as_deref().unwrap_or_default())
+            .or_default()
+            .push(serde_json::to_value(item)?);
+        written.items += 1;
+    }
+    let records: Vec<DayRecord> = days.into_iter()
+        .map(|(date, items)| DayRecord { date: date.to_string(), count: items.len() as u32, items })
+        .collect();
+
+    let tmp_path = output.with_extension("json.tmp");
+    let file = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create IPOS results file: {}", tmp_path.display()))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, &records).context("Failed to write IPOS results file")?;
+    writer.flush().context("Failed to write IPOS results file")?;
+    fs::rename(&tmp_path, output)
+        .with_context(|| format!("Failed to save IPOS results file: {}", output.display()))?;
+    Ok(written)
+}
@@ -0,0 +1,453 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::data::MarkText;
+use crate::images::sidecar::Sidecar;
+
+use super::breakdown::BucketBounds;
+use super::dataset::DatasetEntry;
+use super::dedup;
+use super::format::{self, CorruptImage, TooLarge};
+use super::results::{Checkpoint, Provenance};
+use super::sample::{self, SampleManifest, Stratify};
+use super::source::ImageSource;
+
+/// One image scheduled for extraction
+#[derive(Debug, Clone)]
+pub struct Job {
+    /// Position of the image in the sample
+    pub index: usize,
+    pub image_name: String,
+    pub image_path: PathBuf,
+    pub ground_truth: Option<String>,
+    pub ground_truth_words: Option<String>,
+    pub ground_truth_device: Option<String>,
+    pub provenance: Provenance,
+}
+
+/// How the images of a run are picked from the dataset
+#[derive(Debug, Clone, Copy)]
+pub enum Sampling<'a> {
+    /// Every entry, the mismatches of `--mismatches-only`
+    All,
+    /// The failed images of `--retry-failed`, listed in `from`
+    Retry { names: &'a HashSet<String>, from: &'a Path },
+    /// The sample recorded by an earlier run, `--reuse-sample`
+    Reuse(&'a Path),
+    /// A new sample of `size` images, spread over strata with `stratify`
+    Fresh {
+        size: usize,
+        seed: u64,
+        stratify: Option<Stratify>,
+        buckets: &'a BucketBounds,
+        equal_strata: bool,
+    },
+}
+
+/// Pick the entries of `data` to process. A fresh sample comes with the
+/// manifest to save so it can be reused, naming `dataset`.
+pub fn pick<'a>(data: &'a [DatasetEntry], sampling: Sampling, dataset: &Path) -> Result<(Vec<&'a DatasetEntry>, Option<SampleManifest>)> {
+    match sampling {
+        Sampling::All => {
+            info!("Querying again the {} mismatched images", data.len());
+            Ok((data.iter().collect(), None))
+        }
+        Sampling::Retry { names, from } => {
+            let entries: Vec<_> = data.iter().filter(|entry| names.contains(&entry.image_name)).collect();
+            if entries.len() < names.len() {
+                warn!("{} failed images are not in the dataset", names.len() - entries.len());
+            }
+            info!("Retrying the {} failed images of {}", entries.len(), from.display());
+            Ok((entries, None))
+        }
+        Sampling::Reuse(path) => {
+            let manifest = SampleManifest::load(path)?;
+            let entries = manifest.select(data, |entry| entry.image_name.as_str());
+            if entries.len() < manifest.image_names.len() {
+                warn!("{} images of the sample are not in the dataset", manifest.image_names.len() - entries.len());
+            }
+            info!("Reusing the sample of {} images from {}", entries.len(), path.display());
+            Ok((entries, None))
+        }
+        Sampling::Fresh { size, seed, stratify, buckets, equal_strata } => {
+            let (entries, strata) = match stratify {
+                Some(by) => {
+                    let (entries, strata) = sample::stratified_by(data, |entry| entry.chinese_character.as_deref(), by,
+                        buckets, size, seed, equal_strata);
+                    info!("Stratified by {}", strata.describe());
+                    (entries, Some(strata))
+                }
+                None => (sample::sample(data, size, seed), None),
+            };
+            let manifest = SampleManifest {
+                dataset: dataset.to_path_buf(),
+                seed,
+                image_names: entries.iter().map(|entry| entry.image_name.clone()).collect(),
+                strata,
+            };
+            Ok((entries, Some(manifest)))
+        }
+    }
+}
+
+/// The results of `config_id` already in `output`, less those of the
+/// images in `redo`, which are processed again
+pub fn resume(output: &Path, config_id: &str, redo: &[&HashSet<String>]) -> Result<Checkpoint> {
+    let mut checkpoint = Checkpoint::load(output, config_id)?;
+    for names in redo {
+        checkpoint.forget(names);
+    }
+    if checkpoint.other_config > 0 {
+        warn!("Ignoring {} results in {} from a different configuration", checkpoint.other_config, output.display());
+    }
+    if checkpoint.malformed > 0 {
+        warn!("Ignoring {} unreadable lines in {}", checkpoint.malformed, output.display());
+    }
+    Ok(checkpoint)
+}
+
+/// Which images the run wants by whether they have ground truth characters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Labels {
+    /// `--include-unlabeled`
+    pub include_unlabeled: bool,
+    /// `--only-unlabeled`
+    pub only_unlabeled: bool,
+    /// The run extracts the characters; without them, labels don't matter
+    pub chinese: bool,
+}
+
+impl Labels {
+    fn wants(self, labeled: bool) -> bool {
+        if labeled {
+            !self.only_unlabeled
+        } else {
+            self.include_unlabeled || self.only_unlabeled || !self.chinese
+        }
+    }
+}
+
+/// What became of the images of the sample before the run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Selected {
+    /// Images already in the results file with `--resume`
+    pub resumed: usize,
+    /// Images not on disk
+    pub missing: usize,
+    /// Images without ground truth characters, processed or left out
+    pub unlabeled: usize,
+    /// Images left out by `--include-unlabeled` and `--only-unlabeled`
+    pub left_out: usize,
+}
+
+/// The jobs of the sampled `entries` still to process: those not in the
+/// checkpoint, on disk and labeled as the run asks for
+pub fn select(
+    entries: &[&DatasetEntry],
+    checkpoint: &Checkpoint,
+    source: &ImageSource,
+    images_dir: &Path,
+    labels: Labels,
+) -> (Vec<Job>, Selected) {
+    let mut selected = Selected::default();
+    let mut jobs = Vec::new();
+
+    for (index, entry) in entries.iter().enumerate() {
+        if checkpoint.contains(&entry.image_name) {
+            selected.resumed += 1;
+            continue;
+        }
+
+        let image_path = entry.image_path.clone()
+            .unwrap_or_else(|| source.image_path(images_dir, &entry.image_name));
+
+        // Skip if file doesn't exist
+        if !source.exists(&image_path) {
+            warn!("Image not found: {:?}", image_path);
+            selected.missing += 1;
+            continue;
+        }
+
+        // Get the mark's text, preferring the image's sidecar, and skip
+        // unlabeled images unless asked for them
+        let sidecar = Sidecar::load(&image_path);
+        let provenance = entry.provenance(sidecar.as_ref());
+        let mark = match sidecar {
+            Some(sidecar) => sidecar.mark,
+            None => MarkText {
+                words_in_mark: entry.words_in_mark.clone(),
+                chinese_character: entry.chinese_character.clone(),
+                descr_of_device: entry.descr_of_device.clone(),
+            },
+        };
+        let chinese_chars = mark.chinese_character;
+        selected.unlabeled += usize::from(chinese_chars.is_none());
+        if !labels.wants(chinese_chars.is_some()) {
+            selected.left_out += 1;
+            continue;
+        }
+
+        jobs.push(Job {
+            index,
+            image_name: entry.image_name.clone(),
+            image_path,
+            ground_truth: chinese_chars,
+            ground_truth_words: mark.words_in_mark,
+            ground_truth_device: mark.descr_of_device,
+            provenance,
+        });
+    }
+    (jobs, selected)
+}
+
+/// Split off the jobs whose file is over `limit` bytes
+pub fn split_too_large(source: &ImageSource, jobs: Vec<Job>, limit: u64) -> (Vec<Job>, Vec<(Job, TooLarge)>) {
+    let mut kept = Vec::new();
+    let mut too_large = Vec::new();
+    for job in jobs {
+        match format::check_size(source, &job.image_path, limit) {
+            Ok(()) => kept.push(job),
+            Err(e) => too_large.push((job, e)),
+        }
+    }
+    (kept, too_large)
+}
+
+/// Split off the jobs whose file can't be read as an image, checking
+/// `workers` at a time. More than `strict` percent of them unreadable, as
+/// with a wrong `--images-dir`, fails the run.
+pub async fn split_unreadable(
+    source: &ImageSource,
+    jobs: Vec<Job>,
+    workers: usize,
+    strict: Option<f64>,
+) -> Result<(Vec<Job>, Vec<(Job, CorruptImage)>)> {
+    let checks = format::check_all(source, jobs.iter().map(|job| job.image_path.clone()).collect(), workers).await;
+    let mut readable = Vec::new();
+    let mut corrupt = Vec::new();
+    for (job, check) in jobs.into_iter().zip(checks) {
+        match check {
+            Ok(_) => readable.push(job),
+            Err(e) => corrupt.push((job, e)),
+        }
+    }
+    if !corrupt.is_empty() {
+        let checked = readable.len() + corrupt.len();
+        let share = 100.0 * corrupt.len() as f64 / checked as f64;
+        info!("{} of {} images are corrupt or unreadable ({:.1}%) and won't be sent",
+            corrupt.len(), checked, share);
+        if let Some(limit) = strict
+            && share > limit
+        {
+            anyhow::bail!("{:.1}% of the images are corrupt, more than the {}% allowed by --strict-images; is --images-dir right?",
+                share, limit);
+        }
+    }
+    Ok((readable, corrupt))
+}
+
+/// Group the jobs of images with identical contents, each group a job to
+/// send and the duplicates that reuse its result. Without `dedup` every job
+/// is a group of its own.
+pub async fn group(source: &ImageSource, jobs: Vec<Job>, workers: usize, dedup: bool) -> Vec<(Job, Vec<Job>)> {
+    let groups = if dedup {
+        dedup::group_duplicates(source, jobs.iter().map(|job| job.image_path.clone()).collect(), workers).await
+    } else {
+        (0..jobs.len()).map(|i| vec![i]).collect()
+    };
+    let mut jobs: Vec<Option<Job>> = jobs.into_iter().map(Some).collect();
+    let groups: Vec<(Job, Vec<Job>)> = groups.into_iter()
+        .filter_map(|group| {
+            let mut members = group.into_iter().filter_map(|i| jobs[i].take());
+            members.next().map(|job| (job, members.collect()))
+        })
+        .collect();
+    let duplicates = groups.iter().filter(|(_, duplicates)| !duplicates.is_empty()).count();
+    if duplicates > 0 {
+        info!("Found {} groups of identical images; sending {} of {} images",
+            duplicates, groups.len(), jobs.len());
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tm-query-jobs-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Entries `0.jpg`, `1.jpg`, ..., the odd ones labeled
+    fn entries(count: usize) -> Vec<DatasetEntry> {
+        let entries: Vec<_> = (0..count)
+            .map(|n| serde_json::json!({"imageName": format!("{}.jpg", n), "chineseCharacter": (n % 2 == 1).then_some("天")}))
+            .collect();
+        serde_json::from_value(serde_json::Value::Array(entries)).unwrap()
+    }
+
+    fn names<'a>(entries: impl IntoIterator<Item = &'a DatasetEntry>) -> Vec<&'a str> {
+        entries.into_iter().map(|entry| entry.image_name.as_str()).collect()
+    }
+
+    fn buckets() -> BucketBounds {
+        BucketBounds { length_buckets: vec![1], size_buckets_kb: Vec::new(), dimension_buckets: Vec::new(), min_bucket_size: 0 }
+    }
+
+    #[test]
+    fn a_fresh_sample_comes_with_its_manifest() {
+        let data = entries(20);
+        let buckets = buckets();
+        let fresh = Sampling::Fresh { size: 5, seed: 42, stratify: None, buckets: &buckets, equal_strata: false };
+        let (picked, manifest) = pick(&data, fresh, Path::new("cleaned_data.json")).unwrap();
+        let manifest = manifest.unwrap();
+        assert_eq!(picked.len(), 5);
+        assert_eq!(manifest.image_names, names(picked.iter().copied()));
+        assert_eq!((manifest.dataset.as_path(), manifest.seed), (Path::new("cleaned_data.json"), 42));
+        assert!(manifest.strata.is_none());
+        assert_eq!(names(pick(&data, fresh, Path::new("")).unwrap().0), manifest.image_names);
+
+        // Only labeled images are stratified
+        let stratified = Sampling::Fresh { size: 5, seed: 42, stratify: Some(Stratify::CharCount), buckets: &buckets, equal_strata: false };
+        let (picked, manifest) = pick(&data, stratified, Path::new("cleaned_data.json")).unwrap();
+        assert!(picked.iter().all(|entry| entry.chinese_character.is_some()));
+        assert!(manifest.unwrap().strata.is_some());
+    }
+
+    #[test]
+    fn a_reused_sample_or_a_retry_picks_the_images_it_names() {
+        let data = entries(10);
+        let dir = temp_dir();
+        let path = dir.join("sample_manifest.json");
+        let manifest = SampleManifest {
+            dataset: PathBuf::from("cleaned_data.json"),
+            seed: 1,
+            image_names: vec!["7.jpg".to_string(), "gone.jpg".to_string(), "2.jpg".to_string()],
+            strata: None,
+        };
+        manifest.save(&path).unwrap();
+        let (picked, manifest) = pick(&data, Sampling::Reuse(&path), Path::new("")).unwrap();
+        assert_eq!(names(picked), ["7.jpg", "2.jpg"]);
+        assert!(manifest.is_none());
+
+        let failed: HashSet<String> = ["3.jpg", "gone.jpg"].map(String::from).into();
+        let (picked, _) = pick(&data, Sampling::Retry { names: &failed, from: &dir }, Path::new("")).unwrap();
+        assert_eq!(names(picked), ["3.jpg"]);
+        assert_eq!(pick(&data, Sampling::All, Path::new("")).unwrap().0.len(), 10);
+    }
+
+    #[test]
+    fn resuming_forgets_the_images_to_redo() {
+        let dir = temp_dir();
+        let output = dir.join("results.jsonl");
+        let line = |image_name: &str, config_id: &str| serde_json::json!({
+            "image_name": image_name, "chinese_character": "天", "status": "ok", "error": null, "elapsed_ms": 1, "config_id": config_id,
+        }).to_string();
+        fs::write(&output, [line("1.jpg", "abc"), line("2.jpg", "abc"), line("3.jpg", "abc"), line("4.jpg", "other")].join("\n")).unwrap();
+
+        let failed: HashSet<String> = ["2.jpg".to_string()].into();
+        let skipped: HashSet<String> = ["3.jpg".to_string()].into();
+        let checkpoint = resume(&output, "abc", &[&failed, &skipped]).unwrap();
+        assert!(checkpoint.contains("1.jpg"));
+        assert!(!checkpoint.contains("2.jpg") && !checkpoint.contains("3.jpg") && !checkpoint.contains("4.jpg"));
+        assert_eq!(checkpoint.results.len(), 1);
+        assert_eq!(checkpoint.other_config, 1);
+    }
+
+    #[test]
+    fn jobs_leave_out_resumed_missing_and_unwanted_images() {
+        let dir = temp_dir();
+        let data = entries(6);
+        for entry in &data[..5] {
+            fs::write(dir.join(&entry.image_name), b"image").unwrap();
+        }
+        let sample: Vec<&DatasetEntry> = data.iter().collect();
+        let output = dir.join("results.jsonl");
+        fs::write(&output, serde_json::json!({
+            "image_name": "0.jpg", "chinese_character": null, "status": "ok", "error": null, "elapsed_ms": 1, "config_id": "abc",
+        }).to_string()).unwrap();
+        let checkpoint = Checkpoint::load(&output, "abc").unwrap();
+
+        // 0 resumed, 5 missing, 2 and 4 unlabeled
+        let labeled = Labels { chinese: true, ..Labels::default() };
+        let (jobs, selected) = select(&sample, &checkpoint, &ImageSource::Files, &dir, labeled);
+        assert_eq!(jobs.iter().map(|job| job.image_name.as_str()).collect::<Vec<_>>(), ["1.jpg", "3.jpg"]);
+        assert_eq!(jobs.iter().map(|job| job.index).collect::<Vec<_>>(), [1, 3]);
+        assert_eq!(jobs[0].image_path, dir.join("1.jpg"));
+        assert_eq!(jobs[0].ground_truth.as_deref(), Some("天"));
+        assert_eq!(selected, Selected { resumed: 1, missing: 1, unlabeled: 2, left_out: 2 });
+
+        let only_unlabeled = Labels { only_unlabeled: true, ..labeled };
+        let (jobs, _) = select(&sample, &checkpoint, &ImageSource::Files, &dir, only_unlabeled);
+        assert_eq!(jobs.iter().map(|job| job.image_name.as_str()).collect::<Vec<_>>(), ["2.jpg", "4.jpg"]);
+        let (jobs, selected) = select(&sample, &checkpoint, &ImageSource::Files, &dir, Labels::default());
+        assert_eq!((jobs.len(), selected.left_out), (4, 0));
+    }
+
+    #[test]
+    fn files_over_the_limit_are_split_off() {
+        let dir = temp_dir();
+        let data = entries(2);
+        fs::write(dir.join("0.jpg"), [0; 10]).unwrap();
+        fs::write(dir.join("1.jpg"), [0; 100]).unwrap();
+        let sample: Vec<&DatasetEntry> = data.iter().collect();
+        let (jobs, _) = select(&sample, &Checkpoint::default(), &ImageSource::Files, &dir, Labels::default());
+
+        let (kept, too_large) = split_too_large(&ImageSource::Files, jobs, 50);
+        assert_eq!(kept.iter().map(|job| job.image_name.as_str()).collect::<Vec<_>>(), ["0.jpg"]);
+        assert_eq!(too_large.len(), 1);
+        assert_eq!((too_large[0].0.image_name.as_str(), too_large[0].1.bytes), ("1.jpg", 100));
+    }
+
+    /// Jobs of the files `contents` written as `0.jpg`, `1.jpg`, ...
+    fn jobs_of(dir: &Path, contents: &[&[u8]]) -> Vec<Job> {
+        let data = entries(contents.len());
+        for (entry, bytes) in data.iter().zip(contents) {
+            fs::write(dir.join(&entry.image_name), bytes).unwrap();
+        }
+        let sample: Vec<&DatasetEntry> = data.iter().collect();
+        select(&sample, &Checkpoint::default(), &ImageSource::Files, dir, Labels::default()).0
+    }
+
+    #[tokio::test]
+    async fn unreadable_files_are_split_off_and_too_many_fail_the_run() {
+        let dir = temp_dir();
+        let png = {
+            let mut bytes = Vec::new();
+            image::RgbImage::new(1, 1).write_to(&mut std::io::Cursor::new(&mut bytes), image::ImageFormat::Png).unwrap();
+            bytes
+        };
+        let contents: [&[u8]; 4] = [&png, b"not an image", &png, &png];
+
+        let (readable, corrupt) = split_unreadable(&ImageSource::Files, jobs_of(&dir, &contents), 2, None).await.unwrap();
+        assert_eq!(readable.iter().map(|job| job.image_name.as_str()).collect::<Vec<_>>(), ["0.jpg", "2.jpg", "3.jpg"]);
+        assert_eq!(corrupt.iter().map(|(job, _)| job.image_name.as_str()).collect::<Vec<_>>(), ["1.jpg"]);
+
+        // One in four is 25%
+        assert!(split_unreadable(&ImageSource::Files, jobs_of(&dir, &contents), 2, Some(25.0)).await.is_ok());
+        assert!(split_unreadable(&ImageSource::Files, jobs_of(&dir, &contents), 2, Some(20.0)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn identical_images_are_grouped_behind_the_first() {
+        let dir = temp_dir();
+        let contents: [&[u8]; 4] = [b"a", b"b", b"a", b"a"];
+        let names = |groups: &[(Job, Vec<Job>)]| groups.iter()
+            .map(|(job, duplicates)| (job.image_name.clone(), duplicates.iter().map(|job| job.image_name.clone()).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+
+        let groups = group(&ImageSource::Files, jobs_of(&dir, &contents), 2, true).await;
+        assert_eq!(names(&groups), [
+            ("0.jpg".to_string(), vec!["2.jpg".to_string(), "3.jpg".to_string()]),
+            ("1.jpg".to_string(), vec![]),
+        ]);
+        let groups = group(&ImageSource::Files, jobs_of(&dir, &contents), 2, false).await;
+        assert_eq!(groups.len(), 4);
+        assert!(groups.iter().all(|(_, duplicates)| duplicates.is_empty()));
+    }
+}
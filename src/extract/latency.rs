@@ -0,0 +1,159 @@
+use serde::Serialize;
+
+/// Images listed as the slowest
+pub const SLOWEST_IMAGES: usize = 10;
+
+/// Length of one throughput window, in seconds
+const WINDOW_SECS: u64 = 60;
+
+/// Request latencies of a run, and when its results came in
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Latencies {
+    /// Network and model time of each image that made requests, with its name
+    requests: Vec<(String, u64)>,
+    /// Local preparation time of each image that was sent
+    preprocess: Vec<u64>,
+    /// When each result of this run was recorded, in ms since it started
+    completions: Vec<u64>,
+    /// Request time and images of each `--batch-size` request of this run
+    batches: Vec<(u64, usize)>,
+}
+
+/// Request latency percentiles, as reported and saved in the metrics file
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencySummary {
+    /// Images the percentiles cover
+    pub images: usize,
+    pub mean_ms: f64,
+    pub p50_ms: u64,
+    pub p90_ms: u64,
+    pub p99_ms: u64,
+    pub max_ms: u64,
+    /// Mean time spent preparing an image before sending it
+    pub mean_preprocess_ms: Option<f64>,
+    /// Slowest images, slowest first
+    pub slowest: Vec<SlowImage>,
+    /// Whole requests of `--batch-size`, whose time the images above share
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batches: Option<BatchLatency>,
+}
+
+/// Request latency percentiles of the batches, each counted once
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchLatency {
+    pub batches: usize,
+    pub mean_images: f64,
+    pub mean_ms: f64,
+    pub p50_ms: u64,
+    pub p90_ms: u64,
+    pub max_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowImage {
+    pub image_name: String,
+    pub request_ms: u64,
+}
+
+/// Results recorded in one minute of the run
+#[derive(Debug, Clone, Serialize)]
+pub struct ThroughputWindow {
+    /// Start of the window, in seconds since the run started
+    pub start_secs: u64,
+    pub images: usize,
+    /// Over the part of the window the run lasted, for the last one
+    pub images_per_minute: f64,
+}
+
+impl Latencies {
+    /// Count the request and preparation time of an image; either is `None`
+    /// when the image made no request
+    pub fn record(&mut self, image_name: &str, request_ms: Option<u64>, preprocess_ms: Option<u64>) {
+        if let Some(ms) = request_ms {
+            self.requests.push((image_name.to_string(), ms));
+        }
+        self.preprocess.extend(preprocess_ms);
+    }
+
+    /// Count a batch request of `images` images taking `request_ms` over
+    /// its attempts
+    pub fn record_batch(&mut self, request_ms: u64, images: usize) {
+        self.batches.push((request_ms, images));
+    }
+
+    /// Count a result recorded `elapsed_ms` after the run started
+    pub fn record_completion(&mut self, elapsed_ms: u64) {
+        self.completions.push(elapsed_ms);
+    }
+
+    pub fn summary(&self) -> Option<LatencySummary> {
+        let mut sorted: Vec<u64> = self.requests.iter().map(|(_, ms)| *ms).collect();
+        sorted.sort_unstable();
+        let &max_ms = sorted.last()?;
+        let mut slowest = self.requests.clone();
+        slowest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Some(LatencySummary {
+            images: sorted.len(),
+            mean_ms: sorted.iter().sum::<u64>() as f64 / sorted.len() as f64,
+            p50_ms: percentile(&sorted, 50),
+            p90_ms: percentile(&sorted, 90),
+            p99_ms: percentile(&sorted, 99),
+            max_ms,
+            mean_preprocess_ms: (!self.preprocess.is_empty())
+                .then(|| self.preprocess.iter().sum::<u64>() as f64 / self.preprocess.len() as f64),
+            slowest: slowest.into_iter()
+                .take(SLOWEST_IMAGES)
+                .map(|(image_name, request_ms)| SlowImage { image_name, request_ms })
+                .collect(),
+            batches: self.batch_summary(),
+        })
+    }
+
+    fn batch_summary(&self) -> Option<BatchLatency> {
+        let mut sorted: Vec<u64> = self.batches.iter().map(|(ms, _)| *ms).collect();
+        sorted.sort_unstable();
+        let &max_ms = sorted.last()?;
+        Some(BatchLatency {
+            batches: sorted.len(),
+            mean_images: self.batches.iter().map(|(_, images)| *images).sum::<usize>() as f64 / sorted.len() as f64,
+            mean_ms: sorted.iter().sum::<u64>() as f64 / sorted.len() as f64,
+            p50_ms: percentile(&sorted, 50),
+            p90_ms: percentile(&sorted, 90),
+            max_ms,
+        })
+    }
+
+    /// Results per minute of the run, from its first minute to its last result
+    pub fn throughput(&self) -> Vec<ThroughputWindow> {
+        let Some(&last) = self.completions.iter().max() else {
+            return Vec::new();
+        };
+        let window_ms = WINDOW_SECS * 1000;
+        let windows = last / window_ms + 1;
+        (0..windows)
+            .map(|window| {
+                let start = window * window_ms;
+                let images = self.completions.iter().filter(|&&ms| ms >= start && ms < start + window_ms).count();
+                // The last window only lasted until the last result
+                let length_ms = if window + 1 == windows { (last - start).max(1) } else { window_ms };
+                ThroughputWindow {
+                    start_secs: start / 1000,
+                    images,
+                    images_per_minute: images as f64 * 60_000.0 / length_ms as f64,
+                }
+            })
+            .collect()
+    }
+
+    /// Results per minute over the whole run
+    pub fn images_per_minute(&self) -> Option<f64> {
+        let &last = self.completions.iter().max()?;
+        Some(self.completions.len() as f64 * 60_000.0 / last.max(1) as f64)
+    }
+}
+
+/// Nearest rank percentile of sorted values
+pub fn percentile(sorted: &[u64], p: u32) -> u64 {
+    let rank = (p as usize * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
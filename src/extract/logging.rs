@@ -0,0 +1,200 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use clap::ValueEnum;
+use indicatif::MultiProgress;
+use std::fmt::{self, Write as _};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::{LookupSpan, Registry};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Default directory of the log files
+pub const DEFAULT_LOG_DIR: &str = "logs";
+
+/// Debug lines of the extractor itself, such as each image's result, on top
+/// of everything at info level
+const DEBUG_DIRECTIVES: &str = "extract_with_llm=debug,tm_query=debug";
+
+/// How log lines are written (`--log-format`)
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Plain messages on the console, with timestamp, level and image in the file
+    Human,
+    /// One JSON object per line with the fields of each event, for log aggregation
+    Json,
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// `<dir>/extraction_<timestamp>.log`
+pub fn log_path(dir: &Path) -> PathBuf {
+    dir.join(format!("extraction_{}.log", Local::now().format("%Y%m%d_%H%M%S")))
+}
+
+/// Log to the console and to `path`. The console shows info and above, or
+/// what `RUST_LOG` asks for, and with `verbose` the extractor's debug lines
+/// too; the file always gets the debug lines, with the spans of each line
+/// so the lines of one image can be picked out. In the human format console
+/// lines are plain messages on stdout, warnings and errors on stderr; in the
+/// JSON format every line goes to stderr. Console lines clear the progress
+/// bar drawn by `bars` while they are written.
+pub fn init(path: &Path, format: LogFormat, verbose: bool, bars: &MultiProgress) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create log directory: {}", dir.display()))?;
+    }
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create log file: {}", path.display()))?;
+
+    let mut directives = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_else(|_| "info".to_string());
+    if verbose {
+        directives = format!("{},{}", directives, DEBUG_DIRECTIVES);
+    }
+    let console_filter = EnvFilter::try_new(&directives)
+        .with_context(|| format!("Invalid {}: {}", EnvFilter::DEFAULT_ENV, directives))?;
+    let file_filter = EnvFilter::new(format!("info,{}", DEBUG_DIRECTIVES));
+    let console_writer = BarWriter { bars: bars.clone(), all_to_stderr: format == LogFormat::Json };
+
+    let (console, file): (BoxedLayer, BoxedLayer) = match format {
+        LogFormat::Human => (
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .fmt_fields(HumanFields)
+                .event_format(ConsoleFormat)
+                .with_writer(console_writer)
+                .with_filter(console_filter)
+                .boxed(),
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_target(false)
+                .fmt_fields(HumanFields)
+                .with_writer(Mutex::new(file))
+                .with_filter(file_filter)
+                .boxed(),
+        ),
+        LogFormat::Json => (
+            tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_span_list(false)
+                .with_target(false)
+                .with_writer(console_writer)
+                .with_filter(console_filter)
+                .boxed(),
+            tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_span_list(false)
+                .with_target(false)
+                .with_writer(Mutex::new(file))
+                .with_filter(file_filter)
+                .boxed(),
+        ),
+    };
+    tracing_subscriber::registry()
+        .with(vec![console, file])
+        .try_init()
+        .context("Failed to initialize logging")
+}
+
+/// Console lines as plain messages, prefixed with their level unless they
+/// are info
+struct ConsoleFormat;
+
+impl<S, N> FormatEvent<S, N> for ConsoleFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let level = *event.metadata().level();
+        if level != Level::INFO {
+            write!(writer, "{}: ", level)?;
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+/// Fields of a human line: only the message of an event, whose structured
+/// fields are for the JSON format, and `key=value` for the fields of a span
+struct HumanFields;
+
+impl<'w> FormatFields<'w> for HumanFields {
+    fn format_fields<R: RecordFields>(&self, mut writer: Writer<'w>, fields: R) -> fmt::Result {
+        let mut visitor = HumanVisitor::default();
+        fields.record(&mut visitor);
+        match visitor.message {
+            Some(message) => writer.write_str(&message),
+            None => writer.write_str(&visitor.fields),
+        }
+    }
+}
+
+#[derive(Default)]
+struct HumanVisitor {
+    message: Option<String>,
+    fields: String,
+}
+
+impl Visit for HumanVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = Some(format!("{:?}", value));
+        } else {
+            if !self.fields.is_empty() {
+                self.fields.push(' ');
+            }
+            let _ = write!(self.fields, "{}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Console lines to stdout, or stderr for warnings, errors and with
+/// `all_to_stderr`, with the progress bar taken off the screen while a line
+/// is written
+#[derive(Clone)]
+struct BarWriter {
+    bars: MultiProgress,
+    all_to_stderr: bool,
+}
+
+struct BarLine {
+    bars: MultiProgress,
+    stderr: bool,
+}
+
+impl Write for BarLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bars.suspend(|| if self.stderr { io::stderr().write(buf) } else { io::stdout().write(buf) })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.stderr { io::stderr().flush() } else { io::stdout().flush() }
+    }
+}
+
+impl<'a> MakeWriter<'a> for BarWriter {
+    type Writer = BarLine;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        BarLine { bars: self.bars.clone(), stderr: self.all_to_stderr }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        BarLine { bars: self.bars.clone(), stderr: self.all_to_stderr || *meta.level() <= Level::WARN }
+    }
+}
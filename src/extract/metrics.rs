@@ -0,0 +1,879 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use super::bootstrap::{Confidence, Interval};
+use super::autotune::TuningSummary;
+use super::breakdown::Breakdown;
+use super::detect::DetectionCounts;
+use super::device::{Grade, Thresholds};
+use super::drift::LabelDrift;
+use super::earlystop::EarlyStopSummary;
+use super::fewshot::FewShotSummary;
+use super::health::HealthCheck;
+use super::latency::{Latencies, LatencySummary, ThroughputWindow};
+use super::pipeline::PipelineSummary;
+use super::preprocess::Preprocess;
+use super::review::LabelReview;
+use super::script::Script;
+use super::protocol::Field;
+use super::remote::DatasetSource;
+use super::terms::TermScore;
+use super::usage::{Usage, UsageTotals};
+use super::words::WordScore;
+
+/// One step of the alignment of a prediction with the ground truth
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    /// The character is right
+    Keep(char),
+    /// The model read `truth` as `predicted`
+    Substitute { truth: char, predicted: char },
+    /// The model added a character that isn't in the ground truth
+    Insert(char),
+    /// The model left out a character of the ground truth
+    Delete(char),
+}
+
+/// Align a prediction with the ground truth character by character, with
+/// the fewest edits (Levenshtein). Edit distance, character error rate and
+/// the confusion matrix are all read from the one alignment.
+pub fn align(truth: &str, predicted: &str) -> Vec<Edit> {
+    let a: Vec<char> = truth.chars().collect();
+    let b: Vec<char> = predicted.chars().collect();
+
+    // cost[i][j]: edits turning the first i characters of a into the first j of b
+    let mut cost = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in cost.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in cost[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = cost[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            cost[i][j] = substitution.min(cost[i - 1][j] + 1).min(cost[i][j - 1] + 1);
+        }
+    }
+
+    let (mut i, mut j) = (a.len(), b.len());
+    let mut edits = Vec::with_capacity(a.len().max(b.len()));
+    while i > 0 || j > 0 {
+        if i > 0 && j > 0 && cost[i][j] == cost[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]) {
+            edits.push(if a[i - 1] == b[j - 1] {
+                Edit::Keep(a[i - 1])
+            } else {
+                Edit::Substitute { truth: a[i - 1], predicted: b[j - 1] }
+            });
+            i -= 1;
+            j -= 1;
+        } else if i > 0 && cost[i][j] == cost[i - 1][j] + 1 {
+            edits.push(Edit::Delete(a[i - 1]));
+            i -= 1;
+        } else {
+            edits.push(Edit::Insert(b[j - 1]));
+            j -= 1;
+        }
+    }
+    edits.reverse();
+    edits
+}
+
+/// Number of edits in an alignment
+pub fn distance(edits: &[Edit]) -> usize {
+    edits.iter().filter(|e| !matches!(e, Edit::Keep(_))).count()
+}
+
+/// Levenshtein distance between two strings, counted in characters
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    distance(&align(a, b))
+}
+
+/// Levenshtein distance between two sequences, counted in items, for
+/// comparisons by word rather than by character
+pub fn levenshtein<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, x) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, y) in b.iter().enumerate() {
+            current[j + 1] = (previous[j] + usize::from(x != y)).min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// A value as compared when a result has no normalized form: whitespace is
+/// dropped, so `美 心` and `美心` are the same prediction
+pub fn comparable(text: &str) -> String {
+    text.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+/// Character error rate of an alignment: the edit distance over the length
+/// of the ground truth. An empty prediction scores 1, as does any
+/// prediction when the ground truth is empty; both empty scores 0. Long
+/// wrong predictions can score above 1.
+pub fn character_error_rate(edits: &[Edit]) -> f64 {
+    let length = edits.iter().filter(|e| !matches!(e, Edit::Insert(_))).count();
+    let errors = distance(edits);
+    if length == 0 {
+        return if errors == 0 { 0.0 } else { 1.0 };
+    }
+    errors as f64 / length as f64
+}
+
+/// Characters the model got wrong, accumulated over the alignments of a run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Confusions {
+    /// Ground truth character and what the model read instead
+    pub substitutions: HashMap<(char, char), usize>,
+    /// Characters the model added
+    pub insertions: HashMap<char, usize>,
+    /// Ground truth characters the model left out
+    pub deletions: HashMap<char, usize>,
+}
+
+/// One entry of the confusion matrix as written to the JSON and CSV files.
+/// Insertions have no `truth` and deletions no `predicted`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Confusion {
+    pub kind: &'static str,
+    pub truth: Option<char>,
+    pub predicted: Option<char>,
+    pub count: usize,
+}
+
+impl Confusions {
+    pub fn add(&mut self, edits: &[Edit]) {
+        for edit in edits {
+            match *edit {
+                Edit::Keep(_) => {}
+                Edit::Substitute { truth, predicted } => *self.substitutions.entry((truth, predicted)).or_default() += 1,
+                Edit::Insert(c) => *self.insertions.entry(c).or_default() += 1,
+                Edit::Delete(c) => *self.deletions.entry(c).or_default() += 1,
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.substitutions.is_empty() && self.insertions.is_empty() && self.deletions.is_empty()
+    }
+
+    /// Every entry, most frequent first
+    pub fn entries(&self) -> Vec<Confusion> {
+        let substitutions = self.substitutions.iter().map(|(&(truth, predicted), &count)| {
+            Confusion { kind: "substitution", truth: Some(truth), predicted: Some(predicted), count }
+        });
+        let insertions = self.insertions.iter().map(|(&predicted, &count)| {
+            Confusion { kind: "insertion", truth: None, predicted: Some(predicted), count }
+        });
+        let deletions = self.deletions.iter().map(|(&truth, &count)| {
+            Confusion { kind: "deletion", truth: Some(truth), predicted: None, count }
+        });
+
+        let mut entries: Vec<Confusion> = substitutions.chain(insertions).chain(deletions).collect();
+        entries.sort_by(|a, b| b.count.cmp(&a.count)
+            .then(a.kind.cmp(b.kind))
+            .then(a.truth.cmp(&b.truth))
+            .then(a.predicted.cmp(&b.predicted)));
+        entries
+    }
+
+    /// Table of the `n` most frequent substitutions, as printed at the end of a run
+    pub fn report(&self, n: usize) -> String {
+        let mut lines = vec![format!("Most frequent confusions (of {} substitutions, {} insertions, {} deletions):",
+            self.substitutions.values().sum::<usize>(),
+            self.insertions.values().sum::<usize>(),
+            self.deletions.values().sum::<usize>())];
+        lines.push(format!("  {:>6}  {:<5} {:<9}  {}", "Count", "Truth", "Predicted", "Kind"));
+        for entry in self.entries().iter().take(n) {
+            let show = |c: Option<char>| c.map_or("-".to_string(), String::from);
+            lines.push(format!("  {:>6}  {:<5} {:<9}  {}", entry.count, show(entry.truth), show(entry.predicted), entry.kind));
+        }
+        lines.join("\n")
+    }
+
+    /// Write the full matrix to `path` as JSON and next to it as CSV
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let entries = self.entries();
+
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create confusion matrix: {}", path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &entries)
+            .context("Failed to write confusion matrix")?;
+
+        let csv_path = path.with_extension("csv");
+        let mut writer = csv::Writer::from_path(&csv_path)
+            .with_context(|| format!("Failed to create confusion matrix: {}", csv_path.display()))?;
+        for entry in &entries {
+            writer.serialize(entry).context("Failed to write confusion matrix")?;
+        }
+        writer.flush().context("Failed to write confusion matrix")?;
+        Ok(())
+    }
+}
+
+/// Upper bounds of the CER histogram buckets; the last bucket holds the rest
+const CER_BUCKETS: [(f64, &str); 5] = [
+    (0.0, "0"),
+    (0.25, "0-0.25"),
+    (0.5, "0.25-0.5"),
+    (0.75, "0.5-0.75"),
+    (1.0, "0.75-1"),
+];
+
+/// Default metrics file name, written next to the results file
+pub const METRICS_FILE_NAME: &str = "metrics.json";
+
+/// Default confusion matrix file name, written next to the results file with
+/// a CSV copy
+pub const CONFUSIONS_FILE_NAME: &str = "confusions.json";
+
+/// How a prediction compares with the ground truth
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Prediction equals the ground truth, or both are empty
+    ExactMatch,
+    /// Both are present and differ
+    Mismatch,
+    /// The model found no characters where the ground truth has some
+    Missed,
+    /// The model extracted characters where the ground truth has none
+    Spurious,
+    /// The request failed
+    Error,
+}
+
+/// Score of one labeled image, kept for the confidence intervals and for
+/// pairing the image with another run's
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ImageScore {
+    pub exact_match: bool,
+    /// `null` when the request failed
+    pub cer: Option<f64>,
+}
+
+/// Outcome counts of a run, updated as results arrive
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Scoreboard {
+    pub config_id: String,
+    /// `--normalize` mode the comparisons were made in
+    pub normalize: String,
+    /// Text rules applied before comparison, see `normalize::TextRules::names`
+    #[serde(default)]
+    pub text_rules: Vec<String>,
+    /// `sample::sample_id` of the images the run was given
+    #[serde(default)]
+    pub sample_id: String,
+    /// Fields extracted and scored; empty means all of them
+    #[serde(default)]
+    pub fields: Vec<Field>,
+    pub total: usize,
+    pub exact_match: usize,
+    pub mismatch: usize,
+    pub missed: usize,
+    pub spurious: usize,
+    pub errors: usize,
+    /// Exact matches of the characters as written, before normalization
+    pub raw_exact_match: usize,
+    /// Images without ground truth characters, kept out of `total` and the
+    /// accuracies
+    #[serde(default)]
+    pub unlabeled: usize,
+    /// Unlabeled images the model found characters in
+    #[serde(default)]
+    pub new_extractions: usize,
+    /// Dataset entries left out by `--skip-list`, counted nowhere else
+    #[serde(default)]
+    pub skip_listed: usize,
+    /// Answered images with ground truth words in the mark, scored apart
+    /// from the characters
+    #[serde(default)]
+    pub words_total: usize,
+    #[serde(default)]
+    pub words_exact_match: usize,
+    /// Answered images with a ground truth device description
+    #[serde(default)]
+    pub device_total: usize,
+    /// `DescriptionScorer` that compared the descriptions
+    #[serde(default)]
+    pub device_scorer: String,
+    #[serde(default)]
+    pub device_thresholds: Thresholds,
+    /// First stage answers of `--two-stage` runs
+    #[serde(default)]
+    pub detection: DetectionCounts,
+    /// Tokens, image bytes and cost of the images that made requests
+    #[serde(default)]
+    pub usage: UsageTotals,
+    /// Checks of the endpoints before the run; empty with `--skip-health-check`
+    #[serde(default)]
+    pub health_checks: Vec<HealthCheck>,
+    /// Set when the run was interrupted before every image was processed
+    #[serde(default)]
+    pub partial: bool,
+    /// Images left unprocessed by an interrupted run
+    #[serde(default)]
+    pub unprocessed: usize,
+    /// Bootstrap intervals, computed once the run is over
+    #[serde(default)]
+    pub confidence: Option<Confidence>,
+    /// Score of each labeled image, by name
+    #[serde(default)]
+    pub image_scores: BTreeMap<String, ImageScore>,
+    /// Accuracy by ground truth length, file size and image dimensions,
+    /// computed once the run is over
+    #[serde(default)]
+    pub breakdown: Option<Breakdown>,
+    /// Utilization of the preprocessing and request workers, once the run
+    /// is over
+    #[serde(default)]
+    pub pipeline: Option<PipelineSummary>,
+    /// Levels `--auto-tune` went through and the one it ended at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_tune: Option<TuningSummary>,
+    /// Example images shown before each image with `--few-shot`, left out
+    /// of the evaluation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub few_shot: Option<FewShotSummary>,
+    /// Where `--early-stop` stopped the run, when it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub early_stop: Option<EarlyStopSummary>,
+    /// Where the dataset was fetched from, when `--dataset` was a URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dataset_source: Option<DatasetSource>,
+    /// Accuracy adjusted by the verdicts of the `review` subcommand, when
+    /// the mismatches have been reviewed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label_review: Option<LabelReview>,
+    /// Mismatches whose label has changed at IPOS, found by `--verify-labels`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label_drift: Option<LabelDrift>,
+    /// Predictions by writing system, see `script::classify`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub scripts: BTreeMap<Script, usize>,
+    /// Predictions not in Chinese characters alone count as wrong, with
+    /// `--strict-script`
+    #[serde(default)]
+    pub strict_script: bool,
+    /// `--preprocess` applied to the images of the run
+    #[serde(default)]
+    pub preprocess: Preprocess,
+    /// Character error rates of the answered images
+    #[serde(skip)]
+    pub cers: Vec<f64>,
+    /// Character error rates of the answered images whose prediction isn't
+    /// in Chinese characters alone, left out of `cers` and the confusions
+    #[serde(skip)]
+    pub non_cjk_cers: Vec<f64>,
+    /// Sum of `cers`, kept as they are added for the running mean
+    #[serde(skip)]
+    pub cer_sum: f64,
+    #[serde(skip)]
+    pub confusions: Confusions,
+    /// Term scores of the answered images
+    #[serde(skip)]
+    pub term_scores: Vec<TermScore>,
+    /// Word error rates of the words in the mark
+    #[serde(skip)]
+    pub wers: Vec<f64>,
+    /// Similarities of the device descriptions
+    #[serde(skip)]
+    pub device_similarities: Vec<f64>,
+    /// Request times and completions, summarized in the metrics file
+    #[serde(skip)]
+    pub latencies: Latencies,
+}
+
+/// Number of CER values in one histogram bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bucket {
+    pub range: String,
+    pub count: usize,
+}
+
+impl Scoreboard {
+    pub fn new(config_id: &str, normalize: &str, text_rules: &[&str], sample_id: &str) -> Self {
+        Scoreboard {
+            config_id: config_id.to_string(),
+            normalize: normalize.to_string(),
+            text_rules: text_rules.iter().map(|rule| rule.to_string()).collect(),
+            sample_id: sample_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether `field` was extracted and is scored
+    pub fn scores(&self, field: Field) -> bool {
+        self.fields.is_empty() || self.fields.contains(&field)
+    }
+
+    /// Count a result of a run that doesn't extract the Chinese characters
+    pub fn record_request(&mut self, failed: bool) {
+        self.total += 1;
+        if failed {
+            self.errors += 1;
+        }
+    }
+
+    /// Count an image without ground truth characters, and whether the model
+    /// found any
+    pub fn record_unlabeled(&mut self, extracted: bool) {
+        self.unlabeled += 1;
+        if extracted {
+            self.new_extractions += 1;
+        }
+    }
+
+    /// Count the result of `image_name`, with the alignment and term score
+    /// of its prediction if it was answered. The character error rate of a
+    /// prediction in another script than Chinese characters is kept apart.
+    pub fn record(&mut self, image_name: &str, outcome: Outcome, raw_match: bool, edits: Option<&[Edit]>, terms: Option<TermScore>, script: Option<Script>) {
+        self.total += 1;
+        self.term_scores.extend(terms);
+        let cjk = script.is_none_or(|script| script == Script::Cjk);
+        let cer = edits.map(character_error_rate);
+        if let Some(edits) = edits {
+            if cjk {
+                self.cers.extend(cer);
+                self.cer_sum += cer.unwrap_or_default();
+                self.confusions.add(edits);
+            } else {
+                self.non_cjk_cers.extend(cer);
+            }
+        }
+        let cer = cer.filter(|_| cjk);
+        self.image_scores.insert(image_name.to_string(), ImageScore { exact_match: outcome == Outcome::ExactMatch, cer });
+        match outcome {
+            Outcome::ExactMatch => self.exact_match += 1,
+            Outcome::Mismatch => self.mismatch += 1,
+            Outcome::Missed => self.missed += 1,
+            Outcome::Spurious => self.spurious += 1,
+            Outcome::Error => self.errors += 1,
+        }
+        if raw_match {
+            self.raw_exact_match += 1;
+        }
+    }
+
+    /// Count the writing system of a prediction
+    pub fn record_script(&mut self, script: Script) {
+        *self.scripts.entry(script).or_default() += 1;
+    }
+
+    /// Count the score of the words in the mark of an answered image
+    pub fn record_words(&mut self, score: WordScore) {
+        self.words_total += 1;
+        if score.exact_match {
+            self.words_exact_match += 1;
+        }
+        self.wers.push(score.wer);
+    }
+
+    /// Count the detection answer of a `--two-stage` run, against whether
+    /// the ground truth has characters
+    pub fn record_detection(&mut self, detected: bool, present: bool) {
+        self.detection.record(detected, present);
+    }
+
+    /// Count the request and preparation time of an image, `None` when it
+    /// made no request
+    pub fn record_latency(&mut self, image_name: &str, request_ms: Option<u64>, preprocess_ms: Option<u64>) {
+        self.latencies.record(image_name, request_ms, preprocess_ms);
+    }
+
+    /// Count a `--batch-size` request of `images` images
+    pub fn record_batch(&mut self, request_ms: u64, images: usize) {
+        self.latencies.record_batch(request_ms, images);
+    }
+
+    /// Count a result of this run, recorded `elapsed_ms` after it started
+    pub fn record_completion(&mut self, elapsed_ms: u64) {
+        self.latencies.record_completion(elapsed_ms);
+    }
+
+    /// Count the usage of an image that made requests
+    pub fn record_usage(&mut self, usage: Option<Usage>, image_bytes: u64, cost: Option<f64>) {
+        self.usage.record(usage, image_bytes, cost);
+    }
+
+    /// Count the usage of a request made for no image in particular, see
+    /// `UsageTotals::record_request`
+    pub fn record_request_usage(&mut self, usage: Option<Usage>, image_bytes: u64, cost: Option<f64>) {
+        self.usage.record_request(usage, image_bytes, cost);
+    }
+
+    /// Count the similarity of the device description of an answered image
+    pub fn record_device(&mut self, similarity: f64) {
+        self.device_total += 1;
+        self.device_similarities.push(similarity);
+    }
+
+    pub fn mean_device_similarity(&self) -> Option<f64> {
+        (!self.device_similarities.is_empty())
+            .then(|| self.device_similarities.iter().sum::<f64>() / self.device_similarities.len() as f64)
+    }
+
+    /// Device descriptions with the grade `grade` under `device_thresholds`
+    pub fn device_grade_count(&self, grade: Grade) -> usize {
+        self.device_similarities.iter().filter(|s| self.device_thresholds.grade(**s) == grade).count()
+    }
+
+    /// Exact word matches over the images with ground truth words
+    pub fn words_accuracy(&self) -> f64 {
+        ratio(self.words_exact_match, self.words_total)
+    }
+
+    pub fn mean_wer(&self) -> Option<f64> {
+        (!self.wers.is_empty()).then(|| self.wers.iter().sum::<f64>() / self.wers.len() as f64)
+    }
+
+    /// Exact matches over all images, failed requests counting as wrong
+    pub fn accuracy(&self) -> f64 {
+        ratio(self.exact_match, self.total)
+    }
+
+    /// Exact matches over the images the model answered
+    pub fn answered_accuracy(&self) -> f64 {
+        ratio(self.exact_match, self.total - self.errors)
+    }
+
+    pub fn raw_accuracy(&self) -> f64 {
+        ratio(self.raw_exact_match, self.total)
+    }
+
+    pub fn mean_cer(&self) -> Option<f64> {
+        (!self.cers.is_empty()).then(|| self.cers.iter().sum::<f64>() / self.cers.len() as f64)
+    }
+
+    pub fn median_cer(&self) -> Option<f64> {
+        let mut cers = self.cers.clone();
+        cers.sort_by(f64::total_cmp);
+        let middle = cers.len() / 2;
+        match cers.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(cers[middle]),
+            _ => Some((cers[middle - 1] + cers[middle]) / 2.0),
+        }
+    }
+
+    /// Mean term precision, recall and F1 over the answered images
+    pub fn mean_term_score(&self) -> Option<TermScore> {
+        let n = self.term_scores.len() as f64;
+        (!self.term_scores.is_empty()).then(|| TermScore {
+            precision: self.term_scores.iter().map(|s| s.precision).sum::<f64>() / n,
+            recall: self.term_scores.iter().map(|s| s.recall).sum::<f64>() / n,
+            f1: self.term_scores.iter().map(|s| s.f1).sum::<f64>() / n,
+        })
+    }
+
+    /// Answered images whose prediction has exactly the ground truth's terms,
+    /// in any order
+    pub fn term_matches(&self) -> usize {
+        self.term_scores.iter().filter(|s| s.is_complete()).count()
+    }
+
+    /// Count of CER values per bucket: exactly 0, then up to 0.25, 0.5,
+    /// 0.75 and 1, and above 1
+    pub fn cer_histogram(&self) -> Vec<Bucket> {
+        let mut buckets: Vec<Bucket> = CER_BUCKETS.iter()
+            .map(|(_, range)| Bucket { range: range.to_string(), count: 0 })
+            .chain([Bucket { range: ">1".to_string(), count: 0 }])
+            .collect();
+        for cer in &self.cers {
+            let index = CER_BUCKETS.iter().position(|(bound, _)| cer <= bound).unwrap_or(CER_BUCKETS.len());
+            buckets[index].count += 1;
+        }
+        buckets
+    }
+
+    /// The scoreboard as printed at the end of a run, covering the scored
+    /// fields
+    pub fn report(&self) -> String {
+        let percent = |count: usize| 100.0 * ratio(count, self.total);
+        let mut lines = vec![
+            format!("Scoreboard ({} images, normalized with {}{}):", self.total, self.normalize,
+                self.text_rules.iter().map(|rule| format!(", {}", rule)).collect::<String>()),
+        ];
+        if self.partial {
+            lines.push(format!("  Partial run, interrupted with {} images unprocessed", self.unprocessed));
+        }
+        if self.skip_listed > 0 {
+            lines.push(format!("  {} known-bad images of the dataset left out by --skip-list", self.skip_listed));
+        }
+        if self.scores(Field::Chinese) {
+            lines.extend([
+                format!("  Exact match:        {:6} ({:5.1}%)", self.exact_match, percent(self.exact_match)),
+                format!("  Mismatch:           {:6} ({:5.1}%)", self.mismatch, percent(self.mismatch)),
+                format!("  Model found none:   {:6} ({:5.1}%)", self.missed, percent(self.missed)),
+                format!("  Ground truth none:  {:6} ({:5.1}%)", self.spurious, percent(self.spurious)),
+                format!("  Request errors:     {:6} ({:5.1}%)", self.errors, percent(self.errors)),
+                format!("  Accuracy: {:.1}% of all images, {:.1}% of answered ones ({:.1}% before normalization)",
+                    100.0 * self.accuracy(), 100.0 * self.answered_accuracy(), 100.0 * self.raw_accuracy()),
+                format!("  Character error rate: mean {}, median {}",
+                    self.mean_cer().map_or("-".to_string(), |c| format!("{:.3}", c)),
+                    self.median_cer().map_or("-".to_string(), |c| format!("{:.3}", c))),
+            ]);
+            if let Some(confidence) = &self.confidence {
+                let interval = |interval: Option<Interval>, scale: f64, precision: usize| {
+                    interval.map_or("-".to_string(), |i| format!("{:.*} to {:.*}", precision, scale * i.low, precision, scale * i.high))
+                };
+                lines.push(format!("  {:.0}% confidence intervals ({} resamples): accuracy {}%, mean CER {}",
+                    100.0 * confidence.level, confidence.resamples,
+                    interval(confidence.accuracy, 100.0, 1), interval(confidence.mean_cer, 1.0, 3)));
+            }
+            if !self.scripts.is_empty() {
+                let scripts: Vec<String> = self.scripts.iter().map(|(script, count)| format!("{} {}", script.name(), count)).collect();
+                lines.push(format!("  Prediction scripts: {}{}", scripts.join(", "),
+                    if self.strict_script { "; only Chinese characters can match (--strict-script)" } else { "" }));
+            }
+            if self.preprocess != Preprocess::None {
+                lines.push(format!("  Images preprocessed: {}", self.preprocess.name()));
+            }
+            if !self.non_cjk_cers.is_empty() {
+                lines.push(format!("  Not in Chinese characters alone, left out of the character error rate: {} labeled images, mean CER {:.3}",
+                    self.non_cjk_cers.len(), self.non_cjk_cers.iter().sum::<f64>() / self.non_cjk_cers.len() as f64));
+            }
+            lines.extend([
+                match self.mean_term_score() {
+                    Some(s) => format!("  Terms: precision {:.3}, recall {:.3}, F1 {:.3}; {} images with every term, in any order",
+                        s.precision, s.recall, s.f1, self.term_matches()),
+                    None => "  Terms: -".to_string(),
+                },
+            ]);
+            if let Some(breakdown) = self.breakdown.as_ref().map(Breakdown::report).filter(|report| !report.is_empty()) {
+                lines.push(breakdown);
+            }
+        } else {
+            lines.push(format!("  Request errors:     {:6} ({:5.1}%)", self.errors, percent(self.errors)));
+        }
+        if self.unlabeled > 0 {
+            lines.push(format!("New extractions: characters found in {} of {} unlabeled images, not counted above",
+                self.new_extractions, self.unlabeled));
+        }
+        if self.detection.total() > 0 {
+            let d = &self.detection;
+            lines.extend([
+                format!("Chinese detection ({} images, {} stopped after detection):", d.total(), d.negatives()),
+                format!("  Precision {}, recall {}; {} false positives, {} false negatives",
+                    d.precision().map_or("-".to_string(), |p| format!("{:.3}", p)),
+                    d.recall().map_or("-".to_string(), |r| format!("{:.3}", r)),
+                    d.false_positive, d.false_negative),
+            ]);
+        }
+        if self.scores(Field::Words) {
+            lines.extend([
+                format!("Words in mark ({} answered images with ground truth words):", self.words_total),
+                format!("  Exact match: {} ({:.1}%), mean word error rate {}",
+                    self.words_exact_match, 100.0 * self.words_accuracy(),
+                    self.mean_wer().map_or("-".to_string(), |w| format!("{:.3}", w))),
+            ]);
+        }
+        if self.scores(Field::Device) {
+            lines.extend([
+                format!("Device description ({} answered images with a ground truth description, {}):",
+                    self.device_total, self.device_scorer),
+                format!("  Mean similarity {}; good {}, partial {}, poor {}",
+                    self.mean_device_similarity().map_or("-".to_string(), |s| format!("{:.3}", s)),
+                    self.device_grade_count(Grade::Good), self.device_grade_count(Grade::Partial),
+                    self.device_grade_count(Grade::Poor)),
+            ]);
+        }
+        if let Some(latency) = self.latencies.summary() {
+            lines.push(format!("Request latency ({} images): mean {:.0} ms, p50 {} ms, p90 {} ms, p99 {} ms, max {} ms",
+                latency.images, latency.mean_ms, latency.p50_ms, latency.p90_ms, latency.p99_ms, latency.max_ms));
+            if let Some(preprocess) = latency.mean_preprocess_ms {
+                lines.push(format!("  Image preparation, not included: mean {:.0} ms", preprocess));
+            }
+            let slowest: Vec<String> = latency.slowest.iter()
+                .map(|image| format!("{} ({} ms)", image.image_name, image.request_ms))
+                .collect();
+            lines.push(format!("  Slowest: {}", slowest.join(", ")));
+            if let Some(batches) = &latency.batches {
+                lines.push(format!("  Batches, each image above counting its share: {} of {:.1} images, mean {:.0} ms, p50 {} ms, p90 {} ms, max {} ms",
+                    batches.batches, batches.mean_images, batches.mean_ms, batches.p50_ms, batches.p90_ms, batches.max_ms));
+            }
+        }
+        if let Some(rate) = self.latencies.images_per_minute() {
+            let windows: Vec<String> = self.latencies.throughput().iter()
+                .map(|window| format!("{:.0}", window.images_per_minute))
+                .collect();
+            lines.push(format!("Throughput: {:.1} images/minute; by minute of the run: {}", rate, windows.join(", ")));
+        }
+        if let Some(pipeline) = &self.pipeline {
+            lines.push(pipeline.to_string());
+        }
+        if let Some(tuning) = &self.auto_tune {
+            lines.push(tuning.to_string());
+        }
+        if let Some(early_stop) = &self.early_stop {
+            lines.push(early_stop.to_string());
+        }
+        if let Some(review) = &self.label_review {
+            lines.push(review.to_string());
+        }
+        if let Some(drift) = &self.label_drift {
+            lines.push(drift.to_string());
+        }
+        let usage = &self.usage;
+        if usage.images > 0 {
+            lines.push(format!("Usage ({} images that made requests): {} input and {} output tokens, {} tokens per image; {:.1} KB of images sent, {:.1} KB per image",
+                usage.images, usage.input_tokens, usage.output_tokens,
+                usage.tokens_per_image().map_or("-".to_string(), |t| format!("{:.0}", t)),
+                usage.image_bytes as f64 / 1000.0, usage.image_bytes as f64 / 1000.0 / usage.images as f64));
+            if usage.images_without_usage > 0 {
+                lines.push(format!("  {} images had no usage data in their responses; only their image bytes are counted",
+                    usage.images_without_usage));
+            }
+            if let (Some(cost), Some(per_1000)) = (usage.estimated_cost, usage.cost_per_1000_images()) {
+                lines.push(format!("  Estimated cost: {:.4}, {:.4} per 1000 images", cost, per_1000));
+            }
+        }
+        lines.join("\n")
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open metrics file: {}", path.display()))?;
+        serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse metrics file: {}", path.display()))
+    }
+
+    /// Write the metrics atomically, with the accuracies alongside the counts
+    pub fn save(&self, path: &Path) -> Result<()> {
+        #[derive(Serialize)]
+        struct MetricsFile<'a> {
+            #[serde(flatten)]
+            scoreboard: &'a Scoreboard,
+            accuracy: f64,
+            answered_accuracy: f64,
+            raw_accuracy: f64,
+            mean_cer: Option<f64>,
+            median_cer: Option<f64>,
+            cer_histogram: Vec<Bucket>,
+            /// Means over the answered images
+            term_precision: Option<f64>,
+            term_recall: Option<f64>,
+            term_f1: Option<f64>,
+            term_matches: usize,
+            words_accuracy: f64,
+            mean_wer: Option<f64>,
+            mean_device_similarity: Option<f64>,
+            device_good: usize,
+            device_partial: usize,
+            device_poor: usize,
+            detection_precision: Option<f64>,
+            detection_recall: Option<f64>,
+            /// Network and model time of the images that made requests
+            latency: Option<LatencySummary>,
+            images_per_minute: Option<f64>,
+            throughput: Vec<ThroughputWindow>,
+            tokens_per_image: Option<f64>,
+            cost_per_1000_images: Option<f64>,
+        }
+
+        let tmp_path = path.with_extension("json.tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create metrics file: {}", tmp_path.display()))?;
+        let terms = self.mean_term_score();
+        let metrics = MetricsFile {
+            scoreboard: self,
+            accuracy: self.accuracy(),
+            answered_accuracy: self.answered_accuracy(),
+            raw_accuracy: self.raw_accuracy(),
+            mean_cer: self.mean_cer(),
+            median_cer: self.median_cer(),
+            cer_histogram: self.cer_histogram(),
+            term_precision: terms.map(|s| s.precision),
+            term_recall: terms.map(|s| s.recall),
+            term_f1: terms.map(|s| s.f1),
+            term_matches: self.term_matches(),
+            words_accuracy: self.words_accuracy(),
+            mean_wer: self.mean_wer(),
+            mean_device_similarity: self.mean_device_similarity(),
+            device_good: self.device_grade_count(Grade::Good),
+            device_partial: self.device_grade_count(Grade::Partial),
+            device_poor: self.device_grade_count(Grade::Poor),
+            detection_precision: self.detection.precision(),
+            detection_recall: self.detection.recall(),
+            latency: self.latencies.summary(),
+            images_per_minute: self.latencies.images_per_minute(),
+            throughput: self.latencies.throughput(),
+            tokens_per_image: self.usage.tokens_per_image(),
+            cost_per_1000_images: self.usage.cost_per_1000_images(),
+        };
+        serde_json::to_writer_pretty(BufWriter::new(file), &metrics)
+            .context("Failed to write metrics file")?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to save metrics file: {}", path.display()))?;
+        Ok(())
+    }
+}
+
+fn ratio(count: usize, total: usize) -> f64 {
+    if total == 0 { 0.0 } else { count as f64 / total as f64 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_prediction_keeps_every_character() {
+        let edits = align("美心", "美心");
+        assert_eq!(edits, [Edit::Keep('美'), Edit::Keep('心')]);
+        assert_eq!(distance(&edits), 0);
+        assert_eq!(character_error_rate(&edits), 0.0);
+    }
+
+    #[test]
+    fn empty_prediction_deletes_the_truth() {
+        let edits = align("美心", "");
+        assert_eq!(edits, [Edit::Delete('美'), Edit::Delete('心')]);
+        assert_eq!(character_error_rate(&edits), 1.0);
+    }
+
+    #[test]
+    fn empty_truth_scores_any_prediction_as_wrong() {
+        let edits = align("", "龍");
+        assert_eq!(edits, [Edit::Insert('龍')]);
+        assert_eq!(character_error_rate(&edits), 1.0);
+        assert_eq!(character_error_rate(&align("", "")), 0.0);
+    }
+
+    #[test]
+    fn substitutions_insertions_and_deletions_are_told_apart() {
+        assert_eq!(align("大白兔", "大白免"), [
+            Edit::Keep('大'),
+            Edit::Keep('白'),
+            Edit::Substitute { truth: '兔', predicted: '免' },
+        ]);
+        assert_eq!(align("同仁堂", "同堂"), [Edit::Keep('同'), Edit::Delete('仁'), Edit::Keep('堂')]);
+        assert_eq!(align("天福", "天大福"), [Edit::Keep('天'), Edit::Insert('大'), Edit::Keep('福')]);
+    }
+
+    #[test]
+    fn multi_word_truth_counts_every_character() {
+        let edits = align("老干妈 风味", "老千妈风味豆豉");
+        assert_eq!(distance(&edits), 4);
+        assert_eq!(character_error_rate(&edits), 4.0 / 6.0);
+        assert_eq!(edit_distance(&comparable("老干妈 风味"), "老干妈风味"), 0);
+    }
+
+    #[test]
+    fn long_wrong_prediction_scores_above_one() {
+        let edits = align("福", "一二三");
+        assert_eq!(distance(&edits), 3);
+        assert_eq!(character_error_rate(&edits), 3.0);
+    }
+
+    #[test]
+    fn levenshtein_counts_words() {
+        assert_eq!(levenshtein(&["LEE", "KUM", "KEE"], &["LEE", "KEE"]), 1);
+        assert_eq!(levenshtein::<&str>(&[], &["SONY"]), 1);
+    }
+}
@@ -0,0 +1,266 @@
+use clap::ValueEnum;
+use tracing::warn;
+#[cfg(feature = "opencc")]
+use opencc_rust::{DefaultConfig, OpenCC};
+#[cfg(feature = "opencc")]
+use std::sync::Mutex;
+use unicode_normalization::UnicodeNormalization;
+
+/// Script that characters are converted to before comparison
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Normalize {
+    /// Traditional to simplified
+    #[default]
+    T2s,
+    /// Simplified to traditional
+    S2t,
+    /// Compare the characters as they are
+    None,
+}
+
+impl Normalize {
+    pub fn name(self) -> &'static str {
+        match self {
+            Normalize::T2s => "t2s",
+            Normalize::S2t => "s2t",
+            Normalize::None => "none",
+        }
+    }
+}
+
+/// What happens to whitespace before comparison
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Whitespace {
+    /// Remove all whitespace
+    #[default]
+    Strip,
+    /// Trim the ends and turn every run of whitespace into one space
+    Collapse,
+    /// Leave whitespace as it is
+    Keep,
+}
+
+/// Text rules applied to predictions and ground truth before comparison,
+/// besides the script conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextRules {
+    /// Compose characters to Unicode NFC, so `é` as `e` and a combining
+    /// accent equals the precomposed `é`
+    pub nfc: bool,
+    /// Fold full-width ASCII (`Ａ`, `１`, `！`) and the ideographic space to
+    /// their half-width forms
+    pub fold_width: bool,
+    /// Lower-case letters
+    pub fold_case: bool,
+    /// Remove ASCII, CJK and full-width punctuation such as `·`, `。` and `「」`
+    pub strip_punctuation: bool,
+    pub whitespace: Whitespace,
+}
+
+impl Default for TextRules {
+    fn default() -> Self {
+        TextRules { nfc: true, fold_width: false, fold_case: false, strip_punctuation: false, whitespace: Whitespace::Strip }
+    }
+}
+
+impl TextRules {
+    /// Names of the rules in effect, for the metrics file
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.nfc {
+            names.push("nfc");
+        }
+        if self.fold_width {
+            names.push("fold_width");
+        }
+        if self.fold_case {
+            names.push("fold_case");
+        }
+        if self.strip_punctuation {
+            names.push("strip_punctuation");
+        }
+        match self.whitespace {
+            Whitespace::Strip => names.push("strip_whitespace"),
+            Whitespace::Collapse => names.push("collapse_whitespace"),
+            Whitespace::Keep => {}
+        }
+        names
+    }
+}
+
+/// Converts predictions and ground truth to one form before comparison:
+/// the text rules, and one script so a simplified answer to a traditional
+/// mark isn't counted as a mismatch. The OpenCC dictionaries are loaded once
+/// and shared by every task. Without the `opencc` feature the script is
+/// never converted.
+pub struct Normalizer {
+    mode: Normalize,
+    #[cfg(feature = "opencc")]
+    converter: Option<Mutex<OpenCC>>,
+    rules: TextRules,
+}
+
+impl Normalizer {
+    /// Load the OpenCC configuration for `mode`. When its data files are not
+    /// installed this warns and compares without converting the script.
+    #[cfg(feature = "opencc")]
+    pub fn new(mode: Normalize, rules: TextRules) -> Self {
+        let config = match mode {
+            Normalize::T2s => DefaultConfig::T2S,
+            Normalize::S2t => DefaultConfig::S2T,
+            Normalize::None => return Normalizer { mode, converter: None, rules },
+        };
+        match OpenCC::new(config) {
+            Ok(converter) => Normalizer { mode, converter: Some(Mutex::new(converter)), rules },
+            Err(e) => {
+                warn!("Failed to load OpenCC {} ({}), comparing without normalization. Is OpenCC installed?", config.get_file_name(), e);
+                Normalizer { mode: Normalize::None, converter: None, rules }
+            }
+        }
+    }
+
+    /// Built without the `opencc` feature: warns when `mode` asks for a
+    /// conversion and compares without converting the script
+    #[cfg(not(feature = "opencc"))]
+    pub fn new(mode: Normalize, rules: TextRules) -> Self {
+        if mode != Normalize::None {
+            warn!("--normalize {} needs OpenCC, which this build leaves out (the `opencc` cargo feature), comparing without normalization", mode.name());
+        }
+        Normalizer { mode: Normalize::None, rules }
+    }
+
+    /// The conversion in effect, `None` if it couldn't be loaded
+    pub fn mode(&self) -> Normalize {
+        self.mode
+    }
+
+    pub fn rules(&self) -> TextRules {
+        self.rules
+    }
+
+    /// Apply the rules to `text`: NFC, width folding, the script conversion,
+    /// case folding, punctuation and finally whitespace
+    pub fn normalize(&self, text: &str) -> String {
+        let rules = &self.rules;
+        let mut text = if rules.nfc { text.nfc().collect() } else { text.to_string() };
+        if rules.fold_width {
+            text = text.chars().map(fold_width).collect();
+        }
+        #[cfg(feature = "opencc")]
+        if let Some(converter) = &self.converter {
+            text = converter.lock().expect("OpenCC lock poisoned").convert(&text);
+        }
+        if rules.fold_case {
+            text = text.to_lowercase();
+        }
+        if rules.strip_punctuation {
+            text.retain(|c| !is_punctuation(c));
+        }
+        match rules.whitespace {
+            Whitespace::Strip => text.retain(|c| !c.is_whitespace()),
+            Whitespace::Collapse => text = text.split_whitespace().collect::<Vec<_>>().join(" "),
+            Whitespace::Keep => {}
+        }
+        text
+    }
+}
+
+/// Half-width form of a full-width ASCII character or the ideographic space
+fn fold_width(c: char) -> char {
+    match c {
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        '\u{3000}' => ' ',
+        _ => c,
+    }
+}
+
+/// ASCII punctuation and symbols, Latin-1 and general punctuation, and the
+/// CJK, vertical, small and full-width punctuation blocks. The ideographic
+/// iteration marks `々` and `〇` are characters, not punctuation.
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation()
+        || matches!(c,
+            '¡' | '§' | '«' | '¶' | '·' | '»' | '¿'
+            | '\u{2010}'..='\u{2027}'
+            | '\u{2030}'..='\u{205E}'
+            | '\u{3001}'..='\u{3003}'
+            | '\u{3008}'..='\u{3011}'
+            | '\u{3014}'..='\u{301F}'
+            | '\u{30FB}'
+            | '\u{FE10}'..='\u{FE19}'
+            | '\u{FE30}'..='\u{FE6B}'
+            | '\u{FF01}'..='\u{FF0F}'
+            | '\u{FF1A}'..='\u{FF20}'
+            | '\u{FF3B}'..='\u{FF40}'
+            | '\u{FF5B}'..='\u{FF65}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A normalizer with only `rules`, no script conversion
+    fn normalizer(rules: TextRules) -> Normalizer {
+        Normalizer::new(Normalize::None, rules)
+    }
+
+    fn only(f: impl FnOnce(&mut TextRules)) -> TextRules {
+        let mut rules = TextRules { nfc: false, fold_width: false, fold_case: false, strip_punctuation: false, whitespace: Whitespace::Keep };
+        f(&mut rules);
+        rules
+    }
+
+    #[test]
+    fn nfc_composes_accents() {
+        let decomposed = "Cafe\u{301}";
+        assert_eq!(normalizer(only(|r| r.nfc = true)).normalize(decomposed), "Café");
+        assert_eq!(normalizer(only(|_| {})).normalize(decomposed), decomposed);
+    }
+
+    #[test]
+    fn fold_width_turns_full_width_ascii_half_width() {
+        let normalizer = normalizer(only(|r| r.fold_width = true));
+        assert_eq!(normalizer.normalize("ＡＢＣ１２３！\u{3000}美心"), "ABC123! 美心");
+    }
+
+    #[test]
+    fn fold_case_lowers_letters() {
+        assert_eq!(normalizer(only(|r| r.fold_case = true)).normalize("MAXIM'S 美心"), "maxim's 美心");
+    }
+
+    #[test]
+    fn strip_punctuation_keeps_iteration_marks() {
+        let normalizer = normalizer(only(|r| r.strip_punctuation = true));
+        assert_eq!(normalizer.normalize("「美心」·月餅。"), "美心月餅");
+        assert_eq!(normalizer.normalize("MAXIM'S!"), "MAXIMS");
+        assert_eq!(normalizer.normalize("人々〇"), "人々〇");
+    }
+
+    #[test]
+    fn whitespace_is_stripped_collapsed_or_kept() {
+        let text = "  老干妈 \t 风味\n";
+        assert_eq!(normalizer(only(|r| r.whitespace = Whitespace::Strip)).normalize(text), "老干妈风味");
+        assert_eq!(normalizer(only(|r| r.whitespace = Whitespace::Collapse)).normalize(text), "老干妈 风味");
+        assert_eq!(normalizer(only(|r| r.whitespace = Whitespace::Keep)).normalize(text), text);
+    }
+
+    #[test]
+    fn rules_apply_together_in_order() {
+        let rules = TextRules { fold_width: true, fold_case: true, strip_punctuation: true, ..TextRules::default() };
+        assert_eq!(normalizer(rules).normalize(" ＭＡＸＩＭ’Ｓ　美心。"), "maxims美心");
+    }
+
+    #[test]
+    fn rule_names_follow_the_rules() {
+        assert_eq!(TextRules::default().names(), ["nfc", "strip_whitespace"]);
+        let rules = TextRules { nfc: false, fold_width: true, fold_case: true, strip_punctuation: true, whitespace: Whitespace::Collapse };
+        assert_eq!(rules.names(), ["fold_width", "fold_case", "strip_punctuation", "collapse_whitespace"]);
+    }
+
+    #[test]
+    fn no_script_conversion_without_asking() {
+        let normalizer = normalizer(TextRules::default());
+        assert_eq!(normalizer.mode(), Normalize::None);
+        assert_eq!(normalizer.normalize("龍鳳"), "龍鳳");
+    }
+}
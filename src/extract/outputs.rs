@@ -0,0 +1,355 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use crate::images::manifest::{self, Manifest};
+
+use super::baseline::Baseline;
+use super::correlation;
+use super::dataset::{self, DatasetEntry, MISSING_EXAMPLES};
+use super::drift::MarkRecord;
+use super::enrich::{self, Enrichment};
+use super::gallery::{self, Gallery};
+use super::ipos::{self, IposOutput};
+use super::metrics::{self, Scoreboard};
+use super::report::Report;
+use super::results::{self, CsvResultsWriter, ExtractionResult, ResultsWriter};
+use super::snapshot;
+use super::source::ImageSource;
+
+/// Confusions listed at the end of a run
+const TOP_CONFUSIONS: usize = 10;
+
+/// The files a run writes as it goes, next to its results file
+pub struct Writers {
+    pub results: ResultsWriter,
+    pub mismatches: ResultsWriter,
+    pub mismatches_path: PathBuf,
+    /// The id of every request
+    pub request_index: ResultsWriter,
+    /// Snapshots of the run, with `--report-interval`
+    pub progress_metrics: Option<Arc<ResultsWriter>>,
+    /// `--output-csv`
+    pub csv_results: Option<CsvResultsWriter>,
+}
+
+impl Writers {
+    /// Open the files of a run writing to `output`, appending to them when
+    /// resuming. The mismatches of a `--mismatches-only` rerun must not
+    /// replace its `input`.
+    pub fn open(output: &Path, appending: bool, input: Option<&Path>, report_interval: u64, csv: Option<(&Path, bool)>) -> Result<Self> {
+        let mismatches_path = output.with_file_name(results::MISMATCHES_FILE_NAME);
+        if let Some(input) = input
+            && fs::canonicalize(input).ok() == fs::canonicalize(&mismatches_path).ok()
+        {
+            anyhow::bail!("This run writes its mismatches to {}, the --mismatches-only input; pass an --output in another directory",
+                mismatches_path.display());
+        }
+
+        let results = ResultsWriter::open(output, appending)?;
+        info!("Writing results to {}", output.display());
+        let mismatches = ResultsWriter::open(&mismatches_path, appending)?;
+        info!("Writing mismatches to {}", mismatches_path.display());
+        let request_index_path = output.with_file_name(correlation::REQUEST_INDEX_FILE_NAME);
+        let request_index = ResultsWriter::open(&request_index_path, appending)?;
+        info!("Writing the id of every request to {}", request_index_path.display());
+        let progress_metrics = if report_interval > 0 {
+            let path = output.with_file_name(snapshot::PROGRESS_METRICS_FILE_NAME);
+            info!("Logging a snapshot of the run every {}s to {}", report_interval, path.display());
+            Some(Arc::new(ResultsWriter::open(&path, appending)?))
+        } else {
+            None
+        };
+        let csv_results = match csv {
+            Some((path, bom)) => {
+                info!("Writing CSV results to {}", path.display());
+                Some(CsvResultsWriter::create(path, bom, appending)?)
+            }
+            None => None,
+        };
+        Ok(Self { results, mismatches, mismatches_path, request_index, progress_metrics, csv_results })
+    }
+}
+
+/// Save the metrics and the confusion matrix next to the results file
+/// `output`, and log them
+pub fn save_metrics(scoreboard: &Scoreboard, output: &Path) -> Result<()> {
+    let metrics_path = output.with_file_name(metrics::METRICS_FILE_NAME);
+    scoreboard.save(&metrics_path)?;
+    info!("{}", scoreboard.report());
+    info!("Metrics saved to {}", metrics_path.display());
+
+    let confusions_path = output.with_file_name(metrics::CONFUSIONS_FILE_NAME);
+    scoreboard.confusions.save(&confusions_path)?;
+    if !scoreboard.confusions.is_empty() {
+        info!("{}", scoreboard.confusions.report(TOP_CONFUSIONS));
+    }
+    info!("Confusion matrix saved to {} and {}",
+        confusions_path.display(), confusions_path.with_extension("csv").display());
+    Ok(())
+}
+
+/// Compare the run with the baseline at `path`, if it already has one, and
+/// with `save` make the run's metrics the new baseline unless it regressed.
+/// Returns the accuracy drop of a regression.
+pub fn check_baseline(scoreboard: &Scoreboard, baseline: Option<&Baseline>, path: &Path, threshold: f64, save: bool) -> Result<Option<f64>> {
+    let regression = baseline.and_then(|baseline| baseline.compare(scoreboard, path, threshold));
+    if save {
+        if regression.is_some() {
+            info!("Not replacing the baseline {} with a run that regressed", path.display());
+        } else {
+            scoreboard.save(path)?;
+            info!("Saved this run's metrics as the baseline {}", path.display());
+        }
+    }
+    Ok(regression)
+}
+
+/// Write the HTML report of a run to `path`, with the mismatches read back
+/// from `mismatches_path`
+pub fn save_html_report(
+    path: &Path,
+    config: Vec<(&'static str, String)>,
+    scoreboard: &Scoreboard,
+    results: &[ExtractionResult],
+    mismatches_path: &Path,
+) -> Result<()> {
+    let mismatches = results::load_mismatches(mismatches_path)?;
+    Report { config, scoreboard, results, mismatches: &mismatches }.save(path)?;
+    info!("HTML report saved to {}", path.display());
+    Ok(())
+}
+
+/// Write a copy of the run's input to `path` with each entry's result:
+/// the downloader's data file with `raw_data`, the dataset otherwise
+pub fn save_enriched(
+    path: &Path,
+    raw_data: Option<&Path>,
+    dataset: &Path,
+    results: &[ExtractionResult],
+    documents: &HashMap<String, (String, String)>,
+    model: &str,
+) -> Result<Enrichment> {
+    let enrichment = match raw_data {
+        Some(raw_data) => enrich::enrich_raw(raw_data, results, documents, model, path)?,
+        None => enrich::enrich_dataset(dataset, results, model, path)?,
+    };
+    info!("Enriched {} entries with their extraction in {}, {} entries have none",
+        enrichment.enriched, path.display(), enrichment.without_result);
+    if !enrichment.unmatched.is_empty() {
+        let examples: Vec<&str> = enrichment.unmatched.iter().take(MISSING_EXAMPLES).map(String::as_str).collect();
+        warn!("{} results match no entry of the input and were left out: {}{}",
+            enrichment.unmatched.len(), examples.join(", "),
+            if enrichment.unmatched.len() > examples.len() { ", ..." } else { "" });
+    }
+    Ok(enrichment)
+}
+
+/// Write the gallery of at most `limit` of `results` next to the results
+/// file `output`, using the thumbnails of the image manifest in
+/// `images_dir` where there are some. Returns where it was written.
+pub fn save_gallery(
+    output: &Path,
+    title: String,
+    results: &[ExtractionResult],
+    data: &[DatasetEntry],
+    source: &ImageSource,
+    images_dir: &Path,
+    limit: usize,
+) -> Result<PathBuf> {
+    let manifest = Manifest::load(&images_dir.join(manifest::MANIFEST_FILE_NAME))?;
+    let thumbnails: HashMap<String, PathBuf> = manifest.entries()
+        .filter_map(|entry| Some((entry.local_path.file_name()?.to_string_lossy().into_owned(), entry.thumbnail.clone()?)))
+        .filter(|(_, thumbnail)| thumbnail.is_file())
+        .collect();
+    let images = dataset::image_paths(data, source, images_dir);
+    let gallery = Gallery { title, results, images: &images, thumbnails: &thumbnails, limit };
+    let path = output.with_file_name(gallery::GALLERY_FILE_NAME);
+    let shown = gallery.save(&path)?;
+    info!("Gallery of {} images saved to {}", shown, path.display());
+    Ok(path)
+}
+
+/// Write `results` in the IPOS schema next to the results file `output`,
+/// adding to `records` the trademark record of the images it lacks
+pub fn save_ipos(
+    output: &Path,
+    results: &[ExtractionResult],
+    records: &mut HashMap<String, MarkRecord>,
+    data: &[DatasetEntry],
+    source: &ImageSource,
+    images_dir: &Path,
+    model: &str,
+) -> Result<IposOutput> {
+    dataset::find_records(records, results.iter().map(|result| result.image_name.as_str()), data, source, images_dir)?;
+    let path = output.with_file_name(ipos::IPOS_RESULTS_FILE_NAME);
+    let written = ipos::write(results, records, model, &path)?;
+    for image_name in &written.unidentified {
+        error!(event = "ipos_unidentified", image_name = %image_name,
+            "No application number for {}: not in the raw data, without a sidecar or manifest entry, and not named \
+             {{applicationNum}}_{{fileName}}; left out of {}", image_name, path.display());
+    }
+    info!("Wrote {} results in the IPOS schema to {}, leaving out {} failed and {} unidentified images",
+        written.items, path.display(), written.failed, written.unidentified.len());
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tm-query-outputs-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn result(image_name: &str, ground_truth: &str, prediction: &str) -> ExtractionResult {
+        serde_json::from_value(serde_json::json!({
+            "image_name": image_name,
+            "ground_truth": ground_truth,
+            "chinese_character": prediction,
+            "words_in_mark": null,
+            "description_of_device": null,
+            "status": "ok",
+            "error": null,
+            "elapsed_ms": 1,
+            "config_id": "abc",
+        }))
+        .unwrap()
+    }
+
+    fn entries(names: &[&str]) -> Vec<DatasetEntry> {
+        names.iter().map(|name| serde_json::from_value(serde_json::json!({"imageName": name, "chineseCharacter": "天"})).unwrap()).collect()
+    }
+
+    #[test]
+    fn metrics_and_confusions_go_next_to_the_results() {
+        let dir = temp_dir();
+        let output = dir.join("results.jsonl");
+        save_metrics(&Scoreboard::new("abc", "none", &[], "def"), &output).unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.join(metrics::METRICS_FILE_NAME)).unwrap()).unwrap();
+        assert_eq!(saved["config_id"], "abc");
+        assert!(dir.join(metrics::CONFUSIONS_FILE_NAME).is_file());
+    }
+
+    #[test]
+    fn a_run_that_regressed_does_not_replace_the_baseline() {
+        let dir = temp_dir();
+        let path = dir.join("baseline.json");
+        let mut scoreboard = Scoreboard::new("abc", "none", &[], "def");
+        scoreboard.total = 100;
+        scoreboard.exact_match = 80;
+        let baseline = |accuracy: f64| Baseline {
+            config_id: "abc".to_string(),
+            sample_id: "def".to_string(),
+            total: 100,
+            accuracy,
+            answered_accuracy: accuracy,
+            mean_cer: None,
+            median_cer: None,
+            image_scores: BTreeMap::new(),
+        };
+
+        let drop = check_baseline(&scoreboard, Some(&baseline(0.9)), &path, 1.0, true).unwrap();
+        assert!(drop.is_some());
+        assert!(!path.exists());
+
+        assert_eq!(check_baseline(&scoreboard, Some(&baseline(0.8)), &path, 1.0, true).unwrap(), None);
+        assert!((Baseline::load(&path).unwrap().accuracy - 0.8).abs() < 1e-9);
+
+        // A first baseline is only saved
+        fs::remove_file(&path).unwrap();
+        assert_eq!(check_baseline(&scoreboard, None, &path, 1.0, false).unwrap(), None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn the_html_report_shows_the_configuration_and_mismatches() {
+        let dir = temp_dir();
+        let (path, mismatches) = (dir.join("report.html"), dir.join("mismatches.jsonl"));
+        fs::write(&mismatches, serde_json::json!({
+            "image_name": "a.jpg", "image_path": dir.join("a.jpg"), "ground_truth": "天", "prediction": "大",
+            "edit_distance": 1, "raw_output": "{}",
+        }).to_string()).unwrap();
+        let results = [result("a.jpg", "天", "大")];
+        let scoreboard = Scoreboard::new("abc", "none", &[], "def");
+        save_html_report(&path, vec![("Model", "qwen-vl".to_string())], &scoreboard, &results, &mismatches).unwrap();
+
+        let html = fs::read_to_string(&path).unwrap();
+        assert!(html.contains("qwen-vl"));
+        assert!(html.contains("a.jpg"));
+    }
+
+    #[test]
+    fn an_enriched_dataset_reports_what_it_could_not_match() {
+        let dir = temp_dir();
+        let (dataset, path) = (dir.join("cleaned_data.json"), dir.join("enriched.json"));
+        fs::write(&dataset, r#"[{"imageName": "a.jpg", "chineseCharacter": "天"}, {"imageName": "b.jpg", "chineseCharacter": "福"}]"#).unwrap();
+        let results = [result("a.jpg", "天", "天"), result("gone.jpg", "天", "天")];
+
+        let enrichment = save_enriched(&path, None, &dataset, &results, &HashMap::new(), "qwen-vl").unwrap();
+        assert_eq!((enrichment.enriched, enrichment.without_result), (1, 1));
+        assert_eq!(enrichment.unmatched, ["gone.jpg"]);
+        let enriched: Vec<serde_json::Value> = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(enriched[0].get(enrich::ENRICHED_KEY).is_some());
+        assert!(enriched[1].get(enrich::ENRICHED_KEY).is_none());
+    }
+
+    #[test]
+    fn the_gallery_goes_next_to_the_results() {
+        let dir = temp_dir();
+        let results = [result("a.jpg", "天", "天"), result("b.jpg", "天", "大")];
+        let data = entries(&["a.jpg", "b.jpg"]);
+        let path = save_gallery(&dir.join("results.jsonl"), "qwen-vl".to_string(), &results, &data,
+            &ImageSource::Files, &dir, 1).unwrap();
+        assert_eq!(path, dir.join(gallery::GALLERY_FILE_NAME));
+        let html = fs::read_to_string(&path).unwrap();
+        // The mismatch comes first, and only one is shown
+        assert!(html.contains("b.jpg") && !html.contains("a.jpg"));
+    }
+
+    #[test]
+    fn ipos_results_leave_out_images_without_an_application_number() {
+        let dir = temp_dir();
+        let results = [result("40201900001X_rep.jpg", "天", "天"), result("scan.jpg", "天", "天")];
+        let data = entries(&["40201900001X_rep.jpg", "scan.jpg"]);
+        let mut records = HashMap::new();
+
+        let written = save_ipos(&dir.join("results.jsonl"), &results, &mut records, &data, &ImageSource::Files, &dir, "qwen-vl").unwrap();
+        assert_eq!((written.items, written.failed), (1, 0));
+        assert_eq!(written.unidentified, ["scan.jpg"]);
+        assert_eq!(records["40201900001X_rep.jpg"].application_num, "40201900001X");
+        let days: Vec<serde_json::Value> = serde_json::from_str(&fs::read_to_string(dir.join(ipos::IPOS_RESULTS_FILE_NAME)).unwrap()).unwrap();
+        assert_eq!(days[0]["items"][0]["applicationNum"], "40201900001X");
+    }
+
+    #[test]
+    fn writers_open_next_to_the_results_and_keep_them_when_appending() {
+        let dir = temp_dir();
+        let output = dir.join("results.jsonl");
+        fs::write(&output, "{\"image_name\":\"1.jpg\"}\n").unwrap();
+
+        let writers = Writers::open(&output, true, None, 0, None).unwrap();
+        assert!(writers.progress_metrics.is_none() && writers.csv_results.is_none());
+        assert_eq!(writers.mismatches_path, dir.join(results::MISMATCHES_FILE_NAME));
+        assert!(dir.join(correlation::REQUEST_INDEX_FILE_NAME).exists());
+        assert_eq!(fs::read_to_string(&output).unwrap().lines().count(), 1);
+
+        let csv = dir.join("results.csv");
+        let writers = Writers::open(&output, false, None, 30, Some((&csv, false))).unwrap();
+        assert!(writers.progress_metrics.is_some() && writers.csv_results.is_some());
+        assert!(dir.join(snapshot::PROGRESS_METRICS_FILE_NAME).exists());
+        assert_eq!(fs::read_to_string(&output).unwrap(), "");
+
+        // A rerun of the mismatches must not write over them
+        let mismatches = dir.join(results::MISMATCHES_FILE_NAME);
+        fs::write(&mismatches, "{}\n").unwrap();
+        assert!(Writers::open(&output, false, Some(&mismatches), 0, None).is_err());
+        assert_eq!(fs::read_to_string(&mismatches).unwrap(), "{}\n");
+    }
+}
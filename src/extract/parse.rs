@@ -0,0 +1,276 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::LazyLock;
+
+use super::protocol::{ApiResponse, Field, BATCH_KEY};
+
+/// How the fields of a reply were recovered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParseMethod {
+    /// The reply was the JSON object
+    #[default]
+    Json,
+    /// The JSON object was found in a code fence or surrounded by prose
+    EmbeddedJson,
+    /// No JSON object; the fields were picked out of free text
+    Heuristic,
+}
+
+/// Runs of Han characters
+static HAN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\p{Han}+").expect("valid regex"));
+
+/// Han characters named as such, e.g. `Chinese characters are 美心` or `中文: "美心"`
+static LABELED_HAN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i:chinese(?:\s+(?:characters?|text|words?))?|中文|汉字|漢字)\W{0,20}?(\p{Han}+(?:[ \t]*\p{Han}+)*)"#)
+        .expect("valid regex")
+});
+
+/// Latin words named as such, e.g. `English words are MAXIM'S` or `wordsInMark: "Maxim's"`
+static LABELED_WORDS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?:(?i:english(?:\s+(?:words?|text))?|latin\s+text|words\s*in\s*(?:the\s+)?mark|wordsInMark)|英文)\s*(?i:is|are|reads?|says?)?\s*[:：=\-]?\s*["'“‘]?([A-Za-z0-9](?:[A-Za-z0-9'’&.\-]*[A-Za-z0-9'’])?(?:[ \t]+[A-Za-z0-9](?:[A-Za-z0-9'’&.\-]*[A-Za-z0-9'’])?)*)"#)
+        .expect("valid regex")
+});
+
+/// A double-quoted Latin span
+static QUOTED_LATIN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"["“]([A-Za-z0-9][A-Za-z0-9'’&.\- ]{0,60}[A-Za-z0-9'’.])["”]"#).expect("valid regex")
+});
+
+/// Upper case Latin words of two or more letters, e.g. `MAXIM'S`
+static UPPER_WORDS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\b[A-Z][A-Z0-9][A-Z0-9'’&\-]*(?:[ \t]+[A-Z][A-Z0-9][A-Z0-9'’&\-]*)*\b").expect("valid regex")
+});
+
+/// A sentence describing the device or logo
+static DEVICE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)(?:device|logo|图形|圖形)(?:\s+description)?\s*(?:is|shows|depicts|consists\s+of|:|：|=)\s*["'“]?([^."”\n]+)"#)
+        .expect("valid regex")
+});
+
+/// Upper case words models use in prose that are never part of a mark
+const NOT_MARK_WORDS: &[&str] = &["JSON", "CJK", "OCR", "NULL", "N/A", "NONE"];
+
+/// Parse the fields from a model's reply. The JSON object is looked for
+/// first, on its own, in a code fence or surrounded by prose; failing that
+/// the fields are picked out of the text. Errors only when neither finds
+/// anything.
+pub fn parse_content(text: &str) -> Result<ApiResponse> {
+    let text = text.trim();
+    let error = match serde_json::from_str::<ApiResponse>(text) {
+        Ok(response) => return Ok(response),
+        Err(e) => e,
+    };
+
+    let unfenced = strip_code_fence(text);
+    if let (Some(start), Some(end)) = (unfenced.find('{'), unfenced.rfind('}'))
+        && start < end
+        && let Ok(mut response) = serde_json::from_str::<ApiResponse>(&unfenced[start..=end])
+    {
+        response.parse_method = ParseMethod::EmbeddedJson;
+        return Ok(response);
+    }
+
+    if let Some(response) = heuristic(unfenced) {
+        return Ok(response);
+    }
+
+    let excerpt: String = text.chars().take(200).collect();
+    Err(error).with_context(|| format!("Model reply is not the expected JSON: {}", excerpt))
+}
+
+/// The per-image objects of a reply to a batch: the `BATCH_KEY` array of
+/// the JSON object asked for, or a bare array, on its own or in a code fence
+/// or prose, the second value telling which. There is no heuristic
+/// fallback, as free text can't say which image a value belongs to.
+pub fn parse_batch_items(text: &str) -> Result<(Vec<Value>, bool)> {
+    let text = text.trim();
+    let items = |value: Value| match value {
+        Value::Array(items) => Some(items),
+        Value::Object(mut object) => match object.remove(BATCH_KEY) {
+            Some(Value::Array(items)) => Some(items),
+            _ => None,
+        },
+        _ => None,
+    };
+    if let Some(items) = serde_json::from_str(text).ok().and_then(items) {
+        return Ok((items, false));
+    }
+
+    let unfenced = strip_code_fence(text);
+    let start = unfenced.find(['{', '[']);
+    let end = match start.map(|start| &unfenced[start..start + 1]) {
+        Some("[") => unfenced.rfind(']'),
+        _ => unfenced.rfind('}'),
+    };
+    if let (Some(start), Some(end)) = (start, end)
+        && start < end
+        && let Some(items) = serde_json::from_str(&unfenced[start..=end]).ok().and_then(items)
+    {
+        return Ok((items, true));
+    }
+
+    let excerpt: String = text.chars().take(200).collect();
+    anyhow::bail!("Batch reply has no \"{}\" array: {}", BATCH_KEY, excerpt)
+}
+
+fn strip_code_fence(text: &str) -> &str {
+    let Some(inner) = text.strip_prefix("```") else {
+        return text;
+    };
+    // Drop the language tag on the opening line
+    let inner = inner.split_once('\n').map_or("", |(_, rest)| rest);
+    inner.trim_end().strip_suffix("```").unwrap_or(inner).trim()
+}
+
+/// Pick the fields out of free text: Han characters named as Chinese, or
+/// else every run of Han characters; Latin words named as such, or else
+/// quoted spans other than the JSON keys or upper case spans; and a
+/// sentence about the device or logo.
+fn heuristic(text: &str) -> Option<ApiResponse> {
+    let chinese_character = LABELED_HAN.captures(text)
+        .map(|c| c[1].split_whitespace().collect::<String>())
+        .or_else(|| {
+            let runs: String = HAN.find_iter(text).map(|m| m.as_str()).collect();
+            (!runs.is_empty()).then_some(runs)
+        });
+
+    let words_in_mark = LABELED_WORDS.captures(text)
+        .map(|c| c[1].to_string())
+        .filter(|words| !is_filler(words))
+        .or_else(|| QUOTED_LATIN.captures_iter(text)
+            .map(|c| c.get(1).map_or("", |m| m.as_str()))
+            .find(|quoted| !Field::ALL.iter().any(|field| field.key() == *quoted))
+            .map(String::from))
+        .or_else(|| UPPER_WORDS.find_iter(text)
+            .map(|m| m.as_str())
+            .find(|words| !NOT_MARK_WORDS.contains(words))
+            .map(String::from));
+
+    let description_of_device = DEVICE.captures(text)
+        .map(|c| c[1].trim().to_string())
+        .filter(|d| !d.is_empty() && !is_filler(d));
+
+    if chinese_character.is_none() && words_in_mark.is_none() && description_of_device.is_none() {
+        return None;
+    }
+    Some(ApiResponse {
+        words_in_mark,
+        chinese_character,
+        description_of_device,
+        parse_method: ParseMethod::Heuristic,
+        ..Default::default()
+    })
+}
+
+/// Whether a captured span is prose rather than a value, e.g. the `text` of
+/// `no English text` or `none`
+fn is_filler(text: &str) -> bool {
+    let first = text.split_whitespace().next().unwrap_or("").to_ascii_lowercase();
+    matches!(first.trim_end_matches('.'), "" | "none" | "null" | "n/a" | "no" | "not" | "there" | "the" | "text" | "words" | "in" | "is" | "are")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fields expected from a reply: chinese, words and device
+    type Fields = (Option<&'static str>, Option<&'static str>, Option<&'static str>);
+
+    /// Replies seen from vision models, with the fields expected from each
+    /// and how they are found
+    const CORPUS: &[(&str, Fields, ParseMethod)] = &[
+        (
+            r#"{"chineseCharacter": "美心", "wordsInMark": "MAXIM'S", "descrOfDevice": null}"#,
+            (Some("美心"), Some("MAXIM'S"), None),
+            ParseMethod::Json,
+        ),
+        (
+            "  \n{\"chineseCharacter\":\"龍\",\"wordsInMark\":null,\"descrOfDevice\":\"a dragon\"}\n\n",
+            (Some("龍"), None, Some("a dragon")),
+            ParseMethod::Json,
+        ),
+        (
+            "```json\n{\"chineseCharacter\": \"双喜\", \"wordsInMark\": null, \"descrOfDevice\": null}\n```",
+            (Some("双喜"), None, None),
+            ParseMethod::EmbeddedJson,
+        ),
+        (
+            "```\n{\"chineseCharacter\": \"福\", \"wordsInMark\": \"FU\", \"descrOfDevice\": null}\n```",
+            (Some("福"), Some("FU"), None),
+            ParseMethod::EmbeddedJson,
+        ),
+        (
+            "Here is the extracted information:\n{\"chineseCharacter\": \"老干妈\", \"wordsInMark\": \"LAOGANMA\", \"descrOfDevice\": \"a portrait of a woman\"}\nLet me know if you need anything else.",
+            (Some("老干妈"), Some("LAOGANMA"), Some("a portrait of a woman")),
+            ParseMethod::EmbeddedJson,
+        ),
+        (
+            "<think>The mark shows two characters.</think>\n{\"chineseCharacter\": \"天福\", \"wordsInMark\": null, \"descrOfDevice\": null}",
+            (Some("天福"), None, None),
+            ParseMethod::EmbeddedJson,
+        ),
+        (
+            "The Chinese characters are 美心 and the English words are MAXIM'S.",
+            (Some("美心"), Some("MAXIM'S"), None),
+            ParseMethod::Heuristic,
+        ),
+        (
+            "中文: \"大 白 兔\"\nEnglish: WHITE RABBIT\nLogo: a rabbit jumping",
+            (Some("大白兔"), Some("WHITE RABBIT"), Some("a rabbit jumping")),
+            ParseMethod::Heuristic,
+        ),
+        (
+            "The trademark contains the characters 同仁堂 in a red seal, with no English text.",
+            (Some("同仁堂"), None, None),
+            ParseMethod::Heuristic,
+        ),
+        (
+            "I can see the word \"Lee Kum Kee\" written below the characters 李錦記.",
+            (Some("李錦記"), Some("Lee Kum Kee"), None),
+            ParseMethod::Heuristic,
+        ),
+        (
+            "There are no Chinese characters. The JSON would be: wordsInMark is SONY.",
+            (None, Some("SONY"), None),
+            ParseMethod::Heuristic,
+        ),
+        (
+            "The device is a stylized lotus flower. No text appears in the mark.",
+            (None, None, Some("a stylized lotus flower")),
+            ParseMethod::Heuristic,
+        ),
+    ];
+
+    #[test]
+    fn corpus_of_model_replies() {
+        for (reply, (chinese, words, device), method) in CORPUS {
+            let response = parse_content(reply).unwrap_or_else(|e| panic!("{:?}: {:#}", reply, e));
+            let fields = (
+                response.chinese_character.as_deref(),
+                response.words_in_mark.as_deref(),
+                response.description_of_device.as_deref(),
+            );
+            assert_eq!(fields, (*chinese, *words, *device), "{:?}", reply);
+            assert_eq!(response.parse_method, *method, "{:?}", reply);
+        }
+    }
+
+    #[test]
+    fn replies_without_any_field_fail() {
+        for reply in ["", "I'm sorry, I can't help with that.", "```json\n{\"chineseCharacter\": \n```"] {
+            assert!(parse_content(reply).is_err(), "{:?}", reply);
+        }
+    }
+
+    #[test]
+    fn batch_items_from_the_object_a_bare_array_or_prose() {
+        let (items, embedded) = parse_batch_items(r#"{"images": [{"chineseCharacter": "美心"}, {"chineseCharacter": null}]}"#).unwrap();
+        assert_eq!((items.len(), embedded), (2, false));
+        let (items, embedded) = parse_batch_items("```json\n[{\"chineseCharacter\": \"龍\"}]\n```").unwrap();
+        assert_eq!((items.len(), embedded), (1, true));
+        assert!(parse_batch_items("The first image says 美心 and the second 龍.").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// How busy the two stages of a run were: preprocessing workers reading and
+/// encoding images into a bounded queue, and request workers taking them
+/// from it. Shared by every worker.
+#[derive(Debug, Default)]
+pub struct PipelineStats {
+    /// Groups or batches prepared
+    prepared: AtomicU64,
+    preprocess_busy_us: AtomicU64,
+    /// Time prepared items waited for room in the queue
+    blocked_us: AtomicU64,
+    /// Items taken from the queue
+    received: AtomicU64,
+    request_busy_us: AtomicU64,
+    /// Time a free request worker waited for the queue to fill
+    starved_us: AtomicU64,
+    /// Items left in the queue when one was taken, summed and at most
+    queued: AtomicU64,
+    max_queued: AtomicU64,
+}
+
+/// Utilization of each stage, as reported at the end of a run and saved in
+/// the metrics file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PipelineSummary {
+    /// Images, or batches of them, that went through the preprocessing
+    pub prepared: u64,
+    pub preprocess_workers: usize,
+    /// Share of the preprocessing workers' time spent preparing images
+    pub preprocess_utilization: f64,
+    /// Time prepared images waited on full queue, summed over workers
+    pub preprocess_blocked_ms: u64,
+    pub request_workers: usize,
+    /// Share of the request workers' time spent sending images
+    pub request_utilization: f64,
+    /// Time a free request worker waited on an empty queue
+    pub request_starved_ms: u64,
+    pub queue_capacity: usize,
+    pub mean_queued: f64,
+    pub max_queued: u64,
+}
+
+impl PipelineStats {
+    /// Count one prepared item: `busy` reading and encoding, `blocked`
+    /// waiting for room in the queue
+    pub fn record_prepared(&self, busy: Duration, blocked: Duration) {
+        self.prepared.fetch_add(1, Ordering::Relaxed);
+        self.preprocess_busy_us.fetch_add(busy.as_micros() as u64, Ordering::Relaxed);
+        self.blocked_us.fetch_add(blocked.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Count one item taken from the queue, after `waited` on an empty
+    /// queue, with `queued` items left behind it
+    pub fn record_received(&self, waited: Duration, queued: usize) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        self.starved_us.fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+        self.queued.fetch_add(queued as u64, Ordering::Relaxed);
+        self.max_queued.fetch_max(queued as u64, Ordering::Relaxed);
+    }
+
+    /// Count the time a request worker spent on one item
+    pub fn record_request(&self, busy: Duration) {
+        self.request_busy_us.fetch_add(busy.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Utilization over `elapsed` of the run, with the size of each pool
+    pub fn summary(&self, elapsed: Duration, preprocess_workers: usize, request_workers: usize, queue_capacity: usize) -> PipelineSummary {
+        let utilization = |busy_us: &AtomicU64, workers: usize| {
+            let available = elapsed.as_micros() as f64 * workers as f64;
+            if available > 0.0 { (busy_us.load(Ordering::Relaxed) as f64 / available).min(1.0) } else { 0.0 }
+        };
+        let received = self.received.load(Ordering::Relaxed);
+        PipelineSummary {
+            prepared: self.prepared.load(Ordering::Relaxed),
+            preprocess_workers,
+            preprocess_utilization: utilization(&self.preprocess_busy_us, preprocess_workers),
+            preprocess_blocked_ms: self.blocked_us.load(Ordering::Relaxed) / 1000,
+            request_workers,
+            request_utilization: utilization(&self.request_busy_us, request_workers),
+            request_starved_ms: self.starved_us.load(Ordering::Relaxed) / 1000,
+            queue_capacity,
+            mean_queued: if received > 0 { self.queued.load(Ordering::Relaxed) as f64 / received as f64 } else { 0.0 },
+            max_queued: self.max_queued.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for PipelineSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Pipeline ({} images or batches prepared):", self.prepared)?;
+        writeln!(f, "  Preprocessing: {} workers, {:.0}% busy, {:.1}s waiting for room in the queue",
+            self.preprocess_workers, self.preprocess_utilization * 100.0, self.preprocess_blocked_ms as f64 / 1000.0)?;
+        writeln!(f, "  Queue:         {:.1} items waiting on average, at most {} of {}",
+            self.mean_queued, self.max_queued, self.queue_capacity)?;
+        write!(f, "  Requests:      {} workers, {:.0}% busy, {:.1}s waiting for a prepared image",
+            self.request_workers, self.request_utilization * 100.0, self.request_starved_ms as f64 / 1000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, Semaphore};
+
+    const ITEMS: usize = 16;
+    const PREPROCESS: Duration = Duration::from_millis(20);
+    const REQUEST: Duration = Duration::from_millis(40);
+    const PREPROCESS_WORKERS: usize = 2;
+    const REQUEST_WORKERS: usize = 4;
+    const QUEUE: usize = 4;
+
+    /// An endpoint answering every request after `REQUEST`
+    async fn mock_endpoint() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/invoke", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    tokio::time::sleep(REQUEST).await;
+                    let body = "{}";
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        url
+    }
+
+    /// Run `ITEMS` through the two stages the way the extractor does:
+    /// blocking preprocessing workers filling a bounded queue, request
+    /// workers draining it
+    async fn run_pipeline(url: &str) -> (Duration, PipelineSummary) {
+        let stats = Arc::new(PipelineStats::default());
+        let client = reqwest::Client::new();
+        let preprocess_slots = Arc::new(Semaphore::new(PREPROCESS_WORKERS));
+        let request_slots = Arc::new(Semaphore::new(REQUEST_WORKERS));
+        let (ready_tx, mut ready_rx) = mpsc::channel::<usize>(QUEUE);
+        let started = Instant::now();
+
+        let preprocessing = {
+            let stats = Arc::clone(&stats);
+            tokio::spawn(async move {
+                for item in 0..ITEMS {
+                    let permit = Arc::clone(&preprocess_slots).acquire_owned().await.unwrap();
+                    let (stats, ready_tx) = (Arc::clone(&stats), ready_tx.clone());
+                    tokio::spawn(async move {
+                        let busy = Instant::now();
+                        tokio::task::spawn_blocking(|| std::thread::sleep(PREPROCESS)).await.unwrap();
+                        let busy = busy.elapsed();
+                        let queued = Instant::now();
+                        ready_tx.send(item).await.unwrap();
+                        stats.record_prepared(busy, queued.elapsed());
+                        drop(permit);
+                    });
+                }
+            })
+        };
+
+        let mut requests = Vec::new();
+        loop {
+            let permit = Arc::clone(&request_slots).acquire_owned().await.unwrap();
+            let waiting = Instant::now();
+            let Some(item) = ready_rx.recv().await else {
+                break;
+            };
+            stats.record_received(waiting.elapsed(), ready_rx.len());
+            let (stats, client, url) = (Arc::clone(&stats), client.clone(), url.to_string());
+            requests.push(tokio::spawn(async move {
+                let busy = Instant::now();
+                client.post(url).body(item.to_string()).send().await.unwrap().text().await.unwrap();
+                stats.record_request(busy.elapsed());
+                drop(permit);
+            }));
+            if requests.len() == ITEMS {
+                break;
+            }
+        }
+        for request in requests {
+            request.await.unwrap();
+        }
+        preprocessing.await.unwrap();
+        let elapsed = started.elapsed();
+        (elapsed, stats.summary(elapsed, PREPROCESS_WORKERS, REQUEST_WORKERS, QUEUE))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn stages_overlap() {
+        let url = mock_endpoint().await;
+        let (elapsed, summary) = run_pipeline(&url).await;
+
+        // One stage after the other would take the sum of the stages; run
+        // together the slower stage sets the pace
+        let preprocess_stage = PREPROCESS * (ITEMS / PREPROCESS_WORKERS) as u32;
+        let request_stage = REQUEST * (ITEMS / REQUEST_WORKERS) as u32;
+        let sequential = preprocess_stage + request_stage;
+        assert!(elapsed < sequential.mul_f64(0.85), "took {:?}, the stages one after the other {:?}", elapsed, sequential);
+        assert!(elapsed >= preprocess_stage.max(request_stage));
+
+        assert_eq!(summary.prepared, ITEMS as u64);
+        assert!(summary.preprocess_utilization > 0.5, "{}", summary);
+        assert!(summary.request_utilization > 0.5, "{}", summary);
+        assert!(summary.max_queued <= QUEUE as u64);
+    }
+
+    #[test]
+    fn summary_of_an_idle_run_is_zero() {
+        let summary = PipelineStats::default().summary(Duration::ZERO, 2, 4, 8);
+        assert_eq!((summary.prepared, summary.preprocess_utilization, summary.request_utilization), (0, 0.0, 0.0));
+        assert_eq!(summary.mean_queued, 0.0);
+    }
+
+    #[test]
+    fn utilization_is_busy_time_over_the_pool() {
+        let stats = PipelineStats::default();
+        for _ in 0..4 {
+            stats.record_prepared(Duration::from_millis(250), Duration::from_millis(10));
+            stats.record_received(Duration::from_millis(5), 2);
+            stats.record_request(Duration::from_millis(500));
+        }
+        let summary = stats.summary(Duration::from_secs(1), 2, 4, 8);
+        assert_eq!(summary.preprocess_utilization, 0.5);
+        assert_eq!(summary.request_utilization, 0.5);
+        assert_eq!((summary.preprocess_blocked_ms, summary.request_starved_ms), (40, 20));
+        assert_eq!((summary.mean_queued, summary.max_queued), (2.0, 2));
+    }
+}
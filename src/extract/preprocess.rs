@@ -0,0 +1,75 @@
+use clap::ValueEnum;
+use image::{DynamicImage, GrayImage, Luma};
+use serde::{Deserialize, Serialize};
+
+/// Processing of the image after resizing, before it is encoded
+/// (`--preprocess`), for trying whether it helps the model read
+/// low-contrast marks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Preprocess {
+    /// The image as it is
+    #[default]
+    None,
+    /// Shades of gray
+    Grayscale,
+    /// Black and white, split at the gray level found by Otsu's method
+    Binarize,
+}
+
+impl Preprocess {
+    pub fn name(self) -> &'static str {
+        match self {
+            Preprocess::None => "none",
+            Preprocess::Grayscale => "grayscale",
+            Preprocess::Binarize => "binarize",
+        }
+    }
+
+    pub fn apply(self, image: DynamicImage) -> DynamicImage {
+        match self {
+            Preprocess::None => image,
+            Preprocess::Grayscale => DynamicImage::ImageLuma8(image.to_luma8()),
+            Preprocess::Binarize => DynamicImage::ImageLuma8(binarize(image.to_luma8())),
+        }
+    }
+}
+
+/// Pixels above the Otsu threshold of `image` made white, the others black
+fn binarize(mut image: GrayImage) -> GrayImage {
+    let threshold = otsu_threshold(&image);
+    for Luma([level]) in image.pixels_mut() {
+        *level = if *level > threshold { 255 } else { 0 };
+    }
+    image
+}
+
+/// Gray level that best splits the histogram of `image` in two, the one
+/// maximizing the variance between the classes below and above it
+fn otsu_threshold(image: &GrayImage) -> u8 {
+    let mut histogram = [0u64; 256];
+    for Luma([level]) in image.pixels() {
+        histogram[*level as usize] += 1;
+    }
+    let total: u64 = histogram.iter().sum();
+    let sum: f64 = histogram.iter().enumerate().map(|(level, count)| level as f64 * *count as f64).sum();
+
+    let (mut below, mut below_sum) = (0u64, 0.0);
+    let (mut best, mut best_variance) = (0u8, 0.0);
+    for (level, count) in histogram.iter().enumerate() {
+        below += count;
+        below_sum += level as f64 * *count as f64;
+        let above = total - below;
+        if below == 0 || above == 0 {
+            continue;
+        }
+        let below_mean = below_sum / below as f64;
+        let above_mean = (sum - below_sum) / above as f64;
+        let variance = below as f64 * above as f64 * (below_mean - above_mean).powi(2);
+        if variance > best_variance {
+            best = level as u8;
+            best_variance = variance;
+        }
+    }
+    best
+}
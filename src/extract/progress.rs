@@ -0,0 +1,139 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::VecDeque;
+use std::io::IsTerminal;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Time between status lines when stderr isn't a terminal
+const STATUS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Latest completions the throughput behind the ETA is measured over
+const RATE_WINDOW: usize = 50;
+
+/// Progress of a run on stderr: a bar with completed images, running
+/// accuracy, errors and ETA on a terminal, otherwise a status line every
+/// `STATUS_INTERVAL`
+pub struct Progress {
+    bars: MultiProgress,
+    /// Unset when stderr isn't a terminal
+    bar: OnceLock<ProgressBar>,
+    state: Mutex<State>,
+}
+
+struct State {
+    total: u64,
+    done: u64,
+    /// When the latest `RATE_WINDOW` images completed
+    recent: VecDeque<Instant>,
+    last_status: Instant,
+}
+
+impl Progress {
+    /// Progress drawn through `bars`, shown once `start` is called
+    pub fn new(bars: MultiProgress) -> Self {
+        Progress {
+            bars,
+            bar: OnceLock::new(),
+            state: Mutex::new(State { total: 0, done: 0, recent: VecDeque::new(), last_status: Instant::now() }),
+        }
+    }
+
+    /// Start counting towards `total` images
+    pub fn start(&self, total: usize) {
+        if let Ok(mut state) = self.state.lock() {
+            state.total = total as u64;
+            state.last_status = Instant::now();
+        }
+        if std::io::stderr().is_terminal() {
+            let bar = ProgressBar::new(total as u64);
+            if let Ok(style) = ProgressStyle::with_template("{bar:40.cyan/blue} {pos}/{len} images {msg}") {
+                bar.set_style(style.progress_chars("=> "));
+            }
+            let _ = self.bar.set(self.bars.add(bar));
+        }
+    }
+
+    /// Count a completed image, with the run's accuracy so far (`None` when
+    /// the characters aren't scored) and errors
+    pub fn record(&self, accuracy: Option<f64>, errors: usize) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        state.done += 1;
+        state.recent.push_back(now);
+        if state.recent.len() > RATE_WINDOW {
+            state.recent.pop_front();
+        }
+        let status = format!("accuracy {}, {} errors, ETA {}",
+            accuracy.map_or("-".to_string(), |a| format!("{:.1}%", 100.0 * a)),
+            errors,
+            state.eta().map_or("-".to_string(), format_duration));
+        match self.bar.get() {
+            Some(bar) => {
+                bar.set_message(status);
+                bar.inc(1);
+            }
+            None if now.duration_since(state.last_status) >= STATUS_INTERVAL || state.done == state.total => {
+                state.last_status = now;
+                eprintln!("Progress: {}/{} images, {}", state.done, state.total, status);
+            }
+            None => {}
+        }
+    }
+
+    /// Images completed so far
+    pub fn done(&self) -> usize {
+        self.state.lock().map_or(0, |state| state.done as usize)
+    }
+
+    /// Images per minute over the latest completions
+    pub fn images_per_minute(&self) -> Option<f64> {
+        self.state.lock().ok()?.per_second().map(|per_second| per_second * 60.0)
+    }
+
+    /// Time left at the throughput of the latest completions
+    pub fn eta(&self) -> Option<Duration> {
+        self.state.lock().ok()?.eta()
+    }
+
+    /// Images not yet completed
+    pub fn remaining(&self) -> usize {
+        self.state.lock().map_or(0, |state| state.total.saturating_sub(state.done) as usize)
+    }
+
+    /// Take the bar off the screen before the summary is printed
+    pub fn finish(&self) {
+        if let Some(bar) = self.bar.get() {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+impl State {
+    /// Images per second over the latest completions
+    fn per_second(&self) -> Option<f64> {
+        let (first, last) = (self.recent.front()?, self.recent.back()?);
+        let span = last.duration_since(*first).as_secs_f64();
+        if self.recent.len() < 2 || span <= 0.0 {
+            return None;
+        }
+        Some((self.recent.len() - 1) as f64 / span)
+    }
+
+    /// Time left at the throughput of the latest completions
+    fn eta(&self) -> Option<Duration> {
+        let per_second = self.per_second()?;
+        Some(Duration::from_secs_f64(self.total.saturating_sub(self.done) as f64 / per_second))
+    }
+}
+
+/// `1h02m`, `3m05s` or `12s`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, _) => format!("{}h{:02}m", h, m),
+    }
+}
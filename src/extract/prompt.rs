@@ -0,0 +1,87 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+use super::protocol::Field;
+
+/// Prompt used when no `--prompt-file` is given
+pub const DEFAULT_TEMPLATE: &str = "You read trademark images. Reply with a single JSON object with the keys {{fields}}. \
+Use null for anything not present in the mark.";
+
+/// Key the model is asked for and what it should hold, substituted for
+/// `{{fields}}`
+fn describe(field: Field) -> &'static str {
+    match field {
+        Field::Chinese => "\"chineseCharacter\" (the {{language}} characters in the mark, exactly as written)",
+        Field::Words => "\"wordsInMark\" (any words in Latin script)",
+        Field::Device => "\"descrOfDevice\" (a short description of the logo or device)",
+    }
+}
+
+/// The keys of `fields` as a list in prose: `a`, `a and b`, `a, b and c`
+fn field_list(fields: &[Field]) -> String {
+    let described: Vec<&str> = fields.iter().map(|field| describe(*field)).collect();
+    match described.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} and {}", rest.join(", "), last),
+        Some((last, _)) => last.to_string(),
+        None => String::new(),
+    }
+}
+
+/// The instruction text sent to the model
+#[derive(Debug, Clone)]
+pub struct Prompt {
+    pub text: String,
+    /// Short hash of the text, recorded with each result
+    pub hash: String,
+}
+
+impl Prompt {
+    /// Load the template at `path`, or the default one, and fill in its
+    /// placeholders, asking for `fields`. Unknown placeholders are an error.
+    pub fn load(path: Option<&Path>, language: &str, fields: &[Field]) -> Result<Self> {
+        let template = match path {
+            Some(path) => fs::read_to_string(path)
+                .with_context(|| format!("Failed to read prompt file: {}", path.display()))?,
+            None => DEFAULT_TEMPLATE.to_string(),
+        };
+        let text = render(&template, language, fields)
+            .with_context(|| match path {
+                Some(path) => format!("Invalid prompt file: {}", path.display()),
+                None => "Invalid default prompt".to_string(),
+            })?;
+        Ok(Prompt::new(text))
+    }
+
+    /// A prompt of ready text, with its hash
+    pub fn new(text: String) -> Self {
+        let hash = format!("{:x}", Sha256::digest(text.as_bytes()))[..12].to_string();
+        Prompt { text, hash }
+    }
+}
+
+/// Substitute `{{fields}}` and `{{language}}` in a template
+fn render(template: &str, language: &str, fields: &[Field]) -> Result<String> {
+    let placeholder = Regex::new(r"\{\{\s*(\w*)\s*\}\}").expect("valid regex");
+
+    let unknown: Vec<&str> = placeholder.captures_iter(template)
+        .map(|c| c.get(1).map_or("", |m| m.as_str()))
+        .filter(|name| !matches!(*name, "fields" | "language"))
+        .collect();
+    if !unknown.is_empty() {
+        anyhow::bail!("Unknown placeholders {{{{{}}}}}, expected {{{{fields}}}} or {{{{language}}}}", unknown.join("}}, {{"));
+    }
+
+    // Fields are filled in first since they mention the language
+    let text = placeholder.replace_all(template, |c: &regex::Captures| match &c[1] {
+        "fields" => field_list(fields),
+        _ => c[0].to_string(),
+    });
+    let text = placeholder.replace_all(&text, |c: &regex::Captures| match &c[1] {
+        "language" => language.to_string(),
+        _ => c[0].to_string(),
+    });
+    Ok(text.trim().to_string())
+}
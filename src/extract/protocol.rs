@@ -0,0 +1,990 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::fewshot::FewShot;
+use super::format::ImageKind;
+use super::parse::{ParseMethod, parse_batch_items, parse_content};
+use super::usage::Usage;
+
+/// Text sent with the image in the user message
+pub const USER_PROMPT: &str = "Extract the fields of this trademark.";
+
+/// Key of the array of per-image objects in the reply to a batch
+pub const BATCH_KEY: &str = "images";
+
+/// Text sent with the images of a batch, asking for their fields in order
+pub fn batch_prompt(images: usize) -> String {
+    format!(
+        "Extract the fields of each of these {} trademarks. Reply with a JSON object whose \"{}\" array holds one object \
+         of the fields per image, in the order the images are given.",
+        images, BATCH_KEY
+    )
+}
+
+/// Version header required by the Anthropic Messages API
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Longest reply the Anthropic API may generate when `--max-tokens` isn't
+/// given; the API requires a value and the JSON needs far less
+const ANTHROPIC_MAX_TOKENS: u32 = 1024;
+
+/// Environment variable holding the API key for any protocol
+pub const API_KEY_VAR: &str = "EXTRACT_API_KEY";
+
+/// Fields extracted from a trademark image
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ApiResponse {
+    #[serde(rename = "wordsInMark")]
+    pub words_in_mark: Option<String>,
+    #[serde(rename = "chineseCharacter")]
+    pub chinese_character: Option<String>,
+    #[serde(rename = "descrOfDevice")]
+    pub description_of_device: Option<String>,
+    /// How the fields were read from the reply
+    #[serde(skip)]
+    pub parse_method: ParseMethod,
+    /// The model's reply as received: the whole body for the invoke
+    /// protocol, the assistant's text for the others
+    #[serde(skip)]
+    pub raw_output: String,
+}
+
+/// A field of the extraction, selected with `--fields`
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    /// `chineseCharacter`
+    Chinese,
+    /// `wordsInMark`
+    Words,
+    /// `descrOfDevice`
+    Device,
+}
+
+impl Field {
+    pub const ALL: [Field; 3] = [Field::Chinese, Field::Words, Field::Device];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Chinese => "chinese",
+            Field::Words => "words",
+            Field::Device => "device",
+        }
+    }
+
+    /// Key of the field in the JSON answer
+    pub fn key(self) -> &'static str {
+        match self {
+            Field::Chinese => "chineseCharacter",
+            Field::Words => "wordsInMark",
+            Field::Device => "descrOfDevice",
+        }
+    }
+}
+
+impl ApiResponse {
+    /// Drop the values of fields that weren't asked for
+    pub fn keep_only(&mut self, fields: &[Field]) {
+        if !fields.contains(&Field::Chinese) {
+            self.chinese_character = None;
+        }
+        if !fields.contains(&Field::Words) {
+            self.words_in_mark = None;
+        }
+        if !fields.contains(&Field::Device) {
+            self.description_of_device = None;
+        }
+    }
+}
+
+/// Sampling settings passed to the model, under each API's own names
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Generation {
+    pub temperature: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+}
+
+/// Wire format spoken by the extraction endpoint
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// POST `{"image": <base64>}` to `<base-url>/invoke` and read the fields back
+    #[default]
+    Invoke,
+    /// OpenAI-compatible `/v1/chat/completions` with an image_url content part
+    Openai,
+    /// Anthropic Messages API with a base64 image content block
+    Anthropic,
+    /// Ollama `/api/chat` with the image in the message's `images` array
+    Ollama,
+}
+
+/// How the image is put into the request
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageEncoding {
+    /// Bare base64 of the image bytes
+    Base64,
+    /// `data:<mime>;base64,...` URL
+    DataUrl,
+    /// multipart/form-data with the image as the `image` file part
+    Multipart,
+}
+
+impl ImageEncoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageEncoding::Base64 => "base64",
+            ImageEncoding::DataUrl => "data-url",
+            ImageEncoding::Multipart => "multipart",
+        }
+    }
+}
+
+impl Protocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Invoke => "invoke",
+            Protocol::Openai => "openai",
+            Protocol::Anthropic => "anthropic",
+            Protocol::Ollama => "ollama",
+        }
+    }
+
+    /// Base URL used when none is given
+    pub fn default_base_url(self) -> &'static str {
+        match self {
+            Protocol::Invoke | Protocol::Openai => "http://localhost:1234",
+            Protocol::Anthropic => "https://api.anthropic.com",
+            Protocol::Ollama => "http://localhost:11434",
+        }
+    }
+
+    /// Provider's own environment variable for the API key, read when
+    /// neither `--api-key` nor `EXTRACT_API_KEY` is set
+    pub fn api_key_var(self) -> Option<&'static str> {
+        match self {
+            Protocol::Openai => Some("OPENAI_API_KEY"),
+            Protocol::Anthropic => Some("ANTHROPIC_API_KEY"),
+            Protocol::Invoke | Protocol::Ollama => None,
+        }
+    }
+
+    /// Reply length used when `--max-tokens` isn't given, if the API needs one
+    pub fn default_max_tokens(self) -> Option<u32> {
+        match self {
+            Protocol::Anthropic => Some(ANTHROPIC_MAX_TOKENS),
+            _ => None,
+        }
+    }
+
+    /// Whether the protocol carries instructions for the model
+    pub fn takes_prompt(self) -> bool {
+        self != Protocol::Invoke
+    }
+
+    /// Whether one request can carry several images, for `--batch-size`;
+    /// the invoke server takes one image per call
+    pub fn takes_batches(self) -> bool {
+        self != Protocol::Invoke
+    }
+
+    /// Whether a request can hold a conversation with images in earlier
+    /// turns, for `--few-shot`
+    pub fn takes_examples(self) -> bool {
+        self != Protocol::Invoke
+    }
+
+    /// Whether requests must name a model
+    pub fn needs_model(self) -> bool {
+        matches!(self, Protocol::Anthropic | Protocol::Ollama)
+    }
+
+    /// Image encoding used when `--image-encoding` isn't given
+    pub fn default_image_encoding(self) -> ImageEncoding {
+        match self {
+            Protocol::Openai => ImageEncoding::DataUrl,
+            Protocol::Invoke | Protocol::Anthropic | Protocol::Ollama => ImageEncoding::Base64,
+        }
+    }
+
+    /// Image encodings the wire format has room for: only the invoke
+    /// server can take a form, and the Anthropic and Ollama APIs want bare
+    /// base64
+    pub fn image_encodings(self) -> &'static [ImageEncoding] {
+        match self {
+            Protocol::Invoke => &[ImageEncoding::Base64, ImageEncoding::DataUrl, ImageEncoding::Multipart],
+            Protocol::Openai => &[ImageEncoding::DataUrl, ImageEncoding::Base64],
+            Protocol::Anthropic | Protocol::Ollama => &[ImageEncoding::Base64],
+        }
+    }
+
+    /// Image formats the endpoint takes, used when `--accept-formats` isn't
+    /// given; `None` for the invoke protocol, whose server is sent any file
+    pub fn accepted_formats(self) -> Option<&'static [ImageKind]> {
+        match self {
+            Protocol::Invoke => None,
+            Protocol::Openai | Protocol::Anthropic => Some(&[ImageKind::Jpeg, ImageKind::Png, ImageKind::Webp, ImageKind::Gif]),
+            Protocol::Ollama => Some(&[ImageKind::Jpeg, ImageKind::Png]),
+        }
+    }
+}
+
+/// An extraction endpoint and how to talk to it
+#[derive(Clone)]
+pub struct Backend {
+    pub protocol: Protocol,
+    pub base_url: String,
+    pub model: Option<String>,
+    pub api_key: Option<String>,
+    /// Instructions sent as the system prompt; the invoke protocol has none
+    pub prompt: String,
+    /// Text sent with the image, usually `USER_PROMPT`
+    pub user_prompt: &'static str,
+    /// Sampling settings; the invoke protocol ignores them
+    pub generation: Generation,
+    /// Ask chat servers for a JSON object through `response_format`
+    pub json_mode: bool,
+    /// Fields read from the replies; the others are dropped
+    pub fields: Vec<Field>,
+    /// One of the protocol's `image_encodings`
+    pub image_encoding: ImageEncoding,
+    /// Worked examples sent as earlier turns before the image, with
+    /// `--few-shot`
+    pub few_shot: Option<Arc<FewShot>>,
+}
+
+// Written out so the API key never ends up in a log
+impl fmt::Debug for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Backend")
+            .field("protocol", &self.protocol)
+            .field("base_url", &self.base_url)
+            .field("model", &self.model)
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .field("prompt", &self.prompt)
+            .field("user_prompt", &self.user_prompt)
+            .field("generation", &self.generation)
+            .field("json_mode", &self.json_mode)
+            .field("fields", &self.fields)
+            .field("image_encoding", &self.image_encoding)
+            .field("few_shot", &self.few_shot.as_ref().map(|few_shot| &few_shot.hash))
+            .finish()
+    }
+}
+
+impl Backend {
+    /// URL the images are posted to
+    pub fn url(&self) -> String {
+        match self.protocol {
+            Protocol::Invoke => format!("{}/invoke", self.base_url),
+            Protocol::Openai => format!("{}/v1/chat/completions", self.base_url),
+            Protocol::Anthropic => format!("{}/v1/messages", self.base_url),
+            Protocol::Ollama => format!("{}/api/chat", self.base_url),
+        }
+    }
+
+    /// Explanation added to connection errors, where a likely cause is known
+    pub fn connect_hint(&self) -> Option<String> {
+        match self.protocol {
+            Protocol::Ollama => Some(format!("Failed to connect, is ollama serving on {}?", self.base_url)),
+            _ => None,
+        }
+    }
+
+    /// POST request to the endpoint with the headers the protocol needs;
+    /// the body sets the content type
+    pub fn post(&self, client: &reqwest::Client) -> reqwest::RequestBuilder {
+        self.with_headers(client.post(self.url()))
+    }
+
+    /// URL checked before a run: the model list of the protocols that have
+    /// one, otherwise the URL the images are posted to
+    pub fn probe_url(&self) -> String {
+        match self.protocol {
+            Protocol::Invoke => self.url(),
+            Protocol::Openai | Protocol::Anthropic => format!("{}/v1/models", self.base_url),
+            Protocol::Ollama => format!("{}/api/tags", self.base_url),
+        }
+    }
+
+    /// Request for `probe_url`: a GET of the model list, or a HEAD for the
+    /// invoke protocol, which has none
+    pub fn probe(&self, client: &reqwest::Client) -> reqwest::RequestBuilder {
+        let request = match self.protocol {
+            Protocol::Invoke => client.head(self.probe_url()),
+            _ => client.get(self.probe_url()),
+        };
+        self.with_headers(request)
+    }
+
+    /// Model names in the reply to `probe`: `data[].id` of OpenAI-compatible
+    /// servers and Anthropic, `models[].name` of Ollama. `None` when the
+    /// reply lists none.
+    pub fn listed_models(&self, body: &str) -> Option<Vec<String>> {
+        let value: Value = serde_json::from_str(body).ok()?;
+        let (list, key) = match self.protocol {
+            Protocol::Invoke => return None,
+            Protocol::Openai | Protocol::Anthropic => ("data", "id"),
+            Protocol::Ollama => ("models", "name"),
+        };
+        let models: Vec<String> = value.get(list)?.as_array()?.iter()
+            .filter_map(|model| model.get(key)?.as_str().map(String::from))
+            .collect();
+        (!models.is_empty()).then_some(models)
+    }
+
+    /// Whether `model` is among `listed`; Ollama lists `llava` as `llava:latest`
+    pub fn lists_model(&self, listed: &[String], model: &str) -> bool {
+        listed.iter().any(|name| {
+            name == model || (self.protocol == Protocol::Ollama && name.strip_suffix(":latest") == Some(model))
+        })
+    }
+
+    /// `request` with the headers the protocol needs
+    fn with_headers(&self, mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if self.protocol == Protocol::Anthropic {
+            request = request.header("anthropic-version", ANTHROPIC_VERSION);
+        }
+        if let Some(key) = &self.api_key {
+            let (name, value) = self.auth_header(key);
+            request = request.header(name, value);
+        }
+        request
+    }
+
+    /// Header carrying the API key: `x-api-key` for Anthropic, a bearer
+    /// token for everything else
+    pub fn auth_header(&self, key: &str) -> (&'static str, String) {
+        match self.protocol {
+            Protocol::Anthropic => ("x-api-key", key.to_string()),
+            _ => ("Authorization", format!("Bearer {}", key)),
+        }
+    }
+
+    /// Request for one image: a multipart form with `image_encoding`
+    /// multipart, otherwise the JSON `body`. `image` is the raw bytes and
+    /// `base64` their encoding.
+    pub fn request(
+        &self,
+        client: &reqwest::Client,
+        image: &[u8],
+        base64: &str,
+        media_type: &str,
+        file_name: &str,
+    ) -> Result<reqwest::RequestBuilder> {
+        let request = self.post(client);
+        Ok(match self.image_encoding {
+            ImageEncoding::Multipart => {
+                let part = reqwest::multipart::Part::bytes(image.to_vec())
+                    .file_name(file_name.to_string())
+                    .mime_str(media_type)
+                    .with_context(|| format!("Invalid media type: {}", media_type))?;
+                request.multipart(reqwest::multipart::Form::new().part("image", part))
+            }
+            ImageEncoding::Base64 | ImageEncoding::DataUrl => {
+                json_request(request, self.body_bytes(&[(base64, media_type)], self.user_prompt)?)
+            }
+        })
+    }
+
+    /// `body_with` serialized, the base64 of `images` copied straight into
+    /// the bytes rather than through a JSON value, so the body is the only
+    /// copy of them made for the request
+    fn body_bytes(&self, images: &[(&str, &str)], text: &str) -> Result<Vec<u8>> {
+        let placeholders: Vec<String> = (0..images.len()).map(|index| format!("\0image{}\0", index)).collect();
+        let templates: Vec<(&str, &str)> = placeholders.iter().zip(images)
+            .map(|(placeholder, (_, media_type))| (placeholder.as_str(), *media_type))
+            .collect();
+        let template = serde_json::to_string(&self.body_with(&templates, text))?;
+        // Placeholders as serialized, in the order they appear; the invoke
+        // protocol leaves out all but the first
+        let mut slots: Vec<(usize, usize, &str)> = placeholders.iter().zip(images)
+            .filter_map(|(placeholder, (image, _))| {
+                let escaped = placeholder.replace('\0', "\\u0000");
+                template.find(&escaped).map(|start| (start, escaped.len(), *image))
+            })
+            .collect();
+        slots.sort_unstable_by_key(|(start, _, _)| *start);
+        let mut body = Vec::with_capacity(template.len() + slots.iter().map(|(_, _, image)| image.len()).sum::<usize>());
+        let mut copied = 0;
+        for (start, len, image) in slots {
+            body.extend_from_slice(&template.as_bytes()[copied..start]);
+            body.extend_from_slice(image.as_bytes());
+            copied = start + len;
+        }
+        body.extend_from_slice(&template.as_bytes()[copied..]);
+        Ok(body)
+    }
+
+    /// The image as a JSON string: bare base64 or a data URL
+    fn image_value(&self, image: &str, media_type: &str) -> String {
+        match self.image_encoding {
+            ImageEncoding::DataUrl => format!("data:{};base64,{}", media_type, image),
+            ImageEncoding::Base64 | ImageEncoding::Multipart => image.to_string(),
+        }
+    }
+
+    /// Request body for one base64 encoded image
+    pub fn body(&self, image: &str, media_type: &str) -> Value {
+        self.body_with(&[(image, media_type)], self.user_prompt)
+    }
+
+    /// Request body for several base64 encoded images, given with their
+    /// media types, in one user message asking for their fields in order
+    pub fn batch_body(&self, images: &[(&str, &str)]) -> Value {
+        self.body_with(images, &batch_prompt(images.len()))
+    }
+
+    /// Request body sending `images` with the user message `text`. The
+    /// invoke protocol only takes the first image.
+    fn body_with(&self, images: &[(&str, &str)], text: &str) -> Value {
+        match self.protocol {
+            Protocol::Invoke => {
+                let (image, media_type) = images[0];
+                json!({ "image": self.image_value(image, media_type) })
+            }
+            Protocol::Openai => {
+                let mut content = vec![json!({ "type": "text", "text": text })];
+                content.extend(images.iter().map(|(image, media_type)| {
+                    json!({ "type": "image_url", "image_url": { "url": self.image_value(image, media_type) } })
+                }));
+                let mut messages = vec![json!({ "role": "system", "content": self.prompt })];
+                for example in self.examples() {
+                    messages.push(json!({ "role": "user", "content": [
+                        { "type": "text", "text": self.user_prompt },
+                        { "type": "image_url", "image_url": { "url": self.image_value(&example.base64, example.media_type) } },
+                    ] }));
+                    messages.push(json!({ "role": "assistant", "content": example.answer }));
+                }
+                messages.push(json!({ "role": "user", "content": content }));
+                let mut body = json!({ "messages": messages });
+                if let Some(model) = &self.model {
+                    body["model"] = json!(model);
+                }
+                let generation = &self.generation;
+                body["temperature"] = json!(generation.temperature);
+                if let Some(top_p) = generation.top_p {
+                    body["top_p"] = json!(top_p);
+                }
+                if let Some(max_tokens) = generation.max_tokens {
+                    body["max_tokens"] = json!(max_tokens);
+                }
+                if !generation.stop.is_empty() {
+                    body["stop"] = json!(generation.stop);
+                }
+                if self.json_mode {
+                    body["response_format"] = json!({ "type": "json_object" });
+                }
+                body
+            }
+            Protocol::Anthropic => {
+                let generation = &self.generation;
+                let mut content: Vec<Value> = images.iter()
+                    .map(|(image, media_type)| {
+                        json!({ "type": "image", "source": { "type": "base64", "media_type": media_type, "data": image } })
+                    })
+                    .collect();
+                content.push(json!({ "type": "text", "text": text }));
+                let mut messages = Vec::new();
+                for example in self.examples() {
+                    messages.push(json!({ "role": "user", "content": [
+                        { "type": "image", "source": { "type": "base64", "media_type": example.media_type, "data": example.base64 } },
+                        { "type": "text", "text": self.user_prompt },
+                    ] }));
+                    messages.push(json!({ "role": "assistant", "content": example.answer }));
+                }
+                messages.push(json!({ "role": "user", "content": content }));
+                let mut body = json!({
+                    "model": self.model,
+                    "max_tokens": generation.max_tokens.unwrap_or(ANTHROPIC_MAX_TOKENS),
+                    "temperature": generation.temperature,
+                    "system": self.prompt,
+                    "messages": messages,
+                });
+                if let Some(top_p) = generation.top_p {
+                    body["top_p"] = json!(top_p);
+                }
+                if !generation.stop.is_empty() {
+                    body["stop_sequences"] = json!(generation.stop);
+                }
+                body
+            }
+            Protocol::Ollama => {
+                // Without "stream": false Ollama answers with one JSON line per token
+                let mut messages = vec![json!({ "role": "system", "content": self.prompt })];
+                for example in self.examples() {
+                    messages.push(json!({ "role": "user", "content": self.user_prompt, "images": [example.base64] }));
+                    messages.push(json!({ "role": "assistant", "content": example.answer }));
+                }
+                messages.push(json!({
+                    "role": "user", "content": text, "images": images.iter().map(|(image, _)| image).collect::<Vec<_>>(),
+                }));
+                let mut body = json!({
+                    "model": self.model,
+                    "stream": false,
+                    "messages": messages,
+                });
+                if self.json_mode {
+                    body["format"] = json!("json");
+                }
+                let generation = &self.generation;
+                let mut options = json!({ "temperature": generation.temperature });
+                if let Some(top_p) = generation.top_p {
+                    options["top_p"] = json!(top_p);
+                }
+                if let Some(max_tokens) = generation.max_tokens {
+                    options["num_predict"] = json!(max_tokens);
+                }
+                if !generation.stop.is_empty() {
+                    options["stop"] = json!(generation.stop);
+                }
+                body["options"] = options;
+                body
+            }
+        }
+    }
+
+    /// The `--few-shot` examples, none without it
+    fn examples(&self) -> &[super::fewshot::Example] {
+        self.few_shot.as_deref().map_or(&[], |few_shot| &few_shot.examples)
+    }
+
+    /// Read the extracted fields from a response body
+    pub fn parse(&self, body: &str) -> Result<ApiResponse> {
+        let text = self.reply_text(body)?;
+        let mut response: ApiResponse = match self.protocol {
+            Protocol::Invoke => serde_json::from_str(&text)?,
+            _ => parse_content(&text)?,
+        };
+        response.keep_only(&self.fields);
+        response.raw_output = text;
+        Ok(response)
+    }
+
+    /// Request for several images in one call, with `batch_body`
+    pub fn batch_request(&self, client: &reqwest::Client, images: &[(&str, &str)]) -> Result<reqwest::RequestBuilder> {
+        Ok(json_request(self.post(client), self.body_bytes(images, &batch_prompt(images.len()))?))
+    }
+
+    /// Read the fields of each of `images` images from the response body of
+    /// a batch, in the order they were sent. Fails unless the reply holds
+    /// exactly one object per image, so no result can land on the wrong
+    /// image.
+    pub fn parse_batch(&self, body: &str, images: usize) -> Result<Vec<ApiResponse>> {
+        let text = self.reply_text(body)?;
+        let (items, embedded) = parse_batch_items(&text)?;
+        anyhow::ensure!(items.len() == images, "Batch reply has {} objects for {} images", items.len(), images);
+        items.iter()
+            .enumerate()
+            .map(|(index, item)| {
+                let mut response = self.parse_batch_item(&item.to_string())
+                    .with_context(|| format!("Object {} of the batch reply", index + 1))?;
+                if embedded {
+                    response.parse_method = ParseMethod::EmbeddedJson;
+                }
+                Ok(response)
+            })
+            .collect()
+    }
+
+    /// Read the fields of one image from its object in a batch reply, as
+    /// kept in the response cache
+    pub fn parse_batch_item(&self, text: &str) -> Result<ApiResponse> {
+        let mut response: ApiResponse = serde_json::from_str(text)
+            .with_context(|| format!("Batch reply object is not the expected JSON: {}", text))?;
+        response.keep_only(&self.fields);
+        response.raw_output = text.to_string();
+        Ok(response)
+    }
+
+    /// The model's reply within a response body
+    pub fn reply_text(&self, body: &str) -> Result<String> {
+        match self.protocol {
+            Protocol::Invoke => Ok(body.to_string()),
+            Protocol::Openai => {
+                #[derive(Deserialize)]
+                struct ChatResponse {
+                    choices: Vec<Choice>,
+                }
+                #[derive(Deserialize)]
+                struct Choice {
+                    message: Message,
+                }
+                #[derive(Deserialize)]
+                struct Message {
+                    content: Option<String>,
+                }
+
+                let response: ChatResponse = serde_json::from_str(body)?;
+                response.choices.into_iter()
+                    .next()
+                    .and_then(|choice| choice.message.content)
+                    .context("Response has no assistant message")
+            }
+            Protocol::Anthropic => {
+                #[derive(Deserialize)]
+                struct MessagesResponse {
+                    content: Vec<ContentBlock>,
+                }
+                #[derive(Deserialize)]
+                struct ContentBlock {
+                    #[serde(rename = "type")]
+                    kind: String,
+                    text: Option<String>,
+                }
+
+                let response: MessagesResponse = serde_json::from_str(body)?;
+                let text: String = response.content.into_iter()
+                    .filter(|block| block.kind == "text")
+                    .filter_map(|block| block.text)
+                    .collect();
+                anyhow::ensure!(!text.is_empty(), "Response has no text content");
+                Ok(text)
+            }
+            Protocol::Ollama => {
+                #[derive(Deserialize)]
+                struct ChatResponse {
+                    message: Message,
+                }
+                #[derive(Deserialize)]
+                struct Message {
+                    content: String,
+                }
+
+                let response: ChatResponse = serde_json::from_str(body)?;
+                Ok(response.message.content)
+            }
+        }
+    }
+
+    /// Tokens billed for a response, from its `usage` block (`prompt_tokens`
+    /// and `completion_tokens` for openai, `input_tokens` and
+    /// `output_tokens` for anthropic) or ollama's `prompt_eval_count` and
+    /// `eval_count`. `None` for the invoke protocol and for responses
+    /// without the counts.
+    pub fn usage(&self, body: &str) -> Option<Usage> {
+        let response: Value = serde_json::from_str(body).ok()?;
+        let count = |value: &Value, key: &str| value.get(key).and_then(Value::as_u64);
+        let (input, output) = match self.protocol {
+            Protocol::Invoke => return None,
+            Protocol::Openai => {
+                let usage = response.get("usage")?;
+                (count(usage, "prompt_tokens"), count(usage, "completion_tokens"))
+            }
+            Protocol::Anthropic => {
+                let usage = response.get("usage")?;
+                (count(usage, "input_tokens"), count(usage, "output_tokens"))
+            }
+            Protocol::Ollama => (count(&response, "prompt_eval_count"), count(&response, "eval_count")),
+        };
+        if input.is_none() && output.is_none() {
+            return None;
+        }
+        Some(Usage { input_tokens: input.unwrap_or_default(), output_tokens: output.unwrap_or_default() })
+    }
+}
+
+/// `request` sending `body`, serialized JSON; the request owns the bytes
+/// from here
+fn json_request(request: reqwest::RequestBuilder, body: Vec<u8>) -> reqwest::RequestBuilder {
+    request.header(reqwest::header::CONTENT_TYPE, "application/json").body(body)
+}
+
+/// Media type of an image for data URLs, from its extension
+pub fn media_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref() {
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("tif") | Some("tiff") => "image/tiff",
+        _ => "image/jpeg",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMAGE: &str = "aGVsbG8=";
+
+    /// Reply of `ollama serve` 0.1.38 with llava:13b to a non-streaming
+    /// `/api/chat` request in JSON mode
+    const OLLAMA_REPLY: &str = r#"{"model":"llava:13b","created_at":"2024-05-14T09:21:03.412881Z","message":{"role":"assistant","content":" {\n  \"chineseCharacter\": \"龍鳳\",\n  \"wordsInMark\": \"DRAGON PHOENIX\",\n  \"descrOfDevice\": \"A dragon and a phoenix facing each other inside a circle\"\n}\n"},"done_reason":"stop","done":true,"total_duration":5843125042,"load_duration":2310625,"prompt_eval_count":598,"prompt_eval_duration":1203945000,"eval_count":42,"eval_duration":4512000000}"#;
+
+    fn backend(protocol: Protocol) -> Backend {
+        Backend {
+            protocol,
+            base_url: "http://localhost:1234".to_string(),
+            model: Some("qwen2-vl".to_string()),
+            api_key: None,
+            prompt: "Read the trademark.".to_string(),
+            user_prompt: USER_PROMPT,
+            generation: Generation { temperature: 0.0, ..Generation::default() },
+            json_mode: true,
+            fields: Field::ALL.to_vec(),
+            image_encoding: protocol.default_image_encoding(),
+            few_shot: None,
+        }
+    }
+
+    /// The request `backend` makes for one PNG image, as it goes on the wire
+    fn request(backend: &Backend) -> reqwest::Request {
+        backend.request(&reqwest::Client::new(), b"hello", IMAGE, "image/png", "40201900001X_rep.png")
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    fn sent_body(request: &reqwest::Request) -> Value {
+        serde_json::from_slice(request.body().and_then(reqwest::Body::as_bytes).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn invoke_request_posts_the_bare_image() {
+        let backend = backend(Protocol::Invoke);
+        let request = request(&backend);
+        assert_eq!(request.url().as_str(), "http://localhost:1234/invoke");
+        assert_eq!(request.headers()["content-type"], "application/json");
+        assert_eq!(sent_body(&request), json!({ "image": IMAGE }));
+    }
+
+    #[test]
+    fn invoke_response_is_the_fields() {
+        let body = r#"{"chineseCharacter":"商标","wordsInMark":"TM","descrOfDevice":"a circle"}"#;
+        let response = backend(Protocol::Invoke).parse(body).unwrap();
+        assert_eq!(response.chinese_character.as_deref(), Some("商标"));
+        assert_eq!(response.words_in_mark.as_deref(), Some("TM"));
+        assert_eq!(response.description_of_device.as_deref(), Some("a circle"));
+        assert_eq!(response.parse_method, ParseMethod::Json);
+        assert_eq!(response.raw_output, body);
+        assert!(backend(Protocol::Invoke).usage(body).is_none());
+    }
+
+    #[test]
+    fn openai_request_is_a_chat_with_a_data_url() {
+        let backend = backend(Protocol::Openai);
+        let request = request(&backend);
+        assert_eq!(request.url().as_str(), "http://localhost:1234/v1/chat/completions");
+        assert_eq!(sent_body(&request), json!({
+            "model": "qwen2-vl",
+            "temperature": 0.0,
+            "response_format": { "type": "json_object" },
+            "messages": [
+                { "role": "system", "content": "Read the trademark." },
+                { "role": "user", "content": [
+                    { "type": "text", "text": USER_PROMPT },
+                    { "type": "image_url", "image_url": { "url": format!("data:image/png;base64,{}", IMAGE) } },
+                ] },
+            ],
+        }));
+    }
+
+    #[test]
+    fn openai_request_leaves_out_json_mode_when_off() {
+        let backend = Backend { json_mode: false, model: None, ..backend(Protocol::Openai) };
+        let body = sent_body(&request(&backend));
+        assert!(body.get("response_format").is_none());
+        assert!(body.get("model").is_none());
+    }
+
+    #[test]
+    fn openai_response_is_the_assistant_message() {
+        let body = json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "choices": [{ "index": 0, "finish_reason": "stop", "message": {
+                "role": "assistant",
+                "content": "```json\n{\"chineseCharacter\": \"商标\", \"wordsInMark\": null, \"descrOfDevice\": null}\n```",
+            } }],
+            "usage": { "prompt_tokens": 812, "completion_tokens": 24, "total_tokens": 836 },
+        })
+        .to_string();
+        let backend = backend(Protocol::Openai);
+        let response = backend.parse(&body).unwrap();
+        assert_eq!(response.chinese_character.as_deref(), Some("商标"));
+        assert_eq!(response.words_in_mark, None);
+        assert_eq!(response.parse_method, ParseMethod::EmbeddedJson);
+        assert!(response.raw_output.starts_with("```json"));
+        let usage = backend.usage(&body).unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (812, 24));
+    }
+
+    #[test]
+    fn openai_response_without_a_message_fails() {
+        let body = r#"{"choices":[]}"#;
+        assert!(backend(Protocol::Openai).parse(body).is_err());
+    }
+
+    #[test]
+    fn fields_not_asked_for_are_dropped() {
+        let backend = Backend { fields: vec![Field::Chinese], ..backend(Protocol::Invoke) };
+        let response = backend.parse(r#"{"chineseCharacter":"商标","wordsInMark":"TM"}"#).unwrap();
+        assert_eq!(response.chinese_character.as_deref(), Some("商标"));
+        assert_eq!(response.words_in_mark, None);
+    }
+
+    #[test]
+    fn anthropic_request_is_a_message_with_an_image_block() {
+        let backend = Backend { model: Some("claude-3-5-sonnet-latest".to_string()), ..backend(Protocol::Anthropic) };
+        let request = request(&backend);
+        assert_eq!(request.url().as_str(), "http://localhost:1234/v1/messages");
+        assert_eq!(request.headers()["anthropic-version"], ANTHROPIC_VERSION);
+        assert_eq!(sent_body(&request), json!({
+            "model": "claude-3-5-sonnet-latest",
+            "max_tokens": ANTHROPIC_MAX_TOKENS,
+            "temperature": 0.0,
+            "system": "Read the trademark.",
+            "messages": [{ "role": "user", "content": [
+                { "type": "image", "source": { "type": "base64", "media_type": "image/png", "data": IMAGE } },
+                { "type": "text", "text": USER_PROMPT },
+            ] }],
+        }));
+    }
+
+    #[test]
+    fn anthropic_request_takes_the_sampling_settings() {
+        let generation = Generation { temperature: 0.7, top_p: Some(0.9), max_tokens: Some(200), stop: vec!["}".to_string()] };
+        let body = sent_body(&request(&Backend { generation, ..backend(Protocol::Anthropic) }));
+        assert_eq!(body["max_tokens"], 200);
+        assert_eq!(body["top_p"], 0.9);
+        assert_eq!(body["stop_sequences"], json!(["}"]));
+    }
+
+    #[test]
+    fn anthropic_response_joins_the_text_blocks_without_fences() {
+        let body = json!({
+            "id": "msg_01",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-3-5-sonnet-latest",
+            "content": [
+                { "type": "text", "text": "```json\n{\"chineseCharacter\": \"商标\"," },
+                { "type": "text", "text": " \"wordsInMark\": \"TM\", \"descrOfDevice\": null}\n```" },
+            ],
+            "stop_reason": "end_turn",
+            "usage": { "input_tokens": 1520, "output_tokens": 31 },
+        })
+        .to_string();
+        let backend = backend(Protocol::Anthropic);
+        let response = backend.parse(&body).unwrap();
+        assert_eq!(response.chinese_character.as_deref(), Some("商标"));
+        assert_eq!(response.words_in_mark.as_deref(), Some("TM"));
+        assert_eq!(response.parse_method, ParseMethod::EmbeddedJson);
+        let usage = backend.usage(&body).unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (1520, 31));
+    }
+
+    #[test]
+    fn anthropic_response_without_text_fails() {
+        let body = r#"{"content":[{"type":"tool_use","id":"toolu_01","name":"x","input":{}}]}"#;
+        assert!(backend(Protocol::Anthropic).parse(body).is_err());
+    }
+
+    #[test]
+    fn anthropic_errors_map_onto_the_retry_classes() {
+        use super::super::retry::{self, HttpStatusError};
+        let error = |status: u16, kind: &str| anyhow::Error::new(HttpStatusError {
+            status,
+            body: json!({ "type": "error", "error": { "type": kind, "message": "try again" } }).to_string(),
+            retry_after: None,
+        });
+        let overloaded = error(529, "overloaded_error");
+        assert!(retry::is_retryable(&overloaded));
+        assert_eq!(retry::failure_class(&retry::classify(&overloaded)), "http_error");
+        assert!(retry::rate_limited(&error(429, "rate_limit_error")).is_some());
+        assert!(!retry::is_retryable(&error(400, "invalid_request_error")));
+        assert!(retry::is_auth_error(&error(401, "authentication_error")));
+    }
+
+    #[test]
+    fn ollama_request_is_a_non_streaming_chat() {
+        let backend = Backend { model: Some("llava".to_string()), ..backend(Protocol::Ollama) };
+        let request = request(&backend);
+        assert_eq!(request.url().as_str(), "http://localhost:1234/api/chat");
+        assert_eq!(sent_body(&request), json!({
+            "model": "llava",
+            "stream": false,
+            "format": "json",
+            "options": { "temperature": 0.0 },
+            "messages": [
+                { "role": "system", "content": "Read the trademark." },
+                { "role": "user", "content": USER_PROMPT, "images": [IMAGE] },
+            ],
+        }));
+    }
+
+    #[test]
+    fn recorded_ollama_reply_is_parsed() {
+        let backend = backend(Protocol::Ollama);
+        let response = backend.parse(OLLAMA_REPLY).unwrap();
+        assert_eq!(response.chinese_character.as_deref(), Some("龍鳳"));
+        assert_eq!(response.words_in_mark.as_deref(), Some("DRAGON PHOENIX"));
+        assert_eq!(response.description_of_device.as_deref(), Some("A dragon and a phoenix facing each other inside a circle"));
+        assert!(response.raw_output.starts_with(" {\n  \"chineseCharacter\""));
+        let usage = backend.usage(OLLAMA_REPLY).unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (598, 42));
+    }
+
+    #[test]
+    fn ollama_prose_reply_falls_back_to_the_heuristic() {
+        let body = json!({
+            "model": "llava:7b",
+            "message": { "role": "assistant", "content": "The trademark shows the Chinese characters 龍鳳 above a circle." },
+            "done": true,
+        })
+        .to_string();
+        let response = backend(Protocol::Ollama).parse(&body).unwrap();
+        assert_eq!(response.chinese_character.as_deref(), Some("龍鳳"));
+        assert_eq!(response.parse_method, ParseMethod::Heuristic);
+        assert!(backend(Protocol::Ollama).usage(&body).is_none());
+    }
+
+    #[test]
+    fn ollama_connection_errors_ask_whether_it_is_serving() {
+        let ollama = Backend { base_url: "http://gpu-3:11434".to_string(), ..backend(Protocol::Ollama) };
+        assert_eq!(ollama.connect_hint().as_deref(), Some("Failed to connect, is ollama serving on http://gpu-3:11434?"));
+        assert_eq!(backend(Protocol::Openai).connect_hint(), None);
+    }
+
+    #[test]
+    fn api_key_is_sent_in_each_protocol_header() {
+        let expected = [
+            (Protocol::Invoke, "authorization", "Bearer sk-test-123"),
+            (Protocol::Openai, "authorization", "Bearer sk-test-123"),
+            (Protocol::Anthropic, "x-api-key", "sk-test-123"),
+            (Protocol::Ollama, "authorization", "Bearer sk-test-123"),
+        ];
+        for (protocol, header, value) in expected {
+            let backend = Backend { api_key: Some("sk-test-123".to_string()), ..backend(protocol) };
+            let request = request(&backend);
+            assert_eq!(request.headers()[header], value, "{:?}", protocol);
+            let probe = backend.probe(&reqwest::Client::new()).build().unwrap();
+            assert_eq!(probe.headers()[header], value, "{:?} probe", protocol);
+        }
+    }
+
+    #[test]
+    fn no_auth_header_without_a_key() {
+        for protocol in [Protocol::Invoke, Protocol::Openai, Protocol::Anthropic, Protocol::Ollama] {
+            let headers = request(&backend(protocol)).headers().clone();
+            assert!(!headers.contains_key("authorization") && !headers.contains_key("x-api-key"), "{:?}", protocol);
+        }
+    }
+
+    #[test]
+    fn api_key_is_redacted_from_debug_output() {
+        let backend = Backend { api_key: Some("sk-test-123".to_string()), ..backend(Protocol::Openai) };
+        let debug = format!("{:?}", backend);
+        assert!(!debug.contains("sk-test-123"));
+        assert!(debug.contains("<redacted>"));
+    }
+}
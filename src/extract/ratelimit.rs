@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{Instant, sleep_until};
+
+/// Spaces requests evenly so all workers together stay under a request rate.
+/// This is a token bucket holding a single token: concurrency still decides
+/// how many requests may be in flight, the limiter only when they may start.
+#[derive(Debug)]
+pub struct RequestLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+    waits: AtomicUsize,
+}
+
+impl RequestLimiter {
+    pub fn per_second(rate: f64) -> Self {
+        RequestLimiter {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            next: Mutex::new(Instant::now()),
+            waits: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Number of requests that had to wait for their turn
+    pub fn waits(&self) -> usize {
+        self.waits.load(Ordering::Relaxed)
+    }
+
+    /// Wait until the next request may start
+    pub async fn acquire(&self) {
+        let slot = {
+            let mut next = self.next.lock().await;
+            let now = Instant::now();
+            let slot = (*next).max(now);
+            *next = slot + self.interval;
+            slot
+        };
+        if slot > Instant::now() {
+            self.waits.fetch_add(1, Ordering::Relaxed);
+            sleep_until(slot).await;
+        }
+    }
+
+    /// Hold every worker back for `delay`, after the server asked us to slow down
+    pub async fn pause(&self, delay: Duration) {
+        let mut next = self.next.lock().await;
+        *next = (*next).max(Instant::now() + delay);
+    }
+}
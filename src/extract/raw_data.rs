@@ -0,0 +1,88 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::data::{self, MarkText};
+use crate::images::{self, manifest::Manifest, shard::Shard};
+
+/// List of the images of a raw data file that aren't on disk, written next
+/// to the results file
+pub const MISSING_IMAGES_FILE_NAME: &str = "missing_images.txt";
+
+/// One document of the downloader's data file, found on disk
+#[derive(Debug, Clone)]
+pub struct RawImage {
+    /// `{app_num}_{file_name}`, as the downloader names the file
+    pub image_name: String,
+    pub image_path: PathBuf,
+    pub mark: MarkText,
+    pub application_num: String,
+    /// Day the downloader queried the record for, YYYY-MM-DD
+    pub lodgement_date: String,
+    /// File id of the document, or its file name when it has none
+    pub document_id: String,
+    pub file_id: Option<String>,
+}
+
+/// The documents of a data file, split by whether their image is on disk
+#[derive(Debug, Default)]
+pub struct RawDataset {
+    pub images: Vec<RawImage>,
+    /// Where the missing images were expected, in the flat layout unless the
+    /// manifest says otherwise
+    pub missing: Vec<PathBuf>,
+}
+
+impl RawDataset {
+    /// Walk the items of the downloader's `trademark_data.json` and find
+    /// each document's image under `images_dir`: through the image manifest
+    /// when it has the document, otherwise by the downloader's naming in
+    /// any of the directory layouts. Documents repeated across days are
+    /// taken once.
+    pub fn load(data_path: &Path, images_dir: &Path, manifest: &Manifest) -> Result<Self> {
+        let recorded: HashMap<(&str, &str), &Path> = manifest.entries()
+            .filter(|entry| entry.skipped.is_none())
+            .map(|entry| ((entry.application_num.as_str(), entry.file_name.as_str()), entry.image_path()))
+            .collect();
+
+        let mut dataset = RawDataset::default();
+        let mut seen = HashSet::new();
+        data::for_each_record(data_path, |record| {
+            for task in record.items.iter().flat_map(data::download_tasks) {
+                let image_name = format!("{}_{}", task.app_num, task.file_name);
+                if !seen.insert(image_name.clone()) {
+                    continue;
+                }
+                let candidates: Vec<PathBuf> = match recorded.get(&(task.app_num.as_str(), task.file_name.as_str())) {
+                    Some(path) => vec![path.to_path_buf()],
+                    None => Shard::value_variants().iter()
+                        .map(|shard| images::image_path(images_dir, *shard, &task.app_num, &task.file_name))
+                        .collect(),
+                };
+                match candidates.iter().find(|path| path.is_file()) {
+                    Some(path) => dataset.images.push(RawImage {
+                        image_name,
+                        image_path: path.clone(),
+                        document_id: task.file_id.clone().unwrap_or(task.file_name),
+                        file_id: task.file_id,
+                        application_num: task.app_num,
+                        lodgement_date: record.date.clone(),
+                        mark: task.mark,
+                    }),
+                    None => dataset.missing.push(candidates[0].clone()),
+                }
+            }
+            Ok(())
+        })?;
+        Ok(dataset)
+    }
+
+    /// Write the expected paths of the missing images, one per line
+    pub fn save_missing(&self, path: &Path) -> Result<()> {
+        let lines: String = self.missing.iter().map(|p| format!("{}\n", p.display())).collect();
+        fs::write(path, lines)
+            .with_context(|| format!("Failed to write missing images list: {}", path.display()))
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use futures::StreamExt;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+use crate::images::checksum;
+use crate::images::s3::S3Target;
+
+/// Directory under the system's temporary directory that fetched datasets
+/// are kept in
+const DATASETS_DIR: &str = "tm-query-datasets";
+
+/// Attempts at an `s3://` download
+const S3_RETRIES: u32 = 3;
+
+/// Where a `--dataset` given as a URL came from, recorded in the metrics
+/// file for provenance
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DatasetSource {
+    pub url: String,
+    pub sha256: String,
+    /// Local copy the run read
+    pub path: PathBuf,
+    pub fetched_at: String,
+    /// Reused from an earlier download that matched `--dataset-sha256`
+    #[serde(default)]
+    pub cached: bool,
+}
+
+/// Whether a `--dataset` is a URL to fetch rather than a local path
+pub fn is_remote(dataset: &Path) -> bool {
+    dataset.to_str().is_some_and(|s| ["http://", "https://", "s3://"].iter().any(|scheme| s.starts_with(scheme)))
+}
+
+/// Parse a `--dataset-header` given as `Name: value`
+pub fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s.split_once(':').ok_or_else(|| format!("expected Name: value, got {}", s))?;
+    let name = name.trim();
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name: {}", name))?;
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// Download the dataset at `url` (http, https or s3) into the temporary
+/// directory and check it against `sha256` when given. A copy from an
+/// earlier run that matches `sha256` is used without downloading again;
+/// without it the dataset is always fetched afresh. `headers` go with
+/// http requests, which follow redirects.
+pub async fn fetch(url: &str, headers: &[(String, String)], sha256: Option<&str>) -> Result<DatasetSource> {
+    let dir = std::env::temp_dir().join(DATASETS_DIR);
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+    let file_name = url.rsplit('/').next().filter(|name| !name.is_empty()).unwrap_or("dataset.json");
+    let url_hash = format!("{:x}", Sha256::digest(url.as_bytes()));
+    let path = dir.join(format!("{}-{}", &url_hash[..12], file_name));
+
+    if let Some(expected) = sha256
+        && path.is_file()
+        && checksum::hash_file(&path).is_ok_and(|(_, hash)| hash.eq_ignore_ascii_case(expected))
+    {
+        info!("Using the copy of {} in {}, which matches --dataset-sha256", url, path.display());
+        return Ok(DatasetSource {
+            url: url.to_string(),
+            sha256: expected.to_ascii_lowercase(),
+            path,
+            fetched_at: Local::now().to_rfc3339(),
+            cached: true,
+        });
+    }
+
+    info!("Downloading the dataset from {}", url);
+    let tmp_path = path.with_extension("part");
+    match url.strip_prefix("s3://") {
+        Some(location) => {
+            let (bucket, key) = location.split_once('/').with_context(|| format!("Expected s3://bucket/key, got {}", url))?;
+            S3Target::new(bucket.to_string(), "", None, S3_RETRIES).await.download(key, &tmp_path).await?;
+        }
+        None => download(url, headers, &tmp_path).await?,
+    }
+    let (size, hash) = checksum::hash_file(&tmp_path)?;
+    if let Some(expected) = sha256
+        && !hash.eq_ignore_ascii_case(expected)
+    {
+        let _ = fs::remove_file(&tmp_path);
+        anyhow::bail!("The dataset from {} has sha256 {}, not the {} of --dataset-sha256", url, hash, expected);
+    }
+    fs::rename(&tmp_path, &path).with_context(|| format!("Failed to save dataset: {}", path.display()))?;
+    info!("Saved {} bytes to {} (sha256 {})", size, path.display(), hash);
+    Ok(DatasetSource { url: url.to_string(), sha256: hash, path, fetched_at: Local::now().to_rfc3339(), cached: false })
+}
+
+async fn download(url: &str, headers: &[(String, String)], path: &Path) -> Result<()> {
+    let mut header_map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes()).with_context(|| format!("Invalid header name: {}", name))?;
+        let value = HeaderValue::from_str(value).with_context(|| format!("Invalid value of header {}", name))?;
+        header_map.insert(name, value);
+    }
+    let response = reqwest::Client::new()
+        .get(url)
+        .headers(header_map)
+        .send()
+        .await
+        .with_context(|| format!("Failed to download {}", url))?
+        .error_for_status()
+        .with_context(|| format!("Failed to download {}", url))?;
+
+    let mut file = tokio::fs::File::create(path).await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut body = response.bytes_stream();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.with_context(|| format!("Failed to download {}", url))?;
+        file.write_all(&chunk).await.with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    file.flush().await.with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
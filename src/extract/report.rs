@@ -0,0 +1,228 @@
+use anyhow::{Context, Result};
+use base64::{Engine as _, engine::general_purpose};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use super::device::Grade;
+use super::latency;
+use super::metrics::Scoreboard;
+use super::protocol::Field;
+use super::results::{ExtractionResult, Mismatch};
+use crate::images::thumbnail;
+
+/// Mismatches listed in the report, worst first
+pub const MAX_MISMATCHES: usize = 50;
+
+/// Longest side of an embedded thumbnail, in pixels
+const THUMBNAIL_SIZE: u32 = 160;
+
+/// Largest thumbnail embedded, in bytes; bigger ones are left out so the
+/// report stays small enough to email
+const MAX_THUMBNAIL_BYTES: usize = 48 * 1024;
+
+/// Latency percentiles shown in the report
+const PERCENTILES: [u32; 4] = [50, 90, 95, 99];
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;margin-bottom:2em}\
+th,td{border:1px solid #ccc;padding:4px 8px;text-align:left;vertical-align:top}\
+th{background:#f3f3f3}td.num{text-align:right}\
+td.raw{font-family:monospace;font-size:smaller;max-width:40em;white-space:pre-wrap;word-break:break-all}";
+
+/// Everything shown in the HTML report of a run
+pub struct Report<'a> {
+    /// Settings of the run as label and value
+    pub config: Vec<(&'static str, String)>,
+    pub scoreboard: &'a Scoreboard,
+    /// Results of the run's configuration, for latencies and failure classes
+    pub results: &'a [ExtractionResult],
+    /// Mismatches, worst first
+    pub mismatches: &'a [Mismatch],
+}
+
+impl Report<'_> {
+    /// Write the report as a single HTML file with the thumbnails inlined
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.render())
+            .with_context(|| format!("Failed to write HTML report: {}", path.display()))
+    }
+
+    pub fn render(&self) -> String {
+        let mut html = String::new();
+        let _ = write!(html, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+            <title>Extraction report</title><style>{}</style></head><body>\n<h1>Extraction report</h1>\n", STYLE);
+        self.summary(&mut html);
+        self.failures(&mut html);
+        self.latency(&mut html);
+        self.worst_mismatches(&mut html);
+        self.configuration(&mut html);
+        html.push_str("</body></html>\n");
+        html
+    }
+
+    fn summary(&self, html: &mut String) {
+        let s = self.scoreboard;
+        let percent = |count: usize| if s.total == 0 { 0.0 } else { 100.0 * count as f64 / s.total as f64 };
+        let cer = |c: Option<f64>| c.map_or("-".to_string(), |c| format!("{:.3}", c));
+
+        let mut counts = vec![("Images", s.total)];
+        if s.scores(Field::Chinese) {
+            counts.extend([
+                ("Exact match", s.exact_match),
+                ("Mismatch", s.mismatch),
+                ("Model found none", s.missed),
+                ("Ground truth none", s.spurious),
+            ]);
+        }
+        counts.push(("Request errors", s.errors));
+
+        let mut values = Vec::new();
+        if s.scores(Field::Chinese) {
+            values.extend([
+                ("Accuracy", format!("{:.1}%", 100.0 * s.accuracy())),
+                ("Answered accuracy", format!("{:.1}%", 100.0 * s.answered_accuracy())),
+                ("Accuracy before normalization", format!("{:.1}%", 100.0 * s.raw_accuracy())),
+                ("Mean CER", cer(s.mean_cer())),
+                ("Median CER", cer(s.median_cer())),
+            ]);
+        }
+        if s.unlabeled > 0 {
+            values.extend([
+                ("Unlabeled images", s.unlabeled.to_string()),
+                ("New extractions", s.new_extractions.to_string()),
+            ]);
+        }
+        if s.detection.total() > 0 {
+            values.extend([
+                ("Detection: stopped after detection", s.detection.negatives().to_string()),
+                ("Detection: precision", cer(s.detection.precision())),
+                ("Detection: recall", cer(s.detection.recall())),
+            ]);
+        }
+        if s.scores(Field::Words) {
+            values.extend([
+                ("Words in mark: images with ground truth", s.words_total.to_string()),
+                ("Words in mark: exact match", format!("{:.1}%", 100.0 * s.words_accuracy())),
+                ("Words in mark: mean WER", cer(s.mean_wer())),
+            ]);
+        }
+        if s.scores(Field::Device) {
+            values.extend([
+                ("Device: images with ground truth", s.device_total.to_string()),
+                ("Device: mean similarity", cer(s.mean_device_similarity())),
+                ("Device: good / partial / poor", format!("{} / {} / {}", s.device_grade_count(Grade::Good),
+                    s.device_grade_count(Grade::Partial), s.device_grade_count(Grade::Poor))),
+            ]);
+        }
+
+        html.push_str("<h2>Summary</h2>\n<table>\n");
+        for (label, count) in counts {
+            let _ = writeln!(html, "<tr><th>{}</th><td class=\"num\">{}</td><td class=\"num\">{:.1}%</td></tr>",
+                label, count, percent(count));
+        }
+        for (label, value) in values {
+            let _ = writeln!(html, "<tr><th>{}</th><td class=\"num\" colspan=\"2\">{}</td></tr>", label, value);
+        }
+        html.push_str("</table>\n");
+    }
+
+    fn failures(&self, html: &mut String) {
+        let mut classes: BTreeMap<&str, usize> = BTreeMap::new();
+        for result in self.results {
+            if let Some(class) = &result.error_class {
+                *classes.entry(class.as_str()).or_default() += 1;
+            }
+        }
+
+        html.push_str("<h2>Failures</h2>\n");
+        if classes.is_empty() {
+            html.push_str("<p>No failed images.</p>\n");
+            return;
+        }
+        html.push_str("<table>\n<tr><th>Class</th><th>Images</th></tr>\n");
+        for (class, count) in classes {
+            let _ = writeln!(html, "<tr><td>{}</td><td class=\"num\">{}</td></tr>", escape(class), count);
+        }
+        html.push_str("</table>\n");
+    }
+
+    fn latency(&self, html: &mut String) {
+        let mut latencies: Vec<u64> = self.results.iter().filter_map(|r| r.request_ms).collect();
+        latencies.sort_unstable();
+
+        html.push_str("<h2>Latency</h2>\n");
+        let Some(&max) = latencies.last() else {
+            html.push_str("<p>No requests.</p>\n");
+            return;
+        };
+        html.push_str("<table>\n<tr>");
+        for p in PERCENTILES {
+            let _ = write!(html, "<th>p{}</th>", p);
+        }
+        html.push_str("<th>max</th></tr>\n<tr>");
+        for p in PERCENTILES {
+            let _ = write!(html, "<td class=\"num\">{} ms</td>", latency::percentile(&latencies, p));
+        }
+        let _ = writeln!(html, "<td class=\"num\">{} ms</td></tr>\n</table>", max);
+    }
+
+    fn worst_mismatches(&self, html: &mut String) {
+        html.push_str("<h2>Worst mismatches</h2>\n");
+        if self.mismatches.is_empty() {
+            html.push_str("<p>No mismatches.</p>\n");
+            return;
+        }
+        if self.mismatches.len() > MAX_MISMATCHES {
+            let _ = writeln!(html, "<p>The {} with the largest edit distance of {}.</p>", MAX_MISMATCHES, self.mismatches.len());
+        }
+        html.push_str("<table>\n<tr><th>Image</th><th>Name</th><th>Ground truth</th><th>Prediction</th>\
+            <th>Edit distance</th><th>Model output</th></tr>\n");
+        for mismatch in self.mismatches.iter().take(MAX_MISMATCHES) {
+            let _ = writeln!(html, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td class=\"num\">{}</td><td class=\"raw\">{}</td></tr>",
+                embedded_thumbnail(&mismatch.image_path),
+                escape(&mismatch.image_name),
+                escape(mismatch.ground_truth.as_deref().unwrap_or("")),
+                escape(mismatch.prediction.as_deref().unwrap_or("")),
+                mismatch.edit_distance,
+                escape(&mismatch.raw_output));
+        }
+        html.push_str("</table>\n");
+    }
+
+    fn configuration(&self, html: &mut String) {
+        html.push_str("<h2>Configuration</h2>\n<table>\n");
+        for (label, value) in &self.config {
+            let _ = writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", label, escape(value));
+        }
+        html.push_str("</table>\n");
+    }
+}
+
+/// An `<img>` with the image's thumbnail as a data URL, or a note when the
+/// image can't be read or its thumbnail is too large
+fn embedded_thumbnail(path: &Path) -> String {
+    match thumbnail::thumbnail_jpeg(path, THUMBNAIL_SIZE) {
+        Ok(bytes) if bytes.len() <= MAX_THUMBNAIL_BYTES => format!(
+            "<img src=\"data:image/jpeg;base64,{}\" alt=\"\">", general_purpose::STANDARD.encode(&bytes)),
+        Ok(_) => "<em>too large</em>".to_string(),
+        Err(_) => "<em>unavailable</em>".to_string(),
+    }
+}
+
+/// `text` with the characters special to HTML escaped
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
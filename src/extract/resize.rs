@@ -0,0 +1,502 @@
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use tracing::warn;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Cursor, Read, Seek};
+use std::path::Path;
+
+use super::format::{ConversionError, ImageKind};
+use super::preprocess::Preprocess;
+use super::protocol;
+use super::source::ImageSource;
+
+/// Limits on the images sent to the API (`--max-dimension`, `--jpeg-quality`)
+#[derive(Debug, Clone, Copy)]
+pub struct Resize {
+    /// Longest side in pixels
+    pub max_dimension: u32,
+    /// Quality of the JPEG a shrunk image is re-encoded as
+    pub jpeg_quality: u8,
+}
+
+/// Quality of a JPEG re-encoded only to turn it upright or preprocess it,
+/// without `--max-dimension` and its `--jpeg-quality`
+const UPRIGHT_JPEG_QUALITY: u8 = 90;
+
+impl Resize {
+    fn fits(&self, width: u32, height: u32) -> bool {
+        width.max(height) <= self.max_dimension
+    }
+}
+
+/// The image sent to the API, recorded with each result
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SentImage {
+    pub width: u32,
+    pub height: u32,
+    pub bytes: u64,
+    /// Shrunk and re-encoded rather than sent as it is on disk
+    pub resized: bool,
+    /// Format of the file on disk when it was converted to PNG
+    #[serde(default)]
+    pub converted_from: Option<ImageKind>,
+    /// Size of the image on disk, before any shrinking
+    #[serde(default)]
+    pub original_width: Option<u32>,
+    #[serde(default)]
+    pub original_height: Option<u32>,
+    /// EXIF orientation, 2 to 8, the image on disk was stored in and turned
+    /// upright from; `null` when it was upright
+    #[serde(default)]
+    pub orientation: Option<u8>,
+}
+
+impl SentImage {
+    /// An image sent as it is on disk, after checking its size
+    fn untouched(width: u32, height: u32, bytes: u64) -> Self {
+        SentImage {
+            width,
+            height,
+            bytes,
+            resized: false,
+            converted_from: None,
+            original_width: Some(width),
+            original_height: Some(height),
+            orientation: None,
+        }
+    }
+
+    /// Longest side of the image on disk, when it is known
+    pub fn original_longest_side(&self) -> Option<u32> {
+        Some(self.original_width?.max(self.original_height?))
+    }
+}
+
+/// Image bytes ready for the request body
+#[derive(Debug)]
+pub struct PreparedImage {
+    pub bytes: Vec<u8>,
+    pub media_type: &'static str,
+    /// Set when the image was converted or checked against `--max-dimension`
+    pub sent: Option<SentImage>,
+}
+
+/// Read an image and make it sendable. A format outside `accepted` (`None`
+/// takes anything) is converted to PNG, only the first page of a
+/// multi-page TIFF being kept; if that fails the error is a
+/// `ConversionError`. A JPEG or TIFF whose EXIF orientation isn't upright
+/// is turned upright first. With `resize`, an image whose longest side is
+/// over the limit is scaled to fit. A turned or scaled image is re-encoded:
+/// PNG stays PNG to keep transparency, anything else becomes JPEG. If that
+/// fails the image is sent as it is with a warning. Images that need
+/// neither are passed through untouched, unless `preprocess` changes them
+/// after any scaling; a binarized image is always PNG.
+pub fn prepare(
+    source: &ImageSource,
+    path: &Path,
+    accepted: Option<&[ImageKind]>,
+    resize: Option<Resize>,
+    preprocess: Preprocess,
+) -> Result<PreparedImage> {
+    let original = source.read(path)
+        .with_context(|| format!("Failed to read image file: {:?}", path))?;
+    let kind = ImageKind::detect(&original);
+    let media_type = kind.map_or_else(|| protocol::media_type(path), ImageKind::media_type);
+
+    if !accepts(accepted, kind) {
+        return convert(&original, kind, resize, preprocess);
+    }
+    let processed = preprocess != Preprocess::None;
+    if resize.is_none() && !processed && !has_orientation(kind) {
+        return Ok(PreparedImage { bytes: original, media_type, sent: None });
+    }
+    match reader(&original).and_then(|reader| header(reader, kind)) {
+        Ok(((width, height), Orientation::NoTransforms)) if !processed && resize.is_none_or(|resize| resize.fits(width, height)) => {
+            let sent = resize.map(|_| SentImage::untouched(width, height, original.len() as u64));
+            Ok(PreparedImage { bytes: original, media_type, sent })
+        }
+        // Sent as it would be without the orientation check
+        Err(_) if resize.is_none() && !processed => Ok(PreparedImage { bytes: original, media_type, sent: None }),
+        header => match header.and_then(|_| reencode(&original, kind, resize, preprocess)) {
+            Ok(prepared) => Ok(prepared),
+            Err(e) => {
+                warn!("Failed to prepare {}: {:#}; sending the original image", path.display(), e);
+                Ok(PreparedImage { bytes: original, media_type, sent: None })
+            }
+        },
+    }
+}
+
+/// An image `prepare` would send as it is on disk
+#[derive(Debug)]
+pub struct PassThrough {
+    pub media_type: &'static str,
+    pub sent: Option<SentImage>,
+}
+
+/// Whether `prepare` would pass an image through untouched, found from the
+/// start of the file and its header without reading the whole of it, so
+/// the image can be encoded straight from the file. `None` when it would be
+/// converted, turned, shrunk or preprocessed, or its header can't be read.
+pub fn pass_through(
+    source: &ImageSource,
+    path: &Path,
+    accepted: Option<&[ImageKind]>,
+    resize: Option<Resize>,
+    preprocess: Preprocess,
+) -> Result<Option<PassThrough>> {
+    let mut file = source.open(path)
+        .with_context(|| format!("Failed to read image file: {:?}", path))?;
+    let mut head = Vec::new();
+    (&mut file).take(16).read_to_end(&mut head)
+        .with_context(|| format!("Failed to read image file: {:?}", path))?;
+    let kind = ImageKind::detect(&head);
+    let media_type = kind.map_or_else(|| protocol::media_type(path), ImageKind::media_type);
+
+    if !accepts(accepted, kind) || preprocess != Preprocess::None {
+        return Ok(None);
+    }
+    if resize.is_none() && !has_orientation(kind) {
+        return Ok(Some(PassThrough { media_type, sent: None }));
+    }
+    let bytes = source.size(path)?;
+    file.rewind()?;
+    let header = ImageReader::new(BufReader::new(file))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| header(reader, kind).ok());
+    Ok(match header {
+        Some(((width, height), Orientation::NoTransforms)) if resize.is_none_or(|resize| resize.fits(width, height)) => {
+            Some(PassThrough { media_type, sent: resize.map(|_| SentImage::untouched(width, height, bytes)) })
+        }
+        _ => None,
+    })
+}
+
+/// Whether images of `kind` may carry an EXIF orientation that is applied
+fn has_orientation(kind: Option<ImageKind>) -> bool {
+    matches!(kind, Some(ImageKind::Jpeg | ImageKind::Tiff))
+}
+
+/// Size of an image as stored and its orientation, read from its header;
+/// upright for formats without `has_orientation`
+fn header<R: BufRead + Seek>(reader: ImageReader<R>, kind: Option<ImageKind>) -> Result<((u32, u32), Orientation)> {
+    let mut decoder = reader.into_decoder().context("Failed to read image")?;
+    let orientation = if has_orientation(kind) {
+        decoder.orientation().context("Failed to read image orientation")?
+    } else {
+        Orientation::NoTransforms
+    };
+    Ok((decoder.dimensions(), orientation))
+}
+
+/// Whether an image of `kind` is sent without converting it
+fn accepts(accepted: Option<&[ImageKind]>, kind: Option<ImageKind>) -> bool {
+    accepted.is_none_or(|accepted| kind.is_some_and(|kind| accepted.contains(&kind)))
+}
+
+/// Decode an image the endpoint doesn't take and encode it as PNG, shrunk
+/// to fit `resize` and preprocessed
+fn convert(original: &[u8], kind: Option<ImageKind>, resize: Option<Resize>, preprocess: Preprocess) -> Result<PreparedImage> {
+    let failed = |reason: String| ConversionError { from: kind, reason };
+    let (image, orientation) = decode(original, kind).map_err(|e| failed(format!("{:#}", e)))?;
+    let (original_width, original_height) = (image.width(), image.height());
+    let (image, resized) = fit(upright(image, orientation), resize);
+    let image = preprocess.apply(image);
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .map_err(|e| failed(e.to_string()))?;
+    let sent = SentImage {
+        width: image.width(),
+        height: image.height(),
+        bytes: bytes.len() as u64,
+        resized,
+        converted_from: kind,
+        original_width: Some(original_width),
+        original_height: Some(original_height),
+        orientation: turned(orientation),
+    };
+    Ok(PreparedImage { bytes, media_type: ImageKind::Png.media_type(), sent: Some(sent) })
+}
+
+/// Turn an image upright, scale it down to fit `resize`, preprocess it
+/// and re-encode it
+fn reencode(original: &[u8], kind: Option<ImageKind>, resize: Option<Resize>, preprocess: Preprocess) -> Result<PreparedImage> {
+    let (image, orientation) = decode(original, kind)?;
+    let (original_width, original_height) = (image.width(), image.height());
+    let (image, resized) = fit(upright(image, orientation), resize);
+    let image = preprocess.apply(image);
+    let mut bytes = Vec::new();
+    // JPEG artifacts would put gray back around the edges of a binarized image
+    let media_type = if kind == Some(ImageKind::Png) || preprocess == Preprocess::Binarize {
+        image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).context("Failed to encode image")?;
+        ImageKind::Png.media_type()
+    } else {
+        let quality = resize.map_or(UPRIGHT_JPEG_QUALITY, |resize| resize.jpeg_quality);
+        let encoder = JpegEncoder::new_with_quality(&mut bytes, quality);
+        match &image {
+            DynamicImage::ImageLuma8(_) => image.write_with_encoder(encoder),
+            _ => DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder),
+        }
+        .context("Failed to encode image")?;
+        ImageKind::Jpeg.media_type()
+    };
+    let sent = SentImage {
+        width: image.width(),
+        height: image.height(),
+        bytes: bytes.len() as u64,
+        resized,
+        converted_from: None,
+        original_width: Some(original_width),
+        original_height: Some(original_height),
+        orientation: turned(orientation),
+    };
+    Ok(PreparedImage { bytes, media_type, sent: Some(sent) })
+}
+
+/// Scale an image down to fit `resize` when it is over the limit; also
+/// whether it was scaled
+fn fit(image: DynamicImage, resize: Option<Resize>) -> (DynamicImage, bool) {
+    match resize {
+        Some(resize) if !resize.fits(image.width(), image.height()) => {
+            (image.thumbnail(resize.max_dimension, resize.max_dimension), true)
+        }
+        _ => (image, false),
+    }
+}
+
+/// Apply the EXIF orientation an image was stored in
+fn upright(mut image: DynamicImage, orientation: Orientation) -> DynamicImage {
+    image.apply_orientation(orientation);
+    image
+}
+
+/// EXIF value of an orientation that isn't upright
+fn turned(orientation: Orientation) -> Option<u8> {
+    (orientation != Orientation::NoTransforms).then(|| orientation.to_exif())
+}
+
+fn reader(bytes: &[u8]) -> Result<ImageReader<Cursor<&[u8]>>> {
+    ImageReader::new(Cursor::new(bytes)).with_guessed_format().context("Failed to read image")
+}
+
+/// Decode an image as stored, with the orientation to turn it upright by
+fn decode(bytes: &[u8], kind: Option<ImageKind>) -> Result<(DynamicImage, Orientation)> {
+    let mut decoder = reader(bytes)?.into_decoder().context("Failed to decode image")?;
+    let orientation = if has_orientation(kind) {
+        decoder.orientation().context("Failed to read image orientation")?
+    } else {
+        Orientation::NoTransforms
+    };
+    let image = DynamicImage::from_decoder(decoder).context("Failed to decode image")?;
+    Ok((image, orientation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgb, RgbImage};
+    use std::path::PathBuf;
+
+    const PHOTO_FORMATS: &[ImageKind] = &[ImageKind::Jpeg, ImageKind::Png];
+
+    fn encoded(image: RgbImage, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(image).write_to(&mut Cursor::new(&mut bytes), format).unwrap();
+        bytes
+    }
+
+    /// A TIFF of two pages, the first 4x2 red and the second 8x8 blue
+    fn two_page_tiff() -> Vec<u8> {
+        use tiff::encoder::{colortype, TiffEncoder};
+        let mut bytes = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut bytes).unwrap();
+        encoder.write_image::<colortype::RGB8>(4, 2, &[255, 0, 0].repeat(8)).unwrap();
+        encoder.write_image::<colortype::RGB8>(8, 8, &[0, 0, 255].repeat(64)).unwrap();
+        bytes.into_inner()
+    }
+
+    /// `bytes` written to a file in a new directory
+    fn file(name: &str, bytes: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tm-query-resize-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn prepared(name: &str, bytes: &[u8], accepted: Option<&[ImageKind]>, resize: Option<Resize>) -> Result<PreparedImage> {
+        let path = file(name, bytes);
+        let prepared = prepare(&ImageSource::Files, &path, accepted, resize, Preprocess::None);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        prepared
+    }
+
+    fn decoded(prepared: &PreparedImage) -> DynamicImage {
+        image::load_from_memory(&prepared.bytes).unwrap()
+    }
+
+    #[test]
+    fn accepted_formats_are_sent_untouched() {
+        let png = encoded(RgbImage::new(6, 4), ImageFormat::Png);
+        let prepared = prepared("mark.png", &png, Some(PHOTO_FORMATS), None).unwrap();
+        assert_eq!(prepared.bytes, png);
+        assert_eq!(prepared.media_type, "image/png");
+        assert!(prepared.sent.is_none());
+    }
+
+    #[test]
+    fn any_format_is_accepted_without_a_list() {
+        let tiff = encoded(RgbImage::new(6, 4), ImageFormat::Tiff);
+        let prepared = prepared("mark.tif", &tiff, None, None).unwrap();
+        assert_eq!(prepared.bytes, tiff);
+        assert_eq!(prepared.media_type, "image/tiff");
+    }
+
+    #[test]
+    fn tiff_is_converted_to_png() {
+        let tiff = encoded(RgbImage::from_pixel(6, 4, Rgb([10, 200, 30])), ImageFormat::Tiff);
+        let prepared = prepared("mark.tif", &tiff, Some(PHOTO_FORMATS), None).unwrap();
+        assert_eq!(ImageKind::detect(&prepared.bytes), Some(ImageKind::Png));
+        assert_eq!(prepared.media_type, "image/png");
+        let sent = prepared.sent.unwrap();
+        assert_eq!(sent.converted_from, Some(ImageKind::Tiff));
+        assert_eq!((sent.width, sent.height, sent.resized), (6, 4, false));
+        assert_eq!(decoded(&prepared).to_rgb8().get_pixel(0, 0), &Rgb([10, 200, 30]));
+    }
+
+    #[test]
+    fn first_page_of_a_multi_page_tiff_is_kept() {
+        let prepared = prepared("mark.tif", &two_page_tiff(), Some(PHOTO_FORMATS), None).unwrap();
+        let image = decoded(&prepared).to_rgb8();
+        assert_eq!(image.dimensions(), (4, 2));
+        assert_eq!(image.get_pixel(0, 0), &Rgb([255, 0, 0]));
+    }
+
+    #[test]
+    fn unconvertible_formats_fail_with_a_conversion_error() {
+        let bmp = [b"BM".as_slice(), &[0; 30]].concat();
+        let error = prepared("mark.bmp", &bmp, Some(PHOTO_FORMATS), None).unwrap_err();
+        let conversion = error.downcast_ref::<ConversionError>().unwrap();
+        assert_eq!(conversion.from, Some(ImageKind::Bmp));
+        assert_eq!(conversion.class(), "conversion_error");
+    }
+
+    #[test]
+    fn large_images_are_shrunk_in_their_format() {
+        let resize = Some(Resize { max_dimension: 50, jpeg_quality: 80 });
+        let png = prepared("mark.png", &encoded(RgbImage::new(200, 100), ImageFormat::Png), None, resize).unwrap();
+        assert_eq!(png.media_type, "image/png");
+        assert_eq!(decoded(&png).dimensions(), (50, 25));
+        let sent = png.sent.unwrap();
+        assert!(sent.resized);
+        assert_eq!(sent.original_longest_side(), Some(200));
+
+        let jpeg = prepared("mark.jpg", &encoded(RgbImage::new(100, 200), ImageFormat::Jpeg), None, resize).unwrap();
+        assert_eq!(jpeg.media_type, "image/jpeg");
+        assert_eq!(decoded(&jpeg).dimensions(), (25, 50));
+
+        let small = encoded(RgbImage::new(40, 20), ImageFormat::Png);
+        let untouched = prepared("small.png", &small, None, resize).unwrap();
+        assert_eq!(untouched.bytes, small);
+        assert!(!untouched.sent.unwrap().resized);
+    }
+
+    /// Color of the pixel numbered `n` in the orientation fixtures
+    fn numbered(n: u8) -> Rgb<u8> {
+        Rgb([n * 40, 255 - n * 40, 100])
+    }
+
+    /// The orientation fixture: pixels 1 2 3 over 4 5 6 as stored, tagged
+    /// with EXIF orientation `orientation`, as a lossless TIFF
+    fn oriented_tiff(orientation: u8) -> Vec<u8> {
+        use tiff::encoder::{colortype, TiffEncoder};
+        use tiff::tags::Tag;
+        let data: Vec<u8> = (1..=6).flat_map(|n| numbered(n).0).collect();
+        let mut bytes = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut bytes).unwrap();
+        let mut image = encoder.new_image::<colortype::RGB8>(3, 2).unwrap();
+        image.encoder().write_tag(Tag::Orientation, u16::from(orientation)).unwrap();
+        image.write_data(&data).unwrap();
+        bytes.into_inner()
+    }
+
+    /// A 30x20 JPEG tagged with EXIF orientation `orientation`
+    fn oriented_jpeg(orientation: u8) -> Vec<u8> {
+        use image::ImageEncoder;
+        let exif = [b"MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0".as_slice(), &[orientation, 0, 0, 0, 0, 0, 0]].concat();
+        let mut bytes = Vec::new();
+        let mut encoder = JpegEncoder::new_with_quality(&mut bytes, 90);
+        encoder.set_exif_metadata(exif).unwrap();
+        encoder.write_image(&[128; 30 * 20 * 3], 30, 20, image::ExtendedColorType::Rgb8).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn each_exif_orientation_is_turned_upright() {
+        // The pixels as displayed, row by row, for each orientation
+        let table: [(u8, &[&[u8]]); 8] = [
+            (1, &[&[1, 2, 3], &[4, 5, 6]]),
+            (2, &[&[3, 2, 1], &[6, 5, 4]]),
+            (3, &[&[6, 5, 4], &[3, 2, 1]]),
+            (4, &[&[4, 5, 6], &[1, 2, 3]]),
+            (5, &[&[1, 4], &[2, 5], &[3, 6]]),
+            (6, &[&[4, 1], &[5, 2], &[6, 3]]),
+            (7, &[&[6, 3], &[5, 2], &[4, 1]]),
+            (8, &[&[3, 6], &[2, 5], &[1, 4]]),
+        ];
+        for (orientation, rows) in table {
+            let (image, read) = decode(&oriented_tiff(orientation), Some(ImageKind::Tiff)).unwrap();
+            assert_eq!(read.to_exif(), orientation);
+            let image = upright(image, read).to_rgb8();
+            let expected: Vec<Rgb<u8>> = rows.iter().flat_map(|row| row.iter().map(|&n| numbered(n))).collect();
+            assert_eq!(image.dimensions(), (rows[0].len() as u32, rows.len() as u32), "orientation {}", orientation);
+            assert_eq!(image.pixels().copied().collect::<Vec<_>>(), expected, "orientation {}", orientation);
+        }
+    }
+
+    #[test]
+    fn turned_images_record_their_orientation() {
+        for orientation in 1..=8 {
+            let prepared = prepared("mark.tif", &oriented_tiff(orientation), None, None).unwrap();
+            match orientation {
+                1 => assert!(prepared.sent.is_none()),
+                _ => {
+                    let sent = prepared.sent.unwrap();
+                    assert_eq!(sent.orientation, Some(orientation));
+                    let turned = if orientation >= 5 { (2, 3) } else { (3, 2) };
+                    assert_eq!((sent.width, sent.height), turned, "orientation {}", orientation);
+                    assert_eq!((sent.original_width, sent.original_height), (Some(3), Some(2)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn exif_orientation_of_a_jpeg_is_applied() {
+        let sideways = oriented_jpeg(6);
+        let prepared = prepared("mark.jpg", &sideways, Some(PHOTO_FORMATS), None).unwrap();
+        assert_eq!(prepared.media_type, "image/jpeg");
+        assert_eq!(decoded(&prepared).dimensions(), (20, 30));
+        assert_eq!(prepared.sent.unwrap().orientation, Some(6));
+
+        let path = file("mark.jpg", &sideways);
+        assert!(pass_through(&ImageSource::Files, &path, None, None, Preprocess::None).unwrap().is_none());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn upright_images_pass_through() {
+        let upright = oriented_jpeg(1);
+        let prepared = prepared("mark.jpg", &upright, None, None).unwrap();
+        assert_eq!(prepared.bytes, upright);
+        assert!(prepared.sent.is_none());
+
+        let path = file("mark.jpg", &upright);
+        let passed = pass_through(&ImageSource::Files, &path, None, None, Preprocess::None).unwrap().unwrap();
+        assert_eq!(passed.media_type, "image/jpeg");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
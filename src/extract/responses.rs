@@ -0,0 +1,91 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// One API response as written by `--save-responses`
+#[derive(Debug, Serialize)]
+pub struct SavedResponse<'a> {
+    /// Id of the request, see `correlation`
+    pub request_id: &'a str,
+    pub image_name: &'a str,
+    /// `extract`, or `detect` for the first stage of `--two-stage`
+    pub stage: &'a str,
+    /// Request number for this image and stage, from 1
+    pub attempt: u32,
+    /// Status of an error response; `null` for a 2xx response
+    pub http_status: Option<u16>,
+    /// Why the response was rejected, `null` if it parsed
+    pub error: Option<String>,
+    pub saved_at: String,
+    /// Endpoint, encoding and request body, with the image left out
+    pub request: Value,
+    /// The response body exactly as received
+    pub body: &'a str,
+}
+
+/// Directory of raw API responses, for looking into replies that didn't parse
+#[derive(Debug)]
+pub struct ResponseLog {
+    dir: PathBuf,
+    /// Most files to write in one run
+    limit: Option<usize>,
+    /// Write only responses that failed
+    failures_only: bool,
+    saved: AtomicUsize,
+    /// Responses not written because the limit was reached
+    skipped: AtomicUsize,
+}
+
+impl ResponseLog {
+    pub fn open(dir: &Path, limit: Option<usize>, failures_only: bool) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create responses directory: {}", dir.display()))?;
+        Ok(ResponseLog {
+            dir: dir.to_path_buf(),
+            limit,
+            failures_only,
+            saved: AtomicUsize::new(0),
+            skipped: AtomicUsize::new(0),
+        })
+    }
+
+    /// Write a response to `<image name>.<stage>.<request id>.json`. Returns
+    /// the file written, or `None` for a success with `failures_only` or
+    /// once `limit` files have been written.
+    pub fn save(&self, response: &SavedResponse) -> Result<Option<PathBuf>> {
+        if self.failures_only && response.error.is_none() {
+            return Ok(None);
+        }
+        let limit = self.limit.unwrap_or(usize::MAX);
+        if self.saved.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n < limit).then_some(n + 1)).is_err() {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        }
+        let path = self.dir.join(format!("{}.{}.{}.json", response.image_name, response.stage, response.request_id));
+        fs::write(&path, serde_json::to_string_pretty(response)?)
+            .with_context(|| format!("Failed to write response file: {}", path.display()))?;
+        Ok(Some(path))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn saved(&self) -> usize {
+        self.saved.load(Ordering::Relaxed)
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    /// What was saved where, and what the limit left out
+    pub fn describe(&self) -> String {
+        let skipped = self.skipped();
+        format!("Saved {} responses to {}{}", self.saved(), self.dir.display(),
+            if skipped > 0 { format!(" ({} more not saved, over --save-responses-limit)", skipped) } else { String::new() })
+    }
+}
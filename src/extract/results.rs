@@ -0,0 +1,721 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::breakdown::ImageBuckets;
+use super::device::Grade;
+use super::metrics::{self, Edit, Outcome};
+use super::parse::ParseMethod;
+use super::preprocess::Preprocess;
+use super::protocol::Generation;
+use super::resize::SentImage;
+use super::script::Script;
+use super::skiplist::SkipList;
+use super::terms::TermScore;
+use super::usage::Usage;
+use super::vote::Votes;
+use super::words::WordScore;
+use crate::images;
+
+/// Default results file name
+pub const RESULTS_FILE_NAME: &str = "results.jsonl";
+
+/// Review file of the answers that differ from the ground truth, written
+/// next to the results file
+pub const MISMATCHES_FILE_NAME: &str = "mismatches.jsonl";
+
+/// Whether the extraction of an image succeeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Error,
+    /// The first stage of `--two-stage` found no characters, so nothing was
+    /// extracted; scored as a prediction of none
+    NoChineseDetected,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Error => "error",
+            Status::NoChineseDetected => "no_chinese_detected",
+        }
+    }
+}
+
+/// One line of the results file. Keys are part of the file format and read
+/// by downstream tooling, so they must not change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtractionResult {
+    pub image_name: String,
+    /// `chineseCharacter` of the dataset or sidecar
+    pub ground_truth: Option<String>,
+    pub chinese_character: Option<String>,
+    pub words_in_mark: Option<String>,
+    pub description_of_device: Option<String>,
+    /// `ground_truth` and `chinese_character` after the text rules and
+    /// `--normalize`, as compared; `null` in results of older runs
+    #[serde(default)]
+    pub normalized_ground_truth: Option<String>,
+    #[serde(default)]
+    pub normalized_chinese_character: Option<String>,
+    pub status: Status,
+    pub error: Option<String>,
+    /// Class of the error: `timeout`, `connect`, `http <status>`, `parse`,
+    /// `empty_extraction`, `garbage_output` or `other`
+    #[serde(default)]
+    pub error_kind: Option<String>,
+    /// Coarse failure class, see `retry::failure_class`
+    #[serde(default)]
+    pub error_class: Option<String>,
+    /// Levenshtein distance between prediction and ground truth, in
+    /// characters after normalization
+    #[serde(default)]
+    pub edit_distance: Option<usize>,
+    /// Character error rate: `edit_distance` over the ground truth's length
+    #[serde(default)]
+    pub cer: Option<f64>,
+    /// Precision, recall and F1 of the predicted terms of a series mark,
+    /// see `terms::split`
+    #[serde(default)]
+    pub term_precision: Option<f64>,
+    #[serde(default)]
+    pub term_recall: Option<f64>,
+    #[serde(default)]
+    pub term_f1: Option<f64>,
+    /// `wordsInMark` of the dataset or sidecar
+    #[serde(default)]
+    pub ground_truth_words_in_mark: Option<String>,
+    /// Whether `words_in_mark` has the ground truth's words, see
+    /// `words::tokens`; `null` without ground truth words or on error
+    #[serde(default)]
+    pub words_match: Option<bool>,
+    /// Word error rate of `words_in_mark`, `null` like `words_match`
+    #[serde(default)]
+    pub wer: Option<f64>,
+    /// `descrOfDevice` of the dataset or sidecar
+    #[serde(default)]
+    pub ground_truth_description_of_device: Option<String>,
+    /// Similarity of `description_of_device` to the ground truth, from 0 to
+    /// 1; `null` without a ground truth description or on error
+    #[serde(default)]
+    pub device_similarity: Option<f64>,
+    /// `good`, `partial` or `poor` by `--device-good` and `--device-partial`
+    #[serde(default)]
+    pub device_grade: Option<Grade>,
+    /// Time from reading the image to the parsed response, including retries
+    pub elapsed_ms: u64,
+    /// Time spent reading, converting and encoding the image; `null` when
+    /// no request was made
+    #[serde(default)]
+    pub preprocess_ms: Option<u64>,
+    /// Time spent on requests and replies over every attempt, without
+    /// preparation or waiting; `null` when no request was made
+    #[serde(default)]
+    pub request_ms: Option<u64>,
+    /// Time spent waiting on the rate limit and retry backoff
+    #[serde(default)]
+    pub wait_ms: Option<u64>,
+    /// Images sent in the same request with `--batch-size`, this one
+    /// included; `null` when the image was sent on its own
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    /// Time spent on the batch's requests and replies, of which
+    /// `request_ms` is this image's share
+    #[serde(default)]
+    pub batch_ms: Option<u64>,
+    /// Requests made for the image
+    #[serde(default)]
+    pub attempts: u32,
+    /// Tokens reported over every request; `null` when no request was made
+    /// or the endpoint reports no usage
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    /// Image bytes sent over every request; `null` when no request was made
+    #[serde(default)]
+    pub sent_bytes: Option<u64>,
+    /// `usage` at `--price-per-1k-input` and `--price-per-1k-output`
+    #[serde(default)]
+    pub estimated_cost: Option<f64>,
+    /// Identity of the endpoint, model and prompt that produced the result
+    #[serde(default)]
+    pub config_id: String,
+    /// Hash of the prompt sent with the image, for protocols that take one
+    #[serde(default)]
+    pub prompt_hash: Option<String>,
+    /// Sampling settings sent with the request, for protocols that take them
+    #[serde(default)]
+    pub generation: Option<Generation>,
+    /// How the fields were read from the reply: `json`, `embedded_json` or `heuristic`
+    #[serde(default)]
+    pub parse_method: Option<ParseMethod>,
+    /// When the result was recorded, RFC 3339
+    #[serde(default)]
+    pub completed_at: Option<String>,
+    /// The response came from `--cache-dir` rather than the API
+    #[serde(default)]
+    pub cached: bool,
+    /// Answer of the first stage of `--two-stage`: whether the mark has
+    /// characters; `null` without `--two-stage` or when detection failed
+    #[serde(default)]
+    pub detected: Option<bool>,
+    /// Image with the same contents whose extraction this result copies
+    #[serde(default)]
+    pub duplicate_of: Option<String>,
+    /// Size of the image sent with `--max-dimension`; `null` without it,
+    /// when the response was cached or when resizing failed
+    #[serde(default)]
+    pub sent_image: Option<SentImage>,
+    /// Last response saved by `--save-responses` for this image
+    #[serde(default)]
+    pub response_file: Option<PathBuf>,
+    /// Id of the last request made for the image, as sent in `X-Request-Id`
+    /// and listed in the request index; `null` when no request was made
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// `--base-url` that answered the last request when several are given;
+    /// `null` with one, or when no request was made
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// `--model-spec` that produced the result; `null` outside a comparison
+    #[serde(default)]
+    pub model_spec: Option<String>,
+    /// Size of the image file on disk
+    #[serde(default)]
+    pub file_bytes: Option<u64>,
+    /// Buckets of the accuracy breakdown the image falls in
+    #[serde(default)]
+    pub buckets: Option<ImageBuckets>,
+    /// The answers of the `--votes` samples and their agreement; `null`
+    /// with a single sample
+    #[serde(default)]
+    pub votes: Option<Votes>,
+    /// Writing system of `chinese_character`, see `script::classify`;
+    /// `null` without a prediction or on error
+    #[serde(default)]
+    pub script: Option<Script>,
+    /// The prediction isn't in Chinese characters alone and
+    /// `--strict-script` counts it as wrong, even when it equals the ground
+    /// truth
+    #[serde(default)]
+    pub script_rejected: bool,
+    /// `--preprocess` applied to the image before it was sent
+    #[serde(default)]
+    pub preprocess: Preprocess,
+    #[serde(flatten)]
+    pub provenance: Provenance,
+}
+
+/// The trademark record an image's document belongs to, carried from the
+/// dataset to its results untouched so they can be joined back to IPOS
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// `applicationNum` of the dataset or sidecar
+    #[serde(default)]
+    pub application_num: Option<String>,
+    /// `application_num` was parsed from the `{applicationNum}_{fileName}`
+    /// image name, the dataset and sidecar having none
+    #[serde(default)]
+    pub application_num_derived: bool,
+    /// `lodgementDate` of the dataset or sidecar, as given
+    #[serde(default)]
+    pub lodgement_date: Option<String>,
+    /// `fileId` of the document
+    #[serde(default)]
+    pub file_id: Option<String>,
+}
+
+impl Provenance {
+    /// Fill in a missing application number from the image name, marked as
+    /// derived
+    pub fn or_derived(mut self, image_name: &str) -> Self {
+        if self.application_num.is_none() {
+            self.application_num = images::application_num_of(image_name).map(String::from);
+            self.application_num_derived = self.application_num.is_some();
+        }
+        self
+    }
+}
+
+/// One line of the mismatches file: an answered image whose prediction
+/// differs from the ground truth after normalization
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mismatch {
+    pub image_name: String,
+    /// Local path of the image, so it can be opened or queried again
+    pub image_path: PathBuf,
+    pub ground_truth: Option<String>,
+    pub prediction: Option<String>,
+    pub edit_distance: usize,
+    /// The model's reply before parsing
+    pub raw_output: String,
+    #[serde(flatten)]
+    pub provenance: Provenance,
+}
+
+/// Read a mismatches file, skipping lines that can't be parsed
+pub fn load_mismatches(path: &Path) -> Result<Vec<Mismatch>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open mismatches file: {}", path.display()))?;
+
+    let mut mismatches = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Failed to read mismatches file: {}", path.display()))?;
+        if let Ok(mismatch) = serde_json::from_str(&line) {
+            mismatches.push(mismatch);
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Rewrite a mismatches file with the largest edit distances first
+pub fn sort_mismatches(path: &Path) -> Result<()> {
+    let mut mismatches = load_mismatches(path)?;
+    mismatches.sort_by(|a, b| b.edit_distance.cmp(&a.edit_distance).then_with(|| a.image_name.cmp(&b.image_name)));
+
+    let mut text = String::new();
+    for mismatch in &mismatches {
+        text.push_str(&serde_json::to_string(mismatch).context("Failed to serialize mismatch")?);
+        text.push('\n');
+    }
+    let temp = path.with_extension("jsonl.tmp");
+    std::fs::write(&temp, text)
+        .with_context(|| format!("Failed to write mismatches file: {}", temp.display()))?;
+    std::fs::rename(&temp, path)
+        .with_context(|| format!("Failed to write mismatches file: {}", path.display()))
+}
+
+/// Short identity of an extraction configuration. Results are only reused by
+/// `--resume` when they were produced with the same one.
+pub fn config_id(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())[..12].to_string()
+}
+
+/// Read a results file. Lines that can't be parsed, such as one cut short by
+/// a crash, are skipped and counted.
+pub fn load(path: &Path) -> Result<(Vec<ExtractionResult>, usize)> {
+    let mut results = Vec::new();
+    let malformed = scan(path, |result| results.push(result))?;
+    Ok((results, malformed))
+}
+
+/// The results of configuration `config_id` in a results file, leaving out
+/// the images of `skip_list`
+pub fn load_config(path: &Path, config_id: &str, skip_list: &SkipList) -> Result<Vec<ExtractionResult>> {
+    let mut results = Vec::new();
+    scan(path, |result| {
+        if result.config_id == config_id && !skip_list.contains(&result.image_name) {
+            results.push(result);
+        }
+    })?;
+    Ok(results)
+}
+
+/// Pass each result of a results file to `f` as it is read, without holding
+/// the file in memory, and return the number of lines that can't be parsed
+pub fn scan(path: &Path, mut f: impl FnMut(ExtractionResult)) -> Result<usize> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open results file: {}", path.display()))?;
+
+    let mut malformed = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Failed to read results file: {}", path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(result) => f(result),
+            Err(_) => malformed += 1,
+        }
+    }
+    Ok(malformed)
+}
+
+/// Images already recorded in a results file for a configuration
+#[derive(Debug, Default)]
+pub struct Checkpoint {
+    pub processed: HashSet<String>,
+    /// Results of this configuration, in file order
+    pub results: Vec<ExtractionResult>,
+    /// Results produced by another configuration, which don't count
+    pub other_config: usize,
+    pub malformed: usize,
+}
+
+impl Checkpoint {
+    /// Load the checkpoint from an existing results file; a missing file is
+    /// an empty checkpoint
+    pub fn load(path: &Path, config_id: &str) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Checkpoint::default());
+        }
+        let (results, malformed) = load(path)?;
+
+        let mut checkpoint = Checkpoint { malformed, ..Default::default() };
+        for result in results {
+            if result.config_id == config_id {
+                checkpoint.processed.insert(result.image_name.clone());
+                checkpoint.results.push(result);
+            } else {
+                checkpoint.other_config += 1;
+            }
+        }
+        Ok(checkpoint)
+    }
+
+    pub fn contains(&self, image_name: &str) -> bool {
+        self.processed.contains(image_name)
+    }
+
+    /// Leave out the results of `image_names`, so they are processed again
+    pub fn forget(&mut self, image_names: &HashSet<String>) {
+        self.processed.retain(|name| !image_names.contains(name));
+        self.results.retain(|result| !image_names.contains(&result.image_name));
+    }
+}
+
+/// Rewrite a results file keeping only the last result of each of
+/// `image_names` for `config_id`, so the results of a `--retry-failed` run
+/// replace the failures they were appended after. Other lines are kept as
+/// written. Returns the number of results dropped.
+pub fn drop_superseded(path: &Path, config_id: &str, image_names: &HashSet<String>) -> Result<usize> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read results file: {}", path.display()))?;
+    let retried = |line: &str| {
+        serde_json::from_str::<ExtractionResult>(line).ok()
+            .filter(|result| result.config_id == config_id && image_names.contains(&result.image_name))
+            .map(|result| result.image_name)
+    };
+    let mut last = HashMap::new();
+    for (index, line) in text.lines().enumerate() {
+        if let Some(name) = retried(line) {
+            last.insert(name, index);
+        }
+    }
+
+    let mut kept = String::with_capacity(text.len());
+    let mut dropped = 0;
+    for (index, line) in text.lines().enumerate() {
+        if retried(line).is_some_and(|name| last[&name] != index) {
+            dropped += 1;
+            continue;
+        }
+        kept.push_str(line);
+        kept.push('\n');
+    }
+    if dropped == 0 {
+        return Ok(0);
+    }
+    let temp = path.with_extension("jsonl.tmp");
+    std::fs::write(&temp, kept)
+        .with_context(|| format!("Failed to write results file: {}", temp.display()))?;
+    std::fs::rename(&temp, path)
+        .with_context(|| format!("Failed to write results file: {}", path.display()))?;
+    Ok(dropped)
+}
+
+impl ExtractionResult {
+    /// Ground truth and prediction to compare: the normalized forms when the
+    /// result has them, otherwise the raw ones without whitespace
+    fn compared(&self) -> (Option<String>, Option<String>) {
+        if self.normalized_ground_truth.is_some() || self.normalized_chinese_character.is_some() {
+            (self.normalized_ground_truth.clone(), self.normalized_chinese_character.clone())
+        } else {
+            (self.ground_truth.as_deref().map(metrics::comparable), self.chinese_character.as_deref().map(metrics::comparable))
+        }
+    }
+
+    /// The ground truth characters, as compared with the prediction
+    pub fn compared_ground_truth(&self) -> Option<String> {
+        self.compared().0
+    }
+
+    /// The characters predicted, as compared with the ground truth
+    pub fn compared_prediction(&self) -> Option<String> {
+        self.compared().1
+    }
+
+    /// How the prediction compares with the ground truth, after normalization
+    pub fn outcome(&self) -> Outcome {
+        if self.status == Status::Error {
+            return Outcome::Error;
+        }
+        let (truth, predicted) = self.compared();
+        match compare(truth.as_deref(), predicted.as_deref()) {
+            Outcome::ExactMatch if self.script_rejected => Outcome::Mismatch,
+            outcome => outcome,
+        }
+    }
+
+    /// Whether the predicted characters equal the ground truth, after normalization
+    pub fn is_match(&self) -> bool {
+        self.outcome() == Outcome::ExactMatch
+    }
+
+    /// Whether the predicted characters equal the ground truth as written
+    pub fn is_raw_match(&self) -> bool {
+        self.status != Status::Error
+            && !self.script_rejected
+            && compare(
+                self.ground_truth.as_deref().map(metrics::comparable).as_deref(),
+                self.chinese_character.as_deref().map(metrics::comparable).as_deref(),
+            ) == Outcome::ExactMatch
+    }
+
+    /// Term scores of a successful request
+    pub fn term_score(&self) -> Option<TermScore> {
+        Some(TermScore { precision: self.term_precision?, recall: self.term_recall?, f1: self.term_f1? })
+    }
+
+    /// Score of the words in the mark, if the ground truth has some and the
+    /// request succeeded
+    pub fn word_score(&self) -> Option<WordScore> {
+        Some(WordScore { exact_match: self.words_match?, wer: self.wer? })
+    }
+
+    /// Character alignment of prediction and ground truth after
+    /// normalization, for successful requests. Missing values count as empty.
+    pub fn alignment(&self) -> Option<Vec<Edit>> {
+        if self.status == Status::Error {
+            return None;
+        }
+        let (truth, predicted) = self.compared();
+        Some(metrics::align(&truth.unwrap_or_default(), &predicted.unwrap_or_default()))
+    }
+}
+
+/// Compare a prediction with the ground truth, both already normalized
+fn compare(truth: Option<&str>, predicted: Option<&str>) -> Outcome {
+    let truth = truth.filter(|t| !t.is_empty());
+    let predicted = predicted.filter(|p| !p.is_empty());
+    match (truth, predicted) {
+        (Some(truth), Some(predicted)) if truth == predicted => Outcome::ExactMatch,
+        (Some(_), Some(_)) => Outcome::Mismatch,
+        (Some(_), None) => Outcome::Missed,
+        (None, Some(_)) => Outcome::Spurious,
+        (None, None) => Outcome::ExactMatch,
+    }
+}
+
+/// Appends results or mismatches as JSON lines, one write per line, so a
+/// crash loses at most the requests still in flight
+#[derive(Debug)]
+pub struct ResultsWriter {
+    file: Mutex<File>,
+}
+
+impl ResultsWriter {
+    /// Create the results file, or append to it if `append` is set
+    pub fn open(path: &Path, append: bool) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .with_context(|| format!("Failed to open results file: {}", path.display()))?;
+
+        // A line cut short by a crash must not swallow the next result
+        if append && !ends_with_newline(&mut file)? {
+            file.write_all(b"\n").context("Failed to write result")?;
+        }
+
+        Ok(ResultsWriter { file: Mutex::new(file) })
+    }
+
+    pub fn write<T: Serialize>(&self, result: &T) -> Result<()> {
+        let mut line = serde_json::to_vec(result).context("Failed to serialize result")?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().expect("results file lock poisoned");
+        file.write_all(&line).context("Failed to write result")?;
+        file.flush().context("Failed to write result")
+    }
+
+    /// Make the results written so far durable on disk
+    pub fn sync(&self) -> Result<()> {
+        let file = self.file.lock().expect("results file lock poisoned");
+        file.sync_all().context("Failed to sync results file")
+    }
+}
+
+fn ends_with_newline(file: &mut File) -> Result<bool> {
+    let len = file.metadata().context("Failed to read results file")?.len();
+    if len == 0 {
+        return Ok(true);
+    }
+    let mut last = [0];
+    file.seek(SeekFrom::Start(len - 1)).context("Failed to read results file")?;
+    file.read_exact(&mut last).context("Failed to read results file")?;
+    Ok(last[0] == b'\n')
+}
+
+/// One row of the CSV results
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    image_name: &'a str,
+    ground_truth: Option<&'a str>,
+    predicted_chinese: Option<&'a str>,
+    words_in_mark: Option<&'a str>,
+    device_description: Option<&'a str>,
+    #[serde(rename = "match")]
+    is_match: bool,
+    edit_distance: Option<usize>,
+    error: Option<&'a str>,
+    raw_match: bool,
+}
+
+/// Writes results as CSV for spreadsheet review, flushing every row
+#[derive(Debug)]
+pub struct CsvResultsWriter {
+    writer: Mutex<csv::Writer<File>>,
+}
+
+impl CsvResultsWriter {
+    /// Create the CSV file, starting with a UTF-8 byte order mark if `bom` is
+    /// set so Excel detects the encoding of the Chinese text. With `append`,
+    /// rows are added to an existing file without repeating the header.
+    pub fn create(path: &Path, bom: bool, append: bool) -> Result<Self> {
+        let continuing = append && path.metadata().is_ok_and(|m| m.len() > 0);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(continuing)
+            .truncate(!continuing)
+            .open(path)
+            .with_context(|| format!("Failed to create CSV results file: {}", path.display()))?;
+        if bom && !continuing {
+            file.write_all(b"\xEF\xBB\xBF").context("Failed to write CSV results")?;
+        }
+
+        let writer = csv::WriterBuilder::new()
+            .has_headers(!continuing)
+            .from_writer(file);
+        Ok(CsvResultsWriter { writer: Mutex::new(writer) })
+    }
+
+    pub fn write(&self, result: &ExtractionResult) -> Result<()> {
+        let mut writer = self.writer.lock().expect("CSV results lock poisoned");
+        writer.serialize(CsvRow {
+            image_name: &result.image_name,
+            ground_truth: result.ground_truth.as_deref(),
+            predicted_chinese: result.chinese_character.as_deref(),
+            words_in_mark: result.words_in_mark.as_deref(),
+            device_description: result.description_of_device.as_deref(),
+            is_match: result.is_match(),
+            edit_distance: result.edit_distance,
+            error: result.error.as_deref(),
+            raw_match: result.is_raw_match(),
+        }).context("Failed to write CSV results")?;
+        writer.flush().context("Failed to write CSV results")
+    }
+
+    /// Make the rows written so far durable on disk
+    pub fn sync(&self) -> Result<()> {
+        let mut writer = self.writer.lock().expect("CSV results lock poisoned");
+        writer.flush().context("Failed to write CSV results")?;
+        writer.get_ref().sync_all().context("Failed to sync CSV results file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explicit_application_num_is_kept() {
+        let provenance = Provenance {
+            application_num: Some("40201400001U".to_string()),
+            lodgement_date: Some("2014-01-02".to_string()),
+            file_id: Some("123".to_string()),
+            ..Provenance::default()
+        }
+        .or_derived("40201912345X_rep.jpg");
+        assert_eq!(provenance.application_num.as_deref(), Some("40201400001U"));
+        assert!(!provenance.application_num_derived);
+        assert_eq!(provenance.lodgement_date.as_deref(), Some("2014-01-02"));
+        assert_eq!(provenance.file_id.as_deref(), Some("123"));
+    }
+
+    #[test]
+    fn missing_application_num_is_derived_from_the_name() {
+        let provenance = Provenance::default().or_derived("40201912345X_rep.jpg");
+        assert_eq!(provenance.application_num.as_deref(), Some("40201912345X"));
+        assert!(provenance.application_num_derived);
+        assert_eq!(provenance.lodgement_date, None);
+    }
+
+    #[test]
+    fn names_of_another_shape_derive_nothing() {
+        let provenance = Provenance::default().or_derived("dup_x.png");
+        assert_eq!(provenance.application_num, None);
+        assert!(!provenance.application_num_derived);
+    }
+
+    #[test]
+    fn provenance_round_trips_flat_in_a_results_line() {
+        let provenance = Provenance::default().or_derived("40201912345X_rep.jpg");
+        let value = serde_json::to_value(&provenance).unwrap();
+        assert_eq!(value["application_num"], "40201912345X");
+        assert_eq!(value["application_num_derived"], true);
+        let read: Provenance = serde_json::from_str("{}").unwrap();
+        assert_eq!(read, Provenance::default());
+    }
+
+    fn line(image_name: &str, config_id: &str) -> String {
+        serde_json::json!({
+            "image_name": image_name,
+            "chinese_character": "商标",
+            "status": "ok",
+            "error": null,
+            "elapsed_ms": 120,
+            "config_id": config_id,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn checkpoint_of_a_partial_results_file() {
+        let path = std::env::temp_dir().join(format!("tm-query-checkpoint-{}.jsonl", uuid::Uuid::new_v4()));
+        let truncated = line("40201900003X_rep.jpg", "abc");
+        let text = [
+            line("40201900001X_rep.jpg", "abc"),
+            line("40201900002X_rep.jpg", "other"),
+            String::new(),
+            line("40201900002X_rep.jpg", "abc"),
+            truncated[..truncated.len() / 2].to_string(),
+        ]
+        .join("\n");
+        std::fs::write(&path, text).unwrap();
+
+        let checkpoint = Checkpoint::load(&path, "abc").unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let names: Vec<&str> = checkpoint.results.iter().map(|r| r.image_name.as_str()).collect();
+        assert_eq!(names, ["40201900001X_rep.jpg", "40201900002X_rep.jpg"]);
+        assert!(checkpoint.contains("40201900002X_rep.jpg"));
+        assert!(!checkpoint.contains("40201900003X_rep.jpg"));
+        assert_eq!(checkpoint.other_config, 1);
+        assert_eq!(checkpoint.malformed, 1);
+    }
+
+    #[test]
+    fn checkpoint_of_a_missing_results_file_is_empty() {
+        let path = std::env::temp_dir().join(format!("tm-query-checkpoint-{}.jsonl", uuid::Uuid::new_v4()));
+        let checkpoint = Checkpoint::load(&path, "abc").unwrap();
+        assert!(checkpoint.processed.is_empty());
+        assert_eq!((checkpoint.other_config, checkpoint.malformed), (0, 0));
+    }
+}
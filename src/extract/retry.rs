@@ -0,0 +1,289 @@
+use rand::Rng;
+use std::fmt;
+use std::time::Duration;
+
+use super::format::{ConversionError, CorruptImage, TooLarge};
+use super::validate::ValidationError;
+
+/// Longest delay between retries, before the jitter
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Longest part of an error response body kept in messages
+const BODY_EXCERPT_CHARS: usize = 200;
+
+/// A non-2xx response from the API, with its body
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: u16,
+    /// The whole body; messages show only its start
+    pub body: String,
+    /// Delay requested by a `Retry-After` header
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let excerpt: String = self.body.trim().chars().take(BODY_EXCERPT_CHARS).collect();
+        if excerpt.is_empty() {
+            write!(f, "API returned HTTP {}", self.status)
+        } else {
+            write!(f, "API returned HTTP {}: {}", self.status, excerpt)
+        }
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// The failure of a request for several images, as recorded for each of
+/// them: the message and kind of the original error, which can't be copied
+#[derive(Debug)]
+pub struct SharedFailure {
+    pub kind: String,
+    pub auth: bool,
+    message: String,
+}
+
+impl SharedFailure {
+    pub fn new(error: &anyhow::Error) -> Self {
+        SharedFailure { kind: classify(error), auth: is_auth_error(error), message: format!("{:#}", error) }
+    }
+}
+
+impl fmt::Display for SharedFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for SharedFailure {}
+
+/// Pass successful responses through and turn any other into an
+/// `HttpStatusError`, so the status isn't hidden behind a parse error
+pub async fn check_status(response: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = response.headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after);
+    let body = response.text().await.unwrap_or_default();
+    Err(HttpStatusError {
+        status: status.as_u16(),
+        body,
+        retry_after,
+    }.into())
+}
+
+/// Parse a `Retry-After` value, either seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().ok()
+}
+
+/// The 429 response behind an error, if the server asked us to slow down
+pub fn rate_limited(error: &anyhow::Error) -> Option<&HttpStatusError> {
+    error.chain()
+        .find_map(|cause| cause.downcast_ref::<HttpStatusError>())
+        .filter(|e| e.status == 429)
+}
+
+/// Whether the server refused the credentials (401 or 403). Every other
+/// request would fail the same way, so the run should stop.
+pub fn is_auth_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.downcast_ref::<HttpStatusError>().is_some_and(|e| matches!(e.status, 401 | 403))
+            || cause.downcast_ref::<SharedFailure>().is_some_and(|e| e.auth)
+    })
+}
+
+/// Classify a failed API call as `timeout`, `connect`, `http <status>`,
+/// `parse`, `empty_extraction`, `garbage_output` or `other`, for the results
+/// file and retry decisions
+pub fn classify(error: &anyhow::Error) -> String {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<SharedFailure>() {
+            return e.kind.clone();
+        }
+        if let Some(e) = cause.downcast_ref::<ValidationError>() {
+            return e.class().to_string();
+        }
+        if let Some(e) = cause.downcast_ref::<ConversionError>() {
+            return e.class().to_string();
+        }
+        if let Some(e) = cause.downcast_ref::<CorruptImage>() {
+            return e.class().to_string();
+        }
+        if let Some(e) = cause.downcast_ref::<TooLarge>() {
+            return e.class().to_string();
+        }
+        if let Some(e) = cause.downcast_ref::<HttpStatusError>() {
+            return format!("http {}", e.status);
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return if e.is_timeout() {
+                "timeout".to_string()
+            } else if e.is_connect() {
+                "connect".to_string()
+            } else if let Some(status) = e.status() {
+                format!("http {}", status.as_u16())
+            } else if e.is_decode() {
+                "parse".to_string()
+            } else {
+                "other".to_string()
+            };
+        }
+        if cause.downcast_ref::<serde_json::Error>().is_some() {
+            return "parse".to_string();
+        }
+    }
+    "other".to_string()
+}
+
+/// Coarse failure class of an error kind from `classify`, for the results
+/// file and the end of run summary: `http_error`, `timeout`,
+/// `connection_error`, `invalid_json`, `empty_extraction`, `garbage_output`,
+/// `conversion_error`, `corrupt_image`, `too_large`
+/// or `other`. The first three point at the plumbing, the rest at the model.
+pub fn failure_class(kind: &str) -> &'static str {
+    match kind {
+        "timeout" => "timeout",
+        "connect" => "connection_error",
+        "parse" => "invalid_json",
+        "empty_extraction" => "empty_extraction",
+        "garbage_output" => "garbage_output",
+        "conversion_error" => "conversion_error",
+        "corrupt_image" => "corrupt_image",
+        "too_large" => "too_large",
+        _ if kind.starts_with("http ") => "http_error",
+        _ => "other",
+    }
+}
+
+/// Whether a failed API call is worth repeating: timeouts, connection errors
+/// and server errors. Client errors and responses that arrived but could not
+/// be parsed or validated would only fail the same way again.
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    let kind = classify(error);
+    match kind.strip_prefix("http ").and_then(|code| code.parse::<u16>().ok()) {
+        Some(code) => code >= 500,
+        None => matches!(kind.as_str(), "timeout" | "connect"),
+    }
+}
+
+/// Delay before retry number `attempt` (starting at 1): `base` doubled for
+/// each earlier retry up to `MAX_BACKOFF`, scaled by a random factor
+/// between 0.5 and 1.5 so parallel requests don't retry in lockstep
+pub fn backoff(attempt: u32, base: Duration) -> Duration {
+    let exponential = base.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(MAX_BACKOFF);
+    exponential.mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve one response per connection with the statuses of `script` in
+    /// turn, then 200s; returns the base URL and the number of requests
+    async fn mock_server(script: Vec<u16>) -> (String, Arc<AtomicU32>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicU32::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    head.extend_from_slice(&buf[..n]);
+                }
+                let index = counter.fetch_add(1, Ordering::SeqCst) as usize;
+                let status = script.get(index).copied().unwrap_or(200);
+                let body = if status == 200 { "{\"ok\":true}" } else { "overloaded" };
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, body.len(), body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    /// Request `url` the way the extractor does: retry what `is_retryable`
+    /// allows, up to `max_retries` times, with a short `backoff`
+    async fn get_with_retries(url: &str, max_retries: u32) -> (anyhow::Result<String>, u32) {
+        let client = reqwest::Client::new();
+        let mut retries = 0;
+        loop {
+            let outcome = async { Ok(check_status(client.get(url).send().await?).await?.text().await?) }.await;
+            match &outcome {
+                Err(e) if retries < max_retries && is_retryable(e) => {
+                    retries += 1;
+                    tokio::time::sleep(backoff(retries, Duration::from_millis(5))).await;
+                }
+                _ => return (outcome, retries + 1),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn succeeds_on_the_third_attempt() {
+        let (url, requests) = mock_server(vec![500, 503]).await;
+        let (outcome, attempts) = get_with_retries(&url, 3).await;
+        assert_eq!(outcome.unwrap(), "{\"ok\":true}");
+        assert_eq!(attempts, 3);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_retry() {
+        let (url, requests) = mock_server(vec![500; 10]).await;
+        let (outcome, attempts) = get_with_retries(&url, 3).await;
+        let error = outcome.unwrap_err();
+        assert_eq!(classify(&error), "http 500");
+        assert_eq!(failure_class(&classify(&error)), "http_error");
+        assert_eq!(attempts, 4);
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (url, requests) = mock_server(vec![400]).await;
+        let (outcome, attempts) = get_with_retries(&url, 3).await;
+        assert!(!is_retryable(&outcome.unwrap_err()));
+        assert_eq!(attempts, 1);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn backoff_doubles_within_the_jitter() {
+        let base = Duration::from_millis(100);
+        for attempt in 1..=5 {
+            let expected = 100.0 * 2f64.powi(attempt as i32 - 1);
+            let delay = backoff(attempt, base).as_secs_f64() * 1000.0;
+            assert!(delay >= expected * 0.5 && delay <= expected * 1.5, "attempt {}: {}ms", attempt, delay);
+        }
+    }
+
+    #[test]
+    fn backoff_stops_growing_at_the_ceiling() {
+        for attempt in [8, 20, 64, u32::MAX] {
+            let delay = backoff(attempt, Duration::from_secs(1));
+            assert!(delay >= MAX_BACKOFF.mul_f64(0.5) && delay <= MAX_BACKOFF.mul_f64(1.5), "attempt {}: {:?}", attempt, delay);
+        }
+    }
+}
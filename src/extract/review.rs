@@ -0,0 +1,366 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+use image::RgbImage;
+use ratatui::buffer::Buffer;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph, Widget, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use super::metrics::Scoreboard;
+use super::results::{Mismatch, ResultsWriter};
+
+/// Verdicts of the `review` subcommand, written next to the mismatches file
+pub const VERDICTS_FILE_NAME: &str = "review_verdicts.jsonl";
+
+/// Who got a mismatched image wrong, by a reviewer's reading of the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    ModelWrong,
+    /// The ground truth is wrong and the prediction may well be right
+    LabelWrong,
+    /// The image can't settle it
+    Ambiguous,
+}
+
+impl Verdict {
+    pub fn name(self) -> &'static str {
+        match self {
+            Verdict::ModelWrong => "model wrong",
+            Verdict::LabelWrong => "label wrong",
+            Verdict::Ambiguous => "ambiguous",
+        }
+    }
+}
+
+/// One line of the verdicts file. An image reviewed again gets another
+/// line, and its last one counts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerdictRecord {
+    pub image_name: String,
+    pub verdict: Verdict,
+    pub ground_truth: Option<String>,
+    pub prediction: Option<String>,
+    pub reviewed_at: String,
+}
+
+/// Last verdict of each image in a verdicts file; a missing file has none,
+/// and lines that can't be parsed are skipped
+pub fn load_verdicts(path: &Path) -> Result<HashMap<String, Verdict>> {
+    let mut verdicts = HashMap::new();
+    if !path.exists() {
+        return Ok(verdicts);
+    }
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open verdicts file: {}", path.display()))?;
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Failed to read verdicts file: {}", path.display()))?;
+        if let Ok(record) = serde_json::from_str::<VerdictRecord>(&line) {
+            verdicts.insert(record.image_name, record.verdict);
+        }
+    }
+    Ok(verdicts)
+}
+
+/// Accuracy once the reviewed mismatches are taken into account: images
+/// whose label was wrong count as matches, and ambiguous ones are left out
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabelReview {
+    /// Answered mismatches of the run with a verdict
+    pub reviewed: usize,
+    /// Answered mismatches of the run
+    pub mismatches: usize,
+    pub model_wrong: usize,
+    pub label_wrong: usize,
+    pub ambiguous: usize,
+    pub adjusted_accuracy: f64,
+}
+
+impl LabelReview {
+    /// The review of `scoreboard`'s answered mismatches by `verdicts`
+    pub fn new(scoreboard: &Scoreboard, verdicts: &HashMap<String, Verdict>) -> Self {
+        let mut review = LabelReview { reviewed: 0, mismatches: 0, model_wrong: 0, label_wrong: 0, ambiguous: 0, adjusted_accuracy: 0.0 };
+        for (name, score) in &scoreboard.image_scores {
+            if score.exact_match || score.cer.is_none() {
+                continue;
+            }
+            review.mismatches += 1;
+            let Some(verdict) = verdicts.get(name) else {
+                continue;
+            };
+            review.reviewed += 1;
+            match verdict {
+                Verdict::ModelWrong => review.model_wrong += 1,
+                Verdict::LabelWrong => review.label_wrong += 1,
+                Verdict::Ambiguous => review.ambiguous += 1,
+            }
+        }
+        let total = scoreboard.total.saturating_sub(review.ambiguous);
+        if total > 0 {
+            review.adjusted_accuracy = (scoreboard.exact_match + review.label_wrong) as f64 / total as f64;
+        }
+        review
+    }
+}
+
+impl fmt::Display for LabelReview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Label review: {} of {} mismatches reviewed, {} model wrong, {} label wrong, {} ambiguous; label-adjusted accuracy {:.2}%",
+            self.reviewed, self.mismatches, self.model_wrong, self.label_wrong, self.ambiguous, self.adjusted_accuracy * 100.0)
+    }
+}
+
+/// How a review session ended
+#[derive(Debug, Default)]
+pub struct ReviewSummary {
+    /// Verdicts given in this session
+    pub marked: usize,
+    /// Mismatches with a verdict, from this session or an earlier one
+    pub reviewed: usize,
+    pub mismatches: usize,
+}
+
+/// Walk `mismatches` in the terminal from the first one without a verdict,
+/// appending each verdict to `verdicts_path` as it is given so the review
+/// can be left and picked up at any time. `viewer` opens the image in
+/// another program; `preview` starts with the image drawn in the terminal.
+pub fn run(mismatches: Vec<Mismatch>, verdicts_path: &Path, viewer: &str, preview: bool) -> Result<ReviewSummary> {
+    let verdicts = load_verdicts(verdicts_path)?;
+    let writer = ResultsWriter::open(verdicts_path, true)?;
+    let index = mismatches.iter().position(|m| !verdicts.contains_key(&m.image_name)).unwrap_or(0);
+    let mut review = Review {
+        mismatches,
+        verdicts,
+        writer,
+        viewer: viewer.to_string(),
+        index,
+        scroll: 0,
+        preview,
+        thumbnail: None,
+        marked: 0,
+        status: String::new(),
+    };
+
+    let mut terminal = ratatui::try_init().context("Failed to set up the terminal")?;
+    let outcome = review.event_loop(&mut terminal);
+    ratatui::try_restore().context("Failed to restore the terminal")?;
+    outcome?;
+
+    Ok(ReviewSummary {
+        marked: review.marked,
+        reviewed: review.mismatches.iter().filter(|m| review.verdicts.contains_key(&m.image_name)).count(),
+        mismatches: review.mismatches.len(),
+    })
+}
+
+struct Review {
+    mismatches: Vec<Mismatch>,
+    verdicts: HashMap<String, Verdict>,
+    writer: ResultsWriter,
+    viewer: String,
+    /// Mismatch on screen
+    index: usize,
+    /// Lines of the raw output scrolled past
+    scroll: u16,
+    preview: bool,
+    thumbnail: Option<Thumbnail>,
+    marked: usize,
+    /// Message of the last action, shown under the keys
+    status: String,
+}
+
+impl Review {
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        if self.mismatches.is_empty() {
+            return Ok(());
+        }
+        loop {
+            terminal.draw(|frame| self.draw(frame)).context("Failed to draw the review")?;
+            let Event::Key(key) = event::read().context("Failed to read a key")? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Char('m') | KeyCode::Char('1') => self.mark(Verdict::ModelWrong)?,
+                KeyCode::Char('l') | KeyCode::Char('2') => self.mark(Verdict::LabelWrong)?,
+                KeyCode::Char('a') | KeyCode::Char('3') => self.mark(Verdict::Ambiguous)?,
+                KeyCode::Char('n') | KeyCode::Right => self.go_to(self.index + 1),
+                KeyCode::Char('p') | KeyCode::Left => self.go_to(self.index.saturating_sub(1)),
+                KeyCode::Char('u') => self.go_to_unreviewed(),
+                KeyCode::Char('o') => self.open(),
+                KeyCode::Char('i') => self.preview = !self.preview,
+                KeyCode::Down | KeyCode::Char('j') => self.scroll = self.scroll.saturating_add(1),
+                KeyCode::Up | KeyCode::Char('k') => self.scroll = self.scroll.saturating_sub(1),
+                KeyCode::PageDown => self.scroll = self.scroll.saturating_add(10),
+                KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+                _ => {}
+            }
+        }
+    }
+
+    /// Record a verdict on the mismatch on screen, then move to the next
+    /// one without a verdict
+    fn mark(&mut self, verdict: Verdict) -> Result<()> {
+        let mismatch = &self.mismatches[self.index];
+        self.writer.write(&VerdictRecord {
+            image_name: mismatch.image_name.clone(),
+            verdict,
+            ground_truth: mismatch.ground_truth.clone(),
+            prediction: mismatch.prediction.clone(),
+            reviewed_at: Local::now().to_rfc3339(),
+        })?;
+        self.verdicts.insert(mismatch.image_name.clone(), verdict);
+        self.marked += 1;
+        self.status = format!("Marked {} as {}", mismatch.image_name, verdict.name());
+        self.go_to_unreviewed();
+        Ok(())
+    }
+
+    fn go_to(&mut self, index: usize) {
+        let index = index.min(self.mismatches.len() - 1);
+        if index != self.index {
+            self.index = index;
+            self.scroll = 0;
+        }
+    }
+
+    /// Move to the next mismatch without a verdict, wrapping around
+    fn go_to_unreviewed(&mut self) {
+        let count = self.mismatches.len();
+        let next = (1..=count)
+            .map(|step| (self.index + step) % count)
+            .find(|&index| !self.verdicts.contains_key(&self.mismatches[index].image_name));
+        match next {
+            Some(index) => self.go_to(index),
+            None => self.status = format!("All {} mismatches are reviewed; q to quit", count),
+        }
+    }
+
+    /// Open the image in `viewer` without waiting for it
+    fn open(&mut self) {
+        let path = &self.mismatches[self.index].image_path;
+        self.status = match Command::new(&self.viewer).arg(path)
+            .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(_) => format!("Opened {} with {}", path.display(), self.viewer),
+            Err(e) => format!("Failed to run {}: {}", self.viewer, e),
+        };
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let mismatch = &self.mismatches[self.index];
+        let reviewed = self.mismatches.iter().filter(|m| self.verdicts.contains_key(&m.image_name)).count();
+        let [header, body, footer] = Layout::vertical([Constraint::Length(7), Constraint::Min(3), Constraint::Length(3)])
+            .areas(frame.area());
+
+        let label = Style::default().add_modifier(Modifier::BOLD);
+        let verdict = self.verdicts.get(&mismatch.image_name).map_or("none yet", |verdict| verdict.name());
+        let lines = vec![
+            Line::from(vec![Span::styled("Image:         ", label), Span::raw(mismatch.image_name.as_str())]),
+            Line::from(vec![Span::styled("Path:          ", label), Span::raw(mismatch.image_path.display().to_string())]),
+            Line::from(vec![Span::styled("Ground truth:  ", label), Span::raw(mismatch.ground_truth.as_deref().unwrap_or("-"))]),
+            Line::from(vec![Span::styled("Prediction:    ", label), Span::raw(mismatch.prediction.as_deref().unwrap_or("-"))]),
+            Line::from(vec![Span::styled("Edit distance: ", label), Span::raw(mismatch.edit_distance.to_string()),
+                Span::styled("   Verdict: ", label), Span::raw(verdict)]),
+        ];
+        let title = format!(" Mismatch {} of {}, {} reviewed ", self.index + 1, self.mismatches.len(), reviewed);
+        frame.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title)), header);
+
+        let raw_output = Paragraph::new(mismatch.raw_output.as_str())
+            .wrap(Wrap { trim: false })
+            .scroll((self.scroll, 0))
+            .block(Block::default().borders(Borders::ALL).title(" Raw output "));
+        if self.preview {
+            let [text, image] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(body);
+            frame.render_widget(raw_output, text);
+            let block = Block::default().borders(Borders::ALL).title(" Preview ");
+            let inner = block.inner(image);
+            frame.render_widget(block, image);
+            self.draw_preview(frame, inner);
+        } else {
+            frame.render_widget(raw_output, body);
+        }
+
+        let keys = "m model wrong  l label wrong  a ambiguous  n/p next/previous  u next unreviewed  o open  i preview  j/k scroll  q quit";
+        let footer_lines = vec![Line::from(keys), Line::from(self.status.as_str())];
+        frame.render_widget(Paragraph::new(footer_lines).block(Block::default().borders(Borders::TOP)), footer);
+    }
+
+    /// Draw the image on screen into `area`, reading it again only when the
+    /// image or the size of the area changed
+    fn draw_preview(&mut self, frame: &mut Frame, area: Rect) {
+        let size = (area.width, area.height);
+        if area.width == 0 || area.height == 0 {
+            return;
+        }
+        if !self.thumbnail.as_ref().is_some_and(|thumbnail| thumbnail.index == self.index && thumbnail.size == size) {
+            let path = &self.mismatches[self.index].image_path;
+            let image = image::open(path)
+                .map(|image| image.thumbnail(u32::from(area.width), u32::from(area.height) * 2).to_rgb8())
+                .map_err(|e| format!("Can't read {}: {}", path.display(), e));
+            self.thumbnail = Some(Thumbnail { index: self.index, size, image });
+        }
+        match self.thumbnail.as_ref().map(|thumbnail| &thumbnail.image) {
+            Some(Ok(image)) => frame.render_widget(HalfBlocks(image), area),
+            Some(Err(e)) => frame.render_widget(Paragraph::new(e.as_str()).wrap(Wrap { trim: false }), area),
+            None => {}
+        }
+    }
+}
+
+/// Preview of the mismatch at `index`, drawn to fit an area of `size`
+struct Thumbnail {
+    index: usize,
+    size: (u16, u16),
+    image: Result<RgbImage, String>,
+}
+
+/// An image drawn with one upper half block per two rows of pixels, the
+/// upper in the foreground color and the lower in the background, for
+/// terminals with 24-bit color
+struct HalfBlocks<'a>(&'a RgbImage);
+
+impl Widget for HalfBlocks<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let color = |x: u32, y: u32| {
+            self.0.get_pixel_checked(x, y).map(|pixel| Color::Rgb(pixel[0], pixel[1], pixel[2]))
+        };
+        for row in 0..area.height.min(self.0.height().div_ceil(2) as u16) {
+            for column in 0..area.width.min(self.0.width() as u16) {
+                let (x, y) = (u32::from(column), u32::from(row) * 2);
+                let Some(cell) = buf.cell_mut((area.x + column, area.y + row)) else {
+                    continue;
+                };
+                cell.set_char('▀');
+                if let Some(top) = color(x, y) {
+                    cell.set_fg(top);
+                }
+                cell.set_bg(color(x, y + 1).unwrap_or(Color::Reset));
+            }
+        }
+    }
+}
+
+/// Default program the images are opened with
+pub fn default_viewer() -> &'static str {
+    if cfg!(target_os = "macos") { "open" } else { "xdg-open" }
+}
+
+/// `VERDICTS_FILE_NAME` next to a mismatches file
+pub fn verdicts_path(mismatches_path: &Path) -> PathBuf {
+    mismatches_path.with_file_name(VERDICTS_FILE_NAME)
+}
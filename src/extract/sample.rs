@@ -0,0 +1,292 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use super::breakdown::BucketBounds;
+use super::metrics;
+
+/// Default sample manifest name, written to the working directory
+pub const SAMPLE_MANIFEST_FILE_NAME: &str = "sample_manifest.json";
+
+/// The images picked for a run, so the same sample can be processed again
+/// when comparing models or prompts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SampleManifest {
+    pub dataset: PathBuf,
+    pub seed: u64,
+    pub image_names: Vec<String>,
+    /// How the sample was spread over strata; absent for a uniform sample
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strata: Option<Strata>,
+}
+
+/// Property the labeled images are split by for a stratified sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stratify {
+    /// Ground truth characters, in the buckets of `--length-buckets`
+    CharCount,
+}
+
+impl Stratify {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stratify::CharCount => "char-count",
+        }
+    }
+}
+
+/// Composition of a stratified sample
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Strata {
+    pub by: Stratify,
+    /// Each stratum given the same share of the sample rather than its
+    /// share of the dataset
+    pub equal: bool,
+    pub strata: Vec<Stratum>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stratum {
+    pub bucket: String,
+    /// Images of the dataset in the stratum
+    pub available: usize,
+    pub sampled: usize,
+}
+
+impl Strata {
+    /// How the sample was stratified and what each stratum gave, for the log
+    pub fn describe(&self) -> String {
+        format!("{}{}: {}", self.by.name(), if self.equal { ", equal strata" } else { "" },
+            self.strata.iter()
+                .map(|stratum| format!("{} {} of {}", stratum.bucket, stratum.sampled, stratum.available))
+                .collect::<Vec<_>>().join(", "))
+    }
+}
+
+impl SampleManifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open sample manifest: {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse sample manifest: {}", path.display()))
+    }
+
+    /// Write the manifest atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("json.tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create sample manifest: {}", tmp_path.display()))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)
+            .context("Failed to write sample manifest")?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to save sample manifest: {}", path.display()))?;
+        Ok(())
+    }
+
+    /// The items of the sample, those `name` finds in its image names, in
+    /// the order of the manifest
+    pub fn select<'a, T>(&self, items: &'a [T], name: impl Fn(&T) -> &str) -> Vec<&'a T> {
+        let by_name: HashMap<&str, &T> = items.iter().map(|item| (name(item), item)).collect();
+        self.image_names.iter().filter_map(|image_name| by_name.get(image_name.as_str()).copied()).collect()
+    }
+}
+
+/// Short identity of the set of images a run processed, in any order, so
+/// metrics of two runs can be checked to cover the same sample
+pub fn sample_id<'a>(image_names: impl IntoIterator<Item = &'a str>) -> String {
+    let mut names: Vec<&str> = image_names.into_iter().collect();
+    names.sort_unstable();
+    let mut hasher = Sha256::new();
+    for name in names {
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())[..12].to_string()
+}
+
+/// Pick `size` items uniformly at random. The same items, seed and size
+/// always give the same sample in the same order.
+pub fn sample<T>(items: &[T], size: usize, seed: u64) -> Vec<&T> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut shuffled: Vec<&T> = items.iter().collect();
+    shuffled.shuffle(&mut rng);
+    shuffled.truncate(size);
+    shuffled
+}
+
+/// Pick `size` items spread over `strata`, given as bucket name and items,
+/// in proportion to their sizes or with `equal` the same number from each;
+/// a stratum too small for its share gives the rest to the others. The
+/// items of each stratum are drawn uniformly and the sample is shuffled, so
+/// the same strata, seed and size always give the same sample in the same
+/// order.
+pub fn stratified<T>(strata: Vec<(String, Vec<&T>)>, size: usize, seed: u64, equal: bool) -> (Vec<&T>, Vec<Stratum>) {
+    let available: Vec<usize> = strata.iter().map(|(_, items)| items.len()).collect();
+    let shares = if equal { equal_shares(&available, size) } else { proportional_shares(&available, size) };
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut sampled = Vec::new();
+    let mut composition = Vec::new();
+    for ((bucket, mut items), share) in strata.into_iter().zip(shares) {
+        composition.push(Stratum { bucket, available: items.len(), sampled: share });
+        items.shuffle(&mut rng);
+        sampled.extend(items.into_iter().take(share));
+    }
+    sampled.shuffle(&mut rng);
+    (sampled, composition)
+}
+
+/// Sample `size` of the labeled items, those `truth` gives ground truth
+/// characters for, spread over the strata of `by` as [`stratified`] does;
+/// unlabeled items and empty strata are left out
+pub fn stratified_by<'a, T>(
+    items: &'a [T],
+    truth: impl Fn(&T) -> Option<&str>,
+    by: Stratify,
+    buckets: &BucketBounds,
+    size: usize,
+    seed: u64,
+    equal: bool,
+) -> (Vec<&'a T>, Strata) {
+    let mut strata: Vec<(String, Vec<&T>)> = buckets.length_names().into_iter()
+        .map(|bucket| (bucket, Vec::new()))
+        .collect();
+    for item in items {
+        let Some(truth) = truth(item).map(metrics::comparable).filter(|truth| !truth.is_empty()) else {
+            continue;
+        };
+        let index = match by {
+            Stratify::CharCount => buckets.length_bucket(truth.chars().count()),
+        };
+        strata[index].1.push(item);
+    }
+    strata.retain(|(_, items)| !items.is_empty());
+    let (sampled, composition) = stratified(strata, size, seed, equal);
+    (sampled, Strata { by, equal, strata: composition })
+}
+
+/// Shares of `size` in proportion to `available`, the places left over by
+/// rounding down going to the largest remainders
+fn proportional_shares(available: &[usize], size: usize) -> Vec<usize> {
+    let total: usize = available.iter().sum();
+    if size >= total {
+        return available.to_vec();
+    }
+    let mut shares: Vec<usize> = available.iter().map(|&n| n * size / total).collect();
+    let mut by_remainder: Vec<usize> = (0..available.len()).collect();
+    by_remainder.sort_by_key(|&i| std::cmp::Reverse(available[i] * size % total));
+    let left = size - shares.iter().sum::<usize>();
+    for &i in by_remainder.iter().take(left) {
+        shares[i] += 1;
+    }
+    shares
+}
+
+/// The same share of `size` for each stratum, one place at a time so that
+/// the places a small stratum can't fill go to the others
+fn equal_shares(available: &[usize], size: usize) -> Vec<usize> {
+    let mut shares = vec![0; available.len()];
+    let mut left = size.min(available.iter().sum());
+    while left > 0 {
+        for (share, &n) in shares.iter_mut().zip(available) {
+            if left > 0 && *share < n {
+                *share += 1;
+                left -= 1;
+            }
+        }
+    }
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Vec<String> {
+        (1..=40).map(|n| format!("{:08}.jpg", 40_000_000 + n)).collect()
+    }
+
+    #[test]
+    fn a_fixed_seed_draws_the_same_sample() {
+        let names = names();
+        let first = sample(&names, 10, 42);
+        assert_eq!(first, sample(&names, 10, 42));
+        assert_ne!(first, sample(&names, 10, 43));
+
+        let mut distinct = first.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), 10);
+    }
+
+    #[test]
+    fn a_sample_larger_than_the_dataset_is_all_of_it() {
+        let names = names();
+        assert_eq!(sample(&names, 100, 7).len(), names.len());
+    }
+
+    #[test]
+    fn a_fixed_seed_draws_the_same_stratified_sample() {
+        let names = names();
+        let strata = || vec![
+            ("short".to_string(), names[..30].iter().collect()),
+            ("long".to_string(), names[30..].iter().collect()),
+        ];
+        let (first, composition) = stratified(strata(), 8, 42, false);
+        assert_eq!(first, stratified(strata(), 8, 42, false).0);
+        assert_eq!(composition.iter().map(|s| s.sampled).collect::<Vec<_>>(), vec![6, 2]);
+
+        let (_, equal) = stratified(strata(), 8, 42, true);
+        assert_eq!(equal.iter().map(|s| s.sampled).collect::<Vec<_>>(), vec![4, 4]);
+    }
+
+    #[test]
+    fn the_sample_id_ignores_order() {
+        let names = names();
+        let forward = sample_id(names.iter().map(String::as_str));
+        let backward = sample_id(names.iter().rev().map(String::as_str));
+        assert_eq!(forward, backward);
+        assert_eq!(forward.len(), 12);
+        assert_ne!(forward, sample_id(names[1..].iter().map(String::as_str)));
+    }
+
+    #[test]
+    fn strata_by_character_count_leave_out_unlabeled_items() {
+        let buckets = BucketBounds {
+            length_buckets: vec![1, 3],
+            size_buckets_kb: Vec::new(),
+            dimension_buckets: Vec::new(),
+            min_bucket_size: 0,
+        };
+        let items = [("a", Some("天")), ("b", Some("天 福")), ("c", Some("天大福")), ("d", None), ("e", Some(" "))];
+
+        let (sampled, strata) = stratified_by(&items, |item| item.1, Stratify::CharCount, &buckets, 10, 42, false);
+        let mut names: Vec<&str> = sampled.iter().map(|item| item.0).collect();
+        names.sort();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(strata.describe(), "char-count: 1-2 2 of 2, 3+ 1 of 1");
+    }
+
+    #[test]
+    fn a_manifest_selects_its_images_in_its_order() {
+        let names = names();
+        let manifest = SampleManifest {
+            dataset: PathBuf::from("cleaned_data.json"),
+            seed: 42,
+            image_names: vec![names[5].clone(), "gone.jpg".to_string(), names[2].clone()],
+            strata: None,
+        };
+        assert_eq!(manifest.select(&names, String::as_str), [&names[5], &names[2]]);
+    }
+}
@@ -0,0 +1,77 @@
+use serde::{Deserialize, Serialize};
+
+/// Writing system of a predicted `chinese_character`. Models that can't read
+/// a mark tend to answer in pinyin, kana or English rather than say so.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Script {
+    /// Chinese characters only
+    Cjk,
+    /// Latin letters only, pinyin with tone marks included
+    Latin,
+    /// Chinese characters with letters of another script
+    Mixed,
+    /// No Chinese characters, and letters other than Latin ones: kana,
+    /// hangul, Cyrillic, or no letters at all
+    Other,
+}
+
+impl Script {
+    pub fn name(self) -> &'static str {
+        match self {
+            Script::Cjk => "cjk",
+            Script::Latin => "latin",
+            Script::Mixed => "mixed",
+            Script::Other => "other",
+        }
+    }
+}
+
+/// Script of `text` by the Unicode ranges of its letters; digits,
+/// punctuation, symbols and whitespace don't count. `None` for empty text,
+/// which is no prediction at all.
+pub fn classify(text: &str) -> Option<Script> {
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    let (mut han, mut latin, mut other) = (false, false, false);
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        if is_han(c) {
+            han = true;
+        } else if is_latin(c) {
+            latin = true;
+        } else {
+            other = true;
+        }
+    }
+    Some(match (han, latin, other) {
+        (true, false, false) => Script::Cjk,
+        (true, _, _) => Script::Mixed,
+        (false, true, false) => Script::Latin,
+        (false, _, _) => Script::Other,
+    })
+}
+
+/// Han ideographs: the unified blocks and their extensions, the
+/// compatibility blocks, the radicals and the ideographic zero
+fn is_han(c: char) -> bool {
+    matches!(c,
+        '\u{3007}'
+        | '\u{2E80}'..='\u{2FDF}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{20000}'..='\u{2FA1F}'
+        | '\u{30000}'..='\u{323AF}')
+}
+
+/// Latin letters, accented and full-width ones included
+fn is_latin(c: char) -> bool {
+    matches!(c,
+        'A'..='Z' | 'a'..='z'
+        | '\u{00C0}'..='\u{024F}'
+        | '\u{1E00}'..='\u{1EFF}'
+        | '\u{FF21}'..='\u{FF3A}'
+        | '\u{FF41}'..='\u{FF5A}')
+}
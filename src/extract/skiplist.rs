@@ -0,0 +1,76 @@
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use super::review::Verdict;
+
+/// Images left out of a run and its metrics with `--skip-list`: one image
+/// name per line, `#` starting a comment to the end of the line
+#[derive(Debug, Default)]
+pub struct SkipList {
+    names: HashSet<String>,
+}
+
+impl SkipList {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read skip list: {}", path.display()))?;
+        Ok(SkipList { names: parse(&text).map(String::from).collect() })
+    }
+
+    pub fn names(&self) -> &HashSet<String> {
+        &self.names
+    }
+
+    pub fn contains(&self, image_name: &str) -> bool {
+        self.names.contains(image_name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+/// Image names of a skip list's text, without comments and blank lines
+fn parse(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(|line| line.split_once('#').map_or(line, |(name, _)| name).trim())
+        .filter(|name| !name.is_empty())
+}
+
+/// Append to the skip list at `path`, creating it if needed, the images of
+/// `verdicts` whose label was judged wrong or ambiguous and that it doesn't
+/// list yet, each with its verdict as a comment. Returns the number added.
+pub fn export_verdicts(verdicts: &HashMap<String, Verdict>, path: &Path) -> Result<usize> {
+    let existing = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read skip list: {}", path.display())),
+    };
+    let listed: HashSet<&str> = parse(&existing).collect();
+    // Sorted so the list reads the same whatever the order of the review
+    let added: BTreeMap<&str, Verdict> = verdicts.iter()
+        .filter(|(name, verdict)| matches!(verdict, Verdict::LabelWrong | Verdict::Ambiguous) && !listed.contains(name.as_str()))
+        .map(|(name, verdict)| (name.as_str(), *verdict))
+        .collect();
+    if added.is_empty() {
+        return Ok(0);
+    }
+    // A last line without its newline must not run into the first added
+    let mut lines = if existing.is_empty() || existing.ends_with('\n') { String::new() } else { "\n".to_string() };
+    lines += &added.iter().map(|(name, verdict)| format!("{}  # {}\n", name, verdict.name())).collect::<String>();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open skip list: {}", path.display()))?;
+    file.write_all(lines.as_bytes())
+        .with_context(|| format!("Failed to write skip list: {}", path.display()))?;
+    Ok(added.len())
+}
@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use super::progress;
+
+/// Snapshots of a run every `--report-interval`, written next to the
+/// results file
+pub const PROGRESS_METRICS_FILE_NAME: &str = "progress_metrics.jsonl";
+
+/// The state of a run at one moment, as logged and appended to the
+/// progress metrics file. Built from counts kept up to date as results
+/// arrive, so taking one costs the same at any point of the run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// When the snapshot was taken, RFC 3339
+    pub at: String,
+    pub elapsed_secs: u64,
+    /// Images completed by this run, and the images it was given
+    pub processed: usize,
+    pub total: usize,
+    /// Labeled images scored so far, resumed ones included, with the share
+    /// that matched exactly and the mean CER of the answered ones
+    pub scored: usize,
+    pub accuracy: Option<f64>,
+    pub mean_cer: Option<f64>,
+    pub errors: usize,
+    /// Failed images over the images scored
+    pub error_rate: Option<f64>,
+    /// Failed images by failure class, see `retry::failure_class`
+    pub errors_by_class: BTreeMap<String, usize>,
+    /// Images per minute over the latest completions
+    pub images_per_minute: Option<f64>,
+    pub eta_secs: Option<u64>,
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |value: Option<f64>| value.map_or("-".to_string(), |value| format!("{:.1}%", value * 100.0));
+        write!(f, "After {}: {}/{} images, accuracy {}, mean CER {}, {} errors ({})",
+            progress::format_duration(Duration::from_secs(self.elapsed_secs)), self.processed, self.total,
+            percent(self.accuracy), self.mean_cer.map_or("-".to_string(), |cer| format!("{:.3}", cer)),
+            self.errors, percent(self.error_rate))?;
+        if !self.errors_by_class.is_empty() {
+            let classes: Vec<String> = self.errors_by_class.iter().map(|(class, count)| format!("{} {}", class, count)).collect();
+            write!(f, " [{}]", classes.join(", "))?;
+        }
+        write!(f, ", {} images/minute, ETA {}",
+            self.images_per_minute.map_or("-".to_string(), |rate| format!("{:.1}", rate)),
+            self.eta_secs.map_or("-".to_string(), |secs| progress::format_duration(Duration::from_secs(secs))))
+    }
+}
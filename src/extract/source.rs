@@ -0,0 +1,287 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+use crate::images::checksum;
+
+/// Where the images of a run are read from: files on disk, or the members
+/// of an `--images-archive`. Every read of an image goes through it, so
+/// checking, hashing, caching and encoding work the same for both.
+#[derive(Debug, Clone, Default)]
+pub enum ImageSource {
+    #[default]
+    Files,
+    Archive(Arc<ArchiveImages>),
+}
+
+/// A reader over one image, for the image crate
+pub trait ImageRead: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ImageRead for T {}
+
+impl ImageSource {
+    /// The members of `archive`, indexed off the runtime's threads, or the
+    /// files on disk without one
+    pub async fn load(archive: Option<&Path>) -> Result<Self> {
+        let Some(path) = archive else {
+            return Ok(ImageSource::Files);
+        };
+        if !path.is_file() {
+            anyhow::bail!("Images archive not found: {}", path.display());
+        }
+        info!("Indexing the images of {}", path.display());
+        let (path, spool_dir) = (path.to_path_buf(), std::env::temp_dir());
+        let archive = tokio::task::spawn_blocking(move || ArchiveImages::open(&path, &spool_dir))
+            .await
+            .context("Archive indexing task panicked")??;
+        info!("Found {} files in {}", archive.len(), archive.path().display());
+        Ok(ImageSource::Archive(Arc::new(archive)))
+    }
+
+    /// Path standing for an image in this source: under the archive's own
+    /// path for an archive member
+    pub fn image_path(&self, images_dir: &Path, image_name: &str) -> PathBuf {
+        match self {
+            ImageSource::Files => images_dir.join(image_name),
+            ImageSource::Archive(archive) => archive.path.join(image_name),
+        }
+    }
+
+    pub fn exists(&self, path: &Path) -> bool {
+        match self {
+            ImageSource::Files => path.is_file(),
+            ImageSource::Archive(archive) => archive.member(path).is_some(),
+        }
+    }
+
+    /// Size of an image in bytes, without reading it
+    pub fn size(&self, path: &Path) -> io::Result<u64> {
+        match self {
+            ImageSource::Files => fs::metadata(path).map(|metadata| metadata.len()),
+            ImageSource::Archive(archive) => archive.member(path).map(|member| member.size).ok_or_else(|| not_found(path)),
+        }
+    }
+
+    pub fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self {
+            ImageSource::Files => fs::read(path),
+            ImageSource::Archive(archive) => archive.read(path),
+        }
+    }
+
+    /// Open an image for reading from the start. An archive member is read
+    /// whole into memory.
+    pub fn open(&self, path: &Path) -> io::Result<Box<dyn ImageRead>> {
+        match self {
+            ImageSource::Files => Ok(Box::new(BufReader::new(File::open(path)?))),
+            ImageSource::Archive(archive) => Ok(Box::new(Cursor::new(archive.read(path)?))),
+        }
+    }
+
+    /// SHA-256 of an image's contents, as `checksum::hash_file` gives it
+    pub fn hash(&self, path: &Path) -> Result<String> {
+        match self {
+            ImageSource::Files => checksum::hash_file(path).map(|(_, hash)| hash),
+            ImageSource::Archive(archive) => {
+                let bytes = archive.read(path).with_context(|| format!("Failed to read file: {}", path.display()))?;
+                Ok(format!("{:x}", Sha256::digest(&bytes)))
+            }
+        }
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("not in the archive: {}", path.display()))
+}
+
+/// Archive formats `--images-archive` reads, picked from the file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    fn from_path(path: &Path) -> Result<Self> {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_lowercase()).unwrap_or_default();
+        if name.ends_with(".zip") {
+            Ok(ArchiveFormat::Zip)
+        } else if name.ends_with(".tar") {
+            Ok(ArchiveFormat::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(ArchiveFormat::TarGz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Ok(ArchiveFormat::TarZst)
+        } else {
+            anyhow::bail!("Unsupported archive type (expected .zip, .tar, .tar.gz or .tar.zst): {}", path.display())
+        }
+    }
+}
+
+/// An archive of images indexed at startup, whose members are read on
+/// demand. Members are found by their name in the archive, or by their
+/// file name alone when no other member shares it.
+#[derive(Debug)]
+pub struct ArchiveImages {
+    path: PathBuf,
+    members: HashMap<String, Member>,
+    /// Member name of each file name that is unique in the archive
+    file_names: HashMap<String, Option<String>>,
+    storage: Storage,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Member {
+    /// Index of a zip member, or offset of a tar member's data
+    position: u64,
+    size: u64,
+}
+
+/// Handles onto the archive, taken by one read at a time since reading
+/// moves them; as many are opened as reads run at once
+#[derive(Debug)]
+enum Storage {
+    /// Tar members are byte ranges of the file, of a compressed tar
+    /// unpacked once into `spool`
+    Tar { file: PathBuf, spool: Option<PathBuf>, handles: Mutex<Vec<File>> },
+    Zip { handles: Mutex<Vec<zip::ZipArchive<BufReader<File>>>> },
+}
+
+impl ArchiveImages {
+    /// Index the members of an archive. A compressed tar can't be read from
+    /// the middle, so it is first unpacked into one plain tar in `spool_dir`,
+    /// removed when the run is over.
+    pub fn open(path: &Path, spool_dir: &Path) -> Result<Self> {
+        let format = ArchiveFormat::from_path(path)?;
+        let open = || File::open(path).with_context(|| format!("Failed to open archive: {}", path.display()));
+        let (members, storage) = match format {
+            ArchiveFormat::Zip => {
+                let mut archive = zip::ZipArchive::new(BufReader::new(open()?))
+                    .with_context(|| format!("Failed to read archive: {}", path.display()))?;
+                let mut members = HashMap::new();
+                for index in 0..archive.len() {
+                    let member = archive.by_index(index)
+                        .with_context(|| format!("Failed to read archive: {}", path.display()))?;
+                    if member.is_file() {
+                        members.insert(member.name().to_string(), Member { position: index as u64, size: member.size() });
+                    }
+                }
+                (members, Storage::Zip { handles: Mutex::new(vec![archive]) })
+            }
+            ArchiveFormat::Tar => {
+                let members = index_tar(open()?)?;
+                (members, Storage::Tar { file: path.to_path_buf(), spool: None, handles: Mutex::default() })
+            }
+            ArchiveFormat::TarGz | ArchiveFormat::TarZst => {
+                let spool = spool_dir.join(format!("tm-query-{}-{}.tar", std::process::id(),
+                    path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()));
+                info!("Unpacking {} into {} to read its members", path.display(), spool.display());
+                let mut decoder: Box<dyn Read> = match format {
+                    ArchiveFormat::TarGz => Box::new(flate2::read::GzDecoder::new(BufReader::new(open()?))),
+                    _ => Box::new(zstd::Decoder::new(open()?).context("Failed to start zstd decoder")?),
+                };
+                let mut unpacked = File::create(&spool)
+                    .with_context(|| format!("Failed to create {}", spool.display()))?;
+                let unpacking = io::copy(&mut decoder, &mut unpacked)
+                    .with_context(|| format!("Failed to unpack archive: {}", path.display()));
+                let members = unpacking.and_then(|_| index_tar(File::open(&spool)?));
+                if members.is_err() {
+                    let _ = fs::remove_file(&spool);
+                }
+                (members?, Storage::Tar { file: spool.clone(), spool: Some(spool), handles: Mutex::default() })
+            }
+        };
+
+        let mut file_names: HashMap<String, Option<String>> = HashMap::new();
+        for name in members.keys() {
+            let file_name = name.rsplit('/').next().unwrap_or(name).to_string();
+            file_names.entry(file_name)
+                .and_modify(|unique| *unique = None)
+                .or_insert_with(|| Some(name.clone()));
+        }
+        Ok(ArchiveImages { path: path.to_path_buf(), members, file_names, storage })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// The member an image path of `ImageSource::image_path` stands for
+    fn member(&self, path: &Path) -> Option<Member> {
+        let name = path.strip_prefix(&self.path).ok()?.to_string_lossy().replace('\\', "/");
+        self.members.get(&name).or_else(|| {
+            let unique = self.file_names.get(&name)?.as_ref()?;
+            self.members.get(unique)
+        }).copied()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let member = self.member(path).ok_or_else(|| not_found(path))?;
+        match &self.storage {
+            Storage::Tar { file, handles, .. } => {
+                let mut handle = match handles.lock().ok().and_then(|mut handles| handles.pop()) {
+                    Some(handle) => handle,
+                    None => File::open(file)?,
+                };
+                handle.seek(SeekFrom::Start(member.position))?;
+                let mut bytes = Vec::with_capacity(member.size as usize);
+                (&mut handle).take(member.size).read_to_end(&mut bytes)?;
+                if let Ok(mut handles) = handles.lock() {
+                    handles.push(handle);
+                }
+                Ok(bytes)
+            }
+            Storage::Zip { handles } => {
+                let mut archive = match handles.lock().ok().and_then(|mut handles| handles.pop()) {
+                    Some(archive) => archive,
+                    None => zip::ZipArchive::new(BufReader::new(File::open(&self.path)?)).map_err(io::Error::other)?,
+                };
+                let mut bytes = Vec::with_capacity(member.size as usize);
+                archive.by_index(member.position as usize).map_err(io::Error::other)?.read_to_end(&mut bytes)?;
+                if let Ok(mut handles) = handles.lock() {
+                    handles.push(archive);
+                }
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl Drop for ArchiveImages {
+    fn drop(&mut self) {
+        if let Storage::Tar { spool: Some(spool), .. } = &self.storage {
+            let _ = fs::remove_file(spool);
+        }
+    }
+}
+
+/// Data offset and size of each regular file of a plain tar
+fn index_tar(file: File) -> Result<HashMap<String, Member>> {
+    let mut archive = tar::Archive::new(BufReader::new(file));
+    let mut members = HashMap::new();
+    for entry in archive.entries().context("Failed to read tar archive")? {
+        let entry = entry.context("Failed to read tar entry")?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path().context("Invalid tar entry path")?.to_string_lossy().into_owned();
+        let name = name.strip_prefix("./").map(str::to_string).unwrap_or(name);
+        members.insert(name, Member { position: entry.raw_file_position(), size: entry.size() });
+    }
+    Ok(members)
+}
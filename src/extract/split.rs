@@ -0,0 +1,238 @@
+use anyhow::{Context, Result};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::sample::Stratify;
+
+/// Default directory of the split files
+pub const SPLITS_DIR: &str = "splits";
+
+/// Name of the split manifest, written next to the split files
+pub const SPLIT_MANIFEST_FILE_NAME: &str = "split_manifest.json";
+
+/// Names of the splits, in the order of `Ratios`; each is written to
+/// `<name>.json`
+pub const SPLIT_NAMES: [&str; 3] = ["train", "val", "test"];
+
+/// Shares of the images going to train, validation and test, given as
+/// `train,val,test` with `--ratios` and scaled to add up to 1
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Ratios {
+    pub train: f64,
+    pub val: f64,
+    pub test: f64,
+}
+
+impl Default for Ratios {
+    fn default() -> Self {
+        Ratios { train: 0.8, val: 0.1, test: 0.1 }
+    }
+}
+
+impl Ratios {
+    fn shares(&self) -> [f64; 3] {
+        [self.train, self.val, self.test]
+    }
+}
+
+impl FromStr for Ratios {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let shares: Vec<f64> = s.split(',')
+            .map(|share| share.trim().parse::<f64>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("expected train,val,test as three numbers, got {}", s))?;
+        let [train, val, test] = shares[..] else {
+            return Err(format!("expected train,val,test as three numbers, got {}", s));
+        };
+        let total = train + val + test;
+        if shares.iter().any(|share| *share < 0.0 || !share.is_finite()) || total <= 0.0 {
+            return Err(format!("ratios must be non-negative and not all zero, got {}", s));
+        }
+        Ok(Ratios { train: train / total, val: val / total, test: test / total })
+    }
+}
+
+impl fmt::Display for Ratios {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{}", self.train, self.val, self.test)
+    }
+}
+
+/// How a labeled dataset was split, so the split can be made again or
+/// checked later
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SplitManifest {
+    pub dataset: PathBuf,
+    pub seed: u64,
+    pub ratios: Ratios,
+    /// Property each split was balanced over; absent for a plain shuffle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stratify_by: Option<Stratify>,
+    pub created_at: String,
+    /// Labeled images whose file was found, all of them in one split
+    pub images: usize,
+    /// Entries left out for having no ground truth characters
+    pub unlabeled: usize,
+    /// Labeled entries left out for their image not being on disk
+    pub missing: usize,
+    /// Sets of identical images, each kept together in one split
+    pub duplicate_groups: usize,
+    pub splits: Vec<SplitCount>,
+}
+
+/// One split file and what went into it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SplitCount {
+    pub name: String,
+    pub file: PathBuf,
+    pub images: usize,
+    /// Identity of the split's image names, as `sample_id` of a run
+    pub sample_id: String,
+    /// Images of each stratum, with `stratify_by`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub strata: Vec<StratumCount>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StratumCount {
+    pub bucket: String,
+    pub images: usize,
+}
+
+impl SplitManifest {
+    /// Write the manifest atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        save_json(path, self)
+            .with_context(|| format!("Failed to save split manifest: {}", path.display()))
+    }
+}
+
+/// Write the dataset entries of one split as a JSON array, atomically
+pub fn save_split(path: &Path, entries: &[&Value]) -> Result<()> {
+    save_json(path, &entries)
+        .with_context(|| format!("Failed to save split: {}", path.display()))
+}
+
+fn save_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    let file = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(file), value)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Split index, into `SPLIT_NAMES`, of each of `groups`, given as the
+/// stratum and number of images of a set of identical images. The groups of
+/// each stratum are shuffled and laid end to end, and a group goes to the
+/// split its middle image falls in by `ratios`, so every split gets close
+/// to its share of each stratum and a group is never divided. The same
+/// groups, ratios and seed always give the same split.
+pub fn assign(groups: &[(usize, usize)], ratios: &Ratios, seed: u64) -> Vec<usize> {
+    let mut strata: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (index, &(stratum, _)) in groups.iter().enumerate() {
+        strata.entry(stratum).or_default().push(index);
+    }
+    let shares = ratios.shares();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut splits = vec![0; groups.len()];
+    for members in strata.values_mut() {
+        members.shuffle(&mut rng);
+        let total: usize = members.iter().map(|&i| groups[i].1).sum();
+        let mut before = 0;
+        for &i in members.iter() {
+            let middle = (before as f64 + groups[i].1 as f64 / 2.0) / total as f64;
+            let mut bound = 0.0;
+            splits[i] = shares.iter()
+                .position(|share| {
+                    bound += share;
+                    middle < bound
+                })
+                .unwrap_or_else(|| shares.iter().rposition(|share| *share > 0.0).unwrap_or_default());
+            before += groups[i].1;
+        }
+    }
+    splits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Images of each split, in each stratum, with the splits of `assigned`
+    fn sizes(groups: &[(usize, usize)], assigned: &[usize], stratum: usize) -> [usize; 3] {
+        let mut sizes = [0; 3];
+        for (&(group_stratum, images), &split) in groups.iter().zip(assigned) {
+            if group_stratum == stratum {
+                sizes[split] += images;
+            }
+        }
+        sizes
+    }
+
+    /// The groups of each split, which together must be every group once
+    fn members(assigned: &[usize]) -> [Vec<usize>; 3] {
+        let mut members: [Vec<usize>; 3] = Default::default();
+        for (group, &split) in assigned.iter().enumerate() {
+            members[split].push(group);
+        }
+        let mut union: Vec<usize> = members.iter().flatten().copied().collect();
+        union.sort();
+        assert_eq!(union, (0..assigned.len()).collect::<Vec<_>>());
+        members
+    }
+
+    #[test]
+    fn splits_are_disjoint_cover_the_input_and_follow_the_ratios() {
+        let groups = vec![(0, 1); 1000];
+        let ratios = Ratios::default();
+        let assigned = assign(&groups, &ratios, 42);
+        let members = members(&assigned);
+        assert!(members.iter().all(|split| split.windows(2).all(|pair| pair[0] < pair[1])));
+        assert_eq!(members.iter().map(Vec::len).collect::<Vec<_>>(), [800, 100, 100]);
+
+        // The seed alone decides the split
+        assert_eq!(assign(&groups, &ratios, 42), assigned);
+        assert_ne!(assign(&groups, &ratios, 7), assigned);
+
+        // A split with no share gets nothing
+        let ratios: Ratios = "3,0,1".parse().unwrap();
+        assert_eq!(sizes(&groups, &assign(&groups, &ratios, 42), 0), [750, 0, 250]);
+    }
+
+    #[test]
+    fn stratified_splits_follow_the_ratios_in_each_stratum() {
+        // Strata of 600, 300 and 100 images, some in sets of three identical ones
+        let mut groups = Vec::new();
+        for (stratum, images) in [(0, 600), (1, 300), (2, 100)] {
+            groups.extend(std::iter::repeat_n((stratum, 3), 20));
+            groups.extend(std::iter::repeat_n((stratum, 1), images - 60));
+        }
+        let ratios: Ratios = "0.6,0.2,0.2".parse().unwrap();
+        let assigned = assign(&groups, &ratios, 42);
+        members(&assigned);
+
+        for (stratum, images) in [(0, 600), (1, 300), (2, 100)] {
+            let sizes = sizes(&groups, &assigned, stratum);
+            assert_eq!(sizes.iter().sum::<usize>(), images);
+            for (size, share) in sizes.iter().zip(ratios.shares()) {
+                // Off by at most a set of identical images at each bound
+                let expected = share * images as f64;
+                assert!((*size as f64 - expected).abs() <= 3.0, "stratum {}: {:?}", stratum, sizes);
+            }
+        }
+    }
+}
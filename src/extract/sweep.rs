@@ -0,0 +1,174 @@
+use anyhow::{Context, Result};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use super::bootstrap::Interval;
+use super::metrics::Scoreboard;
+use super::preprocess::Preprocess;
+
+/// One value of the parameter a sweep varies, `--sweep-temperature` or
+/// `--sweep-preprocess`
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Setting {
+    Temperature(f64),
+    Preprocess(Preprocess),
+}
+
+impl Setting {
+    /// Name of the swept parameter
+    pub fn parameter(self) -> &'static str {
+        match self {
+            Setting::Temperature(_) => "temperature",
+            Setting::Preprocess(_) => "preprocess",
+        }
+    }
+
+    /// Name of the directory next to `--output` the run with the setting
+    /// writes its files to
+    pub fn dir_name(self) -> String {
+        format!("{}-{}", self.parameter(), self)
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Setting::Temperature(_) => "Temperature",
+            Setting::Preprocess(_) => "Preprocess",
+        }
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Setting::Temperature(temperature) => write!(f, "{}", temperature),
+            Setting::Preprocess(preprocess) => f.write_str(preprocess.name()),
+        }
+    }
+}
+
+/// A setting as its bare value, as `best` has always been written
+fn serialize_value<S: Serializer>(setting: &Setting, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    match setting {
+        Setting::Temperature(temperature) => temperature.serialize(serializer),
+        Setting::Preprocess(preprocess) => preprocess.serialize(serializer),
+    }
+}
+
+/// Figures of one setting of a sweep
+#[derive(Debug, Clone, Serialize)]
+pub struct SettingSummary {
+    /// The setting under its parameter's name, e.g. `"temperature": 0.2`
+    #[serde(flatten)]
+    pub setting: Setting,
+    pub config_id: String,
+    pub total: usize,
+    pub exact_match: usize,
+    pub errors: usize,
+    pub accuracy: f64,
+    pub mean_cer: Option<f64>,
+    pub accuracy_interval: Option<Interval>,
+    pub estimated_cost: Option<f64>,
+}
+
+/// The runs of a sweep on one sample side by side, written under
+/// `temperature_sweep` or `preprocess_sweep` in the metrics file next to
+/// `--output`
+#[derive(Debug, Clone, Serialize)]
+pub struct Sweep {
+    /// `sample::sample_id` of the images every setting was run on
+    pub sample_id: String,
+    pub settings: Vec<SettingSummary>,
+    /// `wins[i][j]`: labeled images setting `i` matched exactly and setting
+    /// `j` didn't, among those both scored
+    pub wins: Vec<Vec<usize>>,
+    /// Setting with the highest accuracy, the lowest temperature or the
+    /// least preprocessing on a tie
+    #[serde(serialize_with = "serialize_value")]
+    pub best: Setting,
+}
+
+impl Sweep {
+    /// Compare the scoreboards of the runs, given with their settings in
+    /// the order they were swept. All must be of the same sample.
+    pub fn new(runs: &[(Setting, Scoreboard)]) -> Result<Self> {
+        let (first_setting, first) = runs.first().context("The sweep has no finished run")?;
+        if let Some((setting, other)) = runs.iter().find(|(_, scoreboard)| scoreboard.sample_id != first.sample_id) {
+            anyhow::bail!("The run with {} {} processed sample {}, not {}; the settings can't be compared",
+                setting.parameter(), setting, other.sample_id, first.sample_id);
+        }
+        let settings: Vec<SettingSummary> = runs.iter()
+            .map(|(setting, scoreboard)| SettingSummary {
+                setting: *setting,
+                config_id: scoreboard.config_id.clone(),
+                total: scoreboard.total,
+                exact_match: scoreboard.exact_match,
+                errors: scoreboard.errors,
+                accuracy: scoreboard.accuracy(),
+                mean_cer: scoreboard.mean_cer(),
+                accuracy_interval: scoreboard.confidence.as_ref().and_then(|confidence| confidence.accuracy),
+                estimated_cost: scoreboard.usage.estimated_cost,
+            })
+            .collect();
+        let wins = runs.iter()
+            .map(|(_, a)| runs.iter()
+                .map(|(_, b)| a.image_scores.iter()
+                    .filter(|(image_name, score)| {
+                        score.exact_match && b.image_scores.get(*image_name).is_some_and(|other| !other.exact_match)
+                    })
+                    .count())
+                .collect())
+            .collect();
+        let best = settings.iter()
+            .fold(None::<&SettingSummary>, |best, setting| match best {
+                Some(best) if best.accuracy > setting.accuracy
+                    || (best.accuracy == setting.accuracy && best.setting <= setting.setting) => Some(best),
+                _ => Some(setting),
+            })
+            .map_or(*first_setting, |best| best.setting);
+        Ok(Sweep { sample_id: first.sample_id.clone(), settings, wins, best })
+    }
+
+    /// Table of the settings and the win matrix
+    pub fn report(&self) -> String {
+        let title = self.best.title();
+        let mut lines = vec![
+            format!("{} sweep over {} settings on sample {}:", title, self.settings.len(), self.sample_id),
+            format!("  {:>11}  {:>8}  {:>13}  {:>8}  {:>6}  {:>10}", title, "Accuracy", "95% CI", "Mean CER", "Errors", "Cost"),
+        ];
+        for setting in &self.settings {
+            lines.push(format!("  {:>11}  {:>7.1}%  {:>13}  {:>8}  {:>6}  {:>10}",
+                setting.setting.to_string(), 100.0 * setting.accuracy,
+                setting.accuracy_interval.map_or("-".to_string(), |interval| {
+                    format!("{:.1}-{:.1}%", 100.0 * interval.low, 100.0 * interval.high)
+                }),
+                setting.mean_cer.map_or("-".to_string(), |cer| format!("{:.3}", cer)),
+                setting.errors,
+                setting.estimated_cost.map_or("-".to_string(), |cost| format!("{:.4}", cost))));
+        }
+        lines.push("Wins, images the row setting matched and the column setting didn't:".to_string());
+        lines.push(format!("  {:>11}{}", "", self.settings.iter().map(|setting| format!("  {:>9}", setting.setting.to_string())).collect::<String>()));
+        for (setting, row) in self.settings.iter().zip(&self.wins) {
+            let cells: String = row.iter().zip(&self.settings)
+                .map(|(wins, other)| if other.setting == setting.setting { format!("  {:>9}", "-") } else { format!("  {:>9}", wins) })
+                .collect();
+            lines.push(format!("  {:>11}{}", setting.setting.to_string(), cells));
+        }
+        lines.push(format!("Best accuracy with {} {}", self.best.parameter(), self.best));
+        lines.join("\n")
+    }
+
+    /// Write the sweep under `temperature_sweep` or `preprocess_sweep` to
+    /// `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut sweep_file = serde_json::Map::new();
+        sweep_file.insert(format!("{}_sweep", self.best.parameter()), serde_json::to_value(self)?);
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create metrics file: {}", path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &sweep_file)
+            .context("Failed to write metrics file")
+    }
+}
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Characters that always separate the terms of a series mark, in their
+/// ASCII and full-width forms, and line breaks
+const DELIMITERS: &[char] = &[';', '；', ',', '，', '、', '/', '／', '|', '｜', '\n', '\r'];
+
+/// Shortest piece, in characters, that spaces are taken to separate from
+/// its neighbours. Marks are often written with spaced out characters
+/// (`美 心`) or a short word set apart (`日月 明`); those stay one term.
+const MIN_SPACED_TERM_CHARS: usize = 2;
+
+/// Split a `chineseCharacter` value into its terms. Delimiters always
+/// separate terms; whitespace only does when every piece it separates has at
+/// least two characters, e.g. `美心 食品` but not `美 心`. Terms keep their
+/// inner spacing, and empty ones are dropped.
+pub fn split(text: &str) -> Vec<&str> {
+    let mut terms = Vec::new();
+    for chunk in text.split(DELIMITERS).map(str::trim).filter(|chunk| !chunk.is_empty()) {
+        let pieces: Vec<&str> = chunk.split_whitespace().collect();
+        if pieces.len() > 1 && pieces.iter().all(|piece| piece.chars().count() >= MIN_SPACED_TERM_CHARS) {
+            terms.extend(pieces);
+        } else {
+            terms.push(chunk);
+        }
+    }
+    terms
+}
+
+/// Term-level agreement of a prediction with the ground truth, comparing
+/// the terms as multisets so their order doesn't matter
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TermScore {
+    /// Share of the predicted terms that are in the ground truth
+    pub precision: f64,
+    /// Share of the ground truth terms that were predicted
+    pub recall: f64,
+    pub f1: f64,
+}
+
+impl TermScore {
+    /// Whether the prediction has exactly the ground truth's terms
+    pub fn is_complete(&self) -> bool {
+        self.f1 == 1.0
+    }
+}
+
+/// Score the terms of a prediction against the ground truth, each term
+/// passed through `normalize` first. Missing values have no terms; both
+/// without terms scores 1, one without terms scores 0.
+pub fn score(truth: Option<&str>, predicted: Option<&str>, normalize: impl Fn(&str) -> String) -> TermScore {
+    let terms = |value: Option<&str>| -> Vec<String> {
+        split(value.unwrap_or_default()).into_iter()
+            .map(&normalize)
+            .filter(|term| !term.is_empty())
+            .collect()
+    };
+    let truth = terms(truth);
+    let predicted = terms(predicted);
+    if truth.is_empty() && predicted.is_empty() {
+        return TermScore { precision: 1.0, recall: 1.0, f1: 1.0 };
+    }
+
+    let mut remaining: HashMap<&str, usize> = HashMap::new();
+    for term in &truth {
+        *remaining.entry(term).or_default() += 1;
+    }
+    let mut found = 0;
+    for term in &predicted {
+        if let Some(count) = remaining.get_mut(term.as_str())
+            && *count > 0
+        {
+            *count -= 1;
+            found += 1;
+        }
+    }
+
+    let share = |total: usize| if total == 0 { 0.0 } else { found as f64 / total as f64 };
+    let precision = share(predicted.len());
+    let recall = share(truth.len());
+    let f1 = if precision + recall == 0.0 { 0.0 } else { 2.0 * precision * recall / (precision + recall) };
+    TermScore { precision, recall, f1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_values_as_they_appear_in_the_dataset() {
+        let cases: &[(&str, &[&str])] = &[
+            ("美心", &["美心"]),
+            ("美 心", &["美 心"]),
+            ("日月 明", &["日月 明"]),
+            ("美心 食品", &["美心", "食品"]),
+            ("美心；美心月饼", &["美心", "美心月饼"]),
+            ("大白兔, 大白兔奶糖", &["大白兔", "大白兔奶糖"]),
+            ("同仁堂、北京同仁堂", &["同仁堂", "北京同仁堂"]),
+            ("李錦記 / 李锦记", &["李錦記", "李锦记"]),
+            ("天福｜天福茗茶", &["天福", "天福茗茶"]),
+            ("老干妈\n风味豆豉", &["老干妈", "风味豆豉"]),
+            ("双喜；；双 喜 ", &["双喜", "双 喜"]),
+            (" ; ，", &[]),
+        ];
+        for (value, terms) in cases {
+            assert_eq!(split(value), *terms, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn terms_are_scored_in_any_order() {
+        let score = score(Some("美心；美心月饼"), Some("美心月饼，美心"), str::to_string);
+        assert!(score.is_complete());
+    }
+
+    #[test]
+    fn missing_and_extra_terms_lower_recall_and_precision() {
+        let partial = score(Some("同仁堂、北京同仁堂"), Some("同仁堂"), str::to_string);
+        assert_eq!((partial.precision, partial.recall), (1.0, 0.5));
+        let extra = score(Some("天福"), Some("天福；茗茶"), str::to_string);
+        assert_eq!((extra.precision, extra.recall), (0.5, 1.0));
+        assert!((extra.f1 - 2.0 / 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn empty_values_score_by_whether_both_are_empty() {
+        assert!(score(None, Some(""), str::to_string).is_complete());
+        assert_eq!(score(Some("美心"), None, str::to_string).f1, 0.0);
+        assert_eq!(score(None, Some("美心"), str::to_string).f1, 0.0);
+    }
+
+    #[test]
+    fn terms_are_compared_normalized() {
+        let strip = |term: &str| term.chars().filter(|c| !c.is_whitespace()).collect();
+        assert!(score(Some("美 心；食品"), Some("美心；食品"), strip).is_complete());
+    }
+}
@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+
+/// Tokens billed for the requests of an image, as reported by the endpoint
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: Usage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+    }
+}
+
+/// `--price-per-1k-input` and `--price-per-1k-output`
+#[derive(Debug, Clone, Copy)]
+pub struct Pricing {
+    pub input_per_1k: f64,
+    pub output_per_1k: f64,
+}
+
+impl Pricing {
+    /// Either price given; the other is taken as free
+    pub fn new(input_per_1k: Option<f64>, output_per_1k: Option<f64>) -> Option<Self> {
+        (input_per_1k.is_some() || output_per_1k.is_some()).then(|| Pricing {
+            input_per_1k: input_per_1k.unwrap_or_default(),
+            output_per_1k: output_per_1k.unwrap_or_default(),
+        })
+    }
+
+    pub fn cost(&self, usage: Usage) -> f64 {
+        (usage.input_tokens as f64 * self.input_per_1k + usage.output_tokens as f64 * self.output_per_1k) / 1000.0
+    }
+}
+
+/// Usage of the images of a run that made requests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageTotals {
+    /// Images that made requests
+    pub images: usize,
+    /// Of those, images whose responses reported no usage
+    pub images_without_usage: usize,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// Image bytes sent over every request, the only measure of what
+    /// endpoints without usage data were given
+    pub image_bytes: u64,
+    /// Sum of the estimated costs, `None` without a price
+    pub estimated_cost: Option<f64>,
+}
+
+impl UsageTotals {
+    pub fn record(&mut self, usage: Option<Usage>, image_bytes: u64, cost: Option<f64>) {
+        self.images += 1;
+        match usage {
+            Some(usage) => {
+                self.input_tokens += usage.input_tokens;
+                self.output_tokens += usage.output_tokens;
+            }
+            None => self.images_without_usage += 1,
+        }
+        self.image_bytes += image_bytes;
+        if let Some(cost) = cost {
+            *self.estimated_cost.get_or_insert(0.0) += cost;
+        }
+    }
+
+    /// Count a request whose usage belongs to none of the images, such as a
+    /// batch whose reply didn't split and whose images were sent again on
+    /// their own
+    pub fn record_request(&mut self, usage: Option<Usage>, image_bytes: u64, cost: Option<f64>) {
+        if let Some(usage) = usage {
+            self.input_tokens += usage.input_tokens;
+            self.output_tokens += usage.output_tokens;
+        }
+        self.image_bytes += image_bytes;
+        if let Some(cost) = cost {
+            *self.estimated_cost.get_or_insert(0.0) += cost;
+        }
+    }
+
+    /// Estimated cost of 1000 images at this run's average
+    pub fn cost_per_1000_images(&self) -> Option<f64> {
+        let cost = self.estimated_cost?;
+        (self.images > 0).then(|| cost * 1000.0 / self.images as f64)
+    }
+
+    /// Tokens of both kinds per image that reported usage
+    pub fn tokens_per_image(&self) -> Option<f64> {
+        let images = self.images - self.images_without_usage;
+        (images > 0).then(|| (self.input_tokens + self.output_tokens) as f64 / images as f64)
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+
+use super::protocol::ApiResponse;
+
+/// Longest plausible value of any field; longer ones are echoed input or rambling
+pub const MAX_FIELD_CHARS: usize = 500;
+
+/// Why a parsed response was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// Every field is missing or blank
+    Empty,
+    /// `chineseCharacter` holds no CJK characters
+    NoCjk(String),
+    /// A field is longer than `MAX_FIELD_CHARS`
+    TooLong { field: &'static str, chars: usize },
+}
+
+impl ValidationError {
+    /// Failure class recorded in the results file
+    pub fn class(&self) -> &'static str {
+        match self {
+            ValidationError::Empty => "empty_extraction",
+            ValidationError::NoCjk(_) | ValidationError::TooLong { .. } => "garbage_output",
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::Empty => write!(f, "Response has no extracted fields"),
+            ValidationError::NoCjk(value) => {
+                let excerpt: String = value.chars().take(50).collect();
+                write!(f, "chineseCharacter has no CJK characters: {:?}", excerpt)
+            }
+            ValidationError::TooLong { field, chars } => {
+                write!(f, "{} is {} characters long, more than {}", field, chars, MAX_FIELD_CHARS)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Check that a response looks like an extraction rather than noise
+pub fn validate(response: &ApiResponse) -> Result<(), ValidationError> {
+    let fields = [
+        ("chineseCharacter", &response.chinese_character),
+        ("wordsInMark", &response.words_in_mark),
+        ("descrOfDevice", &response.description_of_device),
+    ];
+
+    if fields.iter().all(|(_, value)| is_blank(value.as_deref())) {
+        return Err(ValidationError::Empty);
+    }
+    for (field, value) in fields {
+        let chars = value.as_deref().map_or(0, |v| v.chars().count());
+        if chars > MAX_FIELD_CHARS {
+            return Err(ValidationError::TooLong { field, chars });
+        }
+    }
+    if let Some(chinese) = response.chinese_character.as_deref()
+        && !is_blank(Some(chinese))
+        && !chinese.chars().any(is_cjk)
+    {
+        return Err(ValidationError::NoCjk(chinese.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Missing, empty, or a word models use for nothing
+fn is_blank(value: Option<&str>) -> bool {
+    value.is_none_or(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "" | "null" | "none" | "n/a"))
+}
+
+/// CJK ideographs, including the extension and compatibility blocks
+pub fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF | 0x20000..=0x2FA1F | 0x30000..=0x323AF)
+}
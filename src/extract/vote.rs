@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::metrics;
+use super::protocol::{ApiResponse, Field};
+
+/// Temperature the samples of `--votes` are drawn at when `--temperature`
+/// is 0, where every sample would give the same answer
+pub const VOTE_TEMPERATURE: f64 = 0.7;
+
+/// How the samples of an image voted, recorded with its result
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Votes {
+    /// Samples asked for, and those that came back with fields that parsed
+    pub samples: usize,
+    pub answered: usize,
+    /// Share of the answered samples agreeing with the elected answer on
+    /// every voted field
+    pub agreement: f64,
+    /// Answers each field got, by JSON key, most votes first
+    pub distribution: BTreeMap<String, Vec<Tally>>,
+    /// Fields whose top answers tied, decided by the lowest CER against
+    /// the other answers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ties: Vec<String>,
+}
+
+/// One answer of a field and the samples that gave it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tally {
+    pub value: Option<String>,
+    pub votes: usize,
+}
+
+/// Cache settings of sample `vote` of `votes`: each sample is a request of
+/// its own, so it needs its own key. A single sample keeps the plain key.
+pub fn cache_settings(image_settings: &str, votes: usize, vote: usize) -> String {
+    if votes > 1 {
+        format!("{} vote {}", image_settings, vote)
+    } else {
+        image_settings.to_string()
+    }
+}
+
+fn value(response: &ApiResponse, field: Field) -> Option<&str> {
+    match field {
+        Field::Chinese => response.chinese_character.as_deref(),
+        Field::Words => response.words_in_mark.as_deref(),
+        Field::Device => response.description_of_device.as_deref(),
+    }
+}
+
+fn set_value(response: &mut ApiResponse, field: Field, value: Option<String>) {
+    match field {
+        Field::Chinese => response.chinese_character = value,
+        Field::Words => response.words_in_mark = value,
+        Field::Device => response.description_of_device = value,
+    }
+}
+
+/// The answer elected from `answers`, the parsed replies of `samples`
+/// requests: for each of `fields`, the value most samples gave, values
+/// compared after trimming. A tie goes to the tied value with the lowest
+/// total CER against all the answers, the one closest to the plurality.
+/// The reply kept as the raw output is that of the sample agreeing with
+/// the most elected values. `None` when no sample was answered.
+pub fn elect(answers: Vec<ApiResponse>, fields: &[Field], samples: usize) -> Option<(ApiResponse, Votes)> {
+    let first = answers.first()?;
+    let mut elected = Vec::new();
+    let mut distribution = BTreeMap::new();
+    let mut ties = Vec::new();
+    for &field in fields {
+        let values: Vec<Option<&str>> = answers.iter().map(|answer| value(answer, field).map(str::trim)).collect();
+        let mut tallies: Vec<Tally> = Vec::new();
+        for value in &values {
+            match tallies.iter_mut().find(|tally| tally.value.as_deref() == *value) {
+                Some(tally) => tally.votes += 1,
+                None => tallies.push(Tally { value: value.map(String::from), votes: 1 }),
+            }
+        }
+        // Stable, so equal counts keep the order the answers came in
+        tallies.sort_by_key(|tally| std::cmp::Reverse(tally.votes));
+        let top = tallies[0].votes;
+        let tied: Vec<&Tally> = tallies.iter().filter(|tally| tally.votes == top).collect();
+        let winner = if tied.len() > 1 {
+            ties.push(field.key().to_string());
+            let cost = |candidate: &Tally| -> f64 {
+                let candidate = candidate.value.as_deref().unwrap_or_default();
+                values.iter()
+                    .map(|other| {
+                        let other = other.unwrap_or_default();
+                        metrics::edit_distance(other, candidate) as f64 / other.chars().count().max(1) as f64
+                    })
+                    .sum()
+            };
+            tied.into_iter()
+                .min_by(|a, b| cost(a).total_cmp(&cost(b)))
+                .and_then(|tally| tally.value.clone())
+        } else {
+            tallies[0].value.clone()
+        };
+        elected.push((field, winner));
+        distribution.insert(field.key().to_string(), tallies);
+    }
+
+    let agreeing = |answer: &ApiResponse| {
+        elected.iter().filter(|(field, winner)| value(answer, *field).map(str::trim) == winner.as_deref()).count()
+    };
+    let unanimous = answers.iter().filter(|answer| agreeing(answer) == elected.len()).count();
+    // Reversed so the earliest of equally agreeing samples is kept
+    let representative = answers.iter().rev().max_by_key(|answer| agreeing(answer)).unwrap_or(first);
+    let mut response = representative.clone();
+    for (field, winner) in elected {
+        set_value(&mut response, field, winner);
+    }
+    let votes = Votes {
+        samples,
+        answered: answers.len(),
+        agreement: unanimous as f64 / answers.len() as f64,
+        distribution,
+        ties,
+    };
+    Some((response, votes))
+}
@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use super::metrics;
+
+/// Words of a `wordsInMark` value as compared: NFKC folds full-width and
+/// compatibility forms, letters are lower-cased, apostrophes are dropped
+/// within words (`don't` is `dont`) and any other punctuation or
+/// whitespace separates words
+pub fn tokens(text: &str) -> Vec<String> {
+    let folded: String = text.nfkc()
+        .flat_map(char::to_lowercase)
+        .filter(|c| !matches!(c, '\'' | '’'))
+        .collect();
+    folded.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// How the predicted words in the mark compare with the ground truth
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WordScore {
+    /// The prediction has the ground truth's words in order
+    pub exact_match: bool,
+    /// Word error rate: word-level edit distance over the number of ground
+    /// truth words. A missing prediction scores 1.
+    pub wer: f64,
+}
+
+/// Score the predicted words against the ground truth, or `None` when the
+/// ground truth has no words to compare with
+pub fn score(truth: &str, predicted: Option<&str>) -> Option<WordScore> {
+    let truth = tokens(truth);
+    if truth.is_empty() {
+        return None;
+    }
+    let predicted = tokens(predicted.unwrap_or_default());
+    let distance = metrics::levenshtein(&truth, &predicted);
+    Some(WordScore { exact_match: distance == 0, wer: distance as f64 / truth.len() as f64 })
+}
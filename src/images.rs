@@ -0,0 +1,1190 @@
+pub mod archive;
+pub mod checksum;
+pub mod convert;
+pub mod diskspace;
+pub mod export;
+pub mod failures;
+pub mod hook;
+pub mod inflight;
+pub mod manifest;
+pub mod relink;
+pub mod s3;
+pub mod shard;
+pub mod sidecar;
+pub mod stats;
+pub mod throttle;
+pub mod thumbnail;
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use clap::Args;
+use futures::StreamExt;
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::time::sleep;
+
+use crate::data::DownloadTask;
+use crate::extract::retry;
+use archive::ArchiveWriter;
+use convert::ConvertFormat;
+use failures::ImageFailure;
+use hook::PostDownloadHook;
+use inflight::InFlight;
+use manifest::{Manifest, ManifestEntry};
+use relink::UrlRefresher;
+use s3::S3Target;
+use shard::Shard;
+use sidecar::Sidecar;
+use stats::{DownloadStats, RunSummary};
+use throttle::RateLimiter;
+
+/// Most retries `--image-retries` and `--s3-retries` take
+pub const MAX_RETRIES: u32 = 20;
+
+/// Options controlling the image phase
+#[derive(Args, Debug, Clone)]
+pub struct ImageArgs {
+    /// Directory to save images (defaults to ./images)
+    #[arg(long, default_value = "images")]
+    pub images_dir: PathBuf,
+
+    /// Image manifest path (defaults to <images-dir>/images_manifest.json)
+    #[arg(long)]
+    pub manifest: Option<PathBuf>,
+
+    /// Write a <image>.json provenance sidecar next to each image
+    #[arg(long)]
+    pub sidecars: bool,
+
+    /// Directory layout for image files
+    #[arg(long, value_enum, default_value_t = Shard::Flat)]
+    pub shard: Shard,
+
+    /// Skip documents larger than this many bytes (files already on disk are kept)
+    #[arg(long)]
+    pub max_image_bytes: Option<u64>,
+
+    /// Cap the combined download rate of all images, e.g. 10MB/s or 512KiB/s
+    #[arg(long, value_parser = throttle::parse_rate)]
+    pub max_bandwidth: Option<u64>,
+
+    /// Download at most this many documents per application, picked by fileId
+    #[arg(long)]
+    pub max_images_per_mark: Option<usize>,
+
+    /// Convert downloaded TIFFs to this format (first page only)
+    #[arg(long, value_enum)]
+    pub convert_to: Option<ConvertFormat>,
+
+    /// Keep the original TIFF next to the converted file
+    #[arg(long, requires = "convert_to")]
+    pub keep_original: bool,
+
+    /// Write a small preview of every image into this directory
+    #[arg(long)]
+    pub thumbnails: Option<PathBuf>,
+
+    /// Longest side of a thumbnail in pixels
+    #[arg(long, default_value_t = 256)]
+    pub thumbnail_size: u32,
+
+    /// Shell command to run after each download; {path}, {app_num}, {file_id},
+    /// {file_name}, {doc_type} and {sha256} are substituted
+    #[arg(long)]
+    pub post_download_cmd: Option<String>,
+
+    /// Number of post-download commands allowed to run at once
+    #[arg(long, default_value_t = 4)]
+    pub post_download_jobs: usize,
+
+    /// Seconds before a post-download command is killed
+    #[arg(long, default_value_t = 60)]
+    pub post_download_timeout: u64,
+
+    /// Abort the run once more than this many post-download commands fail
+    #[arg(long)]
+    pub max_post_download_failures: Option<usize>,
+
+    /// Also store images in this .tar.zst or .zip archive
+    #[arg(long)]
+    pub archive: Option<PathBuf>,
+
+    /// Remove loose images once they are in the archive
+    #[arg(long, requires = "archive")]
+    pub archive_only: bool,
+
+    /// Upload images to this S3 bucket and remove the local copies
+    #[arg(long)]
+    pub s3_bucket: Option<String>,
+
+    /// Key prefix for uploaded images
+    #[arg(long, requires = "s3_bucket")]
+    pub s3_prefix: Option<String>,
+
+    /// Endpoint of an S3-compatible service, e.g. http://localhost:9000 for MinIO
+    #[arg(long, requires = "s3_bucket")]
+    pub s3_endpoint: Option<String>,
+
+    /// Times to retry a failed upload
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(0..=MAX_RETRIES as i64))]
+    pub s3_retries: u32,
+
+    /// Re-download images on disk when the server has a newer copy
+    #[arg(long)]
+    pub refresh: bool,
+
+    /// Write the document URLs to this file (.jsonl, otherwise TSV) instead of downloading
+    #[arg(long)]
+    pub export_urls: Option<PathBuf>,
+
+    /// Write a CSV inventory of every document to this file instead of downloading
+    #[arg(long)]
+    pub export_documents_csv: Option<PathBuf>,
+
+    /// Times to retry a download after a network error, server error or rate limit
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(0..=MAX_RETRIES as i64))]
+    pub image_retries: u32,
+
+    /// Failure report path (defaults to <images-dir>/image_failures.json)
+    #[arg(long)]
+    pub failures: Option<PathBuf>,
+
+    /// Run summary path (defaults to <images-dir>/images_summary.json)
+    #[arg(long)]
+    pub summary: Option<PathBuf>,
+
+    /// Start downloading even if the estimated space needed exceeds the free space
+    #[arg(long)]
+    pub ignore_disk_check: bool,
+
+    /// Number of documents sized with HEAD requests to estimate the space needed
+    #[arg(long, default_value_t = 20)]
+    pub disk_check_sample: usize,
+
+    /// Stop starting new downloads when free space drops below this, e.g. 1GB (0 disables)
+    #[arg(long, default_value = "1GB", value_parser = throttle::parse_size)]
+    pub min_free_space: u64,
+
+    /// Re-query the API for new URLs of documents failing with 403/404 when the
+    /// data file is older than this many days
+    #[arg(long)]
+    pub refresh_urls_after_days: Option<u64>,
+
+    /// Age of the data file the tasks were read from, if any
+    #[arg(skip)]
+    pub data_file_age: Option<Duration>,
+}
+
+impl ImageArgs {
+    pub fn manifest_path(&self) -> PathBuf {
+        self.manifest.clone()
+            .unwrap_or_else(|| self.images_dir.join(manifest::MANIFEST_FILE_NAME))
+    }
+
+    pub fn failures_path(&self) -> PathBuf {
+        self.failures.clone()
+            .unwrap_or_else(|| self.images_dir.join(failures::FAILURES_FILE_NAME))
+    }
+
+    pub fn summary_path(&self) -> PathBuf {
+        self.summary.clone()
+            .unwrap_or_else(|| self.images_dir.join(stats::SUMMARY_FILE_NAME))
+    }
+
+    /// Local path of the image for a task
+    pub fn image_path(&self, task: &DownloadTask) -> PathBuf {
+        image_path(&self.images_dir, self.shard, &task.app_num, &task.file_name)
+    }
+}
+
+/// Drop tasks repeating the applicationNum and fileId of an earlier task.
+/// Tasks without a fileId are all kept. Returns the remaining tasks and the
+/// number dropped.
+pub fn dedup_by_file_id(tasks: &[DownloadTask]) -> (Vec<DownloadTask>, usize) {
+    let mut seen = std::collections::HashSet::new();
+    let unique: Vec<DownloadTask> = tasks.iter()
+        .filter(|task| match &task.file_id {
+            Some(file_id) => seen.insert((task.app_num.as_str(), file_id.as_str())),
+            None => true,
+        })
+        .cloned()
+        .collect();
+    let dropped = tasks.len() - unique.len();
+
+    (unique, dropped)
+}
+
+/// Keep at most `limit` tasks per application number, choosing by fileId so
+/// reruns pick the same documents. Returns the kept and the capped tasks.
+pub fn cap_per_mark(tasks: &[DownloadTask], limit: Option<usize>) -> (Vec<DownloadTask>, Vec<DownloadTask>) {
+    let Some(limit) = limit else {
+        return (tasks.to_vec(), Vec::new());
+    };
+
+    let mut by_mark: std::collections::BTreeMap<&str, Vec<&DownloadTask>> = std::collections::BTreeMap::new();
+    for task in tasks {
+        by_mark.entry(task.app_num.as_str()).or_default().push(task);
+    }
+
+    let mut kept = Vec::new();
+    let mut capped = Vec::new();
+    for (_, mut documents) in by_mark {
+        // Documents without a fileId sort last, by file name
+        documents.sort_by(|a, b| {
+            (a.file_id.is_none(), &a.file_id, &a.file_name).cmp(&(b.file_id.is_none(), &b.file_id, &b.file_name))
+        });
+        for (i, task) in documents.into_iter().enumerate() {
+            if i < limit {
+                kept.push(task.clone());
+            } else {
+                capped.push(task.clone());
+            }
+        }
+    }
+
+    (kept, capped)
+}
+
+/// Human readable byte count, e.g. `12.3 MB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Whether a file in the images directory is one of our own bookkeeping files
+/// rather than an image
+pub fn is_bookkeeping(name: &str) -> bool {
+    name == checksum::SUMS_FILE_NAME
+        || name.starts_with(manifest::MANIFEST_FILE_NAME)
+        || name.starts_with(failures::FAILURES_FILE_NAME)
+        || name.starts_with(stats::SUMMARY_FILE_NAME)
+        || name.ends_with(".part")
+        || name.ends_with(".tmp")
+}
+
+/// Result of a single successful download task
+#[derive(Debug)]
+pub enum DownloadOutcome {
+    /// The file was fetched and written; `replaced` is set when it updated a
+    /// stale copy under `--refresh`. `elapsed` covers the request and body.
+    Downloaded { path: PathBuf, size: u64, sha256: String, etag: Option<String>, replaced: bool, elapsed: Duration },
+    /// The file was already on disk and left untouched
+    Existing(PathBuf),
+    /// The document exceeds the size cap; carries the advertised size, or the
+    /// bytes read before the download was aborted
+    TooLarge(u64),
+}
+
+/// Limits applied while reading a response body
+#[derive(Debug, Clone, Default)]
+pub struct DownloadLimits {
+    pub max_bytes: Option<u64>,
+    pub bandwidth: Option<Arc<RateLimiter>>,
+}
+
+/// Final status of one task in the image phase
+#[derive(Debug)]
+enum TaskStatus {
+    Downloaded(Duration),
+    Refreshed(Duration),
+    /// Already present, with its size on disk or in the manifest
+    Existing(u64),
+    TooLarge(ImageFailure),
+    Failed(ImageFailure),
+}
+
+/// What the image phase left to do
+#[derive(Debug, Default)]
+pub struct ImagePhaseReport {
+    /// Downloads that failed after all retries; documents skipped on purpose
+    /// (such as those over `--max-image-bytes`) are not counted
+    pub failed: usize,
+    /// Documents never started because free space fell below `--min-free-space`
+    pub not_attempted: usize,
+}
+
+/// Local path of an image under `dir` in the given layout
+pub fn image_path(dir: &Path, shard: Shard, app_num: &str, file_name: &str) -> PathBuf {
+    shard.path(dir, &format!("{}_{}", app_num, file_name))
+}
+
+/// Application number of an image named `{applicationNum}_{fileName}` as
+/// the downloader names them, such as `40201912345X_rep.jpg`; `None` for
+/// names of another shape. IPOS numbers are digits with at most a trailing
+/// check letter, so a name like `dup_x.png` yields none.
+pub fn application_num_of(image_name: &str) -> Option<&str> {
+    let (application_num, file_name) = image_name.split_once('_')?;
+    let digits = application_num.strip_suffix(|c: char| c.is_ascii_uppercase()).unwrap_or(application_num);
+    (!digits.is_empty() && !file_name.is_empty() && digits.chars().all(|c| c.is_ascii_digit()))
+        .then_some(application_num)
+}
+
+/// Remote validators for an image already on disk, used by `--refresh`
+#[derive(Debug, Clone, Default)]
+pub struct Revalidation {
+    /// ETag recorded when the image was last downloaded
+    pub etag: Option<String>,
+}
+
+/// Download an image to `img_path`. Files already on disk are skipped unless
+/// `refresh` is given, in which case they are only replaced when the server
+/// reports a newer copy whose contents actually differ.
+pub async fn download_image(
+    client: &Client,
+    url: &str,
+    img_path: PathBuf,
+    limits: &DownloadLimits,
+    refresh: Option<&Revalidation>,
+) -> Result<DownloadOutcome> {
+    let mut request = client.get(url);
+
+    // Check if file already exists
+    let existing = img_path.exists();
+    if existing {
+        let Some(revalidation) = refresh else {
+            return Ok(DownloadOutcome::Existing(img_path));
+        };
+        if let Some(since) = modified_time(&img_path) {
+            let since = since.with_timezone(&chrono::Utc).format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, since);
+        }
+        if let Some(etag) = &revalidation.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+    }
+
+    // Shard directories are created on demand
+    if let Some(parent) = img_path.parent() {
+        fs::create_dir_all(parent).context("Failed to create image directory")?;
+    }
+
+    // Download the image
+    let started = Instant::now();
+    let response = request.send().await.context("Failed to download image")?;
+    if existing && !remote_differs(&response, &img_path, refresh) {
+        return Ok(DownloadOutcome::Existing(img_path));
+    }
+    let response = response.error_for_status().context("Failed to download image")?;
+    let etag = response.headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+
+    // Check the advertised size before reading any of the body
+    if let (Some(limit), Some(length)) = (limits.max_bytes, response.content_length())
+        && length > limit
+    {
+        return Ok(DownloadOutcome::TooLarge(length));
+    }
+
+    // Stream the body into a partial file, hashing as we go, and only move it
+    // into place once complete so a crash never leaves a truncated image
+    let part_path = partial_path(&img_path);
+    let result = write_body(response, &part_path, limits).await;
+    let (size, sha256) = match result {
+        Ok(BodyWritten::Complete { size, sha256 }) => (size, sha256),
+        Ok(BodyWritten::TooLarge(read)) => {
+            let _ = fs::remove_file(&part_path);
+            return Ok(DownloadOutcome::TooLarge(read));
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&part_path);
+            return Err(e);
+        }
+    };
+
+    // Servers that ignore conditional requests resend identical files
+    if existing {
+        let current = img_path.clone();
+        let unchanged = tokio::task::spawn_blocking(move || checksum::hash_file(&current))
+            .await
+            .context("Hashing task panicked")?
+            .is_ok_and(|(_, current_sha256)| current_sha256 == sha256);
+        if unchanged {
+            let _ = fs::remove_file(&part_path);
+            return Ok(DownloadOutcome::Existing(img_path));
+        }
+    }
+    fs::rename(&part_path, &img_path).context("Failed to save image file")?;
+
+    Ok(DownloadOutcome::Downloaded { path: img_path, size, sha256, etag, replaced: existing, elapsed: started.elapsed() })
+}
+
+fn modified_time(path: &Path) -> Option<chrono::DateTime<Local>> {
+    fs::metadata(path).and_then(|m| m.modified()).ok().map(chrono::DateTime::from)
+}
+
+/// Whether a response to a conditional request may hold a different copy
+/// of the local file. When the server gives no usable validators the body
+/// is fetched and compared by hash instead.
+fn remote_differs(response: &reqwest::Response, local: &Path, refresh: Option<&Revalidation>) -> bool {
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return false;
+    }
+    let header = |name| response.headers().get(name).and_then(|v: &reqwest::header::HeaderValue| v.to_str().ok());
+
+    if let Some(etag) = header(reqwest::header::ETAG)
+        && refresh.and_then(|r| r.etag.as_deref()) == Some(etag)
+    {
+        return false;
+    }
+    if let Some(last_modified) = header(reqwest::header::LAST_MODIFIED)
+        .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok())
+        && let Some(local_modified) = modified_time(local)
+    {
+        return last_modified > local_modified;
+    }
+    true
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+enum BodyWritten {
+    Complete { size: u64, sha256: String },
+    /// The size cap was hit after reading this many bytes
+    TooLarge(u64),
+}
+
+async fn write_body(response: reqwest::Response, path: &Path, limits: &DownloadLimits) -> Result<BodyWritten> {
+    let mut file = tokio::fs::File::create(path).await.context("Failed to save image file")?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+
+    let mut body = response.bytes_stream();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.context("Failed to read image bytes")?;
+        size += chunk.len() as u64;
+        // Servers that don't send Content-Length are capped while streaming
+        if limits.max_bytes.is_some_and(|limit| size > limit) {
+            return Ok(BodyWritten::TooLarge(size));
+        }
+        if let Some(limiter) = &limits.bandwidth {
+            limiter.acquire(chunk.len() as u64).await;
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.context("Failed to save image file")?;
+    }
+    file.flush().await.context("Failed to save image file")?;
+
+    Ok(BodyWritten::Complete { size, sha256: format!("{:x}", hasher.finalize()) })
+}
+
+/// Write the sidecar for a finished task. Freshly downloaded images always get
+/// a new sidecar; existing images only get one if it is missing, mirroring the
+/// skip-if-exists logic of the images themselves.
+fn write_sidecar(task: &DownloadTask, outcome: &DownloadOutcome) {
+    let (path, downloaded_at) = match outcome {
+        DownloadOutcome::Downloaded { path, .. } => (path, Local::now().to_rfc3339()),
+        DownloadOutcome::TooLarge(_) => return,
+        DownloadOutcome::Existing(path) => {
+            if sidecar::sidecar_path(path).exists() {
+                return;
+            }
+            let modified = fs::metadata(path)
+                .and_then(|m| m.modified())
+                .map(|t| chrono::DateTime::<Local>::from(t).to_rfc3339())
+                .unwrap_or_default();
+            (path, modified)
+        }
+    };
+
+    if let Err(e) = Sidecar::new(task, downloaded_at).save(path) {
+        eprintln!("Failed to write sidecar for {}: {}", path.display(), e);
+    }
+}
+
+/// Convert a freshly downloaded image and record the result in its entry.
+/// Failures are only logged: the original stays in place and the download
+/// still counts.
+async fn convert_downloaded(entry: &mut ManifestEntry, format: ConvertFormat, keep_original: bool) {
+    let source = entry.local_path.clone();
+    let result = tokio::task::spawn_blocking(move || convert::convert_tiff(&source, format))
+        .await
+        .context("Conversion task panicked")
+        .and_then(|r| r);
+
+    let converted = match result {
+        Ok(Some(converted)) => converted,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("Failed to convert {}, keeping original: {}", entry.local_path.display(), e);
+            return;
+        }
+    };
+    if converted.pages > 1 {
+        println!("Converted first of {} pages of {}", converted.pages, entry.local_path.display());
+    }
+
+    if !keep_original {
+        match fs::remove_file(&entry.local_path) {
+            Ok(()) => {
+                entry.original_removed = true;
+                // The sidecar follows the file that is left
+                let old_sidecar = sidecar::sidecar_path(&entry.local_path);
+                if old_sidecar.exists() {
+                    let _ = fs::rename(&old_sidecar, sidecar::sidecar_path(&converted.path));
+                }
+            }
+            Err(e) => eprintln!("Failed to remove original {}: {}", entry.local_path.display(), e),
+        }
+    }
+    entry.converted = Some(converted);
+}
+
+/// Make thumbnails for the manifest entries at `paths` on the blocking thread
+/// pool, `workers` at a time, and record them in the manifest. Images that
+/// can't be decoded are skipped with a warning.
+async fn generate_thumbnails(manifest: &mut Manifest, paths: &[PathBuf], dir: &Path, size: u32, workers: usize) {
+    let jobs: Vec<(PathBuf, PathBuf, PathBuf)> = paths.iter()
+        .filter_map(|path| manifest.get(path))
+        .filter(|entry| entry.skipped.is_none())
+        .map(|entry| {
+            let source = entry.image_path().to_path_buf();
+            let target = thumbnail::thumbnail_path(dir, &source);
+            (entry.local_path.clone(), source, target)
+        })
+        .collect();
+
+    let results: Vec<(PathBuf, PathBuf, Result<bool>)> = futures::stream::iter(jobs)
+        .map(|(key, source, target)| async move {
+            let job_target = target.clone();
+            let result = tokio::task::spawn_blocking(move || thumbnail::make_thumbnail(&source, &job_target, size))
+                .await
+                .context("Thumbnail task panicked")
+                .and_then(|r| r);
+            (key, target, result)
+        })
+        .buffer_unordered(workers.max(1))
+        .collect()
+        .await;
+
+    let mut created = 0;
+    for (key, target, result) in results {
+        match result {
+            Ok(new) => {
+                created += usize::from(new);
+                if let Some(entry) = manifest.get_mut(&key) {
+                    entry.thumbnail = Some(target);
+                }
+            }
+            Err(e) => eprintln!("Skipping thumbnail for {}: {:#}", key.display(), e),
+        }
+    }
+    println!("Created {} thumbnails in {}", created, dir.display());
+}
+
+/// Add the images of the manifest entries at `paths` to the archive, skipping
+/// members it already has, and record their member names in the manifest.
+/// Runs after thumbnails since those are made from the loose files.
+async fn archive_images(
+    manifest: &mut Manifest,
+    paths: &[PathBuf],
+    args: &ImageArgs,
+    archive_path: &Path,
+    index: &BTreeSet<String>,
+) -> Result<()> {
+    // (manifest key, file to archive, member name)
+    let mut jobs = Vec::new();
+    for path in paths {
+        let Some(entry) = manifest.get(path) else {
+            continue;
+        };
+        let source = entry.image_path().to_path_buf();
+        if entry.skipped.is_some() || entry.archived_only || !source.exists() {
+            continue;
+        }
+        let member = checksum::relative_name(&args.images_dir, &source);
+        jobs.push((path.clone(), source, member));
+    }
+
+    let new_members: Vec<(PathBuf, String)> = jobs.iter()
+        .filter(|(_, _, member)| !index.contains(member))
+        .map(|(_, source, member)| (source.clone(), member.clone()))
+        .collect();
+    let added = new_members.len();
+    if !new_members.is_empty() {
+        let archive_path = archive_path.to_path_buf();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut writer = ArchiveWriter::open(&archive_path)?;
+            for (source, member) in &new_members {
+                writer.add(member, source)?;
+                let sidecar = sidecar::sidecar_path(source);
+                if sidecar.exists() {
+                    writer.add(&format!("{}.json", member), &sidecar)?;
+                }
+            }
+            writer.finish()
+        })
+        .await
+        .context("Archive task panicked")??;
+    }
+
+    for (key, source, member) in jobs {
+        if args.archive_only {
+            fs::remove_file(&source)
+                .with_context(|| format!("Failed to remove archived image: {}", source.display()))?;
+            let _ = fs::remove_file(sidecar::sidecar_path(&source));
+            // Drop shard directories left empty; fails harmlessly otherwise
+            if let Some(parent) = source.parent()
+                && parent != args.images_dir
+            {
+                let _ = fs::remove_dir(parent);
+            }
+        }
+        if let Some(entry) = manifest.get_mut(&key) {
+            entry.archive_member = Some(member);
+            entry.archived_only = args.archive_only;
+        }
+    }
+    println!("Added {} images to archive {}", added, archive_path.display());
+
+    Ok(())
+}
+
+/// Upload the images of the manifest entries at `paths` (and their sidecars),
+/// removing each local copy once it is stored. Returns the number of images
+/// that failed to upload; those stay on disk for the next run.
+async fn upload_images(
+    manifest: &mut Manifest,
+    paths: &[PathBuf],
+    images_dir: &Path,
+    s3: &S3Target,
+    workers: usize,
+) -> usize {
+    // (manifest key, file to upload, object key)
+    let jobs: Vec<(PathBuf, PathBuf, String)> = paths.iter()
+        .filter_map(|path| manifest.get(path))
+        .filter(|entry| entry.skipped.is_none() && entry.image_path().exists())
+        .map(|entry| {
+            let source = entry.image_path().to_path_buf();
+            let key = s3.key(&checksum::relative_name(images_dir, &source));
+            (entry.local_path.clone(), source, key)
+        })
+        .collect();
+
+    let results: Vec<(PathBuf, String, Result<()>)> = futures::stream::iter(jobs)
+        .map(|(manifest_key, source, key)| async move {
+            let mut result = s3.upload(&source, &key).await;
+            let sidecar = sidecar::sidecar_path(&source);
+            if result.is_ok() && sidecar.exists() {
+                result = s3.upload(&sidecar, &format!("{}.json", key)).await;
+            }
+            if result.is_ok() {
+                let _ = fs::remove_file(&sidecar);
+                result = fs::remove_file(&source)
+                    .with_context(|| format!("Failed to remove uploaded image: {}", source.display()));
+            }
+            (manifest_key, key, result)
+        })
+        .buffer_unordered(workers.max(1))
+        .collect()
+        .await;
+
+    let mut uploaded = 0;
+    let mut failed = 0;
+    for (manifest_key, key, result) in results {
+        match result {
+            Ok(()) => {
+                uploaded += 1;
+                if let Some(entry) = manifest.get_mut(&manifest_key) {
+                    entry.s3_url = Some(s3.url(&key));
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("Failed to upload {}: {:#}", manifest_key.display(), e);
+            }
+        }
+    }
+    println!("Uploaded {} images to S3", uploaded);
+
+    failed
+}
+
+/// Call `download_image`, retrying errors that `failures::is_transient`
+/// accepts with `retry::backoff`. Returns the result of the last attempt
+/// and the number of attempts made.
+async fn download_with_retries(
+    client: &Client,
+    url: &str,
+    img_path: &Path,
+    limits: &DownloadLimits,
+    refresh: Option<&Revalidation>,
+    retries: u32,
+) -> (Result<DownloadOutcome>, u32) {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let result = download_image(client, url, img_path.to_path_buf(), limits, refresh).await;
+        match &result {
+            Err(e) if attempts <= retries && failures::is_transient(&failures::classify(e)) => {
+                let delay = retry::backoff(attempts, Duration::from_secs(1));
+                eprintln!("Failed to download image {}: {:#}, retrying in {:.1}s ({}/{})",
+                    url, e, delay.as_secs_f64(), attempts, retries);
+                sleep(delay).await;
+            }
+            _ => return (result, attempts),
+        }
+    }
+}
+
+/// Download every task into `args.images_dir`, `concurrency` at a time.
+///
+/// Files that already exist are left untouched, so an interrupted run can
+/// simply be started again. The manifest is merged with the entries of this
+/// run rather than rebuilt, and failures are merged into the failure report.
+pub async fn run_image_phase(
+    client: &Client,
+    download_tasks: &[DownloadTask],
+    args: &ImageArgs,
+    concurrency: usize,
+) -> Result<ImagePhaseReport> {
+    fs::create_dir_all(&args.images_dir).context("Failed to create images directory")?;
+
+    let manifest_path = args.manifest_path();
+    let mut manifest = Manifest::load(&manifest_path)?;
+
+    let (download_tasks, duplicates) = dedup_by_file_id(download_tasks);
+    if duplicates > 0 {
+        println!("Ignoring {} duplicate documents", duplicates);
+    }
+
+    let (download_tasks, capped) = cap_per_mark(&download_tasks, args.max_images_per_mark);
+    if !capped.is_empty() {
+        println!("Skipping {} documents beyond {} per mark", capped.len(), args.max_images_per_mark.unwrap_or_default());
+        for task in &capped {
+            // Keep the entries of files that were downloaded before the cap applied
+            let path = args.image_path(task);
+            if !manifest.contains(&path) {
+                manifest.insert(ManifestEntry::skipped(task, &path, "max-images-per-mark"));
+            }
+        }
+    }
+
+    // Tasks that still share a path (colliding names) take turns
+    let in_flight = Arc::new(InFlight::default());
+
+    let limits = DownloadLimits {
+        max_bytes: args.max_image_bytes,
+        bandwidth: args.max_bandwidth.map(|rate| Arc::new(RateLimiter::new(rate))),
+    };
+    if let Some(rate) = args.max_bandwidth {
+        println!("Limiting image downloads to {}/s", format_bytes(rate));
+    }
+    // Members already archived aren't downloaded again when their loose file is gone
+    let archive_index = match &args.archive {
+        Some(path) => {
+            archive::ArchiveKind::from_path(path)?;
+            archive::read_index(path)?
+        }
+        None => BTreeSet::new(),
+    };
+    let s3 = match &args.s3_bucket {
+        Some(bucket) => {
+            let prefix = args.s3_prefix.as_deref().unwrap_or_default();
+            Some(Arc::new(S3Target::new(bucket.clone(), prefix, args.s3_endpoint.as_deref(), args.s3_retries).await))
+        }
+        None => None,
+    };
+    // Stored URLs go stale, so old data files may need fresh ones from the API
+    let refresher = match (args.refresh_urls_after_days, args.data_file_age) {
+        (Some(days), Some(age)) if age >= Duration::from_secs(days * 24 * 60 * 60) => {
+            Some(Arc::new(UrlRefresher::new(client.clone())))
+        }
+        _ => None,
+    };
+    let hook = args.post_download_cmd.clone().map(|template| {
+        Arc::new(PostDownloadHook::new(
+            template,
+            args.post_download_jobs,
+            std::time::Duration::from_secs(args.post_download_timeout),
+        ))
+    });
+
+    if !args.ignore_disk_check {
+        let pending: Vec<&DownloadTask> = download_tasks.iter()
+            .filter(|task| {
+                let path = args.image_path(task);
+                !manifest.contains(&path) && !path.exists()
+            })
+            .collect();
+        diskspace::preflight(client, &args.images_dir, &pending, &manifest, args.disk_check_sample, args.min_free_space).await?;
+    }
+
+    let total_tasks = download_tasks.len();
+    println!("Found {} images to download", total_tasks);
+    let mut downloaded_count = 0;
+    let mut too_large_count = 0;
+    let mut refreshed_count = 0;
+    let mut existing_count = 0;
+    let mut failures = Vec::new();
+    let mut stats = DownloadStats::default();
+    let mut not_attempted = 0;
+    let started_at = Local::now().to_rfc3339();
+    let started = Instant::now();
+
+    // Process in batches to control concurrency
+    for (batch_idx, chunk) in download_tasks.chunks(concurrency).enumerate() {
+        // Stop cleanly rather than failing on a full disk halfway through a file
+        if args.min_free_space > 0
+            && let Ok(free) = diskspace::available(&args.images_dir)
+            && free < args.min_free_space
+        {
+            not_attempted = total_tasks - batch_idx * concurrency;
+            eprintln!("Free space in {} is down to {} (below {}), not starting the remaining {} downloads",
+                args.images_dir.display(),
+                format_bytes(free),
+                format_bytes(args.min_free_space),
+                not_attempted
+            );
+            break;
+        }
+
+        // Each task reports its status and any entry to merge into the manifest
+        let mut tasks: Vec<tokio::task::JoinHandle<(TaskStatus, Option<ManifestEntry>)>> = Vec::new();
+
+        for task in chunk {
+            let client = client.clone();
+            let mut task = task.clone();
+            let img_path = args.image_path(&task);
+            let in_manifest = manifest.contains(&img_path);
+            let refresh = args.refresh.then(|| Revalidation {
+                etag: manifest.get(&img_path).and_then(|e| e.etag.clone()),
+            });
+            // A converted image whose original was removed, or an image kept
+            // only in the archive, counts as present
+            let present_elsewhere = manifest.get(&img_path).is_some_and(|e| {
+                let archived = e.archived_only
+                    && e.archive_member.as_ref().is_some_and(|m| archive_index.contains(m));
+                let converted = e.original_removed
+                    && e.converted.as_ref().is_some_and(|c| c.path.exists());
+                archived || converted
+            });
+            let known_size = manifest.get(&img_path).map_or(0, |e| e.size);
+            let sidecars = args.sidecars;
+            let convert_to = args.convert_to;
+            let keep_original = args.keep_original;
+            let retries = args.image_retries;
+            let limits = limits.clone();
+            let in_flight = in_flight.clone();
+            let hook = hook.clone();
+            let refresher = refresher.clone();
+            // With S3 the bucket, not the local directory, says what is already stored
+            let s3 = s3.clone();
+            let s3_key = s3.as_ref().map(|s3| {
+                manifest.get(&img_path)
+                    .and_then(|e| e.s3_url.as_deref())
+                    .and_then(|url| s3.key_from_url(url))
+                    .map(String::from)
+                    .unwrap_or_else(|| s3.key(&checksum::relative_name(&args.images_dir, &img_path)))
+            });
+
+            tasks.push(tokio::spawn(async move {
+                // Held until the image, its conversion and hook are all done
+                let _claim = in_flight.claim(&img_path).await;
+                if present_elsewhere {
+                    return (TaskStatus::Existing(known_size), None);
+                }
+                if let (Some(s3), Some(key)) = (&s3, &s3_key) {
+                    match s3.exists(key).await {
+                        Ok(true) => return (TaskStatus::Existing(known_size), None),
+                        Ok(false) => {}
+                        Err(e) => eprintln!("{:#}, downloading anyway", e),
+                    }
+                }
+
+                let (mut outcome, mut attempts) = download_with_retries(
+                    &client, &task.url, &img_path, &limits, refresh.as_ref(), retries,
+                ).await;
+                let mut refreshed_url = None;
+                if let (Err(e), Some(refresher)) = (&outcome, &refresher)
+                    && matches!(failures::classify(e).as_str(), "http 403" | "http 404")
+                    && let Some(fresh) = refresher.fresh_task(&task).await
+                {
+                    let (retried, more) = download_with_retries(
+                        &client, &fresh.url, &img_path, &limits, refresh.as_ref(), retries,
+                    ).await;
+                    attempts += more;
+                    if retried.is_ok() {
+                        println!("Downloaded {} from refreshed URL {}", task.url, fresh.url);
+                        refresher.record_success();
+                        refreshed_url = Some(fresh.url.clone());
+                        task = fresh;
+                    }
+                    outcome = retried;
+                }
+                if sidecars && let Ok(outcome) = &outcome {
+                    write_sidecar(&task, outcome);
+                }
+
+                match outcome {
+                    Ok(DownloadOutcome::Downloaded { path, size, sha256, etag, replaced, elapsed }) => {
+                        let mut entry = ManifestEntry::new(&task, &path, size, sha256, Local::now().to_rfc3339());
+                        entry.etag = etag;
+                        entry.refreshed_url = refreshed_url;
+                        if let Some(format) = convert_to {
+                            convert_downloaded(&mut entry, format, keep_original).await;
+                        }
+                        if let Some(hook) = &hook {
+                            hook.run(&entry).await;
+                        }
+                        let status = if replaced { TaskStatus::Refreshed(elapsed) } else { TaskStatus::Downloaded(elapsed) };
+                        (status, Some(entry))
+                    }
+                    Ok(DownloadOutcome::Existing(path)) => {
+                        let size = fs::metadata(&path).map_or(known_size, |m| m.len());
+                        if in_manifest {
+                            return (TaskStatus::Existing(size), None);
+                        }
+                        // Downloaded before the manifest existed, record it now
+                        match ManifestEntry::from_existing(&task, &path) {
+                            Ok(entry) => (TaskStatus::Existing(size), Some(entry)),
+                            Err(e) => {
+                                eprintln!("Failed to record existing image {}: {}", path.display(), e);
+                                (TaskStatus::Existing(size), None)
+                            }
+                        }
+                    }
+                    Ok(DownloadOutcome::TooLarge(size)) => {
+                        eprintln!("Skipped image {}: too large ({} bytes)", task.url, size);
+                        let failure = ImageFailure {
+                            reason: failures::TOO_LARGE.to_string(),
+                            error: format!("{} bytes", size),
+                            attempts,
+                            failed_at: Local::now().to_rfc3339(),
+                            task,
+                        };
+                        (TaskStatus::TooLarge(failure), None)
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to download image {}: {:#}", task.url, e);
+                        let failure = ImageFailure {
+                            reason: failures::classify(&e),
+                            error: format!("{:#}", e),
+                            attempts,
+                            failed_at: Local::now().to_rfc3339(),
+                            task,
+                        };
+                        (TaskStatus::Failed(failure), None)
+                    }
+                }
+            }));
+        }
+
+        // Process results from this batch
+        for task in tasks {
+            if let Ok((status, entry)) = task.await {
+                match status {
+                    TaskStatus::Downloaded(elapsed) | TaskStatus::Refreshed(elapsed) => {
+                        downloaded_count += 1;
+                        if matches!(status, TaskStatus::Refreshed(_)) {
+                            refreshed_count += 1;
+                        }
+                        if let Some(entry) = &entry {
+                            stats.record_download(entry.local_path.clone(), entry.size, elapsed);
+                        }
+                    }
+                    TaskStatus::Existing(size) => {
+                        downloaded_count += 1;
+                        existing_count += 1;
+                        stats.record_skipped(size);
+                    }
+                    TaskStatus::TooLarge(failure) => {
+                        too_large_count += 1;
+                        failures.push(failure);
+                    }
+                    TaskStatus::Failed(failure) => failures.push(failure),
+                }
+                if let Some(entry) = entry {
+                    manifest.insert(entry);
+                }
+            }
+        }
+
+        println!("Completed batch {}/{} - Downloaded {}/{} images ({:.1}%)",
+            batch_idx + 1,
+            total_tasks.div_ceil(concurrency),
+            downloaded_count,
+            total_tasks,
+            downloaded_count as f64 * 100.0 / total_tasks as f64
+        );
+
+        if let (Some(hook), Some(limit)) = (&hook, args.max_post_download_failures)
+            && hook.failures() > limit
+        {
+            // Keep what was downloaded so far before giving up
+            manifest.save(&manifest_path)?;
+            checksum::write_sums(&manifest, &args.images_dir, &args.images_dir.join(checksum::SUMS_FILE_NAME))?;
+            anyhow::bail!("Aborting: {} post-download commands failed (limit {})", hook.failures(), limit);
+        }
+
+        // Add delay between batches to avoid rate limiting
+        sleep(tokio::time::Duration::from_millis(500)).await;
+    }
+
+    println!("Downloaded {}/{} images", downloaded_count, total_tasks);
+    let transfer = stats.summarize(started.elapsed());
+    transfer.print();
+    if args.refresh {
+        println!("Refreshed {} images with newer remote copies", refreshed_count);
+    }
+    if too_large_count > 0 {
+        println!("Skipped {} images: too large", too_large_count);
+    }
+    let failed = failures.len() - too_large_count;
+    if failed > 0 {
+        println!("Failed to download {} images:", failed);
+        for (reason, count) in failures::by_reason(&failures) {
+            if reason != failures::TOO_LARGE {
+                println!("  {:<12} {}", reason, count);
+            }
+        }
+    }
+    if let Some(hook) = &hook
+        && hook.failures() > 0
+    {
+        println!("Post-download command failed for {} images", hook.failures());
+    }
+    if not_attempted > 0 {
+        println!("Not attempted {} images: low disk space", not_attempted);
+    }
+    if let Some(refresher) = &refresher {
+        println!("Refreshed {} expired URLs, {} documents gone from the API",
+            refresher.refreshed(),
+            refresher.gone()
+        );
+    }
+
+    if let Some(dir) = &args.thumbnails {
+        let paths: Vec<PathBuf> = download_tasks.iter().map(|task| args.image_path(task)).collect();
+        generate_thumbnails(&mut manifest, &paths, dir, args.thumbnail_size, concurrency).await;
+    }
+
+    if let Some(archive_path) = &args.archive {
+        let paths: Vec<PathBuf> = download_tasks.iter().map(|task| args.image_path(task)).collect();
+        archive_images(&mut manifest, &paths, args, archive_path, &archive_index).await?;
+    }
+
+    if let Some(s3) = &s3 {
+        let paths: Vec<PathBuf> = download_tasks.iter().map(|task| args.image_path(task)).collect();
+        let failed = upload_images(&mut manifest, &paths, &args.images_dir, s3, concurrency).await;
+        if failed > 0 {
+            println!("Failed to upload {} images to S3 (kept locally for the next run)", failed);
+        }
+    }
+
+    manifest.save(&manifest_path)?;
+    checksum::write_sums(&manifest, &args.images_dir, &args.images_dir.join(checksum::SUMS_FILE_NAME))?;
+    println!("Wrote {} entries to manifest {}", manifest.len(), manifest_path.display());
+
+    let failures_path = args.failures_path();
+    let attempted = download_tasks[..total_tasks - not_attempted].iter().map(|task| task.url.clone()).collect();
+    let remaining = failures::update(&failures_path, &attempted, failures)?;
+    if remaining > 0 {
+        println!("Recorded {} failed documents in {}", remaining, failures_path.display());
+    }
+
+    let summary = RunSummary {
+        started_at,
+        finished_at: Local::now().to_rfc3339(),
+        tasks: total_tasks,
+        downloaded: downloaded_count - existing_count,
+        refreshed: refreshed_count,
+        existing: existing_count,
+        too_large: too_large_count,
+        failed,
+        not_attempted,
+        urls_refreshed: refresher.as_ref().map_or(0, |r| r.refreshed()),
+        urls_gone: refresher.as_ref().map_or(0, |r| r.gone()),
+        transfer,
+    };
+    let summary_path = args.summary_path();
+    summary.save(&summary_path)?;
+    println!("Wrote run summary to {}", summary_path.display());
+
+    Ok(ImagePhaseReport { failed, not_attempted })
+}
+
+/// Regenerate the manifest from scratch by checking which task files exist
+/// in the images directory. Nothing is downloaded.
+pub fn rebuild_manifest(download_tasks: &[DownloadTask], args: &ImageArgs) -> Result<()> {
+    let manifest_path = args.manifest_path();
+    let mut manifest = Manifest::default();
+    let mut missing = 0;
+
+    for task in download_tasks {
+        let path = args.image_path(task);
+        if !path.exists() {
+            missing += 1;
+            continue;
+        }
+        manifest.insert(ManifestEntry::from_existing(task, &path)?);
+    }
+
+    manifest.save(&manifest_path)?;
+    checksum::write_sums(&manifest, &args.images_dir, &args.images_dir.join(checksum::SUMS_FILE_NAME))?;
+    println!("Rebuilt manifest {} with {} entries ({} images not on disk)",
+        manifest_path.display(),
+        manifest.len(),
+        missing
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn application_num_of_downloader_names() {
+        assert_eq!(application_num_of("40201912345X_rep.jpg"), Some("40201912345X"));
+        assert_eq!(application_num_of("40201400001U_ead6e817-8028-4ea7-a620-52d827068638.jpg"), Some("40201400001U"));
+        assert_eq!(application_num_of("402019123456_rep.jpg"), Some("402019123456"));
+    }
+
+    #[test]
+    fn application_num_of_other_names() {
+        assert_eq!(application_num_of("dup_x.png"), None);
+        assert_eq!(application_num_of("mark.png"), None);
+        assert_eq!(application_num_of("_rep.jpg"), None);
+        assert_eq!(application_num_of("40201912345X_"), None);
+        assert_eq!(application_num_of("4020X1912345_rep.jpg"), None);
+        assert_eq!(application_num_of("40201912345XY_rep.jpg"), None);
+    }
+
+    #[test]
+    fn retries_are_bounded() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            images: ImageArgs,
+        }
+        let parse = |retries: &str| <Cli as clap::Parser>::try_parse_from(["images", "--image-retries", retries]);
+        assert_eq!(parse("20").unwrap().images.image_retries, 20);
+        assert!(parse("21").is_err());
+        assert!(parse("64").is_err());
+        let s3 = <Cli as clap::Parser>::try_parse_from(["images", "--s3-bucket", "marks", "--s3-retries", "64"]);
+        assert!(s3.is_err());
+    }
+}
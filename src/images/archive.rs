@@ -0,0 +1,157 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+
+/// Archive formats, picked from the file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    TarZst,
+    Zip,
+}
+
+impl ArchiveKind {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_lowercase()).unwrap_or_default();
+        if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Ok(ArchiveKind::TarZst)
+        } else if name.ends_with(".zip") {
+            Ok(ArchiveKind::Zip)
+        } else {
+            anyhow::bail!("Unsupported archive type (expected .tar.zst or .zip): {}", path.display())
+        }
+    }
+}
+
+/// Names of the members already in an archive, empty if it doesn't exist
+pub fn read_index(path: &Path) -> Result<BTreeSet<String>> {
+    if !path.exists() {
+        return Ok(BTreeSet::new());
+    }
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open archive: {}", path.display()))?;
+
+    match ArchiveKind::from_path(path)? {
+        ArchiveKind::Zip => {
+            let archive = zip::ZipArchive::new(BufReader::new(file))
+                .with_context(|| format!("Failed to read archive: {}", path.display()))?;
+            Ok(archive.file_names().map(String::from).collect())
+        }
+        ArchiveKind::TarZst => {
+            let decoder = zstd::Decoder::new(file).context("Failed to start zstd decoder")?;
+            let mut archive = tar::Archive::new(decoder);
+            let mut names = BTreeSet::new();
+            for entry in archive.entries().context("Failed to read tar archive")? {
+                let entry = entry.context("Failed to read tar entry")?;
+                names.insert(entry.path().context("Invalid tar entry path")?.to_string_lossy().into_owned());
+            }
+            Ok(names)
+        }
+    }
+}
+
+/// Writer adding members to a new or existing archive.
+///
+/// Zip archives are appended to in place. A zstd-compressed tar can't be
+/// appended to, so its existing members are copied into a new archive that
+/// replaces the old one on `finish`.
+pub enum ArchiveWriter {
+    Zip(zip::ZipWriter<File>),
+    TarZst {
+        builder: tar::Builder<zstd::Encoder<'static, BufWriter<File>>>,
+        tmp_path: PathBuf,
+        path: PathBuf,
+    },
+}
+
+impl ArchiveWriter {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        }
+
+        match ArchiveKind::from_path(path)? {
+            ArchiveKind::Zip => {
+                let writer = if path.exists() {
+                    let file = File::options().read(true).write(true).open(path)
+                        .with_context(|| format!("Failed to open archive: {}", path.display()))?;
+                    zip::ZipWriter::new_append(file)
+                        .with_context(|| format!("Failed to read archive: {}", path.display()))?
+                } else {
+                    let file = File::create(path)
+                        .with_context(|| format!("Failed to create archive: {}", path.display()))?;
+                    zip::ZipWriter::new(file)
+                };
+                Ok(ArchiveWriter::Zip(writer))
+            }
+            ArchiveKind::TarZst => {
+                let mut tmp_path = path.as_os_str().to_owned();
+                tmp_path.push(".tmp");
+                let tmp_path = PathBuf::from(tmp_path);
+                let file = File::create(&tmp_path)
+                    .with_context(|| format!("Failed to create archive: {}", tmp_path.display()))?;
+                let encoder = zstd::Encoder::new(BufWriter::new(file), 0)
+                    .context("Failed to start zstd encoder")?;
+                let mut builder = tar::Builder::new(encoder);
+
+                if path.exists() {
+                    let file = File::open(path)
+                        .with_context(|| format!("Failed to open archive: {}", path.display()))?;
+                    let mut existing = tar::Archive::new(zstd::Decoder::new(file).context("Failed to start zstd decoder")?);
+                    for entry in existing.entries().context("Failed to read tar archive")? {
+                        let mut entry = entry.context("Failed to read tar entry")?;
+                        let header = entry.header().clone();
+                        builder.append(&header, &mut entry).context("Failed to copy tar entry")?;
+                    }
+                }
+
+                Ok(ArchiveWriter::TarZst { builder, tmp_path, path: path.to_path_buf() })
+            }
+        }
+    }
+
+    /// Add the file at `source` as `member`
+    pub fn add(&mut self, member: &str, source: &Path) -> Result<()> {
+        match self {
+            ArchiveWriter::Zip(writer) => {
+                // Images are already compressed, so deflating them again mostly wastes time
+                let options = SimpleFileOptions::default()
+                    .compression_method(zip::CompressionMethod::Stored)
+                    .large_file(true);
+                writer.start_file(member, options)
+                    .with_context(|| format!("Failed to add archive member: {}", member))?;
+                let mut file = File::open(source)
+                    .with_context(|| format!("Failed to open file: {}", source.display()))?;
+                std::io::copy(&mut file, writer)
+                    .with_context(|| format!("Failed to archive file: {}", source.display()))?;
+            }
+            ArchiveWriter::TarZst { builder, .. } => {
+                builder.append_path_with_name(source, member)
+                    .with_context(|| format!("Failed to archive file: {}", source.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the archive index and, for tar, move the new archive into place
+    pub fn finish(self) -> Result<()> {
+        match self {
+            ArchiveWriter::Zip(writer) => {
+                writer.finish().context("Failed to finish zip archive")?;
+            }
+            ArchiveWriter::TarZst { builder, tmp_path, path } => {
+                let encoder = builder.into_inner().context("Failed to finish tar archive")?;
+                encoder.finish().context("Failed to finish zstd stream")?
+                    .flush().context("Failed to write archive")?;
+                fs::rename(&tmp_path, &path)
+                    .with_context(|| format!("Failed to save archive: {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
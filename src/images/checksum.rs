@@ -0,0 +1,241 @@
+use anyhow::{Context, Result};
+use futures::stream::{self, StreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::manifest::Manifest;
+use super::{is_bookkeeping, sidecar};
+
+/// Default checksum file name, written inside the images directory
+pub const SUMS_FILE_NAME: &str = "SHA256SUMS";
+
+/// Hash a file by streaming it through the digest
+pub fn hash_file(path: &Path) -> Result<(u64, String)> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    let mut size = 0;
+
+    loop {
+        let n = reader.read(&mut buffer)
+            .with_context(|| format!("Failed to read file: {}", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        size += n as u64;
+    }
+
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Path of a file relative to the images directory, as stored in SHA256SUMS
+pub(crate) fn relative_name(images_dir: &Path, path: &Path) -> String {
+    path.strip_prefix(images_dir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+/// Write a `sha256sum -c` compatible file covering every manifest entry
+pub fn write_sums(manifest: &Manifest, images_dir: &Path, sums_path: &Path) -> Result<()> {
+    let tmp_path = sums_path.with_extension("tmp");
+    let file = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create checksum file: {}", tmp_path.display()))?;
+    let mut writer = BufWriter::new(file);
+
+    for (path, sha256) in manifest.files() {
+        writeln!(writer, "{}  {}", sha256, relative_name(images_dir, path))
+            .context("Failed to write checksum file")?;
+    }
+    writer.flush().context("Failed to write checksum file")?;
+    drop(writer);
+
+    fs::rename(&tmp_path, sums_path)
+        .with_context(|| format!("Failed to replace checksum file: {}", sums_path.display()))?;
+
+    Ok(())
+}
+
+/// Read a SHA256SUMS file into a map of relative path to hash
+pub fn read_sums(sums_path: &Path) -> Result<BTreeMap<String, String>> {
+    let file = File::open(sums_path)
+        .with_context(|| format!("Failed to open checksum file: {}", sums_path.display()))?;
+    let mut sums = BTreeMap::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line.context("Failed to read checksum file")?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        // "<hash>  <name>", with '*' marking binary mode in some tools
+        let (hash, name) = line.split_once(char::is_whitespace)
+            .with_context(|| format!("Malformed checksum line: {}", line))?;
+        let name = name.trim_start().trim_start_matches('*');
+        sums.insert(name.to_string(), hash.to_lowercase());
+    }
+
+    Ok(sums)
+}
+
+/// Recursively list the files under a directory
+pub fn walk_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current)
+            .with_context(|| format!("Failed to read directory: {}", current.display()))?
+        {
+            let path = entry.context("Failed to read directory entry")?.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Outcome of verifying an images directory against its checksum file
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+    pub corrupted: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.corrupted.is_empty()
+    }
+
+    /// Exit status of `verify-images`: 1 when any file is missing, extra or
+    /// corrupted
+    pub fn exit_code(&self) -> i32 {
+        if self.is_ok() { 0 } else { 1 }
+    }
+}
+
+/// Re-hash every file in `images_dir` on a pool of `workers` blocking threads
+/// and compare against the stored sums.
+pub async fn verify(images_dir: &Path, sums_path: &Path, workers: usize) -> Result<VerifyReport> {
+    let sums = read_sums(sums_path)?;
+    let mut report = VerifyReport::default();
+
+    // Bookkeeping files living next to the images are not part of the sums
+    let all_files: BTreeSet<String> = walk_files(images_dir)?
+        .iter()
+        .map(|path| relative_name(images_dir, path))
+        .collect();
+    let on_disk: BTreeSet<String> = all_files.iter()
+        .filter(|name| !is_bookkeeping(name) && !sidecar::is_sidecar(name, &all_files))
+        .cloned()
+        .collect();
+
+    report.missing = sums.keys().filter(|name| !on_disk.contains(*name)).cloned().collect();
+    report.extra = on_disk.iter().filter(|name| !sums.contains_key(*name)).cloned().collect();
+
+    let to_check: Vec<(String, String)> = sums.into_iter()
+        .filter(|(name, _)| on_disk.contains(name))
+        .collect();
+
+    // Each result says whether the file still matches its stored hash
+    let results: Vec<(String, Result<bool>)> = stream::iter(to_check)
+        .map(|(name, expected)| {
+            let path = images_dir.join(&name);
+            async move {
+                let matches = tokio::task::spawn_blocking(move || hash_file(&path))
+                    .await
+                    .context("Hashing task panicked")
+                    .and_then(|r| r)
+                    .map(|(_, hash)| hash == expected);
+                (name, matches)
+            }
+        })
+        .buffer_unordered(workers.max(1))
+        .collect()
+        .await;
+
+    for (name, matches) in results {
+        report.checked += 1;
+        match matches {
+            Ok(true) => {}
+            Ok(false) => report.corrupted.push(name),
+            Err(e) => {
+                eprintln!("Failed to hash {}: {}", name, e);
+                report.corrupted.push(name);
+            }
+        }
+    }
+    report.corrupted.sort();
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::DownloadTask;
+    use crate::images::manifest::{ManifestEntry, MANIFEST_FILE_NAME};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tm-query-checksum-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn task(file_name: &str) -> DownloadTask {
+        serde_json::from_value(serde_json::json!({
+            "url": format!("https://example.com/{}", file_name),
+            "applicationNum": "40201912345X",
+            "fileName": file_name,
+            "fileId": null,
+            "docType": null,
+            "lodgementDate": null,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn verify_sorts_missing_corrupted_and_extra_files() {
+        let dir = temp_dir();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let mut manifest = Manifest::default();
+        for (name, contents) in [("a.jpg", "a"), ("b.jpg", "b"), ("c.jpg", "c"), ("sub/d.jpg", "d")] {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            manifest.insert(ManifestEntry::from_existing(&task(name), &path).unwrap());
+        }
+        manifest.save(&dir.join(MANIFEST_FILE_NAME)).unwrap();
+        fs::write(dir.join("a.jpg.json"), "{}").unwrap();
+        let sums_path = dir.join(SUMS_FILE_NAME);
+        write_sums(&manifest, &dir, &sums_path).unwrap();
+
+        // The sums, the manifest and the sidecar are not images
+        let report = verify(&dir, &sums_path, 2).await.unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!((report.checked, report.exit_code()), (4, 0));
+
+        fs::remove_file(dir.join("b.jpg")).unwrap();
+        fs::write(dir.join("sub/d.jpg"), "tampered").unwrap();
+        fs::write(dir.join("e.jpg"), "e").unwrap();
+        let report = verify(&dir, &sums_path, 2).await.unwrap();
+        assert_eq!(report.missing, ["b.jpg"]);
+        assert_eq!(report.corrupted, ["sub/d.jpg"]);
+        assert_eq!(report.extra, ["e.jpg"]);
+        assert_eq!(report.checked, 3);
+        assert!(!report.is_ok());
+        assert_ne!(report.exit_code(), 0);
+    }
+}
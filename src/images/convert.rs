@@ -0,0 +1,95 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use image::{ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use super::checksum;
+
+/// Format that downloaded TIFFs are converted to
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConvertFormat {
+    Png,
+    Jpeg,
+}
+
+impl ConvertFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ConvertFormat::Png => "png",
+            ConvertFormat::Jpeg => "jpg",
+        }
+    }
+
+    fn image_format(self) -> ImageFormat {
+        match self {
+            ConvertFormat::Png => ImageFormat::Png,
+            ConvertFormat::Jpeg => ImageFormat::Jpeg,
+        }
+    }
+}
+
+/// A converted copy of a downloaded image, as recorded in the manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConvertedImage {
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    /// Pages in the source TIFF; only the first one is converted
+    pub pages: u32,
+}
+
+/// Convert `source` to `format` next to it if it is a TIFF. Returns `None`
+/// for images that are already in another format.
+pub fn convert_tiff(source: &Path, format: ConvertFormat) -> Result<Option<ConvertedImage>> {
+    let reader = ImageReader::open(source)
+        .with_context(|| format!("Failed to open image: {}", source.display()))?
+        .with_guessed_format()
+        .with_context(|| format!("Failed to read image: {}", source.display()))?;
+    if reader.format() != Some(ImageFormat::Tiff) {
+        return Ok(None);
+    }
+
+    let pages = count_pages(source)?;
+    // The image crate decodes the first page of a multi-page TIFF
+    let image = reader.decode()
+        .with_context(|| format!("Failed to decode image: {}", source.display()))?;
+    // JPEG has no alpha channel or 16-bit samples
+    let image = match format {
+        ConvertFormat::Jpeg => image::DynamicImage::ImageRgb8(image.to_rgb8()),
+        ConvertFormat::Png => image,
+    };
+
+    let target = source.with_extension(format.extension());
+    if target == source {
+        anyhow::bail!("TIFF file has a .{} extension: {}", format.extension(), source.display());
+    }
+    let tmp_path = target.with_extension(format!("{}.tmp", format.extension()));
+    image.save_with_format(&tmp_path, format.image_format())
+        .with_context(|| format!("Failed to write converted image: {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, &target)
+        .with_context(|| format!("Failed to save converted image: {}", target.display()))?;
+
+    let (size, sha256) = checksum::hash_file(&target)?;
+    Ok(Some(ConvertedImage { path: target, size, sha256, pages }))
+}
+
+/// Number of pages (image file directories) in a TIFF
+fn count_pages(path: &Path) -> Result<u32> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open image: {}", path.display()))?;
+    let mut decoder = tiff::decoder::Decoder::new(BufReader::new(file))
+        .with_context(|| format!("Failed to read TIFF: {}", path.display()))?;
+
+    let mut pages = 1;
+    while decoder.more_images() {
+        decoder.next_image()
+            .with_context(|| format!("Failed to read TIFF page: {}", path.display()))?;
+        pages += 1;
+    }
+
+    Ok(pages)
+}
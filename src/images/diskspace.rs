@@ -0,0 +1,102 @@
+use anyhow::{Context, Result};
+use futures::future::join_all;
+use reqwest::Client;
+use std::path::Path;
+
+use crate::data::DownloadTask;
+use super::format_bytes;
+use super::manifest::Manifest;
+
+/// Space still needed for the documents of a run, extrapolated from a sample
+#[derive(Debug, Clone, Copy)]
+pub struct Estimate {
+    pub bytes: u64,
+    /// Number of sizes the estimate is based on
+    pub sampled: usize,
+    pub from_head: bool,
+}
+
+/// Bytes available to this user on the filesystem holding `path`
+pub fn available(path: &Path) -> Result<u64> {
+    fs4::available_space(path)
+        .with_context(|| format!("Failed to read free space for {}", path.display()))
+}
+
+/// Estimate the space `pending` will take. Up to `sample` documents spread
+/// evenly over the list are asked for their Content-Length with HEAD requests;
+/// if no server answers with one, the average size of the images already in
+/// the manifest is used instead. Returns `None` when neither is known.
+pub async fn estimate(client: &Client, pending: &[&DownloadTask], manifest: &Manifest, sample: usize) -> Option<Estimate> {
+    if pending.is_empty() {
+        return Some(Estimate { bytes: 0, sampled: 0, from_head: false });
+    }
+
+    let step = pending.len().div_ceil(sample.max(1));
+    let lengths: Vec<u64> = join_all(pending.iter().step_by(step).map(|task| content_length(client, &task.url)))
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+    if !lengths.is_empty() {
+        return Some(extrapolate(&lengths, pending.len(), true));
+    }
+
+    let sizes: Vec<u64> = manifest.entries()
+        .filter(|e| e.skipped.is_none())
+        .map(|e| e.size)
+        .collect();
+    (!sizes.is_empty()).then(|| extrapolate(&sizes, pending.len(), false))
+}
+
+fn extrapolate(sizes: &[u64], total: usize, from_head: bool) -> Estimate {
+    let average = sizes.iter().sum::<u64>() as f64 / sizes.len() as f64;
+    Estimate { bytes: (average * total as f64) as u64, sampled: sizes.len(), from_head }
+}
+
+/// Content-Length advertised for a URL, if the server answers HEAD with one
+async fn content_length(client: &Client, url: &str) -> Option<u64> {
+    let response = client.head(url).send().await.ok()?.error_for_status().ok()?;
+    response.headers()
+        .get(reqwest::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Check that the images directory can hold `pending` on top of the
+/// `min_free` floor, failing with an explanation when it can't
+pub async fn preflight(
+    client: &Client,
+    dir: &Path,
+    pending: &[&DownloadTask],
+    manifest: &Manifest,
+    sample: usize,
+    min_free: u64,
+) -> Result<()> {
+    let Some(estimate) = estimate(client, pending, manifest, sample).await else {
+        println!("Skipping disk space check: no document sizes known");
+        return Ok(());
+    };
+    let free = available(dir)?;
+    println!("Estimated {} needed for {} new images ({} from {} sizes), {} free",
+        format_bytes(estimate.bytes),
+        pending.len(),
+        if estimate.from_head { "extrapolated" } else { "manifest average" },
+        estimate.sampled,
+        format_bytes(free)
+    );
+
+    if estimate.bytes.saturating_add(min_free) > free {
+        anyhow::bail!(
+            "Not enough disk space in {}: about {} needed plus {} to keep free, only {} available. \
+             Free up space, lower --min-free-space or pass --ignore-disk-check",
+            dir.display(),
+            format_bytes(estimate.bytes),
+            format_bytes(min_free),
+            format_bytes(free)
+        );
+    }
+
+    Ok(())
+}
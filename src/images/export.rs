@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::data::DownloadTask;
+use super::manifest::Manifest;
+use super::{ImageArgs, cap_per_mark, dedup_by_file_id};
+
+/// One line of a JSONL URL export
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UrlRecord<'a> {
+    url: &'a str,
+    application_num: &'a str,
+    file_id: Option<&'a str>,
+    doc_type: Option<&'a str>,
+}
+
+/// Write the documents the image phase would download to `path`, one per
+/// line, as JSONL if the file ends in `.jsonl` and TSV otherwise. The same
+/// duplicate and per-mark filters are applied. Returns the number written.
+pub fn export_urls(tasks: &[DownloadTask], args: &ImageArgs, path: &Path) -> Result<usize> {
+    let (tasks, _) = dedup_by_file_id(tasks);
+    let (tasks, _) = cap_per_mark(&tasks, args.max_images_per_mark);
+    let jsonl = path.extension().is_some_and(|ext| ext == "jsonl");
+
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create URL export: {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+
+    if !jsonl {
+        writeln!(writer, "url\tapplicationNum\tfileId\tdocType").context("Failed to write URL export")?;
+    }
+    for task in &tasks {
+        if jsonl {
+            let record = UrlRecord {
+                url: &task.url,
+                application_num: &task.app_num,
+                file_id: task.file_id.as_deref(),
+                doc_type: task.doc_type.as_deref(),
+            };
+            serde_json::to_writer(&mut writer, &record).context("Failed to write URL export")?;
+            writeln!(writer).context("Failed to write URL export")?;
+        } else {
+            writeln!(writer, "{}\t{}\t{}\t{}",
+                tsv_field(&task.url),
+                tsv_field(&task.app_num),
+                tsv_field(task.file_id.as_deref().unwrap_or_default()),
+                tsv_field(task.doc_type.as_deref().unwrap_or_default())
+            ).context("Failed to write URL export")?;
+        }
+    }
+    writer.flush().context("Failed to write URL export")?;
+
+    println!("Exported {} document URLs to {}", tasks.len(), path.display());
+    Ok(tasks.len())
+}
+
+/// One row of the documents CSV
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentRow<'a> {
+    application_num: &'a str,
+    file_id: Option<&'a str>,
+    file_name: &'a str,
+    doc_type: Option<&'a str>,
+    doc_type_description: Option<&'a str>,
+    lodgement_date: Option<&'a str>,
+    url: &'a str,
+    size: Option<u64>,
+    sha256: Option<&'a str>,
+}
+
+/// Inventory of every document in a dataset, one CSV row each, with size and
+/// hash joined from the manifest for images already on disk. Rows are written
+/// as tasks arrive so the data file can be streamed.
+pub struct DocumentsCsv {
+    writer: csv::Writer<File>,
+    manifest: Manifest,
+    args: ImageArgs,
+    rows: usize,
+}
+
+impl DocumentsCsv {
+    pub fn create(path: &Path, args: &ImageArgs) -> Result<Self> {
+        let writer = csv::Writer::from_path(path)
+            .with_context(|| format!("Failed to create documents CSV: {}", path.display()))?;
+        Ok(DocumentsCsv {
+            writer,
+            manifest: Manifest::load(&args.manifest_path())?,
+            args: args.clone(),
+            rows: 0,
+        })
+    }
+
+    pub fn write(&mut self, task: &DownloadTask) -> Result<()> {
+        let path = self.args.image_path(task);
+        let local = self.manifest.get(&path).filter(|e| e.skipped.is_none() && path.exists());
+
+        self.writer.serialize(DocumentRow {
+            application_num: &task.app_num,
+            file_id: task.file_id.as_deref(),
+            file_name: &task.file_name,
+            doc_type: task.doc_type.as_deref(),
+            doc_type_description: task.doc_type_description.as_deref(),
+            lodgement_date: task.lodgement_date.as_deref(),
+            url: &task.url,
+            size: local.map(|e| e.size),
+            sha256: local.map(|e| e.sha256.as_str()),
+        }).context("Failed to write documents CSV")?;
+        self.rows += 1;
+
+        Ok(())
+    }
+
+    /// Flush the file and return the number of rows written
+    pub fn finish(mut self) -> Result<usize> {
+        self.writer.flush().context("Failed to write documents CSV")?;
+        Ok(self.rows)
+    }
+}
+
+/// Keep a value on one TSV line and in one column
+fn tsv_field(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
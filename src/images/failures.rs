@@ -0,0 +1,107 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::path::Path;
+
+use crate::data::DownloadTask;
+
+/// Default failure report name, written inside the images directory
+pub const FAILURES_FILE_NAME: &str = "image_failures.json";
+
+/// Reason recorded for documents skipped by `--max-image-bytes`
+pub const TOO_LARGE: &str = "skipped: too large";
+
+/// A document that could not be downloaded, with enough of its task to try again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageFailure {
+    #[serde(flatten)]
+    pub task: DownloadTask,
+    /// Error class such as `http 404`, `timeout` or `connect`
+    pub reason: String,
+    pub error: String,
+    pub attempts: u32,
+    pub failed_at: String,
+}
+
+/// Classify a download error by the first HTTP or IO error in its chain
+pub fn classify(error: &anyhow::Error) -> String {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return if let Some(status) = e.status() {
+                format!("http {}", status.as_u16())
+            } else if e.is_timeout() {
+                "timeout".to_string()
+            } else if e.is_connect() {
+                "connect".to_string()
+            } else if e.is_body() || e.is_decode() {
+                "body".to_string()
+            } else {
+                "request".to_string()
+            };
+        }
+        if cause.downcast_ref::<std::io::Error>().is_some() {
+            return "io".to_string();
+        }
+    }
+    "other".to_string()
+}
+
+/// Whether a failure class is worth retrying: network trouble, server errors
+/// and rate limiting, but not missing documents or local disk errors
+pub fn is_transient(reason: &str) -> bool {
+    match reason.strip_prefix("http ").and_then(|code| code.parse::<u16>().ok()) {
+        Some(code) => code >= 500 || code == 408 || code == 429,
+        None => matches!(reason, "timeout" | "connect" | "body" | "request"),
+    }
+}
+
+/// Load a failure report, returning an empty list if it doesn't exist
+pub fn load(path: &Path) -> Result<Vec<ImageFailure>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open failure report: {}", path.display()))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to parse failure report: {}", path.display()))
+}
+
+/// Merge the failures of a run into the report at `path`. Entries for URLs
+/// attempted in this run are replaced, so documents that now succeeded drop
+/// out. The file is removed once nothing is left.
+pub fn update(path: &Path, attempted: &BTreeSet<String>, failures: Vec<ImageFailure>) -> Result<usize> {
+    let mut remaining: Vec<ImageFailure> = load(path)?
+        .into_iter()
+        .filter(|f| !attempted.contains(&f.task.url))
+        .collect();
+    remaining.extend(failures);
+
+    if remaining.is_empty() {
+        if path.exists() {
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to remove failure report: {}", path.display()))?;
+        }
+        return Ok(0);
+    }
+
+    let tmp_path = path.with_extension("json.tmp");
+    let file = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create failure report: {}", tmp_path.display()))?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(file), &remaining)
+        .context("Failed to write failure report")?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to save failure report: {}", path.display()))?;
+
+    Ok(remaining.len())
+}
+
+/// Number of failures per reason
+pub fn by_reason(failures: &[ImageFailure]) -> BTreeMap<&str, usize> {
+    let mut counts = BTreeMap::new();
+    for failure in failures {
+        *counts.entry(failure.reason.as_str()).or_default() += 1;
+    }
+    counts
+}
@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+use super::manifest::ManifestEntry;
+
+/// Shell command run after each successful download, shared by all tasks
+#[derive(Debug)]
+pub struct PostDownloadHook {
+    template: String,
+    timeout: Duration,
+    slots: Semaphore,
+    failures: AtomicUsize,
+}
+
+impl PostDownloadHook {
+    pub fn new(template: String, jobs: usize, timeout: Duration) -> Self {
+        PostDownloadHook {
+            template,
+            timeout,
+            slots: Semaphore::new(jobs.max(1)),
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of invocations that failed, timed out or exited non-zero
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Run the hook for a downloaded image. A failure is logged and counted
+    /// but never affects the image itself.
+    pub async fn run(&self, entry: &ManifestEntry) {
+        let command = render(&self.template, entry);
+        // The semaphore is never closed, so acquiring can't fail
+        let _slot = self.slots.acquire().await;
+
+        if let Err(e) = self.invoke(&command).await {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            eprintln!("Post-download command failed for {}: {:#}", entry.image_path().display(), e);
+        }
+    }
+
+    async fn invoke(&self, command: &str) -> Result<()> {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Failed to start command")?;
+
+        let output = tokio::time::timeout(self.timeout, child.wait_with_output())
+            .await
+            .with_context(|| format!("Timed out after {}s", self.timeout.as_secs()))?
+            .context("Failed to wait for command")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stderr = stderr.trim();
+            if stderr.is_empty() {
+                anyhow::bail!("{}", output.status);
+            }
+            anyhow::bail!("{}: {}", output.status, stderr);
+        }
+
+        Ok(())
+    }
+}
+
+/// Substitute `{path}`, `{app_num}`, `{file_id}`, `{file_name}`, `{doc_type}`
+/// and `{sha256}` in a command template. Values are shell-quoted because they
+/// come from the API.
+pub fn render(template: &str, entry: &ManifestEntry) -> String {
+    let path = entry.image_path().to_string_lossy();
+    let placeholders = [
+        ("{path}", path.as_ref()),
+        ("{app_num}", entry.application_num.as_str()),
+        ("{file_id}", entry.file_id.as_deref().unwrap_or_default()),
+        ("{file_name}", entry.file_name.as_str()),
+        ("{doc_type}", entry.doc_type.as_deref().unwrap_or_default()),
+        ("{sha256}", entry.sha256.as_str()),
+    ];
+
+    // A single pass, so placeholders appearing inside values are left alone
+    let mut command = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        command.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match placeholders.iter().find(|(placeholder, _)| rest.starts_with(placeholder)) {
+            Some((placeholder, value)) => {
+                command.push_str(&shell_quote(value));
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                command.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    command.push_str(rest);
+    command
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn entry(local_path: &Path, file_name: &str) -> ManifestEntry {
+        serde_json::from_value(serde_json::json!({
+            "applicationNum": "40201912345X",
+            "fileId": "77",
+            "docType": null,
+            "fileName": file_name,
+            "localPath": local_path,
+            "size": 3,
+            "sha256": "abc",
+            "downloadedAt": "2024-01-01T00:00:00+08:00",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn render_quotes_every_value() {
+        let entry = entry(Path::new("images/40201912345X_it's.jpg"), "it's; rm -rf ~.jpg");
+        assert_eq!(
+            render("convert {path} --name {file_name} --id {file_id} {doc_type}", &entry),
+            r"convert 'images/40201912345X_it'\''s.jpg' --name 'it'\''s; rm -rf ~.jpg' --id '77' ''"
+        );
+    }
+
+    #[test]
+    fn render_substitutes_in_a_single_pass() {
+        // A value holding a placeholder is not expanded again
+        let entry = entry(Path::new("images/a.jpg"), "{sha256}.jpg");
+        assert_eq!(render("{file_name} {sha256}", &entry), "'{sha256}.jpg' 'abc'");
+    }
+
+    #[test]
+    fn render_leaves_unknown_braces() {
+        let entry = entry(Path::new("images/a.jpg"), "a.jpg");
+        assert_eq!(render("awk '{print $1}' {app_num} {", &entry), "awk '{print $1}' '40201912345X' {");
+    }
+
+    #[tokio::test]
+    async fn hook_runs_the_command_and_counts_failures() {
+        let dir = std::env::temp_dir().join(format!("hook-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("40201912345X_rep.jpg");
+        let entry = entry(&image, "rep.jpg");
+
+        let hook = PostDownloadHook::new("printf %s {app_num} > {path}.marker".to_string(), 2, Duration::from_secs(10));
+        hook.run(&entry).await;
+        assert_eq!(std::fs::read_to_string(dir.join("40201912345X_rep.jpg.marker")).unwrap(), "40201912345X");
+        assert_eq!(hook.failures(), 0);
+
+        let failing = PostDownloadHook::new("exit 3".to_string(), 1, Duration::from_secs(10));
+        failing.run(&entry).await;
+        let slow = PostDownloadHook::new("sleep 5".to_string(), 1, Duration::from_millis(100));
+        slow.run(&entry).await;
+        assert_eq!(failing.failures() + slow.failures(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OwnedMutexGuard;
+
+/// Paths currently being downloaded, so two tasks resolving to the same file
+/// never write it at the same time. A task that finds its path taken waits for
+/// the first one to finish and then sees its result on disk.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    paths: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl InFlight {
+    /// Wait until no other task holds `path`, then hold it until the returned
+    /// claim is dropped
+    pub async fn claim(self: &Arc<Self>, path: &Path) -> PathClaim {
+        let lock = self.paths.lock()
+            .expect("in-flight registry poisoned")
+            .entry(path.to_path_buf())
+            .or_default()
+            .clone();
+        let guard = lock.lock_owned().await;
+
+        PathClaim { registry: self.clone(), path: path.to_path_buf(), guard: Some(guard) }
+    }
+}
+
+/// Exclusive hold on a path in the registry
+#[derive(Debug)]
+pub struct PathClaim {
+    registry: Arc<InFlight>,
+    path: PathBuf,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for PathClaim {
+    fn drop(&mut self) {
+        // Let go of the lock first: the next holder may finish before a
+        // guard dropped after this body would give its reference back, and
+        // then neither of us would see the lock unused
+        drop(self.guard.take());
+        let Ok(mut paths) = self.registry.paths.lock() else {
+            return;
+        };
+        // Only the map refers to the lock when nobody holds or waits for it
+        if paths.get(&self.path).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+            paths.remove(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn duplicate_tasks_write_each_path_once() {
+        const PATHS: usize = 8;
+        const TASKS: usize = 400;
+        let registry = Arc::new(InFlight::default());
+        let written = Arc::new(Mutex::new(HashSet::new()));
+        let writes: Arc<Vec<AtomicUsize>> = Arc::new((0..PATHS).map(|_| AtomicUsize::new(0)).collect());
+        let holders: Arc<Vec<AtomicUsize>> = Arc::new((0..PATHS).map(|_| AtomicUsize::new(0)).collect());
+
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|task| {
+                let (registry, written, writes, holders) = (registry.clone(), written.clone(), writes.clone(), holders.clone());
+                tokio::spawn(async move {
+                    let index = task % PATHS;
+                    let path = PathBuf::from(format!("images/{}_rep.jpg", index));
+                    let _claim = registry.claim(&path).await;
+                    assert_eq!(holders[index].fetch_add(1, Ordering::SeqCst), 0, "two tasks hold {}", path.display());
+                    // Like a download: look for the file, then write it after a pause
+                    let exists = written.lock().unwrap().contains(&path);
+                    if !exists {
+                        tokio::task::yield_now().await;
+                        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                        writes[index].fetch_add(1, Ordering::SeqCst);
+                        written.lock().unwrap().insert(path);
+                    }
+                    holders[index].fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert!(writes.iter().all(|writes| writes.load(Ordering::SeqCst) == 1));
+        assert!(registry.paths.lock().unwrap().is_empty(), "claims left behind");
+    }
+}
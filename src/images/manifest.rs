@@ -0,0 +1,228 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use crate::data::DownloadTask;
+use super::checksum;
+use super::convert::ConvertedImage;
+
+/// Default manifest file name, written inside the images directory
+pub const MANIFEST_FILE_NAME: &str = "images_manifest.json";
+
+/// One downloaded image file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestEntry {
+    pub application_num: String,
+    pub file_id: Option<String>,
+    pub doc_type: Option<String>,
+    pub file_name: String,
+    pub local_path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    pub downloaded_at: String,
+
+    /// ETag sent by the server, used by `--refresh` to ask for changes only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+
+    /// Chinese characters of the parent trademark, used as ground truth when
+    /// the manifest is fed to the extractor
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chinese_character: Option<String>,
+
+    /// Why the document was not downloaded; such entries have no local file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+
+    /// PNG/JPEG copy made by `--convert-to`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub converted: Option<ConvertedImage>,
+
+    /// The original was deleted after conversion; `localPath`, `size` and
+    /// `sha256` still describe it as downloaded
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub original_removed: bool,
+
+    /// Preview made by `--thumbnails`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<PathBuf>,
+
+    /// Member name of the image in the `--archive` archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_member: Option<String>,
+
+    /// The loose image was removed after archiving (`--archive-only`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub archived_only: bool,
+
+    /// Where the image was uploaded with `--s3-bucket`; the local copy is
+    /// removed after a successful upload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s3_url: Option<String>,
+
+    /// URL the image was fetched from after the one in the data file expired
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refreshed_url: Option<String>,
+}
+
+impl ManifestEntry {
+    pub fn new(task: &DownloadTask, local_path: &Path, size: u64, sha256: String, downloaded_at: String) -> Self {
+        ManifestEntry {
+            application_num: task.app_num.clone(),
+            file_id: task.file_id.clone(),
+            doc_type: task.doc_type.clone(),
+            file_name: task.file_name.clone(),
+            local_path: local_path.to_path_buf(),
+            size,
+            sha256,
+            downloaded_at,
+            etag: None,
+            chinese_character: task.mark.chinese_character.clone(),
+            skipped: None,
+            converted: None,
+            original_removed: false,
+            thumbnail: None,
+            archive_member: None,
+            archived_only: false,
+            s3_url: None,
+            refreshed_url: None,
+        }
+    }
+
+    /// The file to read this image from: the converted copy if there is one
+    pub fn image_path(&self) -> &Path {
+        self.converted.as_ref().map_or(&self.local_path, |c| &c.path)
+    }
+
+    /// Files of this entry that should be on disk, with their hashes
+    pub fn files(&self) -> Vec<(&Path, &str)> {
+        // Archiving and uploading move only the file returned by `image_path`
+        let moved = self.archived_only || self.s3_url.is_some();
+        let mut files = Vec::new();
+        let original_moved = moved && self.converted.is_none();
+        if self.skipped.is_none() && !self.original_removed && !original_moved {
+            files.push((self.local_path.as_path(), self.sha256.as_str()));
+        }
+        if let Some(converted) = &self.converted
+            && !moved
+        {
+            files.push((converted.path.as_path(), converted.sha256.as_str()));
+        }
+        files
+    }
+
+    /// Build an entry recording a document that was deliberately not downloaded
+    pub fn skipped(task: &DownloadTask, local_path: &Path, reason: &str) -> Self {
+        ManifestEntry {
+            skipped: Some(reason.to_string()),
+            ..Self::new(task, local_path, 0, String::new(), String::new())
+        }
+    }
+
+    /// Build an entry for a file that is already on disk, hashing its contents
+    /// and using its modification time as the download timestamp
+    pub fn from_existing(task: &DownloadTask, local_path: &Path) -> Result<Self> {
+        let (size, sha256) = checksum::hash_file(local_path)?;
+        let modified = fs::metadata(local_path)
+            .and_then(|m| m.modified())
+            .map(|t| DateTime::<Local>::from(t).to_rfc3339())
+            .unwrap_or_default();
+
+        Ok(Self::new(task, local_path, size, sha256, modified))
+    }
+}
+
+/// Image manifest keyed by local path, stored as a JSON array
+#[derive(Debug, Default)]
+pub struct Manifest {
+    entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+impl Manifest {
+    /// Load a manifest, returning an empty one if the file doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open manifest: {}", path.display()))?;
+        let entries: Vec<ManifestEntry> = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse manifest: {}", path.display()))?;
+
+        Ok(Manifest {
+            entries: entries.into_iter().map(|e| (e.local_path.clone(), e)).collect(),
+        })
+    }
+
+    /// Write the manifest via a temporary file so a crash never leaves it truncated
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("json.tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create manifest: {}", tmp_path.display()))?;
+        let entries: Vec<&ManifestEntry> = self.entries.values().collect();
+        serde_json::to_writer_pretty(BufWriter::new(file), &entries)
+            .context("Failed to write manifest")?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to replace manifest: {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// Whether a downloaded file is recorded for a local path
+    pub fn contains(&self, local_path: &Path) -> bool {
+        self.entries.get(local_path).is_some_and(|e| e.skipped.is_none())
+    }
+
+    pub fn get(&self, local_path: &Path) -> Option<&ManifestEntry> {
+        self.entries.get(local_path)
+    }
+
+    pub fn get_mut(&mut self, local_path: &Path) -> Option<&mut ManifestEntry> {
+        self.entries.get_mut(local_path)
+    }
+
+    /// Point the entry for `from` at a new local path after the file moved.
+    /// Converted copies are matched on their own path.
+    pub fn relocate(&mut self, from: &Path, to: &Path) {
+        if let Some(mut entry) = self.entries.remove(from) {
+            entry.local_path = to.to_path_buf();
+            self.entries.insert(entry.local_path.clone(), entry);
+            return;
+        }
+        for entry in self.entries.values_mut() {
+            if let Some(converted) = &mut entry.converted
+                && converted.path == from
+            {
+                converted.path = to.to_path_buf();
+            }
+        }
+    }
+
+    /// Add or replace the entry for a local path
+    pub fn insert(&mut self, entry: ManifestEntry) {
+        self.entries.insert(entry.local_path.clone(), entry);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries.values()
+    }
+
+    /// Every file the manifest expects on disk, with its hash
+    pub fn files(&self) -> impl Iterator<Item = (&Path, &str)> {
+        self.entries.values().flat_map(ManifestEntry::files)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -0,0 +1,91 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+use crate::data::{self, DownloadTask};
+
+/// Looks up fresh document URLs for tasks whose stored URL has expired, by
+/// re-querying the API for the day the document was lodged. Each day is
+/// fetched at most once per run.
+#[derive(Debug)]
+pub struct UrlRefresher {
+    client: reqwest::Client,
+    days: Mutex<HashMap<String, Arc<DayItems>>>,
+    refreshed: AtomicUsize,
+    gone: AtomicUsize,
+}
+
+/// Records of a day, fetched by the first task that needs them; `None` if
+/// the fetch failed
+type DayItems = OnceCell<Option<Arc<Vec<Value>>>>;
+
+impl UrlRefresher {
+    pub fn new(client: reqwest::Client) -> Self {
+        UrlRefresher {
+            client,
+            days: Mutex::new(HashMap::new()),
+            refreshed: AtomicUsize::new(0),
+            gone: AtomicUsize::new(0),
+        }
+    }
+
+    /// Documents downloaded from a refreshed URL
+    pub fn refreshed(&self) -> usize {
+        self.refreshed.load(Ordering::Relaxed)
+    }
+
+    /// Documents whose record no longer offers a different URL
+    pub fn gone(&self) -> usize {
+        self.gone.load(Ordering::Relaxed)
+    }
+
+    /// The task as the API describes it today, if its URL changed. Documents
+    /// missing from the current record, or listed with the same URL, count
+    /// as gone.
+    pub async fn fresh_task(&self, task: &DownloadTask) -> Option<DownloadTask> {
+        let fresh = self.lookup(task).await.filter(|fresh| fresh.url != task.url);
+        if fresh.is_none() {
+            self.gone.fetch_add(1, Ordering::Relaxed);
+        }
+        fresh
+    }
+
+    /// Record that the download from a fresh URL succeeded
+    pub fn record_success(&self) {
+        self.refreshed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    async fn lookup(&self, task: &DownloadTask) -> Option<DownloadTask> {
+        // Lodgement dates may carry a time component
+        let date = task.lodgement_date.as_deref()?.get(..10)?;
+        let items = self.day(date).await?;
+
+        let item = items.iter().find(|item| {
+            item.get("applicationNum").and_then(|a| a.as_str()) == Some(task.app_num.as_str())
+        })?;
+        data::download_tasks(item).into_iter().find(|fresh| match &task.file_id {
+            Some(file_id) => fresh.file_id.as_ref() == Some(file_id),
+            None => fresh.file_name == task.file_name,
+        })
+    }
+
+    async fn day(&self, date: &str) -> Option<Arc<Vec<Value>>> {
+        // The map is only locked to find the day's cell, so a slow fetch
+        // holds up the tasks of that day, not those of the others
+        let cell = Arc::clone(self.days.lock().expect("day map lock poisoned").entry(date.to_string()).or_default());
+        cell.get_or_init(|| async {
+            println!("Re-fetching trademark records for {} to refresh expired URLs", date);
+            match data::fetch_items(&self.client, date).await {
+                Ok(items) => Some(Arc::new(items)),
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    None
+                }
+            }
+        })
+        .await
+        .clone()
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::{Context, Result};
+use aws_sdk_s3::Client;
+use aws_sdk_s3::primitives::ByteStream;
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::sleep;
+
+use crate::extract::retry;
+
+/// Bucket and key prefix that images are uploaded to
+#[derive(Debug, Clone)]
+pub struct S3Target {
+    client: Client,
+    bucket: String,
+    prefix: String,
+    retries: u32,
+}
+
+impl S3Target {
+    /// Connect using the standard AWS credential chain (environment, profile,
+    /// instance metadata). `endpoint` points the client at an S3-compatible
+    /// service such as MinIO.
+    pub async fn new(bucket: String, prefix: &str, endpoint: Option<&str>, retries: u32) -> Self {
+        let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        let mut builder = aws_sdk_s3::config::Builder::from(&config);
+        if let Some(endpoint) = endpoint {
+            // Most self-hosted services don't support virtual-hosted buckets
+            builder = builder.endpoint_url(endpoint).force_path_style(true);
+        }
+
+        S3Target {
+            client: Client::from_conf(builder.build()),
+            bucket,
+            prefix: prefix.trim_matches('/').to_string(),
+            retries,
+        }
+    }
+
+    /// Object key for a path relative to the images directory
+    pub fn key(&self, relative: &str) -> String {
+        if self.prefix.is_empty() {
+            relative.to_string()
+        } else {
+            format!("{}/{}", self.prefix, relative)
+        }
+    }
+
+    pub fn url(&self, key: &str) -> String {
+        format!("s3://{}/{}", self.bucket, key)
+    }
+
+    /// Key of an `s3://` URL in this bucket
+    pub fn key_from_url<'a>(&self, url: &'a str) -> Option<&'a str> {
+        url.strip_prefix("s3://")?.strip_prefix(self.bucket.as_str())?.strip_prefix('/')
+    }
+
+    /// Whether an object exists, via HeadObject
+    pub async fn exists(&self, key: &str) -> Result<bool> {
+        match self.client.head_object().bucket(&self.bucket).key(key).send().await {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Failed to check {}", self.url(key))),
+        }
+    }
+
+    /// Download an object into a file, retrying with `retry::backoff`
+    pub async fn download(&self, key: &str, path: &Path) -> Result<()> {
+        self.with_retries(|| async {
+            let object = self.client.get_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+                .await
+                .with_context(|| format!("Failed to download {}", self.url(key)))?;
+            let bytes = object.body.collect()
+                .await
+                .with_context(|| format!("Failed to download {}", self.url(key)))?
+                .into_bytes();
+            tokio::fs::write(path, &bytes)
+                .await
+                .with_context(|| format!("Failed to write {}", path.display()))
+        })
+        .await
+    }
+
+    /// Upload a file, retrying with `retry::backoff`
+    pub async fn upload(&self, path: &Path, key: &str) -> Result<()> {
+        self.with_retries(|| async {
+            let body = ByteStream::from_path(path)
+                .await
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            self.client.put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .body(body)
+                .send()
+                .await
+                .with_context(|| format!("Failed to upload to {}", self.url(key)))?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Run `request` until it succeeds or has been retried `retries` times
+    async fn with_retries<F, Fut>(&self, request: F) -> Result<()>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match request().await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    let delay = retry::backoff(attempt, Duration::from_secs(1));
+                    eprintln!("{:#}, retrying in {:.1}s ({}/{})", e, delay.as_secs_f64(), attempt, self.retries);
+                    sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
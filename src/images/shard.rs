@@ -0,0 +1,215 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::manifest::Manifest;
+use super::{checksum, is_bookkeeping, sidecar};
+
+/// How image files are laid out under the images directory
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Shard {
+    /// All files directly in the images directory
+    #[default]
+    Flat,
+    /// Subdirectory named after the first 4 characters of the application number
+    AppPrefix,
+    /// Subdirectory named after the first 2 hex characters of the file name's sha256
+    Hash,
+}
+
+impl Shard {
+    /// Path of an image called `image_name` under `dir` in this layout.
+    ///
+    /// The layout depends only on the image name, so a file can always be
+    /// moved between layouts without knowing which task it came from.
+    pub fn path(self, dir: &Path, image_name: &str) -> PathBuf {
+        match self {
+            Shard::Flat => dir.join(image_name),
+            Shard::AppPrefix => {
+                let prefix: String = image_name.chars().take(4).collect();
+                dir.join(prefix).join(image_name)
+            }
+            Shard::Hash => {
+                let digest = Sha256::digest(image_name.as_bytes());
+                dir.join(format!("{:02x}", digest[0])).join(image_name)
+            }
+        }
+    }
+}
+
+/// What `reshard` did with the images
+#[derive(Debug, Default)]
+pub struct Resharded {
+    /// Images moved into the new layout
+    pub moved: usize,
+    /// Images left where they were because a file already sits at their
+    /// place in the new layout
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Move every image (and its sidecar) in `images_dir` into the `to` layout,
+/// updating the manifest and checksum file. Nothing is re-downloaded.
+pub fn reshard(images_dir: &Path, manifest_path: &Path, to: Shard) -> Result<Resharded> {
+    let mut manifest = Manifest::load(manifest_path)?;
+    let files = checksum::walk_files(images_dir)?;
+    let names: std::collections::BTreeSet<String> = files.iter()
+        .filter_map(|p| p.to_str().map(String::from))
+        .collect();
+    let mut resharded = Resharded::default();
+
+    for path in &files {
+        let Some(image_name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let path_str = path.to_string_lossy();
+        if is_bookkeeping(image_name) || sidecar::is_sidecar(&path_str, &names) {
+            continue;
+        }
+
+        let target = to.path(images_dir, image_name);
+        if target == *path {
+            continue;
+        }
+        if target.exists() {
+            resharded.skipped.push(path.clone());
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        }
+        fs::rename(path, &target)
+            .with_context(|| format!("Failed to move {} to {}", path.display(), target.display()))?;
+
+        let old_sidecar = sidecar::sidecar_path(path);
+        if old_sidecar.exists() {
+            fs::rename(&old_sidecar, sidecar::sidecar_path(&target))
+                .with_context(|| format!("Failed to move sidecar: {}", old_sidecar.display()))?;
+        }
+
+        manifest.relocate(path, &target);
+        resharded.moved += 1;
+    }
+
+    // Documents skipped earlier, replaced by a converted copy or moved into an
+    // archive or bucket have no file here but should still point into the new layout
+    let skipped: Vec<PathBuf> = manifest.entries()
+        .filter(|e| e.skipped.is_some() || e.original_removed || e.archived_only || e.s3_url.is_some())
+        .map(|e| e.local_path.clone())
+        .collect();
+    for path in skipped {
+        if let Some(image_name) = path.file_name().and_then(|n| n.to_str()) {
+            manifest.relocate(&path, &to.path(images_dir, image_name));
+        }
+    }
+
+    remove_empty_dirs(images_dir);
+
+    manifest.save(manifest_path)?;
+    checksum::write_sums(&manifest, images_dir, &images_dir.join(checksum::SUMS_FILE_NAME))?;
+
+    Ok(resharded)
+}
+
+/// Remove directories left empty below `dir`, keeping `dir` itself
+fn remove_empty_dirs(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            remove_empty_dirs(&path);
+            // Fails harmlessly when the directory still has files in it
+            let _ = fs::remove_dir(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::DownloadTask;
+    use crate::images::manifest::{ManifestEntry, MANIFEST_FILE_NAME};
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tm-query-shard-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn task(file_name: &str) -> DownloadTask {
+        serde_json::from_value(serde_json::json!({
+            "url": format!("https://example.com/{}", file_name),
+            "applicationNum": &file_name[..12],
+            "fileName": file_name,
+            "fileId": null,
+            "docType": null,
+            "lodgementDate": null,
+        }))
+        .unwrap()
+    }
+
+    /// A flat images directory with one image, its sidecar, the manifest and
+    /// the sums
+    fn flat_dir(image_name: &str) -> (PathBuf, PathBuf) {
+        let dir = temp_dir();
+        let image = dir.join(image_name);
+        fs::write(&image, "image").unwrap();
+        fs::write(sidecar::sidecar_path(&image), "{}").unwrap();
+        let mut manifest = Manifest::default();
+        manifest.insert(ManifestEntry::from_existing(&task(image_name), &image).unwrap());
+        let manifest_path = dir.join(MANIFEST_FILE_NAME);
+        manifest.save(&manifest_path).unwrap();
+        checksum::write_sums(&manifest, &dir, &dir.join(checksum::SUMS_FILE_NAME)).unwrap();
+        (dir, manifest_path)
+    }
+
+    /// The images directory as the manifest and the sums have it
+    fn recorded(dir: &Path, manifest_path: &Path) -> (Vec<PathBuf>, Vec<String>) {
+        let manifest = Manifest::load(manifest_path).unwrap();
+        let sums = checksum::read_sums(&dir.join(checksum::SUMS_FILE_NAME)).unwrap();
+        (manifest.entries().map(|entry| entry.local_path.clone()).collect(), sums.into_keys().collect())
+    }
+
+    #[test]
+    fn reshard_moves_images_sidecars_and_records_there_and_back() {
+        let name = "40201912345X_rep.jpg";
+        let (dir, manifest_path) = flat_dir(name);
+        let sharded = Shard::AppPrefix.path(&dir, name);
+        assert_eq!(sharded, dir.join("4020").join(name));
+
+        let resharded = reshard(&dir, &manifest_path, Shard::AppPrefix).unwrap();
+        assert_eq!((resharded.moved, resharded.skipped.len()), (1, 0));
+        assert!(sharded.exists() && sidecar::sidecar_path(&sharded).exists());
+        assert!(!dir.join(name).exists() && !sidecar::sidecar_path(&dir.join(name)).exists());
+        assert_eq!(recorded(&dir, &manifest_path), (vec![sharded.clone()], vec![format!("4020/{}", name)]));
+
+        let resharded = reshard(&dir, &manifest_path, Shard::Flat).unwrap();
+        assert_eq!(resharded.moved, 1);
+        assert!(dir.join(name).exists() && sidecar::sidecar_path(&dir.join(name)).exists());
+        assert!(!dir.join("4020").exists());
+        assert_eq!(recorded(&dir, &manifest_path), (vec![dir.join(name)], vec![name.to_string()]));
+
+        // Nothing left to move
+        assert_eq!(reshard(&dir, &manifest_path, Shard::Flat).unwrap().moved, 0);
+    }
+
+    #[test]
+    fn reshard_leaves_an_image_whose_place_is_taken() {
+        let name = "40201912345X_rep.jpg";
+        let (dir, manifest_path) = flat_dir(name);
+        let sharded = Shard::Hash.path(&dir, name);
+        fs::create_dir_all(sharded.parent().unwrap()).unwrap();
+        fs::write(&sharded, "other").unwrap();
+
+        let resharded = reshard(&dir, &manifest_path, Shard::Hash).unwrap();
+        assert_eq!(resharded.moved, 0);
+        assert_eq!(resharded.skipped, [dir.join(name)]);
+        assert_eq!(fs::read_to_string(dir.join(name)).unwrap(), "image");
+        assert_eq!(recorded(&dir, &manifest_path).0, [dir.join(name)]);
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crate::data::{DownloadTask, MarkText};
+
+/// Provenance written next to each image as `<image name>.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Sidecar {
+    pub application_num: String,
+    pub file_id: Option<String>,
+    pub doc_type: Option<String>,
+    pub lodgement_date: Option<String>,
+    pub url: String,
+    #[serde(flatten)]
+    pub mark: MarkText,
+    pub downloaded_at: String,
+}
+
+impl Sidecar {
+    pub fn new(task: &DownloadTask, downloaded_at: String) -> Self {
+        Sidecar {
+            application_num: task.app_num.clone(),
+            file_id: task.file_id.clone(),
+            doc_type: task.doc_type.clone(),
+            lodgement_date: task.lodgement_date.clone(),
+            url: task.url.clone(),
+            mark: task.mark.clone(),
+            downloaded_at,
+        }
+    }
+
+    /// Read the sidecar of an image, if there is one
+    pub fn load(image_path: &Path) -> Option<Self> {
+        let file = File::open(sidecar_path(image_path)).ok()?;
+        serde_json::from_reader(std::io::BufReader::new(file)).ok()
+    }
+
+    /// Write the sidecar of an image via a temporary file and rename
+    pub fn save(&self, image_path: &Path) -> Result<()> {
+        let path = sidecar_path(image_path);
+        let tmp_path = path.with_extension("json.tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create sidecar: {}", tmp_path.display()))?;
+        serde_json::to_writer_pretty(file, self).context("Failed to write sidecar")?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Failed to save sidecar: {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Sidecar path for an image: the image path with `.json` appended
+pub fn sidecar_path(image_path: &Path) -> PathBuf {
+    let mut name = image_path.as_os_str().to_owned();
+    name.push(".json");
+    PathBuf::from(name)
+}
+
+/// Whether a file name looks like the sidecar of another file in `names`
+pub fn is_sidecar(name: &str, names: &std::collections::BTreeSet<String>) -> bool {
+    name.strip_suffix(".json").is_some_and(|image| names.contains(image))
+}
@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::format_bytes;
+
+/// Default run summary name, written inside the images directory
+pub const SUMMARY_FILE_NAME: &str = "images_summary.json";
+
+/// Number of files listed under `largest`
+const LARGEST_FILES: usize = 10;
+
+/// Upper bounds of the size histogram buckets; the last bucket is open ended
+const BUCKETS: [(&str, u64); 3] = [
+    ("<100KB", 100_000),
+    ("100KB-1MB", 1_000_000),
+    ("1MB-10MB", 10_000_000),
+];
+const LAST_BUCKET: &str = ">10MB";
+
+/// One file transferred in this run
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferredFile {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub seconds: f64,
+}
+
+/// Per-file byte counts and durations gathered during the image phase
+#[derive(Debug, Default)]
+pub struct DownloadStats {
+    files: Vec<TransferredFile>,
+    bytes_skipped: u64,
+    files_skipped: usize,
+}
+
+/// Size distribution bucket
+#[derive(Debug, Serialize)]
+pub struct SizeBucket {
+    pub bucket: &'static str,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// Totals derived from `DownloadStats` for the summary
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferSummary {
+    pub files_downloaded: usize,
+    pub bytes_downloaded: u64,
+    pub files_skipped: usize,
+    /// Bytes of images that already existed and were not transferred
+    pub bytes_skipped: u64,
+    pub elapsed_seconds: f64,
+    /// Bytes downloaded over the wall-clock time of the phase
+    pub average_bytes_per_second: f64,
+    /// Fastest single transfer
+    pub peak_bytes_per_second: f64,
+    pub largest: Vec<TransferredFile>,
+    pub size_histogram: Vec<SizeBucket>,
+}
+
+impl DownloadStats {
+    pub fn record_download(&mut self, path: PathBuf, bytes: u64, elapsed: Duration) {
+        self.files.push(TransferredFile { path, bytes, seconds: elapsed.as_secs_f64() });
+    }
+
+    pub fn record_skipped(&mut self, bytes: u64) {
+        self.files_skipped += 1;
+        self.bytes_skipped += bytes;
+    }
+
+    pub fn bytes_downloaded(&self) -> u64 {
+        self.files.iter().map(|f| f.bytes).sum()
+    }
+
+    pub fn summarize(&self, elapsed: Duration) -> TransferSummary {
+        let bytes_downloaded = self.bytes_downloaded();
+        let elapsed_seconds = elapsed.as_secs_f64();
+        let peak = self.files.iter()
+            .filter(|f| f.seconds > 0.0)
+            .map(|f| f.bytes as f64 / f.seconds)
+            .fold(0.0, f64::max);
+
+        let mut largest = self.files.clone();
+        largest.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+        largest.truncate(LARGEST_FILES);
+
+        let mut histogram: Vec<SizeBucket> = BUCKETS.iter()
+            .map(|(bucket, _)| *bucket)
+            .chain([LAST_BUCKET])
+            .map(|bucket| SizeBucket { bucket, files: 0, bytes: 0 })
+            .collect();
+        for file in &self.files {
+            let index = BUCKETS.iter().position(|(_, limit)| file.bytes < *limit).unwrap_or(BUCKETS.len());
+            histogram[index].files += 1;
+            histogram[index].bytes += file.bytes;
+        }
+
+        TransferSummary {
+            files_downloaded: self.files.len(),
+            bytes_downloaded,
+            files_skipped: self.files_skipped,
+            bytes_skipped: self.bytes_skipped,
+            elapsed_seconds,
+            average_bytes_per_second: bytes_downloaded as f64 / elapsed_seconds.max(f64::EPSILON),
+            peak_bytes_per_second: peak,
+            largest,
+            size_histogram: histogram,
+        }
+    }
+}
+
+impl TransferSummary {
+    /// Print the transfer statistics to stdout
+    pub fn print(&self) {
+        println!("Transferred {} in {:.1}s (average {}/s, peak {}/s)",
+            format_bytes(self.bytes_downloaded),
+            self.elapsed_seconds,
+            format_bytes(self.average_bytes_per_second as u64),
+            format_bytes(self.peak_bytes_per_second as u64)
+        );
+        if self.files_skipped > 0 {
+            println!("Skipped {} images already present ({})", self.files_skipped, format_bytes(self.bytes_skipped));
+        }
+        if self.largest.is_empty() {
+            return;
+        }
+        println!("Size distribution:");
+        for bucket in &self.size_histogram {
+            println!("  {:<10} {:>6} files {:>10}", bucket.bucket, bucket.files, format_bytes(bucket.bytes));
+        }
+        println!("Largest downloads:");
+        for file in &self.largest {
+            println!("  {:>10}  {}", format_bytes(file.bytes), file.path.display());
+        }
+    }
+}
+
+/// Machine-readable record of one image phase run
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunSummary {
+    pub started_at: String,
+    pub finished_at: String,
+    pub tasks: usize,
+    pub downloaded: usize,
+    pub refreshed: usize,
+    pub existing: usize,
+    pub too_large: usize,
+    pub failed: usize,
+    pub not_attempted: usize,
+    /// Documents fetched from a new URL after the stored one expired
+    pub urls_refreshed: usize,
+    /// Documents whose expired URL had no replacement in the API
+    pub urls_gone: usize,
+    pub transfer: TransferSummary,
+}
+
+impl RunSummary {
+    /// Write the summary as pretty JSON, replacing any previous one atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("json.tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create summary: {}", tmp_path.display()))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)
+            .context("Failed to write summary")?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to save summary: {}", path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Instant};
+
+/// Parse a transfer rate such as `10MB/s`, `512KiB/s` or a plain number of
+/// bytes per second. Decimal units are powers of 1000, binary units of 1024.
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim();
+    let rate = parse_size(trimmed.strip_suffix("/s").unwrap_or(trimmed))?;
+    if rate == 0 {
+        return Err("rate must be greater than zero".to_string());
+    }
+    Ok(rate)
+}
+
+/// Parse a byte count such as `1GB`, `1.5KiB` or a plain number of bytes.
+/// The count must come to a whole number of bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid size: {}", s);
+    let trimmed = s.trim();
+    let digits = |from: usize| trimmed[from..].find(|c: char| !c.is_ascii_digit()).map_or(trimmed.len(), |end| from + end);
+    let whole_end = digits(0);
+    let split = if trimmed[whole_end..].starts_with('.') { digits(whole_end + 1) } else { whole_end };
+    let (number, unit) = trimmed.split_at(split);
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if whole.is_empty() && fraction.is_empty() || unit.starts_with(|c: char| c == '.' || c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+
+    let multiplier: u128 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000 * 1000,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "kib" => 1024,
+        "mib" => 1024 * 1024,
+        "gib" => 1024 * 1024 * 1024,
+        other => return Err(format!("unknown unit: {}", other)),
+    };
+
+    // In integers, so a fraction of a byte is caught rather than rounded away
+    let scale = 10u128.checked_pow(fraction.len() as u32).ok_or_else(invalid)?;
+    let scaled: u128 = format!("{}{}", whole, fraction).parse().map_err(|_| invalid())?;
+    let bytes = scaled.checked_mul(multiplier).ok_or_else(invalid)?;
+    if bytes % scale != 0 {
+        return Err(format!("{} is not a whole number of bytes", s));
+    }
+    u64::try_from(bytes / scale).map_err(|_| invalid())
+}
+
+/// Token bucket shared by every download task, so the cap holds no matter
+/// how many downloads run at once
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec as f64,
+            bucket: Mutex::new(Bucket { tokens: bytes_per_sec as f64, last_refill: Instant::now() }),
+        }
+    }
+
+    /// Take `bytes` from the bucket, sleeping until the debt they create has
+    /// been paid off at the configured rate. At most one second of burst is
+    /// allowed to accumulate.
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last_refill).as_secs_f64() * self.bytes_per_sec;
+            bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec);
+            bucket.last_refill = now;
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn rates_take_decimal_and_binary_units() {
+        assert_eq!(parse_rate("10MB/s"), Ok(10_000_000));
+        assert_eq!(parse_rate("512KiB/s"), Ok(512 * 1024));
+        assert_eq!(parse_rate(" 2gib/s "), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_rate("4096"), Ok(4096));
+        assert_eq!(parse_rate("1.5k"), Ok(1500));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("0MB/s").is_err());
+        assert!(parse_rate("10MB/min").is_err());
+        assert!(parse_rate("/s").is_err());
+    }
+
+    #[test]
+    fn sizes_must_be_one_number_of_whole_bytes() {
+        assert_eq!(parse_size("1GB"), Ok(1_000_000_000));
+        assert_eq!(parse_size("1.5KiB"), Ok(1536));
+        assert_eq!(parse_size("0.5mb"), Ok(500_000));
+        assert_eq!(parse_size("100 B"), Ok(100));
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("7."), Ok(7));
+        assert_eq!(parse_size("1.2.3"), Err("invalid size: 1.2.3".to_string()));
+        assert_eq!(parse_size("1.5"), Err("1.5 is not a whole number of bytes".to_string()));
+        assert!(parse_size("1.0001KB").is_err());
+        assert_eq!(parse_size("10TB"), Err("unknown unit: tb".to_string()));
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size(".").is_err());
+        assert!(parse_size("").is_err());
+        assert!(parse_size("99999999999GiB").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_acquirers_share_the_rate() {
+        let limiter = Arc::new(RateLimiter::new(1000));
+        let started = Instant::now();
+        let tasks: Vec<_> = (0..5)
+            .map(|_| {
+                let limiter = Arc::clone(&limiter);
+                tokio::spawn(async move {
+                    for _ in 0..10 {
+                        limiter.acquire(100).await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // 5000 bytes at 1000 a second, after the first second's burst
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(4), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(4500), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,71 @@
+use anyhow::{Context, Result};
+use image::{ImageFormat, ImageReader};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Thumbnail path for an image: its file stem in `dir`, as PNG for PNG
+/// sources (to keep transparency) and JPEG otherwise
+pub fn thumbnail_path(dir: &Path, image: &Path) -> PathBuf {
+    let stem = image.file_stem().unwrap_or(image.as_os_str());
+    let is_png = image.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    dir.join(stem).with_extension(if is_png { "png" } else { "jpg" })
+}
+
+/// Write a thumbnail of `source` whose longest side is at most `size` pixels.
+/// Returns false without decoding anything if the thumbnail already exists.
+pub fn make_thumbnail(source: &Path, target: &Path, size: u32) -> Result<bool> {
+    if target.exists() {
+        return Ok(false);
+    }
+
+    let image = load_scaled(source, size)?;
+    let format = ImageFormat::from_path(target).unwrap_or(ImageFormat::Jpeg);
+    let image = match format {
+        ImageFormat::Png => image,
+        _ => image::DynamicImage::ImageRgb8(image.to_rgb8()),
+    };
+
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+    // Written under a temporary name so an interrupted run isn't mistaken
+    // for a finished thumbnail
+    let mut tmp_path = target.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    image.save_with_format(&tmp_path, format)
+        .with_context(|| format!("Failed to write thumbnail: {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, target)
+        .with_context(|| format!("Failed to save thumbnail: {}", target.display()))?;
+
+    Ok(true)
+}
+
+/// JPEG thumbnail of `source` whose longest side is at most `size` pixels,
+/// kept in memory
+pub fn thumbnail_jpeg(source: &Path, size: u32) -> Result<Vec<u8>> {
+    let image = load_scaled(source, size)?.to_rgb8();
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg)
+        .with_context(|| format!("Failed to encode thumbnail: {}", source.display()))?;
+    Ok(bytes)
+}
+
+/// Decode an image and shrink it to fit `size` pixels
+fn load_scaled(source: &Path, size: u32) -> Result<image::DynamicImage> {
+    let image = ImageReader::open(source)
+        .with_context(|| format!("Failed to open image: {}", source.display()))?
+        .with_guessed_format()
+        .with_context(|| format!("Failed to read image: {}", source.display()))?
+        .decode()
+        .with_context(|| format!("Failed to decode image: {}", source.display()))?;
+
+    // Small images are kept as they are rather than scaled up
+    Ok(if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    })
+}
@@ -0,0 +1,5 @@
+//! Shared code for the trademark downloader and the LLM extraction tools.
+
+pub mod data;
+pub mod extract;
+pub mod images;